@@ -0,0 +1,120 @@
+//! Serve an export over a custom transport with [`Server::serve_connection`].
+//!
+//! With no arguments, this connects the server and a client with an
+//! in-memory duplex (a pair of pipes) in the same process. With `vsock
+//! PORT` (Linux only), it instead accepts connections on a vsock port, for
+//! example to serve a disk to a VM without networking.
+
+use std::io::prelude::*;
+use std::thread;
+
+use color_eyre::eyre::bail;
+use color_eyre::Result;
+use readwrite::ReadWrite;
+
+use nbd::client::Client;
+use nbd::server::{MemBlocks, Server};
+
+/// Create a connected pair of in-memory duplex streams.
+fn duplex() -> (impl Read + Write, impl Read + Write) {
+    let (r1, w1) = pipe::pipe();
+    let (r2, w2) = pipe::pipe();
+    (ReadWrite::new(r1, w2), ReadWrite::new(r2, w1))
+}
+
+fn in_memory() -> Result<()> {
+    let server = Server::new(MemBlocks::new(vec![0u8; 1024 * 1024]));
+    let (server_stream, client_stream) = duplex();
+    let server = thread::spawn(move || server.serve_connection(server_stream));
+
+    let mut client = Client::new(client_stream)?;
+    client.write(4096, b"hello")?;
+    let data = client.read(4096, 5)?;
+    println!("read back {:?}", String::from_utf8_lossy(&data));
+    client.disconnect()?;
+    server.join().unwrap()?;
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+mod vsock {
+    use std::fs::File;
+    use std::io;
+    use std::os::fd::{FromRawFd, OwnedFd, RawFd};
+
+    fn check(r: libc::c_int) -> io::Result<libc::c_int> {
+        if r < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(r)
+    }
+
+    /// A listening vsock socket.
+    pub struct Listener(OwnedFd);
+
+    impl Listener {
+        /// Listen on `port` for connections from any CID.
+        pub fn bind(port: u32) -> io::Result<Self> {
+            // SAFETY: plain system calls; the fd is owned by the Listener as
+            // soon as it is created
+            unsafe {
+                let fd = check(libc::socket(libc::AF_VSOCK, libc::SOCK_STREAM, 0))?;
+                let listener = Self(OwnedFd::from_raw_fd(fd));
+                let mut addr: libc::sockaddr_vm = std::mem::zeroed();
+                addr.svm_family = libc::AF_VSOCK as libc::sa_family_t;
+                addr.svm_cid = libc::VMADDR_CID_ANY;
+                addr.svm_port = port;
+                check(libc::bind(
+                    fd,
+                    &addr as *const libc::sockaddr_vm as *const libc::sockaddr,
+                    std::mem::size_of::<libc::sockaddr_vm>() as libc::socklen_t,
+                ))?;
+                check(libc::listen(fd, 16))?;
+                Ok(listener)
+            }
+        }
+
+        /// Accept a connection, as a File (which is a duplex stream for a
+        /// socket fd).
+        pub fn accept(&self) -> io::Result<File> {
+            use std::os::fd::AsRawFd;
+            let fd: RawFd = self.0.as_raw_fd();
+            // SAFETY: accept returns a new fd that we take ownership of
+            unsafe {
+                let conn = check(libc::accept(fd, std::ptr::null_mut(), std::ptr::null_mut()))?;
+                Ok(File::from(OwnedFd::from_raw_fd(conn)))
+            }
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn serve_vsock(port: u32) -> Result<()> {
+    let server = Server::new(MemBlocks::new(vec![0u8; 10 * 1024 * 1024]));
+    let listener = vsock::Listener::bind(port)?;
+    println!("serving on vsock port {port}");
+    loop {
+        let conn = listener.accept()?;
+        let server = server.clone();
+        thread::spawn(move || {
+            if let Err(err) = server.serve_connection(conn) {
+                eprintln!("error handling client:\n{err:?}");
+            }
+        });
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn serve_vsock(_port: u32) -> Result<()> {
+    bail!("vsock is only supported on Linux")
+}
+
+fn main() -> Result<()> {
+    color_eyre::install()?;
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match &args[..] {
+        [] => in_memory(),
+        [cmd, port] if cmd == "vsock" => serve_vsock(port.parse()?),
+        _ => bail!("usage: duplex [vsock PORT]"),
+    }
+}
@@ -1,11 +1,24 @@
 use clap::Parser;
-use color_eyre::eyre::{bail, WrapErr};
+use color_eyre::eyre::{bail, eyre, WrapErr};
 use color_eyre::Result;
 use fork::{daemon, Fork};
 
 use std::fs::{File, OpenOptions};
+use std::io::prelude::*;
+use std::os::unix::io::IntoRawFd;
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread::{self, sleep};
+use std::time::Duration;
 
-use nbd::{client::Client, kernel};
+use nbd::client::{Client, ClientOptions, Connection, NbdUrl, SetTimeout, TlsRelay, Transport};
+use nbd::kernel;
+use nbd::rustls::{
+    crypto::ring,
+    pki_types::{pem::PemObject, CertificateDer},
+    ClientConfig, RootCertStore,
+};
 
 #[derive(Parser, Debug)]
 #[clap(version, about, long_about = None)]
@@ -13,22 +26,187 @@ struct Args {
     #[clap(short = 'a', long, default_value = "localhost")]
     host: String,
 
+    #[clap(
+        long,
+        conflicts_with = "host",
+        help = "server URL (nbd://host[:port]/export or nbd+unix:///export?socket=PATH)"
+    )]
+    url: Option<String>,
+
+    #[clap(
+        long,
+        value_name = "PATH",
+        conflicts_with_all = ["host", "url"],
+        help = "connect to a server listening on a Unix socket (such as qemu-nbd --socket)"
+    )]
+    socket: Option<String>,
+
+    #[clap(
+        short,
+        long,
+        default_value = "default",
+        conflicts_with = "url",
+        help = "name of the export to connect to"
+    )]
+    export: String,
+
+    #[clap(
+        long,
+        requires = "ca_file",
+        conflicts_with = "socket",
+        help = "connect over TLS (the TLS session is relayed to the kernel by this process)"
+    )]
+    tls: bool,
+
+    #[clap(
+        long,
+        value_name = "PATH",
+        requires = "tls",
+        help = "PEM file with the certificates to trust for --tls"
+    )]
+    ca_file: Option<PathBuf>,
+
+    #[clap(
+        long,
+        value_name = "SECS",
+        help = "give up if the server does not respond for this long, while connecting or once the device is set up (0 for no timeout)"
+    )]
+    timeout: Option<u64>,
+
+    #[clap(
+        long,
+        default_value_t = 1,
+        value_parser = clap::value_parser!(u32).range(1..),
+        help = "number of connections to the server (more than one sets up the device over netlink)"
+    )]
+    connections: u32,
+
     #[clap(short, long, help = "disconnect from an existing client")]
     disconnect: bool,
 
     #[clap(short, long, help = "keep running in the foreground (don't daemonize)")]
     foreground: bool,
 
-    #[clap(default_value = "/dev/nbd0", help = "nbd device to set up")]
-    device: String,
+    #[clap(long, help = "disconnect the device first if it is already connected")]
+    force: bool,
+
+    #[clap(
+        required_if_eq("disconnect", "true"),
+        help = "nbd device to set up (by default, the first one not in use)"
+    )]
+    device: Option<PathBuf>,
 }
 
-fn open_nbd(args: &Args) -> Result<File> {
+fn open_nbd(device: &Path) -> Result<File> {
     OpenOptions::new()
         .read(true)
         .write(true)
-        .open(&args.device)
-        .wrap_err("opening nbd device")
+        .open(device)
+        .wrap_err_with(|| format!("opening nbd device {}", device.display()))
+}
+
+/// Wait for the kernel to finish tearing down an existing connection.
+fn wait_disconnected(device: &Path) -> Result<()> {
+    for _ in 0..50 {
+        if kernel::connected_pid(device)?.is_none() {
+            return Ok(());
+        }
+        sleep(Duration::from_millis(100));
+    }
+    bail!("timed out disconnecting {}", device.display())
+}
+
+/// Get the index of an nbd device, such as 3 for /dev/nbd3.
+fn device_index(device: &Path) -> Result<u32> {
+    device
+        .file_name()
+        .and_then(|name| name.to_str())
+        .and_then(|name| name.strip_prefix("nbd"))
+        .and_then(|n| n.parse().ok())
+        .ok_or_else(|| eyre!("{} is not an nbd device", device.display()))
+}
+
+/// Load a TLS configuration that trusts the certificates in the PEM file
+/// `ca_file`.
+fn tls_config(ca_file: &Path) -> Result<Arc<ClientConfig>> {
+    let mut roots = RootCertStore::empty();
+    let certs = CertificateDer::pem_file_iter(ca_file)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .wrap_err_with(|| format!("reading certificates from {}", ca_file.display()))?;
+    for cert in certs {
+        roots.add(cert)?;
+    }
+    let config = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()?
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    Ok(Arc::new(config))
+}
+
+/// Run `relays` until their connections close.
+fn run_relays(relays: Vec<TlsRelay<Connection>>) -> Result<()> {
+    let threads: Vec<_> = relays
+        .into_iter()
+        .map(|relay| thread::spawn(move || relay.run()))
+        .collect();
+    for thread in threads {
+        thread.join().unwrap().wrap_err("relaying TLS connection")?;
+    }
+    Ok(())
+}
+
+/// Run `relays` in the background, reporting if they fail.
+fn spawn_relays(relays: Vec<TlsRelay<Connection>>) {
+    thread::spawn(move || {
+        if let Err(err) = run_relays(relays) {
+            eprintln!("{err:?}");
+        }
+    });
+}
+
+/// Set up `device` with `clients`, and keep running `relays` (which carry
+/// the clients' connections over TLS) for as long as it is connected.
+fn attach<IO: Read + Write + IntoRawFd>(
+    nbd: &File,
+    device: &Path,
+    clients: Vec<Client<IO>>,
+    relays: Vec<TlsRelay<Connection>>,
+    timeout: Option<Duration>,
+    foreground: bool,
+) -> Result<()> {
+    if clients.len() > 1 {
+        let index = device_index(device)?;
+        // the kernel keeps the device connected without a process to wait in
+        // DO_IT, but TLS connections still need this process to relay them
+        kernel::netlink::set_clients(Some(index), clients, timeout)?;
+        if relays.is_empty() {
+            return Ok(());
+        }
+        if foreground {
+            return run_relays(relays);
+        }
+        if let Ok(Fork::Child) = daemon(false, false) {
+            run_relays(relays)?;
+        }
+        return Ok(());
+    }
+
+    let client = clients.into_iter().next().expect("no clients");
+    kernel::set_client(nbd, client, timeout)?;
+
+    // threads do not survive daemonizing, so the relays start afterward
+    if foreground {
+        spawn_relays(relays);
+        kernel::wait(nbd)?;
+        return Ok(());
+    }
+
+    if let Ok(Fork::Child) = daemon(false, false) {
+        spawn_relays(relays);
+        kernel::wait(nbd)?;
+    }
+
+    Ok(())
 }
 
 fn main() -> Result<()> {
@@ -42,30 +220,95 @@ fn main() -> Result<()> {
     }
 
     if args.disconnect {
-        let nbd = open_nbd(&args)?;
+        // required by clap with --disconnect
+        let device = args.device.as_deref().unwrap();
+        let nbd = open_nbd(device)?;
         kernel::close(&nbd)?;
         return Ok(());
     }
 
-    let client = Client::connect(&args.host).wrap_err("connecting to nbd server")?;
-
-    let nbd = match open_nbd(&args) {
+    let device = match &args.device {
+        Some(device) => device.clone(),
+        None => {
+            let device = kernel::find_free_device()?;
+            println!("{}", device.display());
+            device
+        }
+    };
+    let nbd = match open_nbd(&device) {
         Ok(nbd) => nbd,
         Err(err) => {
             eprintln!("could not open nbd device - do you need to run sudo modprobe nbd?");
             return Err(err);
         }
     };
-    kernel::set_client(&nbd, client)?;
-
-    if args.foreground {
-        kernel::wait(&nbd)?;
-        return Ok(());
+    if let Some(pid) = kernel::connected_pid(&device)? {
+        if !args.force {
+            bail!(
+                "{} is already in use by pid {pid} (use --force to disconnect it)",
+                device.display()
+            );
+        }
+        kernel::close(&nbd)?;
+        wait_disconnected(&device)?;
     }
+    let (transport, export) = match (&args.url, &args.socket) {
+        (Some(url), _) => {
+            let url: NbdUrl = url.parse()?;
+            (url.transport, url.export)
+        }
+        (None, Some(path)) => (Transport::Unix(path.into()), args.export.clone()),
+        (None, None) => (
+            Transport::Tcp {
+                host: args.host.clone(),
+                port: 10809,
+            },
+            args.export.clone(),
+        ),
+    };
+    // 0 means no timeout, which sockets express as None
+    let timeout = args
+        .timeout
+        .filter(|&secs| secs != 0)
+        .map(Duration::from_secs);
+    let connect = || -> Result<Client<Connection>> {
+        let stream = Connection::connect(&transport)
+            .wrap_err_with(|| format!("connecting to {transport:?}"))?;
+        stream.set_timeout(timeout)?;
+        let mut client =
+            Client::new_with_export(stream, &export).wrap_err("connecting to nbd server")?;
+        // the kernel takes over the socket
+        client.set_timeout(None)?;
+        Ok(client)
+    };
 
-    if let Ok(Fork::Child) = daemon(false, false) {
-        kernel::wait(&nbd)?;
+    if let Some(ca_file) = &args.ca_file {
+        let config = tls_config(ca_file)?;
+        let Transport::Tcp { host, .. } = &transport else {
+            bail!("--tls needs a TCP connection to the server");
+        };
+        let connect_tls = || -> Result<(Client<UnixStream>, TlsRelay<Connection>)> {
+            let stream = Connection::connect(&transport)
+                .wrap_err_with(|| format!("connecting to {transport:?}"))?;
+            stream.set_timeout(timeout)?;
+            let opts = ClientOptions {
+                export_name: Some(export.clone()),
+                ..Default::default()
+            };
+            let client = Client::with_tls_options(stream, config.clone(), host, opts)
+                .wrap_err("connecting to nbd server")?;
+            client.into_relay()
+        };
+        let (clients, relays) = (0..args.connections)
+            .map(|_| connect_tls())
+            .collect::<Result<Vec<_>>>()?
+            .into_iter()
+            .unzip();
+        return attach(&nbd, &device, clients, relays, timeout, args.foreground);
     }
 
-    Ok(())
+    let clients = (0..args.connections)
+        .map(|_| connect())
+        .collect::<Result<Vec<_>>>()?;
+    attach(&nbd, &device, clients, vec![], timeout, args.foreground)
 }
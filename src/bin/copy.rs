@@ -0,0 +1,76 @@
+use clap::Parser;
+use color_eyre::eyre::WrapErr;
+use color_eyre::Result;
+use log::info;
+
+use std::fs::File;
+use std::net::TcpStream;
+use std::path::PathBuf;
+
+use nbd::client::{Client, ClientOptions};
+
+/// Copy an export from an NBD server to a local file, keeping it sparse.
+#[derive(Parser, Debug)]
+#[clap(version, about, long_about = None)]
+struct Args {
+    #[clap(short = 'a', long, default_value = "localhost")]
+    host: String,
+
+    #[clap(
+        short,
+        long,
+        default_value = "default",
+        help = "name of the export to copy"
+    )]
+    export: String,
+
+    #[clap(
+        short = 'j',
+        long,
+        default_value_t = 4,
+        help = "number of reads to keep in flight"
+    )]
+    concurrency: usize,
+
+    #[clap(
+        long,
+        value_name = "BYTES",
+        default_value_t = 1 << 30,
+        value_parser = clap::value_parser!(u32).range(1..),
+        help = "bytes of the export to ask about in each block status request"
+    )]
+    status_window: u32,
+
+    #[clap(help = "file to copy the export to (overwritten if it exists)")]
+    output: PathBuf,
+}
+
+fn main() -> Result<()> {
+    color_eyre::install()?;
+    env_logger::init();
+
+    let args = Args::parse();
+
+    let stream = TcpStream::connect((args.host.as_str(), 10809))
+        .wrap_err_with(|| format!("connecting to {}", args.host))?;
+    stream.set_nodelay(true)?;
+    // falls back to reading everything if the server does not support block
+    // status
+    let opts = ClientOptions {
+        structured_replies: true,
+        block_status: true,
+        export_name: Some(args.export.clone()),
+        ..Default::default()
+    };
+    let mut client = Client::with_options(stream, opts).wrap_err("connecting to nbd server")?;
+    if !client.capabilities().block_status {
+        info!("server does not support block status, copying the whole export");
+    }
+
+    let mut file = File::create(&args.output)
+        .wrap_err_with(|| format!("creating {}", args.output.display()))?;
+    client.copy_to(&mut file, args.concurrency, args.status_window)?;
+    file.sync_all()?;
+    client.disconnect()?;
+    Ok(())
+}
@@ -1,8 +1,161 @@
 use clap::Parser;
+use color_eyre::eyre::{bail, eyre, WrapErr};
 use color_eyre::Result;
-use std::fs::OpenOptions;
+use log::warn;
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::os::unix::fs::FileTypeExt;
+use std::str::FromStr;
+use std::sync::Arc;
 
-use nbd::server::{MemBlocks, Server};
+#[cfg(feature = "tls")]
+use nbd::rustls::{
+    crypto::ring,
+    pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+    ServerConfig,
+};
+#[cfg(feature = "testutil")]
+use nbd::server::BadSectorBlocks;
+use nbd::server::{Blocks, MemBlocks, PersistentMemBlocks, Server, ShmBlocks, SubBlocks};
+
+/// An export of a byte range of a file, written as `name=path:offset:length`.
+#[derive(Debug, Clone)]
+struct ExportSpec {
+    name: String,
+    path: String,
+    offset: u64,
+    len: u64,
+}
+
+impl FromStr for ExportSpec {
+    type Err = color_eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (name, range) = s
+            .split_once('=')
+            .ok_or_else(|| eyre!("expected name=path:offset:length"))?;
+        let mut parts = range.rsplitn(3, ':');
+        let (Some(len), Some(offset), Some(path)) = (parts.next(), parts.next(), parts.next())
+        else {
+            bail!("expected name=path:offset:length");
+        };
+        let offset: u64 = offset.parse().wrap_err("invalid offset")?;
+        let len: u64 = len.parse().wrap_err("invalid length")?;
+        if offset.checked_add(len).is_none() {
+            bail!("offset + length overflows");
+        }
+        Ok(Self {
+            name: name.to_string(),
+            path: path.to_string(),
+            offset,
+            len,
+        })
+    }
+}
+
+impl ExportSpec {
+    /// The end of the range (which fits in a u64, as checked when parsing).
+    fn end(&self) -> u64 {
+        self.offset + self.len
+    }
+}
+
+/// Parse a preferred block size, which must be a power of two between 512
+/// and the server's maximum block size (256KiB).
+fn parse_block_size(s: &str) -> Result<u32> {
+    let size: u32 = s
+        .parse()
+        .wrap_err_with(|| format!("invalid block size {s:?}"))?;
+    if !size.is_power_of_two() || !(512..=256 * 1024).contains(&size) {
+        bail!("block size must be a power of two from 512 to 256K");
+    }
+    Ok(size)
+}
+
+/// A size in bytes, written as a number with an optional suffix: `B` for
+/// bytes, or `K`, `M`, `G`, or `T` for KiB, MiB, GiB, or TiB. A bare number is
+/// in MiB.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct ByteSize(u64);
+
+impl FromStr for ByteSize {
+    type Err = color_eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let digits = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+        let (num, suffix) = s.split_at(digits);
+        let num: u64 = num
+            .parse()
+            .wrap_err_with(|| format!("invalid size {s:?}"))?;
+        let shift = match suffix.to_ascii_uppercase().as_str() {
+            "B" => 0,
+            "K" | "KB" | "KIB" => 10,
+            "" | "M" | "MB" | "MIB" => 20,
+            "G" | "GB" | "GIB" => 30,
+            "T" | "TB" | "TIB" => 40,
+            _ => bail!("invalid size suffix {suffix:?} in {s:?}"),
+        };
+        let bytes = num
+            .checked_mul(1 << shift)
+            .ok_or_else(|| eyre!("size {s:?} is too large"))?;
+        Ok(Self(bytes))
+    }
+}
+
+/// A range of simulated bad sectors, written as `START-END` (in 512-byte
+/// sectors, excluding END), optionally followed by `:read` or `:write` to only
+/// fail that kind of access.
+#[cfg(feature = "testutil")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct BadSectorSpec {
+    sectors: std::ops::Range<u64>,
+    reads: bool,
+    writes: bool,
+}
+
+#[cfg(feature = "testutil")]
+impl BadSectorSpec {
+    fn apply<F: Blocks>(&self, blocks: BadSectorBlocks<F>) -> BadSectorBlocks<F> {
+        let blocks = if self.reads {
+            blocks.bad_reads(self.sectors.clone())
+        } else {
+            blocks
+        };
+        if self.writes {
+            blocks.bad_writes(self.sectors.clone())
+        } else {
+            blocks
+        }
+    }
+}
+
+#[cfg(feature = "testutil")]
+impl FromStr for BadSectorSpec {
+    type Err = color_eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (range, access) = s.split_once(':').unwrap_or((s, ""));
+        let (reads, writes) = match access {
+            "" => (true, true),
+            "read" => (true, false),
+            "write" => (false, true),
+            _ => bail!("expected :read or :write after the sector range, not {access:?}"),
+        };
+        let (start, end) = range
+            .split_once('-')
+            .ok_or_else(|| eyre!("expected START-END"))?;
+        let start: u64 = start.parse().wrap_err("invalid start sector")?;
+        let end: u64 = end.parse().wrap_err("invalid end sector")?;
+        if start >= end {
+            bail!("empty sector range {range}");
+        }
+        Ok(Self {
+            sectors: start..end,
+            reads,
+            writes,
+        })
+    }
+}
 
 #[derive(Parser, Debug)]
 #[clap(version, about, long_about = None)]
@@ -10,39 +163,357 @@ struct Args {
     #[clap(long)]
     no_create: bool,
 
-    #[clap(short, long, default_value_t = 10)]
-    size: usize,
+    #[clap(
+        short,
+        long,
+        default_value = "10",
+        help = "export size: a number with an optional suffix B, K, M, G, or T (default MiB)"
+    )]
+    size: ByteSize,
+
+    #[clap(
+        long,
+        help = "truncate FILENAME if it is larger than --size (destroying data past the end)"
+    )]
+    allow_shrink: bool,
 
     #[clap(short, long)]
     mem: bool,
 
-    #[clap(default_value = "disk.img")]
+    #[clap(
+        long,
+        value_name = "NAME",
+        conflicts_with_all = ["mem", "exports"],
+        help = "export the POSIX shared-memory object NAME (such as /vm-disk), \
+                created with --size if it does not exist"
+    )]
+    shm: Option<String>,
+
+    #[clap(
+        long,
+        requires = "mem",
+        help = "initialize the in-memory export from this file (instead of zeros)"
+    )]
+    init_from: Option<String>,
+
+    #[clap(
+        long,
+        requires = "init_from",
+        help = "write the in-memory export back to the --init-from file on flush"
+    )]
+    persist: bool,
+
+    #[clap(
+        long,
+        default_value_t = 4096,
+        value_parser = parse_block_size,
+        help = "preferred block size advertised to clients"
+    )]
+    block_size: u32,
+
+    #[clap(
+        long,
+        help = "export read-only (FILENAME is opened read-only and never created or resized)"
+    )]
+    read_only: bool,
+
+    #[cfg(feature = "tls")]
+    #[clap(
+        long,
+        value_name = "PATH",
+        requires = "tls_key",
+        help = "support TLS with the certificate chain in this PEM file"
+    )]
+    tls_cert: Option<String>,
+
+    #[cfg(feature = "tls")]
+    #[clap(
+        long,
+        value_name = "PATH",
+        requires = "tls_cert",
+        help = "private key for --tls-cert, in a PEM file"
+    )]
+    tls_key: Option<String>,
+
+    #[cfg(feature = "tls")]
+    #[clap(
+        long,
+        requires = "tls_cert",
+        help = "reject clients that do not negotiate TLS"
+    )]
+    require_tls: bool,
+
+    #[clap(
+        long = "export",
+        value_name = "NAME=PATH:OFFSET:LENGTH",
+        help = "export a byte range of a file (can be repeated; replaces FILENAME)"
+    )]
+    exports: Vec<ExportSpec>,
+
+    #[clap(
+        long,
+        value_name = "ADDR",
+        default_value = "127.0.0.1",
+        conflicts_with = "unix",
+        help = "address to listen on, such as 0.0.0.0 for all interfaces"
+    )]
+    bind: String,
+
+    #[clap(
+        long,
+        default_value_t = 10809,
+        conflicts_with = "unix",
+        help = "TCP port to listen on"
+    )]
+    port: u16,
+
+    #[clap(
+        long,
+        value_name = "PATH",
+        help = "listen on a Unix socket at PATH instead of TCP"
+    )]
+    unix: Option<String>,
+
+    #[clap(
+        long,
+        value_name = "PATH",
+        help = "accept management commands (status, flush, disconnect, shutdown) on this Unix socket"
+    )]
+    control_socket: Option<String>,
+
+    #[cfg(feature = "testutil")]
+    #[clap(
+        long,
+        value_name = "START-END[:read|:write]",
+        conflicts_with = "exports",
+        help = "fail accesses to these 512-byte sectors with EIO, for testing (can be repeated)"
+    )]
+    bad_sectors: Vec<BadSectorSpec>,
+
+    #[clap(
+        default_value = "disk.img",
+        help = "file to export, created with --size if it does not exist; a block device \
+                (such as a partition) is exported at its own size"
+    )]
     filename: String,
 }
 
+/// Load the server side of a TLS configuration from PEM files with the
+/// server's certificate chain and private key.
+#[cfg(feature = "tls")]
+fn tls_config(cert: &str, key: &str) -> Result<Arc<ServerConfig>> {
+    let certs = CertificateDer::pem_file_iter(cert)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .wrap_err_with(|| format!("reading TLS certificate {cert}"))?;
+    let key =
+        PrivateKeyDer::from_pem_file(key).wrap_err_with(|| format!("reading TLS key {key}"))?;
+    let config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .wrap_err("invalid TLS certificate or key")?;
+    Ok(Arc::new(config))
+}
+
+fn start<F: Blocks + Sync + Send + 'static>(server: Server<F>, args: &Args) -> Result<()> {
+    let server = server
+        .preferred_block_size(args.block_size)
+        .read_only(args.read_only);
+    #[cfg(feature = "tls")]
+    let server = match (&args.tls_cert, &args.tls_key) {
+        (Some(cert), Some(key)) => server
+            .starttls(tls_config(cert, key)?)
+            .require_tls(args.require_tls),
+        _ => server,
+    };
+    if let Some(path) = &args.control_socket {
+        server.control_socket(path)?;
+    }
+    match &args.unix {
+        Some(path) => server.start_unix(path),
+        None => server.start_on((args.bind.as_str(), args.port)),
+    }
+}
+
+fn serve<F: Blocks + Sync + Send + 'static>(blocks: F, args: &Args) -> Result<()> {
+    #[cfg(feature = "testutil")]
+    if !args.bad_sectors.is_empty() {
+        let blocks = args
+            .bad_sectors
+            .iter()
+            .fold(BadSectorBlocks::new(blocks), |blocks, spec| {
+                spec.apply(blocks)
+            });
+        return start(Server::new(blocks), args);
+    }
+    start(Server::new(blocks), args)
+}
+
+/// Serve each export spec from a range of a file shared among the exports.
+fn serve_ranges(args: &Args) -> Result<()> {
+    let mut files: HashMap<&str, Arc<File>> = HashMap::new();
+    let mut server: Option<Server<SubBlocks<File>>> = None;
+    for (i, spec) in args.exports.iter().enumerate() {
+        let file = match files.get(spec.path.as_str()) {
+            Some(file) => file.clone(),
+            None => {
+                let file = OpenOptions::new()
+                    .read(true)
+                    .write(!args.read_only)
+                    .open(&spec.path)
+                    .wrap_err_with(|| format!("opening {}", spec.path))?;
+                let file = Arc::new(file);
+                files.insert(&spec.path, file.clone());
+                file
+            }
+        };
+        let file_size = file.size()?;
+        if spec.end() > file_size {
+            bail!(
+                "export {} goes past the end of {} ({file_size} bytes)",
+                spec.name,
+                spec.path
+            );
+        }
+        for other in &args.exports[..i] {
+            if other.path == spec.path && other.offset < spec.end() && spec.offset < other.end() {
+                warn!("exports {} and {} overlap", other.name, spec.name);
+            }
+        }
+        let blocks = SubBlocks::new(file, spec.offset, spec.len);
+        server = Some(match server {
+            None => Server::with_export(&spec.name, blocks),
+            Some(server) => server.add_export(&spec.name, blocks),
+        });
+    }
+    start(server.expect("no exports"), args)
+}
+
+/// Warn if the block device at `path` is mounted, since the filesystem and
+/// NBD clients would both write to it.
+fn warn_if_mounted(path: &str) {
+    let Ok(dev) = fs::canonicalize(path) else {
+        return;
+    };
+    let Ok(mounts) = fs::read_to_string("/proc/mounts") else {
+        return;
+    };
+    for line in mounts.lines() {
+        let mut fields = line.split_whitespace();
+        let (Some(source), Some(target)) = (fields.next(), fields.next()) else {
+            continue;
+        };
+        if fs::canonicalize(source).is_ok_and(|source| source == dev) {
+            warn!("{path} is mounted at {target}: concurrent writes will corrupt it");
+        }
+    }
+}
+
 fn main() -> Result<()> {
     color_eyre::install()?;
     env_logger::init();
 
     let args = Args::parse();
     let create = !args.no_create;
-    let size_bytes = args.size as u64 * 1024 * 1024;
+    let size_bytes = args.size.0;
+
+    if !args.exports.is_empty() {
+        return serve_ranges(&args);
+    }
+
+    if let Some(path) = &args.init_from {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(args.persist)
+            .open(path)
+            .wrap_err_with(|| format!("opening {path}"))?;
+        if args.persist {
+            return serve(PersistentMemBlocks::load(file)?, &args);
+        }
+        return serve(MemBlocks::load(&file)?, &args);
+    }
+
+    if let Some(name) = &args.shm {
+        let blocks =
+            ShmBlocks::open(name, size_bytes).wrap_err_with(|| format!("opening {name}"))?;
+        return serve(blocks, &args);
+    }
 
     if args.mem {
         let data = vec![0u8; size_bytes as usize];
-        let export = MemBlocks::new(data);
-        Server::new(export).start()?;
-        return Ok(());
+        return serve(MemBlocks::new(data), &args);
+    }
+
+    if args.read_only {
+        let file =
+            File::open(&args.filename).wrap_err_with(|| format!("opening {}", args.filename))?;
+        return serve(file, &args);
     }
 
     let file = OpenOptions::new()
         .read(true)
         .write(true)
         .create(create)
-        .open(args.filename)?;
+        .open(&args.filename)?;
+
+    if file.metadata()?.file_type().is_block_device() {
+        // export the whole device (for example, a partition) at its own size
+        warn_if_mounted(&args.filename);
+        return serve(file, &args);
+    }
 
-    file.set_len(size_bytes)?;
+    let len = file.metadata()?.len();
+    if len > size_bytes && !args.allow_shrink {
+        bail!(
+            "{} is {len} bytes, larger than the requested size of {size_bytes} bytes \
+            (pass --allow-shrink to truncate it)",
+            args.filename
+        );
+    }
+    if len != size_bytes {
+        file.set_len(size_bytes)?;
+    }
+
+    serve(file, &args)
+}
 
-    Server::new(file).start()?;
-    Ok(())
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_size() -> Result<()> {
+        assert_eq!("512".parse::<ByteSize>()?, ByteSize(512 << 20));
+        assert_eq!("4096B".parse::<ByteSize>()?, ByteSize(4096));
+        assert_eq!("15341b".parse::<ByteSize>()?, ByteSize(15341));
+        assert_eq!("64K".parse::<ByteSize>()?, ByteSize(64 << 10));
+        assert_eq!("10M".parse::<ByteSize>()?, ByteSize(10 << 20));
+        assert_eq!("10MiB".parse::<ByteSize>()?, ByteSize(10 << 20));
+        assert_eq!("2G".parse::<ByteSize>()?, ByteSize(2 << 30));
+        assert_eq!("1T".parse::<ByteSize>()?, ByteSize(1 << 40));
+        for s in ["", "M", "10X", "1.5G", "-1", "99999999999T"] {
+            assert!(s.parse::<ByteSize>().is_err(), "parsed {s:?}");
+        }
+        Ok(())
+    }
+
+    #[cfg(feature = "testutil")]
+    #[test]
+    fn test_parse_bad_sectors() -> Result<()> {
+        let spec: BadSectorSpec = "8-16".parse()?;
+        assert_eq!(
+            spec,
+            BadSectorSpec {
+                sectors: 8..16,
+                reads: true,
+                writes: true
+            }
+        );
+        let spec: BadSectorSpec = "0-1:write".parse()?;
+        assert!(!spec.reads && spec.writes);
+        for s in ["8", "16-8", "1-2:both", "a-b"] {
+            assert!(s.parse::<BadSectorSpec>().is_err(), "parsed {s:?}");
+        }
+        Ok(())
+    }
 }
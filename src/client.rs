@@ -3,22 +3,291 @@
 //! See the documentation for [`Client`].
 #![deny(missing_docs)]
 
-use color_eyre::eyre::bail;
+use color_eyre::eyre::{bail, Report, WrapErr};
 use color_eyre::Result;
 
 use std::{
+    fmt,
     io::prelude::*,
     net::TcpStream,
-    os::unix::io::{IntoRawFd, RawFd},
+    ops::ControlFlow,
+    os::unix::io::{AsFd, BorrowedFd, IntoRawFd, RawFd},
+    os::unix::net::UnixStream,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
 };
 
 use byteorder::{ReadBytesExt, WriteBytesExt, BE};
 
 use crate::proto::*;
 
+#[cfg(feature = "tokio")]
+mod async_client;
+mod copy;
+mod reconnect;
+mod status;
+#[cfg(feature = "tls")]
+mod tls;
+mod url;
+#[cfg(feature = "tokio")]
+pub use async_client::AsyncClient;
+pub use reconnect::ReconnectingClient;
+pub use status::Extent;
+#[cfg(feature = "tls")]
+pub use tls::{TlsRelay, TlsStream};
+pub use url::{NbdUrl, Transport};
+
 #[derive(Debug)]
 struct Export {
     size: u64,
+    flags: TransmitFlags,
+    block_size: BlockSize,
+}
+
+impl Export {
+    /// Read an export's size and transmission flags, as sent in reply to
+    /// NBD_OPT_EXPORT_NAME and in NBD_INFO_EXPORT.
+    fn get(stream: &mut impl Read) -> Result<Self> {
+        let size = stream.read_u64::<BE>()?;
+        let transmit_flags = stream.read_u16::<BE>()?;
+        let flags = TransmitFlags::from_bits(transmit_flags).ok_or_else(|| {
+            ProtocolError::new(format!("invalid transmit flags {transmit_flags}"))
+        })?;
+        Ok(Export {
+            size,
+            flags,
+            block_size: BlockSize::default(),
+        })
+    }
+}
+
+/// Capabilities summarizes the commands and features a server supports for an
+/// export, as negotiated during the handshake.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Capabilities {
+    /// Reads are always supported.
+    pub read: bool,
+    /// The export is writable (not read-only).
+    pub write: bool,
+    /// NBD_CMD_FLUSH is supported.
+    pub flush: bool,
+    /// The FUA (force unit access) flag is supported on writes.
+    pub fua: bool,
+    /// NBD_CMD_TRIM is supported.
+    pub trim: bool,
+    /// NBD_CMD_WRITE_ZEROES is supported.
+    pub write_zeroes: bool,
+    /// NBD_CMD_CACHE is supported.
+    pub cache: bool,
+    /// NBD_CMD_RESIZE is supported.
+    pub resize: bool,
+    /// The server allows several connections to this export at once, with
+    /// a flush on any of them persisting writes from all of them.
+    pub multi_conn: bool,
+    /// [`Client::block_status`] can be used (the base:allocation metadata
+    /// context was negotiated, see [`ClientOptions::block_status`]).
+    pub block_status: bool,
+    /// Structured replies were negotiated (see
+    /// [`ClientOptions::structured_replies`]).
+    pub structured_replies: bool,
+}
+
+/// Errors from transmission-phase operations on a [`Client`].
+///
+/// Client methods return [`color_eyre::Report`]s, which can be downcast to an
+/// `NbdError` to distinguish failures: for example, reconnect logic can check
+/// for an [`NbdError::Io`] with kind [`std::io::ErrorKind::ConnectionReset`].
+#[derive(Debug)]
+pub enum NbdError {
+    /// Communicating with the server failed.
+    Io(std::io::Error),
+    /// The server's reply did not follow the protocol (for example, it had
+    /// the wrong magic or handle). The connection is out of sync afterward, so
+    /// the client fails all further commands with this error.
+    Protocol(String),
+    /// The server did not reply within the timeout set with
+    /// [`Client::set_timeout`]. A late reply would be mistaken for the reply
+    /// to a later command, so the client fails all further commands.
+    TimedOut,
+    /// The server reported an error for a command.
+    Server {
+        /// The command that failed, such as `"READ"`.
+        command: String,
+        /// The NBD error value (a Linux errno, such as 5 for EIO).
+        errno: u32,
+    },
+}
+
+impl NbdError {
+    /// Convert an I/O error anywhere in `report` to an [`NbdError::Io`], and
+    /// a protocol error to an [`NbdError::Protocol`].
+    fn from_report(report: Report) -> Report {
+        let report = match report.downcast::<ProtocolError>() {
+            Ok(err) => return NbdError::Protocol(err.0).into(),
+            Err(report) => report,
+        };
+        match report.downcast::<std::io::Error>() {
+            // a socket read timeout shows up as EAGAIN
+            Ok(err)
+                if matches!(
+                    err.kind(),
+                    std::io::ErrorKind::TimedOut | std::io::ErrorKind::WouldBlock
+                ) =>
+            {
+                NbdError::TimedOut.into()
+            }
+            Ok(err) => NbdError::Io(err).into(),
+            Err(report) => report,
+        }
+    }
+}
+
+impl fmt::Display for NbdError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NbdError::Io(err) => write!(f, "communicating with server: {err}"),
+            NbdError::Protocol(msg) => write!(f, "nbd protocol error: {msg}"),
+            NbdError::TimedOut => write!(f, "timed out waiting for the server"),
+            NbdError::Server { command, errno } => {
+                write!(f, "{command} failed: {}", ErrorType::from_wire(*errno))
+            }
+        }
+    }
+}
+
+impl std::error::Error for NbdError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            NbdError::Io(err) => Some(err),
+            NbdError::Protocol(_) | NbdError::TimedOut | NbdError::Server { .. } => None,
+        }
+    }
+}
+
+/// Options for negotiating a connection, for use with
+/// [`Client::with_options`].
+#[derive(Debug, Clone, Default)]
+pub struct ClientOptions {
+    /// Request structured replies, which let the server skip sending data for
+    /// holes.
+    ///
+    /// The kernel does not support structured replies, so a client
+    /// negotiated with this option cannot be passed to
+    /// [`crate::kernel::set_client`].
+    pub structured_replies: bool,
+    /// Negotiate the base:allocation metadata context, so that
+    /// [`Client::block_status`] can find holes and zeroed ranges. This
+    /// requires `structured_replies`.
+    pub block_status: bool,
+    /// Limit on the length of a single [`Client::read`], in addition to the
+    /// server's maximum block size.
+    pub max_read_len: Option<u32>,
+    /// Name of the export to connect to (the default is "default").
+    pub export_name: Option<String>,
+}
+
+/// The error for an option the server rejected with `reply`, starting with
+/// `msg`.
+fn option_error(msg: &str, reply: &OptReply) -> ProtocolError {
+    let mut msg = msg.to_string();
+    // error replies may carry a message from the server
+    if !reply.data.is_empty() {
+        msg += &format!(": {}", String::from_utf8_lossy(&reply.data));
+    }
+    ProtocolError::new(msg)
+}
+
+/// Read the server's greeting, returning the flags the client replies with.
+fn get_greeting(stream: &mut impl Read) -> Result<ClientHandshakeFlags> {
+    let magic = stream.read_u64::<BE>()?;
+    if magic != MAGIC {
+        bail!(unexpected_start("magic", &magic.to_be_bytes()));
+    }
+    let opt_magic = stream.read_u64::<BE>()?;
+    if opt_magic == OLDSTYLE_MAGIC {
+        bail!(ProtocolError::new(
+            "server is using the oldstyle handshake, which is not supported"
+        ));
+    }
+    if opt_magic != IHAVEOPT {
+        bail!(ProtocolError::new(format!(
+            "unexpected IHAVEOPT value {opt_magic}",
+        )))
+    }
+    let server_flags = stream.read_u16::<BE>()?;
+    let server_flags = HandshakeFlags::from_bits(server_flags)
+        .ok_or_else(|| ProtocolError::new(format!("unexpected server flags {server_flags}")))?;
+    if !server_flags.contains(HandshakeFlags::FIXED_NEWSTYLE | HandshakeFlags::NO_ZEROES) {
+        bail!(ProtocolError::new("server does not support NO_ZEROES"));
+    }
+    Ok(ClientHandshakeFlags::C_FIXED_NEWSTYLE | ClientHandshakeFlags::C_NO_ZEROES)
+}
+
+/// The server's replies to NBD_OPT_GO, collected until it acknowledges the
+/// option.
+#[derive(Debug, Default)]
+struct GoReplies {
+    export: Option<Export>,
+    block_size: BlockSize,
+}
+
+impl GoReplies {
+    /// The NBD_OPT_GO option for export `name`, which (unlike
+    /// NBD_OPT_EXPORT_NAME) also asks for the server's block size
+    /// constraints.
+    fn option(name: &str) -> Result<Opt> {
+        let mut data = vec![];
+        InfoRequest {
+            name: name.to_string(),
+            typs: vec![InfoType::EXPORT, InfoType::BLOCK_SIZE],
+        }
+        .put(&mut data)?;
+        Ok(Opt {
+            typ: OptType::GO,
+            data,
+        })
+    }
+
+    /// Handle the next reply, breaking with the export once the server
+    /// acknowledges the option (or with None if the server does not support
+    /// NBD_OPT_GO).
+    fn handle(&mut self, reply: OptReply) -> Result<ControlFlow<Option<Export>>> {
+        match reply.reply_type {
+            ReplyType::ACK => {
+                let mut export = self
+                    .export
+                    .take()
+                    .ok_or_else(|| ProtocolError::new("server did not send export info"))?;
+                export.block_size = self.block_size;
+                return Ok(ControlFlow::Break(Some(export)));
+            }
+            ReplyType::INFO => {
+                let mut data = &reply.data[..];
+                let typ = data.read_u16::<BE>()?;
+                match InfoType::try_from(typ) {
+                    Ok(InfoType::EXPORT) => {
+                        self.export = Some(Export::get(&mut data)?);
+                    }
+                    Ok(InfoType::BLOCK_SIZE) => {
+                        self.block_size = BlockSize {
+                            minimum: data.read_u32::<BE>()?,
+                            preferred: data.read_u32::<BE>()?,
+                            maximum: data.read_u32::<BE>()?,
+                        };
+                    }
+                    // the client must ignore information it does not understand
+                    _ => {}
+                }
+            }
+            ReplyType::ERR_UNSUP => return Ok(ControlFlow::Break(None)),
+            ReplyType::ERR_TLS_REQD => bail!(option_error(
+                "NBD_OPT_GO failed: server requires TLS",
+                &reply
+            )),
+            typ => bail!(option_error(&format!("NBD_OPT_GO failed: {typ:?}"), &reply)),
+        }
+        Ok(ControlFlow::Continue(()))
+    }
 }
 
 /// Client provides an interface to an export from a remote NBD server.
@@ -26,114 +295,686 @@ struct Export {
 pub struct Client<IO: Read + Write> {
     conn: IO,
     export: Export,
+    structured_replies: bool,
+    /// The server's ID for the base:allocation context, if it was negotiated.
+    allocation_context: Option<u32>,
+    max_read_len: Option<u32>,
+    /// Handle for the next request. Handles only need to be unique within a
+    /// connection, so a counter guarantees that where random values might
+    /// collide.
+    next_handle: AtomicU64,
+    /// A reply broke the protocol, so the connection is out of sync.
+    desynced: Option<String>,
 }
 
 impl<IO: Read + Write> Client<IO> {
     fn initial_handshake(stream: &mut (impl Read + Write)) -> Result<()> {
-        let magic = stream.read_u64::<BE>()?;
-        if magic != MAGIC {
-            bail!(ProtocolError::new(format!("unexpected magic {}", magic)));
-        }
-        let opt_magic = stream.read_u64::<BE>()?;
-        if opt_magic != IHAVEOPT {
-            bail!(ProtocolError::new(format!(
-                "unexpected IHAVEOPT value {opt_magic}",
-            )))
-        }
-        let server_flags = stream.read_u16::<BE>()?;
-        let server_flags = HandshakeFlags::from_bits(server_flags)
-            .ok_or_else(|| ProtocolError::new(format!("unexpected server flags {server_flags}")))?;
-        if !server_flags.contains(HandshakeFlags::FIXED_NEWSTYLE | HandshakeFlags::NO_ZEROES) {
-            bail!(ProtocolError::new("server does not support NO_ZEROES"));
-        }
-        let client_flags =
-            ClientHandshakeFlags::C_FIXED_NEWSTYLE | ClientHandshakeFlags::C_NO_ZEROES;
+        let client_flags = get_greeting(stream)?;
         stream.write_u32::<BE>(client_flags.bits())?;
         Ok(())
     }
 
-    fn get_export_info(stream: &mut impl Read) -> Result<(Export, TransmitFlags)> {
-        let size = stream.read_u64::<BE>()?;
-        let transmit_flags = stream.read_u16::<BE>()?;
-        let transmit_flags = TransmitFlags::from_bits(transmit_flags)
-            .ok_or_else(|| ProtocolError::new("invalid transmit flags {transmit_flags}"))?;
-        let export = Export { size };
-        Ok((export, transmit_flags))
+    /// Negotiate with NBD_OPT_GO, which (unlike NBD_OPT_EXPORT_NAME) also
+    /// gets the server's block size constraints.
+    ///
+    /// Returns Ok(None) if the server does not support NBD_OPT_GO.
+    fn handshake_go(stream: &mut (impl Read + Write), name: &str) -> Result<Option<Export>> {
+        GoReplies::option(name)?.put(stream)?;
+        let mut go = GoReplies::default();
+        loop {
+            if let ControlFlow::Break(export) = go.handle(OptReply::get(stream)?)? {
+                return Ok(export);
+            }
+        }
     }
 
-    fn handshake_haggle(stream: &mut (impl Read + Write)) -> Result<Export> {
+    fn handshake_haggle(stream: &mut (impl Read + Write), name: &str) -> Result<Export> {
+        if let Some(export) = Self::handshake_go(stream, name)? {
+            return Ok(export);
+        }
         Opt {
             typ: OptType::EXPORT_NAME,
-            data: b"default".to_vec(),
+            data: name.as_bytes().to_vec(),
         }
         .put(stream)?;
-        // ignore transmit flags for now (we don't send anything fancy anyway)
-        let (export, _transmit_flags) = Self::get_export_info(stream)?;
-        Ok(export)
+        Export::get(stream)
+    }
+
+    /// Get the names of the server's exports with NBD_OPT_LIST.
+    fn handshake_list(stream: &mut (impl Read + Write)) -> Result<Vec<String>> {
+        Opt {
+            typ: OptType::LIST,
+            data: vec![],
+        }
+        .put(stream)?;
+        let mut names = vec![];
+        loop {
+            let reply = OptReply::get(stream)?;
+            match reply.reply_type {
+                ReplyType::ACK => break,
+                ReplyType::SERVER => {
+                    // S: 32 bits, length of name (unsigned)
+                    // S: Name of the export, as expected by NBD_OPT_EXPORT_NAME
+                    // S: (remaining bytes) details, which this client ignores
+                    let mut data = &reply.data[..];
+                    let name_len = data.read_u32::<BE>()? as usize;
+                    let name = data
+                        .get(..name_len)
+                        .ok_or_else(|| ProtocolError::new("export name is too long"))?;
+                    names.push(String::from_utf8_lossy(name).into_owned());
+                }
+                // the server may stop partway through the list
+                ReplyType::ERR_SHUTDOWN => bail!(
+                    "server is shutting down: {}",
+                    String::from_utf8_lossy(&reply.data)
+                ),
+                typ => bail!(ProtocolError::new(format!("NBD_OPT_LIST failed: {typ:?}"))),
+            }
+        }
+        Ok(names)
+    }
+
+    /// Get the description of export `name` with NBD_OPT_INFO, without
+    /// starting transmission.
+    fn handshake_description(
+        stream: &mut (impl Read + Write),
+        name: &str,
+    ) -> Result<Option<String>> {
+        let mut data = vec![];
+        InfoRequest {
+            name: name.to_string(),
+            typs: vec![InfoType::DESCRIPTION],
+        }
+        .put(&mut data)?;
+        Opt {
+            typ: OptType::INFO,
+            data,
+        }
+        .put(stream)?;
+        let mut description = None;
+        loop {
+            let reply = OptReply::get(stream)?;
+            match reply.reply_type {
+                ReplyType::ACK => break,
+                ReplyType::INFO => {
+                    let mut data = &reply.data[..];
+                    let typ = data.read_u16::<BE>()?;
+                    if matches!(InfoType::try_from(typ), Ok(InfoType::DESCRIPTION)) {
+                        description = Some(String::from_utf8_lossy(data).into_owned());
+                    }
+                }
+                typ => bail!(ProtocolError::new(format!(
+                    "NBD_OPT_INFO for {name:?} failed: {typ:?}"
+                ))),
+            }
+        }
+        Ok(description)
+    }
+
+    fn handshake_abort(stream: &mut impl Write) -> Result<()> {
+        // the server may or may not reply, so this client just hangs up
+        Opt {
+            typ: OptType::ABORT,
+            data: vec![],
+        }
+        .put(stream)?;
+        stream.flush()?;
+        Ok(())
+    }
+
+    /// List the names of the server's exports, then end the connection
+    /// without starting transmission.
+    pub fn list_exports(mut stream: IO) -> Result<Vec<String>> {
+        Self::initial_handshake(&mut stream)?;
+        let names = Self::handshake_list(&mut stream)?;
+        Self::handshake_abort(&mut stream)?;
+        Ok(names)
+    }
+
+    /// List the server's exports along with their descriptions (if the
+    /// server has one for an export), then end the connection without
+    /// starting transmission.
+    pub fn list_exports_detailed(mut stream: IO) -> Result<Vec<(String, Option<String>)>> {
+        Self::initial_handshake(&mut stream)?;
+        let names = Self::handshake_list(&mut stream)?;
+        let mut exports = vec![];
+        for name in names {
+            let description = Self::handshake_description(&mut stream, &name)?;
+            exports.push((name, description));
+        }
+        Self::handshake_abort(&mut stream)?;
+        Ok(exports)
+    }
+
+    /// Request structured replies, returning whether the server agreed.
+    fn negotiate_structured_replies(stream: &mut (impl Read + Write)) -> Result<bool> {
+        Opt {
+            typ: OptType::STRUCTURED_REPLY,
+            data: vec![],
+        }
+        .put(stream)?;
+        let reply = OptReply::get(stream)?;
+        Ok(reply.reply_type == ReplyType::ACK)
+    }
+
+    /// Select the base:allocation metadata context for export `name`,
+    /// returning its ID if the server supports it.
+    fn negotiate_allocation_context(
+        stream: &mut (impl Read + Write),
+        name: &str,
+    ) -> Result<Option<u32>> {
+        let mut data = vec![];
+        MetaContextRequest {
+            name: name.to_string(),
+            queries: vec![ALLOCATION_CONTEXT.to_string()],
+        }
+        .put(&mut data)?;
+        Opt {
+            typ: OptType::SET_META_CONTEXT,
+            data,
+        }
+        .put(stream)?;
+        let mut context = None;
+        loop {
+            let reply = OptReply::get(stream)?;
+            match reply.reply_type {
+                ReplyType::META_CONTEXT => {
+                    // S: 32 bits, NBD metadata context ID.
+                    // S: String, name of the metadata context.
+                    let mut data = &reply.data[..];
+                    let id = data.read_u32::<BE>()?;
+                    if data == ALLOCATION_CONTEXT.as_bytes() {
+                        context = Some(id);
+                    }
+                }
+                ReplyType::ACK => return Ok(context),
+                // the server does not support metadata contexts
+                _ => return Ok(None),
+            }
+        }
     }
 
     /// Establish a handshake with stream and return a `Client` ready for use.
-    pub fn new(mut stream: IO) -> Result<Self> {
+    pub fn new(stream: IO) -> Result<Self> {
+        Self::with_options(stream, ClientOptions::default())
+    }
+
+    /// Establish a handshake with stream, connecting to export `name`.
+    pub fn new_with_export(stream: IO, name: &str) -> Result<Self> {
+        let opts = ClientOptions {
+            export_name: Some(name.to_string()),
+            ..Default::default()
+        };
+        Self::with_options(stream, opts)
+    }
+
+    /// Establish a handshake with stream, negotiating according to `opts`.
+    pub fn with_options(mut stream: IO, opts: ClientOptions) -> Result<Self> {
         Self::initial_handshake(&mut stream)?;
-        let export = Self::handshake_haggle(&mut stream)?;
+        Self::negotiate(stream, opts)
+    }
+
+    /// Establish a handshake over TLS: after the initial handshake on the
+    /// plaintext `stream`, send NBD_OPT_STARTTLS and, once the server agrees,
+    /// call `upgrade` to run the TLS handshake. The rest of negotiation and
+    /// all transmission happens over the upgraded stream.
+    #[cfg(feature = "tls")]
+    fn with_starttls<S: Read + Write>(
+        mut stream: S,
+        opts: ClientOptions,
+        upgrade: impl FnOnce(S) -> Result<IO>,
+    ) -> Result<Self> {
+        Self::initial_handshake(&mut stream)?;
+        Opt {
+            typ: OptType::STARTTLS,
+            data: vec![],
+        }
+        .put(&mut stream)?;
+        let reply = OptReply::get(&mut stream)?;
+        match reply.reply_type {
+            ReplyType::ACK => {}
+            ReplyType::ERR_UNSUP => bail!(option_error(
+                "NBD_OPT_STARTTLS failed: server does not support TLS",
+                &reply
+            )),
+            ReplyType::ERR_POLICY => bail!(option_error(
+                "NBD_OPT_STARTTLS failed: server refused TLS",
+                &reply
+            )),
+            typ => bail!(option_error(
+                &format!("NBD_OPT_STARTTLS failed: {typ:?}"),
+                &reply
+            )),
+        }
+        let stream = upgrade(stream)?;
+        Self::negotiate(stream, opts)
+    }
+
+    /// Negotiate options and an export after the initial handshake.
+    fn negotiate(mut stream: IO, opts: ClientOptions) -> Result<Self> {
+        let structured_replies =
+            opts.structured_replies && Self::negotiate_structured_replies(&mut stream)?;
+        let name = opts.export_name.as_deref().unwrap_or("default");
+        let allocation_context = if structured_replies && opts.block_status {
+            Self::negotiate_allocation_context(&mut stream, name)?
+        } else {
+            None
+        };
+        let export = Self::handshake_haggle(&mut stream, name)?;
         Ok(Self {
             conn: stream,
             export,
+            structured_replies,
+            allocation_context,
+            max_read_len: opts.max_read_len,
+            next_handle: AtomicU64::new(0),
+            desynced: None,
         })
     }
 
     /// Return the size of this export, as reported by the server during the
     /// handshake.
+    ///
+    /// This does not change if the export grows on the server; connect again
+    /// to get the current size.
     pub fn size(&self) -> u64 {
         self.export.size
     }
 
+    /// Return the commands and features the server supports for this export.
+    pub fn capabilities(&self) -> Capabilities {
+        let flags = self.export.flags;
+        Capabilities {
+            read: true,
+            write: !flags.contains(TransmitFlags::READ_ONLY),
+            flush: flags.contains(TransmitFlags::SEND_FLUSH),
+            fua: flags.contains(TransmitFlags::SEND_FUA),
+            trim: flags.contains(TransmitFlags::SEND_TRIM),
+            write_zeroes: flags.contains(TransmitFlags::SEND_WRITE_ZEROES),
+            cache: flags.contains(TransmitFlags::SEND_CACHE),
+            resize: flags.contains(TransmitFlags::SEND_RESIZE),
+            multi_conn: flags.contains(TransmitFlags::CAN_MULTI_CONN),
+            block_status: self.allocation_context.is_some(),
+            structured_replies: self.structured_replies,
+        }
+    }
+
+    /// The export rejects writes, so only reads (and flushes) are useful.
+    ///
+    /// See [`Client::capabilities`] for the other commands the server
+    /// supports.
+    pub fn is_read_only(&self) -> bool {
+        self.export.flags.contains(TransmitFlags::READ_ONLY)
+    }
+
+    /// Return the server's preferred block size for this export (4096 if the
+    /// server did not advertise one).
+    pub fn preferred_block_size(&self) -> u32 {
+        self.export.block_size.preferred
+    }
+
+    /// Return the server's (minimum, preferred, maximum) block sizes for this
+    /// export, as sent in reply to NBD_OPT_GO.
+    ///
+    /// A server that does not advertise constraints gets the defaults from
+    /// the protocol: (1, 4096, 32 MiB).
+    pub fn block_sizes(&self) -> (u32, u32, u32) {
+        let block_size = &self.export.block_size;
+        (block_size.minimum, block_size.preferred, block_size.maximum)
+    }
+
+    /// Number subsequent requests starting at `start`, so tests can predict
+    /// their handles.
+    ///
+    /// Only available with the `testutil` feature.
+    #[cfg(any(test, feature = "testutil"))]
+    pub fn sequential_handles(self, start: u64) -> Self {
+        self.next_handle.store(start, Ordering::Relaxed);
+        self
+    }
+
+    /// Create a request with a new handle.
+    fn request(&self, typ: Cmd, offset: u64, len: u32) -> Request {
+        let handle = self.next_handle.fetch_add(1, Ordering::Relaxed);
+        Request::with_handle(handle, typ, offset, len)
+    }
+
+    /// Get the next simple reply or structured reply chunk, which must be for
+    /// one of `reqs`, placing data for a read in the corresponding buffer in
+    /// `bufs`.
+    ///
+    /// Returns the index of the request, the error this part carries (OK if
+    /// none), and whether the reply to the request is now complete. A server
+    /// may send an error chunk and then more chunks, so callers keep the first
+    /// error until the reply is complete.
+    fn get_reply_part(
+        &mut self,
+        reqs: &[Request],
+        bufs: &mut [&mut [u8]],
+    ) -> Result<(usize, ErrorType, bool)> {
+        let header = ReplyHeader::get(&mut self.conn)?;
+        let handle = match &header {
+            ReplyHeader::Simple { handle, .. } => *handle,
+            ReplyHeader::Structured(chunk) => chunk.handle,
+        };
+        let Some(i) = reqs.iter().position(|req| req.handle == handle) else {
+            bail!(ProtocolError(format!("reply for wrong handle {handle}")));
+        };
+        match header {
+            ReplyHeader::Simple { err, .. } => {
+                if err == ErrorType::OK {
+                    self.conn.read_exact(bufs[i])?;
+                }
+                Ok((i, err, true))
+            }
+            ReplyHeader::Structured(chunk) => {
+                let err = self.get_chunk(&reqs[i], &chunk, bufs[i])?;
+                Ok((i, err, chunk.is_done()))
+            }
+        }
+    }
+
+    /// Get the reply to req, which is either a simple reply or a sequence of
+    /// structured reply chunks. Data for a read is placed in buf.
     fn get_reply_data(&mut self, req: &Request, buf: &mut [u8]) -> Result<()> {
-        let reply = SimpleReply::get(&mut self.conn, buf)?;
-        if reply.handle != req.handle {
-            bail!(format!(
-                "reply for wrong handle {} != {}",
-                reply.handle, req.handle
-            ))
+        let mut first_err = ErrorType::OK;
+        loop {
+            let (_, err, done) =
+                self.get_reply_part(std::slice::from_ref(req), &mut [&mut *buf])?;
+            if first_err == ErrorType::OK {
+                first_err = err;
+            }
+            if done {
+                break;
+            }
         }
-        if reply.err != ErrorType::OK {
-            bail!(format!("{:?} failed: {:?}", req.typ, reply.err))
+        Self::check_reply(req, first_err)
+    }
+
+    /// Turn the error in the reply to `req` into a [`NbdError::Server`].
+    fn check_reply(req: &Request, err: ErrorType) -> Result<()> {
+        if err != ErrorType::OK {
+            bail!(NbdError::Server {
+                command: format!("{:?}", req.typ),
+                errno: err.into(),
+            })
         }
         Ok(())
     }
 
-    fn get_ack(&mut self, req: &Request) -> Result<()> {
-        self.get_reply_data(req, &mut [])
+    /// Get the payload of one structured reply chunk, returning the error it
+    /// carries (if any).
+    fn get_chunk(
+        &mut self,
+        req: &Request,
+        chunk: &ChunkHeader,
+        buf: &mut [u8],
+    ) -> Result<ErrorType> {
+        // find the part of buf covered by a chunk at offset of size len
+        let buf_range = |offset: u64, len: u64| {
+            let start = offset
+                .checked_sub(req.offset)
+                .filter(|start| start + len <= buf.len() as u64);
+            match start {
+                Some(start) => Ok(start as usize..(start + len) as usize),
+                None => Err(ProtocolError::new(format!(
+                    "chunk {offset}+{len} outside of request"
+                ))),
+            }
+        };
+        match ChunkType::try_from(chunk.typ) {
+            Ok(ChunkType::OFFSET_DATA) => {
+                let offset = self.conn.read_u64::<BE>()?;
+                let len = (chunk.len as u64)
+                    .checked_sub(8)
+                    .ok_or_else(|| ProtocolError::new("data chunk is too short"))?;
+                let range = buf_range(offset, len)?;
+                self.conn.read_exact(&mut buf[range])?;
+                Ok(ErrorType::OK)
+            }
+            Ok(ChunkType::OFFSET_HOLE) => {
+                let offset = self.conn.read_u64::<BE>()?;
+                let len = self.conn.read_u32::<BE>()?;
+                let range = buf_range(offset, len as u64)?;
+                buf[range].fill(0);
+                Ok(ErrorType::OK)
+            }
+            Ok(ChunkType::ERROR) | Ok(ChunkType::ERROR_OFFSET) => {
+                let mut payload = vec![0u8; chunk.len as usize];
+                self.conn.read_exact(&mut payload)?;
+                let err = (&payload[..]).read_u32::<BE>()?;
+                Ok(ErrorType::from_wire(err))
+            }
+            _ => {
+                // skip chunks this client does not understand
+                let mut payload = vec![0u8; chunk.len as usize];
+                self.conn.read_exact(&mut payload)?;
+                if chunk.typ & (1 << 15) != 0 {
+                    // an unknown error type
+                    return Ok(ErrorType::EINVAL);
+                }
+                Ok(ErrorType::OK)
+            }
+        }
+    }
+
+    /// Send `req` (with `data` for a write) and get the reply, placing any
+    /// data read into `buf`.
+    ///
+    /// I/O errors communicating with the server are reported as
+    /// [`NbdError::Io`]. After a malformed reply, this and all later commands
+    /// fail with [`NbdError::Protocol`] without using the connection.
+    fn transmit(&mut self, req: &Request, data: &[u8], buf: &mut [u8]) -> Result<()> {
+        self.check_connection()?;
+        let r = req
+            .put(data, &mut self.conn)
+            .and_then(|_| self.get_reply_data(req, buf));
+        self.check_result(r)
+    }
+
+    /// Fail if an earlier reply left the connection out of sync.
+    fn check_connection(&self) -> Result<()> {
+        if let Some(msg) = &self.desynced {
+            bail!(NbdError::Protocol(format!(
+                "connection closed after an earlier error ({msg})"
+            )));
+        }
+        Ok(())
+    }
+
+    /// Convert errors communicating with the server to [`NbdError`]s, noting
+    /// if the connection is now out of sync.
+    fn check_result<T>(&mut self, r: Result<T>) -> Result<T> {
+        let r = r.map_err(NbdError::from_report);
+        if let Err(err) = &r {
+            match err.downcast_ref::<NbdError>() {
+                Some(NbdError::Protocol(msg)) => self.desynced = Some(msg.clone()),
+                Some(NbdError::TimedOut) => self.desynced = Some("a command timed out".to_string()),
+                _ => {}
+            }
+        }
+        r
     }
 
     /// Send a read command to the NBD server.
+    ///
+    /// Reads longer than the server's maximum block size (or
+    /// [`ClientOptions::max_read_len`]) fail without contacting the server.
     pub fn read(&mut self, offset: u64, len: u32) -> Result<Vec<u8>> {
-        let req = Request::new(Cmd::READ, offset, len);
-        req.put(&[], &mut self.conn)?;
+        let max = self.export.block_size.maximum;
+        let max = self.max_read_len.map_or(max, |limit| limit.min(max));
+        if len > max {
+            bail!(format!(
+                "read of {len} bytes is larger than the maximum of {max}"
+            ))
+        }
+        let req = self.request(Cmd::READ, offset, len);
         let mut buf = vec![0; len as usize];
-        self.get_reply_data(&req, &mut buf)?;
+        self.transmit(&req, &[], &mut buf)?;
         Ok(buf)
     }
 
+    /// Check that a write of `len` bytes fits in a single request, returning
+    /// the length to send.
+    fn write_len(&self, len: usize) -> Result<u32> {
+        let max = self.export.block_size.maximum;
+        match u32::try_from(len) {
+            Ok(len) if len <= max => Ok(len),
+            _ => bail!(format!(
+                "write of {len} bytes is larger than the maximum of {max}"
+            )),
+        }
+    }
+
     /// Send a write command to the NBD server.
+    ///
+    /// Writes longer than the server's maximum block size fail without
+    /// contacting the server.
     pub fn write(&mut self, offset: u64, data: &[u8]) -> Result<()> {
-        let req = Request::new(Cmd::WRITE, offset, data.len() as u32);
-        req.put(data, &mut self.conn)?;
-        self.get_ack(&req)?;
+        let len = self.write_len(data.len())?;
+        let req = self.request(Cmd::WRITE, offset, len);
+        self.transmit(&req, data, &mut [])?;
+        Ok(())
+    }
+
+    /// Send a write command with the FUA (force unit access) flag, so the
+    /// server persists the data before replying.
+    pub fn write_fua(&mut self, offset: u64, data: &[u8]) -> Result<()> {
+        let len = self.write_len(data.len())?;
+        let mut req = self.request(Cmd::WRITE, offset, len);
+        req.flags |= CmdFlags::FUA;
+        self.transmit(&req, data, &mut [])?;
+        Ok(())
+    }
+
+    /// Send a write zeroes command to the NBD server, setting `len` bytes
+    /// starting at `offset` to zero.
+    ///
+    /// If `no_hole` is set, the server must keep the range allocated rather
+    /// than punching a hole. Zero-length requests fail without contacting the
+    /// server.
+    pub fn write_zeroes(&mut self, offset: u64, len: u32, no_hole: bool) -> Result<()> {
+        if len == 0 {
+            bail!("write zeroes of 0 bytes is invalid");
+        }
+        let mut req = self.request(Cmd::WRITE_ZEROES, offset, len);
+        if no_hole {
+            req.flags |= CmdFlags::NO_HOLE;
+        }
+        match self.transmit(&req, &[], &mut []) {
+            Err(err)
+                if matches!(
+                    err.downcast_ref::<NbdError>(),
+                    Some(NbdError::Server { errno, .. })
+                        if ErrorType::from_wire(*errno) == ErrorType::ENOTSUP
+                ) =>
+            {
+                Err(err).wrap_err("server does not support write zeroes")
+            }
+            result => result,
+        }
+    }
+
+    /// Send a trim command to the NBD server, telling it that the `len` bytes
+    /// starting at `offset` are no longer needed.
+    ///
+    /// Fails without contacting the server if it did not advertise trim
+    /// support.
+    pub fn trim(&mut self, offset: u64, len: u32) -> Result<()> {
+        if !self.export.flags.contains(TransmitFlags::SEND_TRIM) {
+            bail!("server does not support trim for this export");
+        }
+        let req = self.request(Cmd::TRIM, offset, len);
+        self.transmit(&req, &[], &mut [])?;
         Ok(())
     }
 
+    /// Trim `len` bytes starting at `offset`, which may be larger than a
+    /// single request allows, by sending a trim for each chunk of up to the
+    /// server's maximum block size.
+    pub fn trim_range(&mut self, offset: u64, len: u64) -> Result<()> {
+        let max = self.export.block_size.maximum as u64;
+        let Some(end) = offset.checked_add(len) else {
+            bail!("trim of {len} bytes at {offset} overflows");
+        };
+        let mut off = offset;
+        while off < end {
+            let chunk = (end - off).min(max);
+            self.trim(off, chunk as u32)?;
+            off += chunk;
+        }
+        Ok(())
+    }
+
+    /// Resize the export to `size` bytes, using the NBD_CMD_RESIZE extension.
+    ///
+    /// On success, [`Client::size`] reports `size`.
+    pub fn resize(&mut self, size: u64) -> Result<()> {
+        let req = self.request(Cmd::RESIZE, size, 0);
+        self.transmit(&req, &[], &mut [])?;
+        self.export.size = size;
+        Ok(())
+    }
+
+    /// Check that the server is responsive, returning the round-trip time.
+    ///
+    /// NBD has no dedicated ping command, so this issues a minimal read (of the
+    /// server's minimum block size) at offset 0. Zero-length reads are
+    /// invalid, so for an empty export this sends a flush instead.
+    pub fn ping(&mut self) -> Result<Duration> {
+        let len = (self.export.block_size.minimum as u64).min(self.export.size);
+        let start = Instant::now();
+        if len == 0 {
+            self.flush()?;
+        } else {
+            self.read(0, len as u32)?;
+        }
+        Ok(start.elapsed())
+    }
+
     /// Send a flush command to the NBD server.
     pub fn flush(&mut self) -> Result<()> {
-        let req = Request::new(Cmd::FLUSH, 0, 0);
-        req.put(&[], &mut self.conn)?;
-        self.get_ack(&req)?;
+        let req = self.request(Cmd::FLUSH, 0, 0);
+        self.transmit(&req, &[], &mut [])?;
         Ok(())
     }
 
     /// Disconnect from server cleanly and consume this client.
     pub fn disconnect(mut self) -> Result<()> {
-        Request::new(Cmd::DISCONNECT, 0, 0).put(&[], &mut self.conn)?;
+        let req = self.request(Cmd::DISCONNECT, 0, 0);
+        req.put(&[], &mut self.conn)
+            .map_err(NbdError::from_report)?;
+        Ok(())
+    }
+}
+
+/// A connection whose reads and writes can time out, such as a socket.
+pub trait SetTimeout {
+    /// Make reads and writes that take longer than `timeout` fail (or wait
+    /// forever if `timeout` is None).
+    fn set_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()>;
+}
+
+impl SetTimeout for TcpStream {
+    fn set_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()> {
+        self.set_read_timeout(timeout)?;
+        self.set_write_timeout(timeout)
+    }
+}
+
+impl SetTimeout for UnixStream {
+    fn set_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()> {
+        self.set_read_timeout(timeout)?;
+        self.set_write_timeout(timeout)
+    }
+}
+
+impl<IO: Read + Write + SetTimeout> Client<IO> {
+    /// Fail commands with [`NbdError::TimedOut`] if the server takes longer
+    /// than `timeout` to accept a request or reply to it, rather than
+    /// waiting forever. None removes the timeout.
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) -> Result<()> {
+        self.conn.set_timeout(timeout)?;
         Ok(())
     }
 }
@@ -145,6 +986,102 @@ impl Client<TcpStream> {
         let stream = TcpStream::connect((host, TCP_PORT))?;
         Self::new(stream)
     }
+
+    /// Like [`Client::connect`], but connect to export `name` rather than
+    /// the default export.
+    pub fn connect_export(host: &str, name: &str) -> Result<Self> {
+        let stream = TcpStream::connect((host, TCP_PORT))?;
+        Self::new_with_export(stream, name)
+    }
+}
+
+impl Client<Connection> {
+    /// Connect to the server and export given by an NBD URL, such as
+    /// `nbd://localhost/default` or `nbd+unix:///default?socket=/tmp/nbd.sock`.
+    ///
+    /// See [`NbdUrl`] for the supported syntax.
+    pub fn connect_url(url: &str) -> Result<Self> {
+        let url: NbdUrl = url.parse()?;
+        let stream = Connection::connect(&url.transport)?;
+        let opts = ClientOptions {
+            export_name: Some(url.export),
+            ..Default::default()
+        };
+        Self::with_options(stream, opts)
+    }
+}
+
+/// A connection to a server over either TCP or a Unix domain socket.
+#[derive(Debug)]
+pub enum Connection {
+    /// A TCP connection.
+    Tcp(TcpStream),
+    /// A Unix domain socket connection.
+    Unix(UnixStream),
+}
+
+impl Connection {
+    /// Connect to a server over `transport`, without any negotiation.
+    pub fn connect(transport: &Transport) -> std::io::Result<Self> {
+        Ok(match transport {
+            Transport::Tcp { host, port } => {
+                Connection::Tcp(TcpStream::connect((host.as_str(), *port))?)
+            }
+            Transport::Unix(path) => Connection::Unix(UnixStream::connect(path)?),
+        })
+    }
+}
+
+impl Read for Connection {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            Connection::Tcp(s) => s.read(buf),
+            Connection::Unix(s) => s.read(buf),
+        }
+    }
+}
+
+impl Write for Connection {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Connection::Tcp(s) => s.write(buf),
+            Connection::Unix(s) => s.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Connection::Tcp(s) => s.flush(),
+            Connection::Unix(s) => s.flush(),
+        }
+    }
+}
+
+impl SetTimeout for Connection {
+    fn set_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()> {
+        match self {
+            Connection::Tcp(s) => s.set_timeout(timeout),
+            Connection::Unix(s) => s.set_timeout(timeout),
+        }
+    }
+}
+
+impl AsFd for Connection {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        match self {
+            Connection::Tcp(s) => s.as_fd(),
+            Connection::Unix(s) => s.as_fd(),
+        }
+    }
+}
+
+impl IntoRawFd for Connection {
+    fn into_raw_fd(self) -> RawFd {
+        match self {
+            Connection::Tcp(s) => s.into_raw_fd(),
+            Connection::Unix(s) => s.into_raw_fd(),
+        }
+    }
 }
 
 impl<IO: Read + Write + IntoRawFd> IntoRawFd for Client<IO> {
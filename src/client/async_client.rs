@@ -0,0 +1,308 @@
+//! A client for use from a tokio runtime.
+
+use std::ops::ControlFlow;
+
+use color_eyre::eyre::bail;
+use color_eyre::Result;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+use super::{get_greeting, Export, GoReplies, NbdError};
+use crate::proto::*;
+
+/// AsyncClient is like [`super::Client`], but communicates over an async
+/// stream, such as a [`tokio::net::TcpStream`].
+///
+/// Each client has one command in flight at a time. To issue many requests
+/// concurrently, open several connections to a server that supports them (see
+/// [`AsyncClient::multi_conn`]) and run commands on each from their own
+/// tasks, for example with [`tokio::join!`] or [`tokio::spawn`].
+///
+/// The client does not negotiate structured replies or TLS, and supports the
+/// basic commands: reads, writes, and flushes.
+///
+/// Only available with the `tokio` feature.
+#[derive(Debug)]
+pub struct AsyncClient<IO: AsyncRead + AsyncWrite + Unpin> {
+    conn: IO,
+    export: Export,
+    /// Handle for the next request.
+    next_handle: u64,
+    /// A reply broke the protocol, so the connection is out of sync.
+    desynced: Option<String>,
+}
+
+/// The length of the server's greeting: its magic, IHAVEOPT, and handshake
+/// flags.
+const GREETING_LEN: usize = 18;
+
+/// Write all of `msg` to `stream` and flush it.
+async fn send<IO: AsyncWrite + Unpin>(stream: &mut IO, msg: &[u8]) -> Result<()> {
+    stream.write_all(msg).await?;
+    stream.flush().await?;
+    Ok(())
+}
+
+impl<IO: AsyncRead + AsyncWrite + Unpin> AsyncClient<IO> {
+    /// Establish a handshake with stream and return a client ready for use.
+    pub async fn new(stream: IO) -> Result<Self> {
+        Self::new_with_export(stream, "default").await
+    }
+
+    /// Establish a handshake with stream, connecting to export `name`.
+    pub async fn new_with_export(mut stream: IO, name: &str) -> Result<Self> {
+        let mut greeting = [0u8; GREETING_LEN];
+        stream.read_exact(&mut greeting).await?;
+        let client_flags = get_greeting(&mut &greeting[..])?;
+        let mut msg = client_flags.bits().to_be_bytes().to_vec();
+        GoReplies::option(name)?.put(&mut msg)?;
+        send(&mut stream, &msg).await?;
+        let mut go = GoReplies::default();
+        let export = loop {
+            if let ControlFlow::Break(export) =
+                go.handle(OptReply::get_async(&mut stream).await?)?
+            {
+                break export;
+            }
+        };
+        let export = match export {
+            Some(export) => export,
+            None => {
+                // the server does not support NBD_OPT_GO
+                let mut msg = vec![];
+                Opt {
+                    typ: OptType::EXPORT_NAME,
+                    data: name.as_bytes().to_vec(),
+                }
+                .put(&mut msg)?;
+                send(&mut stream, &msg).await?;
+                // size and transmission flags (NO_ZEROES skips the padding)
+                let mut info = [0u8; 10];
+                stream.read_exact(&mut info).await?;
+                Export::get(&mut &info[..])?
+            }
+        };
+        Ok(Self {
+            conn: stream,
+            export,
+            next_handle: 0,
+            desynced: None,
+        })
+    }
+
+    /// Return the size of this export, as reported by the server during the
+    /// handshake.
+    pub fn size(&self) -> u64 {
+        self.export.size
+    }
+
+    /// The export rejects writes.
+    pub fn is_read_only(&self) -> bool {
+        self.export.flags.contains(TransmitFlags::READ_ONLY)
+    }
+
+    /// The server allows several connections to this export at once, with a
+    /// flush on any of them persisting writes from all of them.
+    pub fn multi_conn(&self) -> bool {
+        self.export.flags.contains(TransmitFlags::CAN_MULTI_CONN)
+    }
+
+    /// Create a request with a new handle.
+    fn request(&mut self, typ: Cmd, offset: u64, len: u32) -> Request {
+        self.next_handle += 1;
+        Request::with_handle(self.next_handle, typ, offset, len)
+    }
+
+    /// Send `req` (with `data` for a write) and get its simple reply, placing
+    /// any data read into `buf`.
+    ///
+    /// Errors are reported like [`super::Client`]'s: after a malformed reply,
+    /// this and all later commands fail with [`NbdError::Protocol`].
+    async fn transmit(&mut self, req: &Request, data: &[u8], buf: &mut [u8]) -> Result<()> {
+        if let Some(msg) = &self.desynced {
+            bail!(NbdError::Protocol(format!(
+                "connection closed after an earlier error ({msg})"
+            )));
+        }
+        let r = self.exchange(req, data, buf).await;
+        let r = r.map_err(NbdError::from_report);
+        if let Err(err) = &r {
+            if let Some(NbdError::Protocol(msg)) = err.downcast_ref::<NbdError>() {
+                self.desynced = Some(msg.clone());
+            }
+        }
+        r
+    }
+
+    /// Send `req` and get its reply, without tracking protocol errors.
+    async fn exchange(&mut self, req: &Request, data: &[u8], buf: &mut [u8]) -> Result<()> {
+        let mut msg = vec![];
+        req.put(data, &mut msg)?;
+        send(&mut self.conn, &msg).await?;
+        let err = match ReplyHeader::get_async(&mut self.conn).await? {
+            ReplyHeader::Simple { err, handle } if handle == req.handle => err,
+            ReplyHeader::Simple { handle, .. } => {
+                bail!(ProtocolError(format!("reply for wrong handle {handle}")))
+            }
+            ReplyHeader::Structured(_) => {
+                bail!(ProtocolError::new("structured reply was not negotiated"))
+            }
+        };
+        if err != ErrorType::OK {
+            bail!(NbdError::Server {
+                command: format!("{:?}", req.typ),
+                errno: err.into(),
+            });
+        }
+        self.conn.read_exact(buf).await?;
+        Ok(())
+    }
+
+    /// Send a read command to the NBD server.
+    ///
+    /// Reads longer than the server's maximum block size fail without
+    /// contacting the server.
+    pub async fn read(&mut self, offset: u64, len: u32) -> Result<Vec<u8>> {
+        let max = self.export.block_size.maximum;
+        if len > max {
+            bail!(format!(
+                "read of {len} bytes is larger than the maximum of {max}"
+            ))
+        }
+        let req = self.request(Cmd::READ, offset, len);
+        let mut buf = vec![0; len as usize];
+        self.transmit(&req, &[], &mut buf).await?;
+        Ok(buf)
+    }
+
+    /// Send a write command to the NBD server.
+    ///
+    /// Writes longer than the server's maximum block size fail without
+    /// contacting the server.
+    pub async fn write(&mut self, offset: u64, data: &[u8]) -> Result<()> {
+        let max = self.export.block_size.maximum;
+        let len = match u32::try_from(data.len()) {
+            Ok(len) if len <= max => len,
+            _ => bail!(format!(
+                "write of {} bytes is larger than the maximum of {max}",
+                data.len()
+            )),
+        };
+        let req = self.request(Cmd::WRITE, offset, len);
+        self.transmit(&req, data, &mut []).await
+    }
+
+    /// Send a flush command to the NBD server.
+    pub async fn flush(&mut self) -> Result<()> {
+        let req = self.request(Cmd::FLUSH, 0, 0);
+        self.transmit(&req, &[], &mut []).await
+    }
+
+    /// Disconnect from server cleanly and consume this client.
+    pub async fn disconnect(mut self) -> Result<()> {
+        let req = self.request(Cmd::DISCONNECT, 0, 0);
+        let mut msg = vec![];
+        req.put(&[], &mut msg)?;
+        send(&mut self.conn, &msg)
+            .await
+            .map_err(NbdError::from_report)?;
+        Ok(())
+    }
+}
+
+impl AsyncClient<TcpStream> {
+    /// Connect to a server, run handshake, and return an `AsyncClient`
+    /// prepared for the transmission phase.
+    pub async fn connect(host: &str) -> Result<Self> {
+        let stream = TcpStream::connect((host, TCP_PORT)).await?;
+        stream.set_nodelay(true)?;
+        Self::new(stream).await
+    }
+
+    /// Like [`AsyncClient::connect`], but connect to export `name` rather
+    /// than the default export.
+    pub async fn connect_export(host: &str, name: &str) -> Result<Self> {
+        let stream = TcpStream::connect((host, TCP_PORT)).await?;
+        stream.set_nodelay(true)?;
+        Self::new_with_export(stream, name).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use color_eyre::{Report, Result};
+    use tokio::net::{TcpListener, TcpStream, UnixStream};
+
+    use super::AsyncClient;
+    use crate::client::NbdError;
+    use crate::server::{AsyncServer, Blocks, MemBlocks, Server};
+
+    #[tokio::test]
+    async fn test_async_client() -> Result<()> {
+        let blocks = Arc::new(MemBlocks::new(vec![0u8; 1 << 20]));
+        let server = AsyncServer::new(Server::new(blocks.clone()).op_log_level(None));
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let serving = tokio::spawn(server.clone().serve(listener));
+
+        // fan out requests over several connections at once, from a task each
+        let mut clients = vec![];
+        for _ in 0..4 {
+            clients.push(AsyncClient::new(TcpStream::connect(addr).await?).await?);
+        }
+        assert!(clients.iter().all(|client| client.size() == 1 << 20));
+        let writes: Vec<_> = clients
+            .into_iter()
+            .enumerate()
+            .map(|(i, mut client)| {
+                tokio::spawn(async move {
+                    client.write(i as u64 * 4096, &[i as u8 + 1; 4096]).await?;
+                    client.flush().await?;
+                    Ok::<_, Report>(client)
+                })
+            })
+            .collect();
+        let mut clients = vec![];
+        for write in writes {
+            clients.push(write.await??);
+        }
+        // each client reads the block the next one wrote
+        let reads: Vec<_> = clients
+            .into_iter()
+            .enumerate()
+            .map(|(i, mut client)| {
+                tokio::spawn(async move {
+                    let next = (i + 1) % 4;
+                    assert_eq!(
+                        client.read(next as u64 * 4096, 4096).await?,
+                        [next as u8 + 1; 4096]
+                    );
+                    client.disconnect().await
+                })
+            })
+            .collect();
+        for read in reads {
+            read.await??;
+        }
+        let mut buf = [0u8; 1];
+        blocks.read_at(&mut buf, 3 * 4096)?;
+        assert_eq!(buf, [4]);
+
+        // errors from the server leave the connection usable
+        let (client_end, server_end) = UnixStream::pair()?;
+        let handling = tokio::spawn(async move { server.handle_client(server_end).await });
+        let mut client = AsyncClient::new_with_export(client_end, "default").await?;
+        let err = client.read(1 << 20, 1).await.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<NbdError>(),
+            Some(NbdError::Server { .. })
+        ));
+        assert_eq!(client.read(0, 2).await?, [1, 1]);
+        client.disconnect().await?;
+        handling.await??;
+        serving.abort();
+        Ok(())
+    }
+}
@@ -0,0 +1,201 @@
+//! Copy a whole export to or from a local file.
+
+use std::io::{prelude::*, SeekFrom};
+
+use color_eyre::eyre::WrapErr;
+use color_eyre::Result;
+
+use super::Client;
+use crate::proto::{Cmd, ErrorType, Request};
+
+impl<IO: Read + Write> Client<IO> {
+    /// Copy the whole export to `writer`, which should be empty (such as a
+    /// newly created file), keeping up to `concurrency` reads in flight at
+    /// once.
+    ///
+    /// The export is read in requests of the server's maximum size, and each
+    /// block (of the server's preferred block size) that is all zeros is
+    /// skipped by seeking over it rather than writing it, so the copy is
+    /// sparse where the filesystem supports it. With structured replies, holes
+    /// in the export are not sent over the network at all, and if block status
+    /// was negotiated (see [`super::ClientOptions::block_status`]), ranges the
+    /// server reports as zeros are not read in the first place. Block status
+    /// is queried `window` bytes at a time (see [`Client::allocated_extents`]).
+    pub fn copy_to<W: Write + Seek>(
+        &mut self,
+        writer: &mut W,
+        concurrency: usize,
+        window: u32,
+    ) -> Result<()> {
+        self.check_connection()?;
+        let size = self.export.size;
+        let block = self.preferred_block_size() as u64;
+        let chunk = self.copy_chunk_size() as u64;
+
+        let mut reads = self
+            .copy_ranges(window)?
+            .into_iter()
+            .flat_map(|(start, end)| {
+                (start..end)
+                    .step_by(chunk as usize)
+                    .map(move |off| (off, chunk.min(end - off)))
+            })
+            .peekable();
+        let mut reqs: Vec<Request> = vec![];
+        let mut bufs: Vec<Vec<u8>> = vec![];
+        // the first error in the reply to each request so far
+        let mut errs: Vec<ErrorType> = vec![];
+        // the last block has been written, so the copy has the full size
+        let mut wrote_end = false;
+        // after a failure, stop sending requests but collect the replies to
+        // those in flight, so the connection stays usable
+        let mut failure = None;
+        while (reads.peek().is_some() && failure.is_none()) || !reqs.is_empty() {
+            while reqs.len() < concurrency.max(1) && failure.is_none() {
+                let Some((off, len)) = reads.next() else {
+                    break;
+                };
+                let req = self.request(Cmd::READ, off, len as u32);
+                let r = req.put(&[], &mut self.conn);
+                self.check_result(r)?;
+                reqs.push(req);
+                bufs.push(vec![0; len as usize]);
+                errs.push(ErrorType::OK);
+            }
+            let mut slices: Vec<&mut [u8]> = bufs.iter_mut().map(|buf| &mut buf[..]).collect();
+            let r = self.get_reply_part(&reqs, &mut slices);
+            let (i, err, done) = self.check_result(r)?;
+            if errs[i] == ErrorType::OK {
+                errs[i] = err;
+            }
+            if !done {
+                continue;
+            }
+            let req = reqs.swap_remove(i);
+            let buf = bufs.swap_remove(i);
+            let err = errs.swap_remove(i);
+            if failure.is_some() {
+                continue;
+            }
+            let r = Self::check_reply(&req, err).and_then(|_| {
+                write_sparse(writer, req.offset, &buf, block as usize)
+                    .wrap_err_with(|| format!("writing copy at offset {}", req.offset))
+            });
+            match r {
+                Ok(wrote_last) if req.offset + buf.len() as u64 == size => wrote_end = wrote_last,
+                Ok(_) => {}
+                Err(err) => failure = Some(err),
+            }
+        }
+        if let Some(err) = failure {
+            return Err(err);
+        }
+        if size > 0 && !wrote_end {
+            // extend the copy over the hole at the end
+            writer.seek(SeekFrom::Start(size - 1))?;
+            writer.write_all(&[0])?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// The ranges of the export to read when copying, as (start, end) pairs:
+    /// the whole export, or if block status was negotiated, only the ranges
+    /// that do not read as zeros (see [`Client::allocated_extents`]).
+    fn copy_ranges(&mut self, window: u32) -> Result<Vec<(u64, u64)>> {
+        let size = self.export.size;
+        if self.allocation_context.is_none() {
+            return Ok(if size > 0 { vec![(0, size)] } else { vec![] });
+        }
+        self.allocated_extents(0, size, window)
+    }
+
+    /// The length of each request when copying: the largest multiple of the
+    /// preferred block size that the server accepts.
+    fn copy_chunk_size(&self) -> usize {
+        let block = self.preferred_block_size();
+        ((self.export.block_size.maximum / block).max(1) * block) as usize
+    }
+
+    /// Copy everything from `reader` to the start of the export, in requests of
+    /// the server's maximum size.
+    ///
+    /// If `sparse` is set and the server supports write zeroes, runs of blocks
+    /// (of the server's preferred block size) that are all zeros are sent as
+    /// write zeroes requests, which are cheaper to send and let the server
+    /// deallocate them, rather than as data.
+    pub fn copy_from<R: Read>(&mut self, reader: &mut R, sparse: bool) -> Result<()> {
+        let sparse = sparse && self.capabilities().write_zeroes;
+        let block = self.preferred_block_size() as usize;
+        let mut buf = vec![0u8; self.copy_chunk_size()];
+        let mut off = 0;
+        loop {
+            let len = read_full(reader, &mut buf).wrap_err("reading copy input")?;
+            if len == 0 {
+                return Ok(());
+            }
+            let data = &buf[..len];
+            if !sparse {
+                self.write(off, data)?;
+                off += len as u64;
+                continue;
+            }
+            let is_zero = |start: usize| {
+                data[start..(start + block).min(len)]
+                    .iter()
+                    .all(|&b| b == 0)
+            };
+            let mut start = 0;
+            while start < len {
+                // find the run of blocks that are all zero or all not zero
+                let zero = is_zero(start);
+                let mut end = start;
+                while end < len && is_zero(end) == zero {
+                    end = (end + block).min(len);
+                }
+                let run_off = off + start as u64;
+                if zero {
+                    self.write_zeroes(run_off, (end - start) as u32, false)?;
+                } else {
+                    self.write(run_off, &data[start..end])?;
+                }
+                start = end;
+            }
+            off += len as u64;
+        }
+    }
+}
+
+/// Fill as much of `buf` as possible from `reader`, returning the number of
+/// bytes read, which is less than `buf.len()` only at the end of the input.
+fn read_full<R: Read>(reader: &mut R, buf: &mut [u8]) -> std::io::Result<usize> {
+    let mut len = 0;
+    while len < buf.len() {
+        match reader.read(&mut buf[len..]) {
+            Ok(0) => break,
+            Ok(n) => len += n,
+            Err(err) if err.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(len)
+}
+
+/// Write the non-zero blocks of `data` to `writer` at offset `off`, returning
+/// whether the last block was written.
+fn write_sparse<W: Write + Seek>(
+    writer: &mut W,
+    off: u64,
+    data: &[u8],
+    block: usize,
+) -> std::io::Result<bool> {
+    let mut wrote = false;
+    for (i, piece) in data.chunks(block).enumerate() {
+        wrote = piece.iter().any(|&b| b != 0);
+        if wrote {
+            writer.seek(SeekFrom::Start(off + (i * block) as u64))?;
+            writer.write_all(piece)?;
+        }
+    }
+    Ok(wrote)
+}
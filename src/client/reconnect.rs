@@ -0,0 +1,168 @@
+//! A client that reconnects to the server when the connection drops.
+
+use std::fmt;
+use std::io::prelude::*;
+use std::net::TcpStream;
+use std::thread;
+use std::time::Duration;
+
+use color_eyre::{Report, Result};
+use log::warn;
+
+use super::{Client, NbdError};
+
+type Connector<IO> = dyn FnMut() -> Result<Client<IO>> + Send;
+
+/// ReconnectingClient wraps a [`Client`], re-establishing the connection and
+/// retrying an operation if it fails because the connection dropped.
+///
+/// Reads and flushes are retried up to [`ReconnectingClient::retries`]
+/// times. A write that fails may or may not have reached the server, so
+/// writes are only retried with [`ReconnectingClient::retry_writes`];
+/// otherwise the error is returned and the next operation reconnects.
+pub struct ReconnectingClient<IO: Read + Write> {
+    connect: Box<Connector<IO>>,
+    client: Option<Client<IO>>,
+    size: u64,
+    retries: usize,
+    retry_delay: Duration,
+    retry_writes: bool,
+}
+
+impl<IO: Read + Write> fmt::Debug for ReconnectingClient<IO> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ReconnectingClient")
+            .field("connected", &self.client.is_some())
+            .field("size", &self.size)
+            .field("retries", &self.retries)
+            .field("retry_delay", &self.retry_delay)
+            .field("retry_writes", &self.retry_writes)
+            .finish()
+    }
+}
+
+/// The error means the connection is gone, rather than that the server
+/// rejected the operation.
+fn is_disconnect(err: &Report) -> bool {
+    matches!(
+        err.downcast_ref::<NbdError>(),
+        Some(NbdError::Io(_) | NbdError::TimedOut)
+    ) || err.downcast_ref::<std::io::Error>().is_some()
+}
+
+impl<IO: Read + Write> ReconnectingClient<IO> {
+    /// Connect with `connect`, which is called again to establish a new
+    /// connection whenever the current one drops.
+    pub fn with_connector<C>(mut connect: C) -> Result<Self>
+    where
+        C: FnMut() -> Result<Client<IO>> + Send + 'static,
+    {
+        let client = connect()?;
+        Ok(Self {
+            connect: Box::new(connect),
+            size: client.size(),
+            client: Some(client),
+            retries: 3,
+            retry_delay: Duration::from_millis(100),
+            retry_writes: false,
+        })
+    }
+
+    /// Set how many times an operation is retried after the connection
+    /// drops (the default is 3).
+    pub fn retries(mut self, retries: usize) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    /// Set how long to wait before each reconnection attempt, multiplied by
+    /// the number of attempts so far (the default is 100ms).
+    pub fn retry_delay(mut self, delay: Duration) -> Self {
+        self.retry_delay = delay;
+        self
+    }
+
+    /// Also retry writes after the connection drops.
+    ///
+    /// Resending a write is only safe if nothing else could have written to
+    /// the same range in the meantime, which the caller has to guarantee.
+    pub fn retry_writes(mut self, retry: bool) -> Self {
+        self.retry_writes = retry;
+        self
+    }
+
+    /// Return the size of the export, as of the most recent connection.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn client(&mut self) -> Result<&mut Client<IO>> {
+        if self.client.is_none() {
+            let client = (self.connect)()?;
+            self.size = client.size();
+            self.client = Some(client);
+        }
+        Ok(self.client.as_mut().unwrap())
+    }
+
+    /// Run `op` on the current connection, reconnecting and running it again
+    /// (if `retry` is set) when the connection drops.
+    fn run<T>(
+        &mut self,
+        retry: bool,
+        mut op: impl FnMut(&mut Client<IO>) -> Result<T>,
+    ) -> Result<T> {
+        let mut attempts = 0;
+        loop {
+            let err = match self.client().and_then(&mut op) {
+                Err(err) if is_disconnect(&err) => err,
+                r => return r,
+            };
+            // the next operation reconnects
+            self.client = None;
+            if !retry || attempts >= self.retries {
+                return Err(err);
+            }
+            attempts += 1;
+            warn!(target: "nbd", "connection lost ({err}), reconnecting (attempt {attempts})");
+            thread::sleep(self.retry_delay * attempts as u32);
+        }
+    }
+
+    /// Read `len` bytes starting at `offset` (see [`Client::read`]).
+    pub fn read(&mut self, offset: u64, len: u32) -> Result<Vec<u8>> {
+        self.run(true, |client| client.read(offset, len))
+    }
+
+    /// Write `data` starting at `offset` (see [`Client::write`]).
+    pub fn write(&mut self, offset: u64, data: &[u8]) -> Result<()> {
+        let retry = self.retry_writes;
+        self.run(retry, |client| client.write(offset, data))
+    }
+
+    /// Flush writes to stable storage (see [`Client::flush`]).
+    ///
+    /// A flush sent after reconnecting only covers writes from earlier
+    /// connections if the server supports multiple connections (see
+    /// [`super::Capabilities::multi_conn`]).
+    pub fn flush(&mut self) -> Result<()> {
+        self.run(true, |client| client.flush())
+    }
+
+    /// Disconnect from the server, if connected.
+    pub fn disconnect(mut self) -> Result<()> {
+        match self.client.take() {
+            Some(client) => client.disconnect(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl ReconnectingClient<TcpStream> {
+    /// Connect to export `name` on `host` (see [`Client::connect_export`]),
+    /// reconnecting whenever the connection drops.
+    pub fn connect(host: &str, name: &str) -> Result<Self> {
+        let (host, name) = (host.to_string(), name.to_string());
+        Self::with_connector(move || Client::connect_export(&host, &name))
+    }
+}
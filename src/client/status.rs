@@ -0,0 +1,172 @@
+//! Querying which parts of an export are holes or read as zeros.
+
+use std::io::prelude::*;
+
+use byteorder::{ReadBytesExt, BE};
+use color_eyre::eyre::{bail, eyre};
+use color_eyre::Result;
+
+use super::Client;
+use crate::proto::*;
+
+/// A run of bytes with the same allocation status, as reported by
+/// [`Client::block_status`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Extent {
+    /// Length of the run in bytes.
+    pub length: u32,
+    /// Status flags in the base:allocation context (see [`Extent::HOLE`] and
+    /// [`Extent::ZERO`]).
+    pub flags: u32,
+}
+
+impl Extent {
+    /// The range is not allocated on the server.
+    pub const HOLE: u32 = STATE_HOLE;
+    /// The range reads as zeros.
+    pub const ZERO: u32 = STATE_ZERO;
+
+    /// The range is not allocated on the server.
+    pub fn is_hole(&self) -> bool {
+        self.flags & Self::HOLE != 0
+    }
+
+    /// The range reads as zeros.
+    pub fn is_zero(&self) -> bool {
+        self.flags & Self::ZERO != 0
+    }
+}
+
+impl<IO: Read + Write> Client<IO> {
+    /// Get the allocation status of the export starting at `offset`, as a
+    /// sequence of extents.
+    ///
+    /// The extents cover at least the start of `[offset, offset+len)`, and may
+    /// describe less than the whole range (or run past it), so callers should
+    /// query again from where they end. This requires connecting with
+    /// [`super::ClientOptions::block_status`] and
+    /// [`super::ClientOptions::structured_replies`].
+    pub fn block_status(&mut self, offset: u64, len: u32) -> Result<Vec<Extent>> {
+        let Some(context) = self.allocation_context else {
+            bail!(
+                "block status was not negotiated (connect with the structured_replies and \
+                block_status options to a server that supports base:allocation)"
+            );
+        };
+        self.check_connection()?;
+        let req = self.request(Cmd::BLOCK_STATUS, offset, len);
+        let r = req
+            .put(&[], &mut self.conn)
+            .and_then(|_| self.get_block_status(&req, context));
+        self.check_result(r)
+    }
+
+    /// Get the ranges of `[offset, offset+len)` that hold data, as (start,
+    /// end) pairs: everything except extents the server reports as reading
+    /// as zeros.
+    ///
+    /// The range is queried with block status requests of up to `window`
+    /// bytes each, so a large window needs fewer requests for a large sparse
+    /// export (more are sent if the server describes less than a whole
+    /// window in a reply). Adjacent ranges are merged. Like [`Client::block_status`], this
+    /// requires block status to have been negotiated.
+    pub fn allocated_extents(
+        &mut self,
+        offset: u64,
+        len: u64,
+        window: u32,
+    ) -> Result<Vec<(u64, u64)>> {
+        if window == 0 {
+            bail!("block status window must be nonzero");
+        }
+        let end = offset
+            .checked_add(len)
+            .ok_or_else(|| eyre!("range {offset}+{len} overflows"))?;
+        let mut ranges: Vec<(u64, u64)> = vec![];
+        let mut off = offset;
+        while off < end {
+            let query = (end - off).min(window as u64) as u32;
+            for extent in self.block_status(off, query)? {
+                if off >= end {
+                    break;
+                }
+                if extent.length == 0 {
+                    bail!(ProtocolError::new("empty extent in block status reply"));
+                }
+                let extent_end = (off + extent.length as u64).min(end);
+                if !extent.is_zero() {
+                    match ranges.last_mut() {
+                        Some(last) if last.1 == off => last.1 = extent_end,
+                        _ => ranges.push((off, extent_end)),
+                    }
+                }
+                off = extent_end;
+            }
+        }
+        Ok(ranges)
+    }
+
+    /// Get the reply to a block status request, keeping the extents for
+    /// metadata context `context`.
+    fn get_block_status(&mut self, req: &Request, context: u32) -> Result<Vec<Extent>> {
+        let mut extents = vec![];
+        // an error chunk may be followed by more chunks, so the error is
+        // reported once the reply is complete
+        let mut first_err = ErrorType::OK;
+        loop {
+            let chunk = match ReplyHeader::get(&mut self.conn)? {
+                ReplyHeader::Simple { err, handle } => {
+                    if handle != req.handle {
+                        bail!(ProtocolError(format!("reply for wrong handle {handle}")));
+                    }
+                    if err == ErrorType::OK {
+                        bail!(ProtocolError::new("simple reply to BLOCK_STATUS"));
+                    }
+                    // an error, since err is not OK
+                    return Self::check_reply(req, err).map(|_| extents);
+                }
+                ReplyHeader::Structured(chunk) => chunk,
+            };
+            if chunk.handle != req.handle {
+                bail!(ProtocolError(format!(
+                    "reply for wrong handle {}",
+                    chunk.handle
+                )));
+            }
+            if chunk.typ == u16::from(ChunkType::BLOCK_STATUS) {
+                // S: 32 bits, metadata context ID
+                // S: list of (32 bits length, 32 bits status flags)
+                if chunk.len < 4 || (chunk.len - 4) % 8 != 0 {
+                    bail!(ProtocolError(format!(
+                        "block status chunk of invalid length {}",
+                        chunk.len
+                    )));
+                }
+                let mut payload = vec![0u8; chunk.len as usize];
+                self.conn.read_exact(&mut payload)?;
+                let mut payload = &payload[..];
+                let id = payload.read_u32::<BE>()?;
+                while !payload.is_empty() {
+                    let length = payload.read_u32::<BE>()?;
+                    let flags = payload.read_u32::<BE>()?;
+                    if id == context {
+                        extents.push(Extent { length, flags });
+                    }
+                }
+            } else {
+                let err = self.get_chunk(req, &chunk, &mut [])?;
+                if first_err == ErrorType::OK {
+                    first_err = err;
+                }
+            }
+            if chunk.is_done() {
+                break;
+            }
+        }
+        Self::check_reply(req, first_err)?;
+        if extents.is_empty() {
+            bail!(ProtocolError::new("no extents in block status reply"));
+        }
+        Ok(extents)
+    }
+}
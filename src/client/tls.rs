@@ -0,0 +1,235 @@
+//! Connecting to a server over TLS with NBD_OPT_STARTTLS.
+
+use std::io::{self, prelude::*};
+use std::net::TcpStream;
+use std::os::fd::{AsFd, AsRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::sync::Arc;
+use std::time::Duration;
+
+use color_eyre::eyre::WrapErr;
+use color_eyre::Result;
+use nix::fcntl::{fcntl, FcntlArg, OFlag};
+use nix::poll::{poll, PollFd, PollFlags, PollTimeout};
+use rustls::pki_types::ServerName;
+use rustls::{ClientConfig, ClientConnection, StreamOwned};
+
+use super::{Client, ClientOptions, SetTimeout};
+use crate::proto::TCP_PORT;
+
+/// A TLS session over a connection to a server, which is what a [`Client`]
+/// from [`Client::new_tls`] runs over.
+pub type TlsStream<IO> = StreamOwned<ClientConnection, IO>;
+
+impl<IO: Read + Write + SetTimeout> SetTimeout for TlsStream<IO> {
+    fn set_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()> {
+        self.sock.set_timeout(timeout)
+    }
+}
+
+impl<IO: Read + Write> Client<TlsStream<IO>> {
+    /// Establish a handshake over TLS, with `tls_config` (which has the
+    /// certificates to trust) and verifying that the server's certificate is
+    /// for `server_name`.
+    ///
+    /// After the initial handshake on the plaintext `stream`, this sends
+    /// NBD_OPT_STARTTLS and, once the server agrees, runs the TLS handshake.
+    /// The rest of negotiation and all transmission happen over TLS. Fails
+    /// if the server does not support TLS, rather than falling back to
+    /// plaintext.
+    ///
+    /// The kernel cannot run the TLS session, so these clients are
+    /// restricted to the userspace API and cannot be passed to
+    /// [`crate::kernel::set_client`] directly; see [`Client::into_relay`] for
+    /// setting up a device over TLS.
+    ///
+    /// Only available with the `tls` feature.
+    pub fn new_tls(stream: IO, tls_config: Arc<ClientConfig>, server_name: &str) -> Result<Self> {
+        Self::with_tls_options(stream, tls_config, server_name, ClientOptions::default())
+    }
+
+    /// Like [`Client::new_tls`], but negotiate according to `opts`.
+    pub fn with_tls_options(
+        stream: IO,
+        tls_config: Arc<ClientConfig>,
+        server_name: &str,
+        opts: ClientOptions,
+    ) -> Result<Self> {
+        let server_name = ServerName::try_from(server_name.to_string())
+            .wrap_err_with(|| format!("invalid TLS server name {server_name:?}"))?;
+        Self::with_starttls(stream, opts, |stream| {
+            let conn = ClientConnection::new(tls_config, server_name)?;
+            let mut stream = StreamOwned::new(conn, stream);
+            while stream.conn.is_handshaking() {
+                stream
+                    .conn
+                    .complete_io(&mut stream.sock)
+                    .wrap_err("TLS handshake failed")?;
+            }
+            Ok(stream)
+        })
+    }
+}
+
+impl Client<TlsStream<TcpStream>> {
+    /// Like [`Client::connect`], but over TLS (see [`Client::new_tls`]),
+    /// verifying the server's certificate for `host`.
+    pub fn connect_tls(host: &str, tls_config: Arc<ClientConfig>) -> Result<Self> {
+        let stream = TcpStream::connect((host, TCP_PORT))?;
+        Self::new_tls(stream, tls_config, host)
+    }
+}
+
+impl<IO: Read + Write + AsFd> Client<TlsStream<IO>> {
+    /// Move the TLS session out of this client into a [`TlsRelay`], which
+    /// forwards it to a plaintext Unix socket, and return a client on the
+    /// other end of that socket.
+    ///
+    /// This is how to set up an NBD device over TLS, like `nbd-client` does:
+    /// pass the returned client to [`crate::kernel::set_client`], and run the
+    /// relay (in its own thread) for as long as the device is connected.
+    pub fn into_relay(self) -> Result<(Client<UnixStream>, TlsRelay<IO>)> {
+        let (plain, relay_end) = UnixStream::pair()?;
+        let Client {
+            conn,
+            export,
+            structured_replies,
+            allocation_context,
+            max_read_len,
+            next_handle,
+            desynced,
+        } = self;
+        let client = Client {
+            conn: plain,
+            export,
+            structured_replies,
+            allocation_context,
+            max_read_len,
+            next_handle,
+            desynced,
+        };
+        let relay = TlsRelay {
+            tls: conn,
+            plain: relay_end,
+        };
+        Ok((client, relay))
+    }
+}
+
+/// Forwards between a TLS session with a server and a plaintext Unix socket,
+/// created with [`Client::into_relay`].
+#[derive(Debug)]
+pub struct TlsRelay<IO: Read + Write> {
+    tls: TlsStream<IO>,
+    plain: UnixStream,
+}
+
+fn set_nonblocking(fd: RawFd) -> io::Result<()> {
+    let flags = OFlag::from_bits_truncate(fcntl(fd, FcntlArg::F_GETFL)?);
+    fcntl(fd, FcntlArg::F_SETFL(flags | OFlag::O_NONBLOCK))?;
+    Ok(())
+}
+
+/// Whether a poll result means the fd can be read (or has hung up, which a
+/// read reports).
+fn readable(events: Option<PollFlags>) -> bool {
+    events
+        .is_some_and(|e| e.intersects(PollFlags::POLLIN | PollFlags::POLLHUP | PollFlags::POLLERR))
+}
+
+impl<IO: Read + Write + AsFd> TlsRelay<IO> {
+    /// Forward requests from the plaintext socket to the server and replies
+    /// back, until either side closes its connection.
+    pub fn run(mut self) -> io::Result<()> {
+        set_nonblocking(self.tls.sock.as_fd().as_raw_fd())?;
+        self.plain.set_nonblocking(true)?;
+        // replies decrypted from the server but not yet written to the
+        // plaintext socket
+        let mut replies: Vec<u8> = vec![];
+        let mut buf = vec![0u8; 64 * 1024];
+        let mut server_closed = false;
+        // requests are only read once the previous ones have been sent, so
+        // this does not buffer without limit
+        self.tls.conn.set_buffer_limit(None);
+        loop {
+            let mut plain_events = PollFlags::empty();
+            if !self.tls.conn.wants_write() {
+                plain_events |= PollFlags::POLLIN;
+            }
+            if !replies.is_empty() {
+                plain_events |= PollFlags::POLLOUT;
+            }
+            let mut sock_events = PollFlags::POLLIN;
+            if self.tls.conn.wants_write() {
+                sock_events |= PollFlags::POLLOUT;
+            }
+            let (plain_ready, sock_ready) = {
+                let mut fds = [
+                    PollFd::new(self.plain.as_fd(), plain_events),
+                    PollFd::new(self.tls.sock.as_fd(), sock_events),
+                ];
+                poll(&mut fds, PollTimeout::NONE)?;
+                (readable(fds[0].revents()), readable(fds[1].revents()))
+            };
+
+            if plain_ready {
+                match self.plain.read(&mut buf) {
+                    Ok(0) => {
+                        // the client hung up, so hang up on the server
+                        self.tls.conn.send_close_notify();
+                        let _ = self.flush_tls();
+                        return Ok(());
+                    }
+                    Ok(n) => self.tls.conn.writer().write_all(&buf[..n])?,
+                    Err(err) if err.kind() == io::ErrorKind::WouldBlock => {}
+                    Err(err) => return Err(err),
+                }
+            }
+            if sock_ready {
+                match self.tls.conn.read_tls(&mut self.tls.sock) {
+                    Ok(0) => server_closed = true,
+                    Ok(_) => {
+                        let state = self
+                            .tls
+                            .conn
+                            .process_new_packets()
+                            .map_err(io::Error::other)?;
+                        let start = replies.len();
+                        replies.resize(start + state.plaintext_bytes_to_read(), 0);
+                        self.tls.conn.reader().read_exact(&mut replies[start..])?;
+                        server_closed |= state.peer_has_closed();
+                    }
+                    Err(err) if err.kind() == io::ErrorKind::WouldBlock => {}
+                    Err(err) => return Err(err),
+                }
+            }
+
+            self.flush_tls()?;
+            while !replies.is_empty() {
+                match self.plain.write(&replies) {
+                    Ok(n) => {
+                        replies.drain(..n);
+                    }
+                    Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                    Err(err) => return Err(err),
+                }
+            }
+            if server_closed && replies.is_empty() {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Send as much pending TLS data to the server as it will take without
+    /// blocking.
+    fn flush_tls(&mut self) -> io::Result<()> {
+        while self.tls.conn.wants_write() {
+            match self.tls.conn.write_tls(&mut self.tls.sock) {
+                Ok(_) => {}
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(())
+    }
+}
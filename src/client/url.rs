@@ -0,0 +1,194 @@
+//! Parsing NBD URLs, following the [NBD URI
+//! specification](https://github.com/NetworkBlockDevice/nbd/blob/master/doc/uri.md).
+
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use color_eyre::eyre::{bail, eyre, Report};
+use color_eyre::Result;
+
+use crate::proto::TCP_PORT;
+
+/// How to reach an NBD server.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Transport {
+    /// A TCP connection to `host` on `port`.
+    Tcp {
+        /// Host name or IP address.
+        host: String,
+        /// Port (the default is 10809).
+        port: u16,
+    },
+    /// A Unix domain socket at this path.
+    Unix(PathBuf),
+}
+
+/// An NBD server and export, parsed from a URL.
+///
+/// Supported URLs are `nbd://host[:port]/[export]` and
+/// `nbd+unix:///[export]?socket=PATH`. TLS (`nbds://`) is not supported.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NbdUrl {
+    /// Where to connect.
+    pub transport: Transport,
+    /// The export name, which is empty if the URL has no path.
+    pub export: String,
+}
+
+/// Decode %XX escapes.
+fn percent_decode(s: &str) -> Result<String> {
+    let mut bytes = vec![];
+    let mut iter = s.bytes();
+    while let Some(b) = iter.next() {
+        if b != b'%' {
+            bytes.push(b);
+            continue;
+        }
+        let hex = [iter.next(), iter.next()];
+        let [Some(hi), Some(lo)] = hex else {
+            bail!("truncated escape in {s:?}");
+        };
+        let hex = std::str::from_utf8(&[hi, lo])
+            .ok()
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+            .ok_or_else(|| eyre!("invalid escape in {s:?}"))?;
+        bytes.push(hex);
+    }
+    Ok(String::from_utf8(bytes)?)
+}
+
+/// Split `host[:port]`, where an IPv6 host is in brackets.
+fn parse_authority(authority: &str) -> Result<(String, u16)> {
+    let (host, port) = if let Some(rest) = authority.strip_prefix('[') {
+        let (host, rest) = rest
+            .split_once(']')
+            .ok_or_else(|| eyre!("unterminated IPv6 address"))?;
+        match rest {
+            "" => (host, None),
+            _ => match rest.strip_prefix(':') {
+                Some(port) => (host, Some(port)),
+                None => bail!("unexpected {rest:?} after IPv6 address"),
+            },
+        }
+    } else {
+        match authority.split_once(':') {
+            Some((host, port)) => (host, Some(port)),
+            None => (authority, None),
+        }
+    };
+    let port = match port {
+        Some(port) => port.parse().map_err(|_| eyre!("invalid port {port:?}"))?,
+        None => TCP_PORT,
+    };
+    let host = if host.is_empty() { "localhost" } else { host };
+    Ok((host.to_string(), port))
+}
+
+impl FromStr for NbdUrl {
+    type Err = Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let parse = || -> Result<Self> {
+            let (scheme, rest) = s.split_once("://").ok_or_else(|| eyre!("missing scheme"))?;
+            let (rest, query) = match rest.split_once('?') {
+                Some((rest, query)) => (rest, query),
+                None => (rest, ""),
+            };
+            let (authority, path) = match rest.find('/') {
+                Some(i) => (&rest[..i], &rest[i + 1..]),
+                None => (rest, ""),
+            };
+            let export = percent_decode(path)?;
+            let mut socket = None;
+            for param in query.split('&').filter(|p| !p.is_empty()) {
+                let (key, value) = param.split_once('=').unwrap_or((param, ""));
+                // unknown parameters are ignored, as the spec allows
+                if key == "socket" {
+                    socket = Some(PathBuf::from(percent_decode(value)?));
+                }
+            }
+            let transport = match scheme {
+                "nbd" => {
+                    let (host, port) = parse_authority(authority)?;
+                    Transport::Tcp { host, port }
+                }
+                "nbd+unix" => {
+                    if !authority.is_empty() {
+                        bail!("unexpected host {authority:?} for a Unix socket");
+                    }
+                    let socket = socket.ok_or_else(|| eyre!("missing socket parameter"))?;
+                    Transport::Unix(socket)
+                }
+                "nbds" | "nbds+unix" => bail!("TLS is not supported"),
+                _ => bail!("unsupported scheme {scheme:?}"),
+            };
+            Ok(Self { transport, export })
+        };
+        parse().map_err(|err| eyre!("invalid NBD URL {s:?}: {err}"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tcp(host: &str, port: u16, export: &str) -> NbdUrl {
+        NbdUrl {
+            transport: Transport::Tcp {
+                host: host.to_string(),
+                port,
+            },
+            export: export.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_parse_tcp() -> Result<()> {
+        assert_eq!(
+            "nbd://example.com".parse::<NbdUrl>()?,
+            tcp("example.com", 10809, "")
+        );
+        assert_eq!(
+            "nbd://example.com:1234/disk".parse::<NbdUrl>()?,
+            tcp("example.com", 1234, "disk")
+        );
+        assert_eq!(
+            "nbd:///disk".parse::<NbdUrl>()?,
+            tcp("localhost", 10809, "disk")
+        );
+        assert_eq!(
+            "nbd://[::1]:1234/a%20b/c".parse::<NbdUrl>()?,
+            tcp("::1", 1234, "a b/c")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_unix() -> Result<()> {
+        assert_eq!(
+            "nbd+unix:///disk?socket=/tmp/nbd.sock".parse::<NbdUrl>()?,
+            NbdUrl {
+                transport: Transport::Unix("/tmp/nbd.sock".into()),
+                export: "disk".to_string(),
+            }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_invalid() {
+        for url in [
+            "localhost",
+            "http://localhost/",
+            "nbds://localhost/",
+            "nbd://localhost:port/",
+            "nbd://[::1/",
+            "nbd://localhost/%zz",
+            "nbd+unix:///disk",
+            "nbd+unix://localhost/disk?socket=/tmp/nbd.sock",
+        ] {
+            let err = url.parse::<NbdUrl>().expect_err(url);
+            assert!(format!("{err}").contains(url), "unexpected error {err}");
+        }
+    }
+}
@@ -23,29 +23,35 @@
 //! protocol and it is the job of the userspace process to create the socket
 //! (e.g., create a TCP connection connected to a remote NBD server) and
 //! negotiate with the remote end.
+//!
+//! Newer kernels also support setting up devices over netlink, which is in
+//! the [`netlink`] module.
 
 #![deny(missing_docs)]
 
-use color_eyre::eyre::WrapErr;
+use color_eyre::eyre::{bail, eyre, WrapErr};
 use color_eyre::Result;
 
 use std::io::{self, prelude::*};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 use std::{
-    fs::File,
+    fs::{self, File},
     os::unix::io::{AsRawFd, IntoRawFd, RawFd},
 };
 
 use crate::{client::Client, proto::TransmitFlags};
 
+pub mod netlink;
+
 /// Wrappers for NBD ioctls.
 ///
 /// See <https://github.com/NetworkBlockDevice/nbd/blob/master/nbd.h>.
 mod ioctl {
-    use nix::{ioctl_none_bad, ioctl_write_int_bad, request_code_none};
+    use nix::{ioctl_none_bad, ioctl_write_int_bad, ioctl_write_ptr_bad, libc, request_code_none};
     const NBD_IOCTL: u8 = 0xAB;
     ioctl_write_int_bad!(set_sock, request_code_none!(NBD_IOCTL, 0));
     ioctl_write_int_bad!(set_blksize, request_code_none!(NBD_IOCTL, 1));
-    ioctl_write_int_bad!(set_size, request_code_none!(NBD_IOCTL, 2));
     ioctl_none_bad!(do_it, request_code_none!(NBD_IOCTL, 3));
     ioctl_none_bad!(clear_sock, request_code_none!(NBD_IOCTL, 4));
     // deprecated
@@ -55,6 +61,16 @@ mod ioctl {
     ioctl_none_bad!(disconnect, request_code_none!(NBD_IOCTL, 8));
     ioctl_write_int_bad!(set_timeout, request_code_none!(NBD_IOCTL, 9));
     ioctl_write_int_bad!(set_flags, request_code_none!(NBD_IOCTL, 10));
+
+    /// NBD_SET_SIZE takes the size in bytes as an unsigned long, which
+    /// `ioctl_write_int_bad!` would truncate to an int (2GiB).
+    pub unsafe fn set_size(fd: libc::c_int, bytes: libc::c_ulong) -> nix::Result<libc::c_int> {
+        let res = unsafe { libc::ioctl(fd, request_code_none!(NBD_IOCTL, 2) as _, bytes) };
+        nix::errno::Errno::result(res)
+    }
+
+    // generic block device ioctl, from linux/fs.h
+    ioctl_write_ptr_bad!(set_read_only, request_code_none!(0x12, 93), i32);
 }
 
 /// Set socket for an NBD device opened at `f`. Should be connected to an NBD server.
@@ -78,11 +94,30 @@ fn set_blksize(f: &File, blksize: u64) -> io::Result<()> {
     Ok(())
 }
 
+/// The block size to configure a device with, given the server's preferred
+/// block size.
+///
+/// The kernel only accepts powers of two from 512 up to the page size, while
+/// servers may prefer larger blocks (qemu-nbd advertises the cluster size of
+/// a qcow2 image, often 64KiB), so larger sizes are clamped to the page size
+/// and anything else that is not a power of two (including 0) falls back to
+/// 4096.
+fn device_block_size(preferred: u32) -> u64 {
+    let page_size = match unsafe { nix::libc::sysconf(nix::libc::_SC_PAGESIZE) } {
+        n if n >= 4096 => n as u64,
+        _ => 4096,
+    };
+    let preferred = preferred as u64;
+    if !preferred.is_power_of_two() || preferred < 512 {
+        return 4096;
+    }
+    preferred.min(page_size)
+}
+
 /// Set size in bytes for an NBD device opened at `f`.
-#[allow(dead_code)]
 fn set_size(f: &File, bytes: u64) -> io::Result<()> {
     let fd = f.as_raw_fd();
-    unsafe { ioctl::set_size(fd, bytes as i32)? };
+    unsafe { ioctl::set_size(fd, bytes as _)? };
     Ok(())
 }
 
@@ -93,6 +128,14 @@ fn set_size_blocks(f: &File, blocks: u64) -> io::Result<()> {
     Ok(())
 }
 
+/// Set the timeout in seconds for requests to NBD device `f`, after which
+/// the kernel gives up on the connection (0 means no timeout).
+fn set_timeout(f: &File, secs: u64) -> io::Result<()> {
+    let fd = f.as_raw_fd();
+    unsafe { ioctl::set_timeout(fd, secs as i32)? };
+    Ok(())
+}
+
 /// Clear the socket previously set for NBD device `f`.
 fn clear_sock(f: &File) -> io::Result<()> {
     let fd = f.as_raw_fd();
@@ -114,6 +157,15 @@ fn set_flags(f: &File, flags: TransmitFlags) -> io::Result<()> {
     Ok(())
 }
 
+/// Mark the block device `f` read-only (or writable), so the kernel rejects
+/// writes before sending them to the server.
+fn set_read_only(f: &File, read_only: bool) -> io::Result<()> {
+    let fd = f.as_raw_fd();
+    let read_only = i32::from(read_only);
+    unsafe { ioctl::set_read_only(fd, &read_only)? };
+    Ok(())
+}
+
 /// Set up NBD device file to connect to a connected client.
 ///
 /// `nbd` should be an open NBD device file (eg, /dev/nbd0).
@@ -166,16 +218,51 @@ fn set_flags(f: &File, flags: TransmitFlags) -> io::Result<()> {
 /// Then we see a handful of configuration ioctl calls followed by `ioctl(3,
 /// NBD_SET_SOCK, 4)`, which is the really important part. Then the process
 /// calls `clone` to keep running in the background.
-pub fn set_client<IO: Read + Write + IntoRawFd>(nbd: &File, client: Client<IO>) -> Result<()> {
+///
+/// The device's block size is the preferred block size advertised by the
+/// server (in reply to NBD_OPT_GO), limited to what the kernel supports: a
+/// power of two from 512 bytes up to the page size. The size is set in
+/// blocks, as `nbd-client` does, unless the export is not a multiple of the
+/// block size, in which case it is set in bytes so the last partial block is
+/// not lost.
+///
+/// If the export is read-only, so is the device (with `BLKROSET`, as in the
+/// trace above), and writes fail in the block layer.
+///
+/// If `timeout` is set (and not zero), the kernel fails requests the server
+/// has not answered within it (rounded up to whole seconds) and tears down
+/// the connection, like `nbd-client -t`; otherwise a hung server blocks I/O
+/// to the device indefinitely.
+pub fn set_client<IO: Read + Write + IntoRawFd>(
+    nbd: &File,
+    client: Client<IO>,
+    timeout: Option<Duration>,
+) -> Result<()> {
     let size = client.size();
-    set_blksize(nbd, 4096)?;
-    set_size_blocks(nbd, size / 4096)?;
+    let blksize = device_block_size(client.preferred_block_size());
+    set_blksize(nbd, blksize).wrap_err_with(|| format!("could not set block size {blksize}"))?;
+    if size.is_multiple_of(blksize) {
+        set_size_blocks(nbd, size / blksize)?;
+    } else {
+        // a whole number of blocks would leave out the end of the export
+        set_size(nbd, size).wrap_err_with(|| format!("could not set size {size}"))?;
+    }
 
-    let flags = TransmitFlags::HAS_FLAGS | TransmitFlags::SEND_FLUSH;
+    let mut flags = TransmitFlags::HAS_FLAGS | TransmitFlags::SEND_FLUSH;
+    let read_only = client.is_read_only();
+    if read_only {
+        flags |= TransmitFlags::READ_ONLY;
+    }
     set_flags(nbd, flags)?;
+    set_read_only(nbd, read_only).wrap_err("could not set read-only mode")?;
 
     clear_sock(nbd)?;
 
+    if let Some(timeout) = timeout.filter(|t| !t.is_zero()) {
+        let secs = timeout.as_secs() + u64::from(timeout.subsec_nanos() > 0);
+        set_timeout(nbd, secs).wrap_err_with(|| format!("could not set timeout {secs}s"))?;
+    }
+
     let sock = client.into_raw_fd();
     set_sock(nbd, sock).wrap_err("could not set nbd sock")?;
     Ok(())
@@ -211,3 +298,74 @@ pub fn close(nbd: &File) -> Result<()> {
 
     Ok(())
 }
+
+/// Get the pid of the process serving the NBD device at `device` (eg,
+/// /dev/nbd0), or None if the device is not connected.
+///
+/// The kernel exposes this as `/sys/block/nbdX/pid`, which only exists while
+/// the device is connected.
+pub fn connected_pid(device: &Path) -> Result<Option<u32>> {
+    let name = device
+        .file_name()
+        .ok_or_else(|| eyre!("invalid nbd device {}", device.display()))?;
+    let pid_path = Path::new("/sys/block").join(name).join("pid");
+    let pid = match fs::read_to_string(&pid_path) {
+        Ok(pid) => pid,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err).wrap_err_with(|| format!("reading {}", pid_path.display())),
+    };
+    let pid = pid
+        .trim()
+        .parse()
+        .wrap_err_with(|| format!("invalid pid {pid:?} in {}", pid_path.display()))?;
+    Ok(Some(pid))
+}
+
+/// Find the first NBD device (in `/dev/nbd0`, `/dev/nbd1`, ...) that is not
+/// connected.
+///
+/// Another process may still claim the device before it is set up, in which
+/// case [`set_client`] fails.
+pub fn find_free_device() -> Result<PathBuf> {
+    let mut devices = vec![];
+    for entry in fs::read_dir("/dev").wrap_err("listing /dev")? {
+        let name = entry?.file_name();
+        // skip partitions such as nbd0p1
+        let n = name
+            .to_str()
+            .and_then(|name| name.strip_prefix("nbd"))
+            .and_then(|n| n.parse::<u32>().ok());
+        if let Some(n) = n {
+            devices.push(n);
+        }
+    }
+    if devices.is_empty() {
+        bail!("no nbd devices found (run sudo modprobe nbd)");
+    }
+    devices.sort_unstable();
+    for n in devices {
+        let device = PathBuf::from(format!("/dev/nbd{n}"));
+        if connected_pid(&device)?.is_none() {
+            return Ok(device);
+        }
+    }
+    bail!("all nbd devices are in use")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::device_block_size;
+
+    #[test]
+    fn test_device_block_size() {
+        assert_eq!(device_block_size(512), 512);
+        assert_eq!(device_block_size(4096), 4096);
+        // larger than a page (at least 4096, but could be more)
+        assert!(device_block_size(1 << 20) >= 4096);
+        assert!(device_block_size(1 << 20) < 1 << 20);
+        // invalid values a server could send
+        assert_eq!(device_block_size(0), 4096);
+        assert_eq!(device_block_size(256), 4096);
+        assert_eq!(device_block_size(3000), 4096);
+    }
+}
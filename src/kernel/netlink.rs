@@ -0,0 +1,397 @@
+//! Setting up NBD devices with the netlink interface.
+//!
+//! The ioctl interface in the [parent module](super) hands the kernel one
+//! socket and then blocks in `NBD_DO_IT` for as long as the device is
+//! connected. Since Linux 4.12 the kernel also has a generic netlink family,
+//! `nbd`, which is what `nbd-client` uses unless run with `-nonetlink`: a
+//! single `NBD_CMD_CONNECT` message configures a device with any number of
+//! sockets, and the device stays connected after the process that set it up
+//! exits, until [`disconnect`] (or [`super::close`]).
+//!
+//! The constants are from `linux/netlink.h`, `linux/genetlink.h`, and
+//! `linux/nbd-netlink.h`. Netlink messages are in native byte order.
+
+use std::fs::File;
+use std::io::{self, prelude::*};
+use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd, RawFd};
+use std::time::Duration;
+
+use color_eyre::eyre::{bail, WrapErr};
+use color_eyre::Result;
+use nix::libc;
+
+use crate::client::{Capabilities, Client};
+use crate::proto::TransmitFlags;
+
+const NLMSG_HDRLEN: usize = 16;
+const NLMSG_ERROR: u16 = 2;
+const NLMSG_DONE: u16 = 3;
+const NLM_F_REQUEST: u16 = 1;
+const NLM_F_ACK: u16 = 4;
+const NLA_F_NESTED: u16 = 1 << 15;
+const NLA_F_NET_BYTEORDER: u16 = 1 << 14;
+
+// the generic netlink controller, which maps family names to IDs
+const GENL_ID_CTRL: u16 = 0x10;
+const CTRL_CMD_GETFAMILY: u8 = 3;
+const CTRL_ATTR_FAMILY_ID: u16 = 1;
+const CTRL_ATTR_FAMILY_NAME: u16 = 2;
+
+const NBD_GENL_FAMILY_NAME: &[u8] = b"nbd\0";
+const NBD_GENL_VERSION: u8 = 1;
+const NBD_CMD_CONNECT: u8 = 1;
+const NBD_CMD_DISCONNECT: u8 = 2;
+const NBD_ATTR_INDEX: u16 = 1;
+const NBD_ATTR_SIZE_BYTES: u16 = 2;
+const NBD_ATTR_BLOCK_SIZE_BYTES: u16 = 3;
+const NBD_ATTR_TIMEOUT: u16 = 4;
+const NBD_ATTR_SERVER_FLAGS: u16 = 5;
+const NBD_ATTR_SOCKETS: u16 = 7;
+const NBD_SOCK_ITEM: u16 = 1;
+const NBD_SOCK_FD: u16 = 1;
+
+/// Round `len` up to the 4-byte alignment of netlink messages and attributes.
+fn align(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+/// A generic netlink request under construction.
+struct Message {
+    buf: Vec<u8>,
+}
+
+impl Message {
+    fn new(family: u16, cmd: u8, version: u8) -> Self {
+        let mut buf = vec![];
+        // struct nlmsghdr: length (filled in by finish), type, flags,
+        // sequence number, port ID
+        buf.extend(0u32.to_ne_bytes());
+        buf.extend(family.to_ne_bytes());
+        buf.extend((NLM_F_REQUEST | NLM_F_ACK).to_ne_bytes());
+        buf.extend(1u32.to_ne_bytes());
+        buf.extend(0u32.to_ne_bytes());
+        // struct genlmsghdr: command, version, reserved
+        buf.extend([cmd, version, 0, 0]);
+        Self { buf }
+    }
+
+    fn attr(&mut self, typ: u16, data: &[u8]) -> &mut Self {
+        let len = 4 + data.len();
+        self.buf.extend((len as u16).to_ne_bytes());
+        self.buf.extend(typ.to_ne_bytes());
+        self.buf.extend(data);
+        self.buf.resize(align(self.buf.len()), 0);
+        self
+    }
+
+    fn attr_u32(&mut self, typ: u16, val: u32) -> &mut Self {
+        self.attr(typ, &val.to_ne_bytes())
+    }
+
+    fn attr_u64(&mut self, typ: u16, val: u64) -> &mut Self {
+        self.attr(typ, &val.to_ne_bytes())
+    }
+
+    /// Add a nested attribute, with the attributes added by `f` inside it.
+    fn nested(&mut self, typ: u16, f: impl FnOnce(&mut Self)) -> &mut Self {
+        let start = self.buf.len();
+        self.attr(typ | NLA_F_NESTED, &[]);
+        f(self);
+        let len = (self.buf.len() - start) as u16;
+        self.buf[start..start + 2].copy_from_slice(&len.to_ne_bytes());
+        self
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        let len = self.buf.len() as u32;
+        self.buf[..4].copy_from_slice(&len.to_ne_bytes());
+        self.buf
+    }
+}
+
+/// Split a sequence of netlink attributes into (type, payload) pairs.
+fn parse_attrs(mut data: &[u8]) -> Result<Vec<(u16, &[u8])>> {
+    let mut attrs = vec![];
+    while data.len() >= 4 {
+        let len = u16::from_ne_bytes([data[0], data[1]]) as usize;
+        let typ = u16::from_ne_bytes([data[2], data[3]]) & !(NLA_F_NESTED | NLA_F_NET_BYTEORDER);
+        if len < 4 || len > data.len() {
+            bail!("invalid netlink attribute length {len}");
+        }
+        attrs.push((typ, &data[4..len]));
+        data = &data[align(len).min(data.len())..];
+    }
+    Ok(attrs)
+}
+
+/// A generic netlink socket, for talking to the kernel.
+struct Socket(File);
+
+impl Socket {
+    fn open() -> io::Result<Self> {
+        let fd = unsafe {
+            libc::socket(
+                libc::AF_NETLINK,
+                libc::SOCK_RAW | libc::SOCK_CLOEXEC,
+                libc::NETLINK_GENERIC,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // writes to an unconnected netlink socket go to the kernel
+        Ok(Self(unsafe { File::from_raw_fd(fd) }))
+    }
+
+    /// Send `msg` to the kernel and wait for it to be acknowledged, returning
+    /// the attributes of the reply (if any).
+    fn request(&mut self, msg: Message) -> Result<Vec<u8>> {
+        self.0.write_all(&msg.finish())?;
+        let mut reply = vec![];
+        let mut buf = vec![0u8; 1 << 15];
+        loop {
+            let n = self.0.read(&mut buf)?;
+            let mut data = &buf[..n];
+            while data.len() >= NLMSG_HDRLEN {
+                let len = u32::from_ne_bytes(data[..4].try_into().unwrap()) as usize;
+                let typ = u16::from_ne_bytes([data[4], data[5]]);
+                if len < NLMSG_HDRLEN || len > data.len() {
+                    bail!("invalid netlink message length {len}");
+                }
+                let payload = &data[NLMSG_HDRLEN..len];
+                match typ {
+                    NLMSG_ERROR => {
+                        // S: 32 bits, negated errno (0 for an ack)
+                        // S: the header of the request
+                        if payload.len() < 4 {
+                            bail!("truncated netlink error");
+                        }
+                        let err = i32::from_ne_bytes(payload[..4].try_into().unwrap());
+                        if err != 0 {
+                            return Err(io::Error::from_raw_os_error(-err).into());
+                        }
+                        return Ok(reply);
+                    }
+                    NLMSG_DONE => return Ok(reply),
+                    // skip the genlmsghdr
+                    _ => reply = payload.get(4..).unwrap_or_default().to_vec(),
+                }
+                data = &data[align(len).min(data.len())..];
+            }
+        }
+    }
+
+    /// Look up the ID of the nbd netlink family.
+    fn nbd_family(&mut self) -> Result<u16> {
+        let mut msg = Message::new(GENL_ID_CTRL, CTRL_CMD_GETFAMILY, 1);
+        msg.attr(CTRL_ATTR_FAMILY_NAME, NBD_GENL_FAMILY_NAME);
+        let reply = self
+            .request(msg)
+            .wrap_err("looking up the nbd netlink family (run sudo modprobe nbd)")?;
+        for (typ, data) in parse_attrs(&reply)? {
+            if typ == CTRL_ATTR_FAMILY_ID && data.len() == 2 {
+                return Ok(u16::from_ne_bytes([data[0], data[1]]));
+            }
+        }
+        bail!("no family ID in reply for the nbd netlink family")
+    }
+}
+
+/// The transmission flags for an export with capabilities `caps`, limited to
+/// the ones the kernel uses.
+fn transmit_flags(caps: &Capabilities) -> TransmitFlags {
+    let mut flags = TransmitFlags::HAS_FLAGS;
+    flags.set(TransmitFlags::READ_ONLY, !caps.write);
+    flags.set(TransmitFlags::SEND_FLUSH, caps.flush);
+    flags.set(TransmitFlags::SEND_FUA, caps.fua);
+    flags.set(TransmitFlags::SEND_TRIM, caps.trim);
+    flags.set(TransmitFlags::CAN_MULTI_CONN, caps.multi_conn);
+    flags
+}
+
+/// Build the NBD_CMD_CONNECT request.
+fn connect_message(
+    family: u16,
+    index: Option<u32>,
+    sockets: &[RawFd],
+    size: u64,
+    block_size: u64,
+    caps: &Capabilities,
+    timeout: Option<Duration>,
+) -> Message {
+    let mut msg = Message::new(family, NBD_CMD_CONNECT, NBD_GENL_VERSION);
+    if let Some(index) = index {
+        msg.attr_u32(NBD_ATTR_INDEX, index);
+    }
+    msg.attr_u64(NBD_ATTR_SIZE_BYTES, size)
+        .attr_u64(NBD_ATTR_BLOCK_SIZE_BYTES, block_size)
+        .attr_u64(NBD_ATTR_SERVER_FLAGS, transmit_flags(caps).bits() as u64);
+    if let Some(timeout) = timeout.filter(|t| !t.is_zero()) {
+        let secs = timeout.as_secs() + u64::from(timeout.subsec_nanos() > 0);
+        msg.attr_u64(NBD_ATTR_TIMEOUT, secs);
+    }
+    msg.nested(NBD_ATTR_SOCKETS, |msg| {
+        for &sock in sockets {
+            msg.nested(NBD_SOCK_ITEM, |msg| {
+                msg.attr_u32(NBD_SOCK_FD, sock as u32);
+            });
+        }
+    });
+    msg
+}
+
+/// Connect NBD device `index` (`/dev/nbd<index>`) to a server over
+/// `sockets`, each of which must already have completed the handshake for
+/// the same export. With no index, the kernel picks a free device.
+///
+/// Returns the index of the device that was set up. Unlike
+/// [`super::set_client`], this does not block: the kernel keeps its own
+/// references to the sockets, and the device stays connected until
+/// [`disconnect`].
+///
+/// `caps` are the export's capabilities, as reported by
+/// [`Client::capabilities`] (the kernel uses flush, FUA, trim, and
+/// read-only), and `timeout` is as in [`super::set_client`]. The kernel
+/// refuses more than one socket unless `caps.multi_conn` is set.
+pub fn connect(
+    index: Option<u32>,
+    sockets: &[RawFd],
+    size: u64,
+    block_size: u64,
+    caps: &Capabilities,
+    timeout: Option<Duration>,
+) -> Result<u32> {
+    if sockets.is_empty() {
+        bail!("no sockets to connect");
+    }
+    let mut sock = Socket::open().wrap_err("opening netlink socket")?;
+    let family = sock.nbd_family()?;
+    let msg = connect_message(family, index, sockets, size, block_size, caps, timeout);
+    let reply = sock.request(msg).wrap_err("connecting nbd device")?;
+    for (typ, data) in parse_attrs(&reply)? {
+        if typ == NBD_ATTR_INDEX && data.len() == 4 {
+            return Ok(u32::from_ne_bytes(data.try_into().unwrap()));
+        }
+    }
+    match index {
+        Some(index) => Ok(index),
+        None => bail!("kernel did not report which nbd device it connected"),
+    }
+}
+
+/// Connect NBD device `index` to a server over one or more clients, which
+/// are all connected to the same export, using the first client's size,
+/// block size, and capabilities.
+///
+/// Returns the index of the device that was set up (see [`connect`]).
+pub fn set_clients<IO: Read + Write + IntoRawFd>(
+    index: Option<u32>,
+    clients: Vec<Client<IO>>,
+    timeout: Option<Duration>,
+) -> Result<u32> {
+    let Some(client) = clients.first() else {
+        bail!("no clients to connect");
+    };
+    let size = client.size();
+    let block_size = super::device_block_size(client.preferred_block_size());
+    let caps = client.capabilities();
+    if clients.len() > 1 && !caps.multi_conn {
+        bail!("server does not support multiple connections to this export");
+    }
+    if clients.iter().any(|c| c.size() != size) {
+        bail!("clients are connected to exports of different sizes");
+    }
+
+    let sockets: Vec<File> = clients
+        .into_iter()
+        .map(|c| unsafe { File::from_raw_fd(c.into_raw_fd()) })
+        .collect();
+    let fds: Vec<RawFd> = sockets.iter().map(|s| s.as_raw_fd()).collect();
+    // the kernel holds its own references, so ours are closed on return
+    connect(index, &fds, size, block_size, &caps, timeout)
+}
+
+/// Disconnect NBD device `index`, which was set up with [`connect`].
+pub fn disconnect(index: u32) -> Result<()> {
+    let mut sock = Socket::open().wrap_err("opening netlink socket")?;
+    let family = sock.nbd_family()?;
+    let mut msg = Message::new(family, NBD_CMD_DISCONNECT, NBD_GENL_VERSION);
+    msg.attr_u32(NBD_ATTR_INDEX, index);
+    sock.request(msg)
+        .wrap_err_with(|| format!("disconnecting nbd{index}"))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_connect_message() -> Result<()> {
+        let caps = Capabilities {
+            read: true,
+            flush: true,
+            ..Capabilities::default()
+        };
+        let msg = connect_message(
+            0x20,
+            Some(3),
+            &[5, 6],
+            1 << 20,
+            4096,
+            &caps,
+            Some(Duration::from_millis(1500)),
+        )
+        .finish();
+        assert_eq!(msg.len() % 4, 0);
+        assert_eq!(u32::from_ne_bytes(msg[..4].try_into()?) as usize, msg.len());
+        assert_eq!(u16::from_ne_bytes([msg[4], msg[5]]), 0x20);
+        assert_eq!(msg[NLMSG_HDRLEN], NBD_CMD_CONNECT);
+
+        let attrs = parse_attrs(&msg[NLMSG_HDRLEN + 4..])?;
+        let types: Vec<u16> = attrs.iter().map(|&(typ, _)| typ).collect();
+        assert_eq!(
+            types,
+            [
+                NBD_ATTR_INDEX,
+                NBD_ATTR_SIZE_BYTES,
+                NBD_ATTR_BLOCK_SIZE_BYTES,
+                NBD_ATTR_SERVER_FLAGS,
+                NBD_ATTR_TIMEOUT,
+                NBD_ATTR_SOCKETS
+            ]
+        );
+        assert_eq!(attrs[0].1, 3u32.to_ne_bytes());
+        assert_eq!(attrs[1].1, (1u64 << 20).to_ne_bytes());
+        let flags = TransmitFlags::HAS_FLAGS | TransmitFlags::SEND_FLUSH | TransmitFlags::READ_ONLY;
+        assert_eq!(attrs[3].1, (flags.bits() as u64).to_ne_bytes());
+        // rounded up to whole seconds
+        assert_eq!(attrs[4].1, 2u64.to_ne_bytes());
+
+        let items = parse_attrs(attrs[5].1)?;
+        assert_eq!(items.len(), 2);
+        let fds = items
+            .iter()
+            .map(|&(typ, item)| {
+                assert_eq!(typ, NBD_SOCK_ITEM);
+                let fd = parse_attrs(item)?;
+                assert_eq!(fd[0].0, NBD_SOCK_FD);
+                Ok(u32::from_ne_bytes(fd[0].1.try_into()?))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        assert_eq!(fds, [5, 6]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_connect_message_defaults() -> Result<()> {
+        let msg = connect_message(0x20, None, &[5], 4096, 512, &Capabilities::default(), None);
+        let msg = msg.finish();
+        let attrs = parse_attrs(&msg[NLMSG_HDRLEN + 4..])?;
+        // the kernel picks the device, and there is no timeout
+        assert!(attrs
+            .iter()
+            .all(|&(typ, _)| typ != NBD_ATTR_INDEX && typ != NBD_ATTR_TIMEOUT));
+        Ok(())
+    }
+}
@@ -1,17 +1,31 @@
 pub mod client;
+#[cfg(feature = "kernel")]
 pub mod kernel;
 mod proto;
 pub mod server;
 
+/// The version of rustls used for TLS, for building client and server
+/// configurations.
+#[cfg(feature = "tls")]
+pub use rustls;
+
 #[cfg(test)]
 mod tests {
     use color_eyre::Result;
+    use pipe::{PipeReader, PipeWriter};
     use readwrite::ReadWrite;
     use std::io::prelude::*;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::{Arc, Mutex};
     use std::thread::{self, JoinHandle};
+    use std::time::Duration;
 
-    use crate::server::MemBlocks;
-    use crate::{client::Client, server::Server};
+    use crate::client::{Capabilities, Client, ClientOptions, NbdError, ReconnectingClient};
+    use crate::proto::{Request, MAGIC, OLDSTYLE_MAGIC};
+    use crate::server::Server;
+    use crate::server::{
+        BadSectorBlocks, Blocks, MemBlocks, SparseMemBlocks, SubBlocks, SECTOR_SIZE,
+    };
 
     struct ServerClient<IO: Read + Write> {
         server: JoinHandle<Result<()>>,
@@ -27,6 +41,20 @@ mod tests {
     }
 
     fn start_server_client(data: Vec<u8>) -> Result<ServerClient<impl Read + Write>> {
+        start_server_client_with(Server::new(MemBlocks::new(data)))
+    }
+
+    fn start_server_client_with<F: Blocks + Sync + Send + 'static>(
+        server: Server<F>,
+    ) -> Result<ServerClient<impl Read + Write>> {
+        start_server_client_opts(server, ClientOptions::default())
+    }
+
+    /// Start handling a connection with `server` in a new thread, returning
+    /// the client's end of the connection.
+    fn start_server_stream<F: Blocks + Sync + Send + 'static>(
+        server: Server<F>,
+    ) -> (JoinHandle<Result<()>>, impl Read + Write) {
         let _ = env_logger::builder().is_test(true).try_init();
         let (r1, w1) = pipe::pipe();
         let (r2, w2) = pipe::pipe();
@@ -34,12 +62,31 @@ mod tests {
         let s2 = ReadWrite::new(r2, w1);
 
         let s_handle = thread::spawn(move || -> Result<()> {
-            let server = Server::new(MemBlocks::new(data));
             server.handle_client(s1)?;
             Ok(())
         });
+        (s_handle, s2)
+    }
+
+    /// Like [`start_server_stream`], but over a Unix socket rather than a
+    /// pipe, for TLS: both sides of a TLS handshake write at once, and a
+    /// pipe's writes wait for the other side to read.
+    #[cfg(feature = "tls")]
+    fn start_server_socket<F: Blocks + Sync + Send + 'static>(
+        server: Server<F>,
+    ) -> Result<(JoinHandle<Result<()>>, std::os::unix::net::UnixStream)> {
+        let _ = env_logger::builder().is_test(true).try_init();
+        let (s1, s2) = std::os::unix::net::UnixStream::pair()?;
+        let s_handle = thread::spawn(move || server.handle_client(s1));
+        Ok((s_handle, s2))
+    }
 
-        let client = Client::new(s2)?;
+    fn start_server_client_opts<F: Blocks + Sync + Send + 'static>(
+        server: Server<F>,
+        opts: ClientOptions,
+    ) -> Result<ServerClient<impl Read + Write>> {
+        let (s_handle, s2) = start_server_stream(server);
+        let client = Client::with_options(s2, opts)?;
 
         Ok(ServerClient {
             server: s_handle,
@@ -81,6 +128,1454 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn client_preferred_block_size() -> Result<()> {
+        let data = vec![1u8; 1024 * 10];
+        let sc = start_server_client(data.clone())?;
+        assert_eq!(sc.client.preferred_block_size(), 4096);
+        sc.shutdown()?;
+
+        let server = Server::new(MemBlocks::new(data))
+            .preferred_block_size(1 << 16)
+            .minimum_block_size(512);
+        let sc = start_server_client_with(server)?;
+        assert_eq!(sc.client.preferred_block_size(), 1 << 16);
+        assert_eq!(sc.client.block_sizes(), (512, 1 << 16, 4096 * 64));
+        sc.shutdown()?;
+        Ok(())
+    }
+
+    #[test]
+    fn client_capabilities() -> Result<()> {
+        let data = vec![1u8; 1024];
+        let sc = start_server_client(data)?;
+
+        assert_eq!(
+            sc.client.capabilities(),
+            Capabilities {
+                read: true,
+                write: true,
+                flush: true,
+                fua: true,
+                write_zeroes: true,
+                trim: true,
+                resize: true,
+                cache: true,
+                multi_conn: true,
+                ..Default::default()
+            }
+        );
+        assert!(!sc.client.is_read_only());
+        sc.shutdown()?;
+
+        let server = Server::new(MemBlocks::new(vec![0u8; 1024])).read_only(true);
+        let sc = start_server_client_with(server)?;
+        assert!(sc.client.is_read_only());
+        assert!(!sc.client.capabilities().write);
+        sc.shutdown()?;
+        Ok(())
+    }
+
+    #[test]
+    fn client_resize() -> Result<()> {
+        let data = vec![1u8; 1024];
+        let mut sc = start_server_client(data)?;
+        let client = &mut sc.client;
+
+        client.resize(4096)?;
+        assert_eq!(client.size(), 4096);
+        // the new space is readable and zero-filled
+        assert_eq!(client.read(1022, 4)?, [1, 1, 0, 0]);
+
+        sc.shutdown()?;
+        Ok(())
+    }
+
+    #[test]
+    fn client_resize_structured() -> Result<()> {
+        // the reply to a resize is a simple reply, even with structured replies
+        let opts = ClientOptions {
+            structured_replies: true,
+            ..Default::default()
+        };
+        let server = Server::new(MemBlocks::new(vec![0u8; 4096]));
+        let mut sc = start_server_client_opts(server, opts)?;
+        sc.client.resize(8192)?;
+        assert_eq!(sc.client.size(), 8192);
+        assert_eq!(sc.client.read(8190, 2)?, [0, 0]);
+        sc.shutdown()?;
+        Ok(())
+    }
+
+    #[test]
+    fn client_resize_unsupported() -> Result<()> {
+        let blocks = SubBlocks::new(Arc::new(MemBlocks::new(vec![0u8; 4096])), 0, 1024);
+        let mut sc = start_server_client_with(Server::new(blocks))?;
+        assert!(!sc.client.capabilities().resize);
+        assert!(sc.client.resize(2048).is_err());
+        assert_eq!(sc.client.size(), 1024);
+        sc.shutdown()?;
+        Ok(())
+    }
+
+    #[test]
+    fn client_structured_read_holes() -> Result<()> {
+        let blocks = SparseMemBlocks::new(4096 * 10);
+        blocks.write_at(&[2u8; 4096], 4096 * 2)?;
+        let opts = ClientOptions {
+            structured_replies: true,
+            ..Default::default()
+        };
+        let mut sc = start_server_client_opts(Server::new(blocks), opts)?;
+        assert!(sc.client.capabilities().structured_replies);
+
+        // hole, data, hole
+        let buf = sc.client.read(4096, 4096 * 3)?;
+        assert!(buf[..4096].iter().all(|&b| b == 0));
+        assert!(buf[4096..4096 * 2].iter().all(|&b| b == 2));
+        assert!(buf[4096 * 2..].iter().all(|&b| b == 0));
+
+        // errors are reported in a structured reply
+        assert!(sc.client.read(4096 * 10, 1).is_err());
+        assert_eq!(sc.client.read(4096 * 3 - 1, 2)?, [2, 0]);
+
+        sc.shutdown()?;
+        Ok(())
+    }
+
+    #[test]
+    fn client_block_status() -> Result<()> {
+        use crate::client::Extent;
+
+        let blocks = SparseMemBlocks::new(4096 * 10);
+        blocks.write_at(&[2u8; 4096 * 2], 4096 * 2)?;
+        let opts = ClientOptions {
+            structured_replies: true,
+            block_status: true,
+            ..Default::default()
+        };
+        let mut sc = start_server_client_opts(Server::new(blocks), opts)?;
+        assert!(sc.client.capabilities().block_status);
+
+        let extents = sc.client.block_status(4096, 4096 * 4)?;
+        let hole = Extent::HOLE | Extent::ZERO;
+        let expected = [(4096, hole), (4096 * 2, 0), (4096, hole)];
+        let expected: Vec<_> = expected
+            .iter()
+            .map(|&(length, flags)| Extent { length, flags })
+            .collect();
+        assert_eq!(extents, expected);
+        assert!(extents[0].is_hole() && extents[0].is_zero());
+        assert!(!extents[1].is_hole() && !extents[1].is_zero());
+
+        // errors leave the connection usable
+        assert!(sc.client.block_status(4096 * 10, 1).is_err());
+        assert_eq!(sc.client.block_status(4096 * 2, 1)?.len(), 1);
+        sc.shutdown()?;
+
+        // without negotiating block status
+        let opts = ClientOptions {
+            structured_replies: true,
+            ..Default::default()
+        };
+        let mut sc = start_server_client_opts(Server::new(MemBlocks::new(vec![0; 1024])), opts)?;
+        assert!(!sc.client.capabilities().block_status);
+        let err = sc.client.block_status(0, 1024).unwrap_err();
+        assert!(
+            err.to_string().contains("block status was not negotiated"),
+            "{err}"
+        );
+        sc.shutdown()?;
+        Ok(())
+    }
+
+    #[test]
+    fn client_ping() -> Result<()> {
+        let data = vec![1u8; 1024 * 10];
+        let mut sc = start_server_client(data)?;
+
+        let rtt = sc.client.ping()?;
+        assert!(rtt < Duration::from_secs(5), "ping took {rtt:?}");
+
+        sc.shutdown()?;
+        Ok(())
+    }
+
+    #[test]
+    fn require_tls_rejects_go() -> Result<()> {
+        let data = vec![1u8; 1024];
+        match start_server_client_with(Server::new(MemBlocks::new(data)).require_tls(true)) {
+            Ok(_) => panic!("client connected without TLS"),
+            Err(err) => assert!(
+                format!("{err}").contains("server requires TLS"),
+                "unexpected error {err:?}"
+            ),
+        }
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "tls")]
+    fn starttls_unsupported() -> Result<()> {
+        let (_, client_config) = tls_configs();
+        let server = Server::new(MemBlocks::new(vec![0u8; 1024])).require_tls(true);
+        let (server, s2) = start_server_stream(server);
+        match Client::new_tls(s2, client_config, "localhost") {
+            Ok(_) => panic!("client connected without TLS"),
+            Err(err) => assert!(
+                format!("{err}").contains("server does not support TLS"),
+                "unexpected error {err:?}"
+            ),
+        }
+        // the client hung up during negotiation
+        let _ = server.join().unwrap();
+        Ok(())
+    }
+
+    #[test]
+    fn list_exports() -> Result<()> {
+        let server = || {
+            Server::with_export("a", MemBlocks::new(vec![0u8; 1024]))
+                .add_export("b", MemBlocks::new(vec![0u8; 1024]))
+                .export_description("b", "the second export")
+        };
+
+        let (s_handle, stream) = start_server_stream(server());
+        assert_eq!(Client::list_exports(stream)?, ["a", "b"]);
+        s_handle.join().unwrap()?;
+
+        let (s_handle, stream) = start_server_stream(server());
+        assert_eq!(
+            Client::list_exports_detailed(stream)?,
+            [
+                ("a".to_string(), None),
+                ("b".to_string(), Some("the second export".to_string()))
+            ]
+        );
+        s_handle.join().unwrap()?;
+        Ok(())
+    }
+
+    #[test]
+    fn list_exports_shutdown() -> Result<()> {
+        use crate::proto::*;
+        use byteorder::{WriteBytesExt, BE};
+
+        let mut input = vec![];
+        input.write_u64::<BE>(MAGIC)?;
+        input.write_u64::<BE>(IHAVEOPT)?;
+        input
+            .write_u16::<BE>((HandshakeFlags::FIXED_NEWSTYLE | HandshakeFlags::NO_ZEROES).bits())?;
+        let mut data = vec![];
+        data.write_u32::<BE>(1)?;
+        data.extend(b"a");
+        OptReply::new(OptType::LIST, ReplyType::SERVER, data).put(&mut input)?;
+        OptReply::new(
+            OptType::LIST,
+            ReplyType::ERR_SHUTDOWN,
+            b"restarting".to_vec(),
+        )
+        .put(&mut input)?;
+        let stream = ReadWrite::new(&input[..], std::io::sink());
+        let err = Client::list_exports(stream).unwrap_err();
+        assert_eq!(err.to_string(), "server is shutting down: restarting");
+        Ok(())
+    }
+
+    #[cfg(feature = "tls")]
+    /// TLS configurations for a server with a self-signed certificate for
+    /// "localhost", and for a client that trusts it.
+    pub(crate) fn tls_configs() -> (Arc<rustls::ServerConfig>, Arc<rustls::ClientConfig>) {
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let key = rustls::pki_types::PrivatePkcs8KeyDer::from(cert.key_pair.serialize_der());
+        let server = rustls::ServerConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_no_client_auth()
+            .with_single_cert(vec![cert.cert.der().clone()], key.into())
+            .unwrap();
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add(cert.cert.der().clone()).unwrap();
+        let client = rustls::ClientConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        (Arc::new(server), Arc::new(client))
+    }
+
+    #[cfg(feature = "tls")]
+    #[test]
+    fn starttls() -> Result<()> {
+        let (server_config, client_config) = tls_configs();
+        let server = Server::new(MemBlocks::new(vec![0u8; 1024]))
+            .require_tls(true)
+            .starttls(server_config);
+        let (server, s2) = start_server_socket(server)?;
+        let mut client = Client::new_tls(s2, client_config, "localhost")?;
+        client.write(10, &[1, 2, 3])?;
+        assert_eq!(client.read(9, 5)?, [0, 1, 2, 3, 0]);
+        client.disconnect()?;
+        server.join().unwrap()?;
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "tls")]
+    fn starttls_relay() -> Result<()> {
+        let (server_config, client_config) = tls_configs();
+        let server = Server::new(MemBlocks::new(vec![0u8; 1024 * 1024])).starttls(server_config);
+        let (server, s2) = start_server_socket(server)?;
+        let client = Client::new_tls(s2, client_config, "localhost")?;
+        let (mut client, relay) = client.into_relay()?;
+        let relay = thread::spawn(move || relay.run());
+
+        // larger than the socket buffers, so the relay has to wait for both
+        // sides
+        let data: Vec<u8> = (0..256 * 1024).map(|i| (i % 251) as u8).collect();
+        client.write(1000, &data)?;
+        assert!(client.read(1000, data.len() as u32)? == data);
+        assert_eq!(client.size(), 1024 * 1024);
+        client.disconnect()?;
+        relay.join().unwrap()?;
+        server.join().unwrap()?;
+        Ok(())
+    }
+
+    #[cfg(feature = "tls")]
+    #[test]
+    fn starttls_untrusted() -> Result<()> {
+        // the client trusts a different certificate than the server's
+        let (server_config, _) = tls_configs();
+        let (_, client_config) = tls_configs();
+        let server = Server::new(MemBlocks::new(vec![0u8; 1024])).starttls(server_config);
+        let (server, s2) = start_server_socket(server)?;
+        let err = match Client::new_tls(s2, client_config, "localhost") {
+            Ok(_) => panic!("client connected to an untrusted server"),
+            Err(err) => err,
+        };
+        assert!(
+            format!("{err:?}").contains("invalid peer certificate"),
+            "unexpected error {err:?}"
+        );
+        assert!(server.join().unwrap().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn client_write_zeroes() -> Result<()> {
+        let data = vec![1u8; 1024 * 10];
+        let mut sc = start_server_client(data)?;
+        let client = &mut sc.client;
+
+        client.write_zeroes(10, 20, false)?;
+        client.write_zeroes(100, 20, true)?;
+        let buf = client.read(8, 24)?;
+        assert_eq!(buf[..2], [1, 1]);
+        assert_eq!(buf[2..22], [0; 20]);
+        assert_eq!(buf[22..], [1, 1]);
+        assert_eq!(client.read(100, 20)?, [0; 20]);
+
+        sc.shutdown()?;
+        Ok(())
+    }
+
+    /// A backend that cannot write zeros.
+    struct NoZeroesBlocks(MemBlocks);
+
+    impl Blocks for NoZeroesBlocks {
+        fn read_at(&self, buf: &mut [u8], off: u64) -> std::io::Result<()> {
+            self.0.read_at(buf, off)
+        }
+
+        fn write_at(&self, buf: &[u8], off: u64) -> std::io::Result<()> {
+            self.0.write_at(buf, off)
+        }
+
+        fn size(&self) -> std::io::Result<u64> {
+            self.0.size()
+        }
+
+        fn flush(&self) -> std::io::Result<()> {
+            Ok(())
+        }
+
+        fn write_zeroes(&self, _off: u64, _len: u64, _no_hole: bool) -> std::io::Result<()> {
+            Err(std::io::ErrorKind::Unsupported.into())
+        }
+    }
+
+    #[test]
+    fn client_write_zeroes_errors() -> Result<()> {
+        let server = Server::new(NoZeroesBlocks(MemBlocks::new(vec![1u8; 1024])));
+        let mut sc = start_server_client_with(server)?;
+        let err = sc.client.write_zeroes(0, 10, false).unwrap_err();
+        assert_eq!(err.to_string(), "server does not support write zeroes");
+        assert!(matches!(
+            err.downcast_ref::<NbdError>(),
+            Some(NbdError::Server { errno: 95, .. })
+        ));
+        assert!(sc.client.write_zeroes(0, 0, false).is_err());
+        assert_eq!(sc.client.read(0, 10)?, [1u8; 10]);
+        sc.shutdown()?;
+        Ok(())
+    }
+
+    #[test]
+    fn client_write_zeroes_no_hole() -> Result<()> {
+        use std::os::unix::fs::MetadataExt;
+
+        let path = std::env::temp_dir().join(format!("nbd-zeroes-{}", rand::random::<u64>()));
+        std::fs::write(&path, vec![1u8; 1 << 20])?;
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&path)?;
+        let blocks = file.metadata()?.blocks();
+
+        let mut sc = start_server_client_with(Server::new(file.try_clone()?))?;
+        sc.client.write_zeroes(0, 1 << 16, true)?;
+        assert_eq!(sc.client.read(0, 1 << 16)?, vec![0; 1 << 16]);
+        sc.shutdown()?;
+
+        let zeroed_blocks = file.metadata()?.blocks();
+        std::fs::remove_file(&path)?;
+        assert_eq!(
+            zeroed_blocks, blocks,
+            "NO_HOLE write-zeroes deallocated blocks"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn client_new_with_export() -> Result<()> {
+        let server = Server::with_export("a", MemBlocks::new(vec![0u8; 1024]))
+            .add_export("b", MemBlocks::new(vec![2u8; 2048]));
+        let (s_handle, stream) = start_server_stream(server);
+        let mut client = Client::new_with_export(stream, "b")?;
+        assert_eq!(client.size(), 2048);
+        assert_eq!(client.read(0, 2)?, [2, 2]);
+        client.disconnect()?;
+        s_handle.join().unwrap()?;
+        Ok(())
+    }
+
+    #[test]
+    fn connect_url_unix() -> Result<()> {
+        use std::os::unix::net::UnixListener;
+
+        let path = std::env::temp_dir().join(format!("nbd-{}.sock", rand::random::<u64>()));
+        let listener = UnixListener::bind(&path)?;
+        let server = Server::with_export("a", MemBlocks::new(vec![0u8; 1024]))
+            .add_export("b", MemBlocks::new(vec![2u8; 2048]));
+        let server = thread::spawn(move || -> Result<()> {
+            let (stream, _) = listener.accept()?;
+            server.handle_client(stream)
+        });
+
+        let url = format!("nbd+unix:///b?socket={}", path.display());
+        let result = Client::connect_url(&url);
+        std::fs::remove_file(&path)?;
+        let mut client = result?;
+        assert_eq!(client.size(), 2048);
+        assert_eq!(client.read(0, 2)?, [2, 2]);
+        client.disconnect()?;
+        server.join().unwrap()?;
+        Ok(())
+    }
+
+    /// A stream that records everything written to it.
+    struct Tap<IO> {
+        inner: IO,
+        written: Arc<Mutex<Vec<u8>>>,
+    }
+
+    impl<IO: Read> Read for Tap<IO> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.inner.read(buf)
+        }
+    }
+
+    impl<IO: Write> Write for Tap<IO> {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            let n = self.inner.write(buf)?;
+            self.written.lock().unwrap().extend_from_slice(&buf[..n]);
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            self.inner.flush()
+        }
+    }
+
+    /// Run `ops` with a client that records its requests, returning their
+    /// handles. If `start` is set, the client numbers requests from there.
+    fn request_handles(
+        start: Option<u64>,
+        ops: impl FnOnce(&mut Client<Tap<ReadWrite<PipeReader, PipeWriter>>>) -> Result<()>,
+    ) -> Result<Vec<u64>> {
+        let (r1, w1) = pipe::pipe();
+        let (r2, w2) = pipe::pipe();
+        let server = Server::new(MemBlocks::new(vec![0u8; 1024]));
+        let server = thread::spawn(move || server.handle_client(ReadWrite::new(r1, w2)));
+        let written = Arc::new(Mutex::new(vec![]));
+        let stream = Tap {
+            inner: ReadWrite::new(r2, w1),
+            written: written.clone(),
+        };
+        let mut client = Client::new(stream)?;
+        if let Some(start) = start {
+            client = client.sequential_handles(start);
+        }
+        // ignore the handshake
+        written.lock().unwrap().clear();
+        ops(&mut client)?;
+        client.disconnect()?;
+        server.join().unwrap()?;
+
+        let written = written.lock().unwrap();
+        let mut data = &written[..];
+        let mut buf = vec![0u8; 1024];
+        let mut handles = vec![];
+        while !data.is_empty() {
+            handles.push(Request::get(&mut data, &mut buf)?.handle);
+        }
+        Ok(handles)
+    }
+
+    #[test]
+    fn sequential_handles() -> Result<()> {
+        let handles = request_handles(Some(100), |client| {
+            client.write(0, &[1, 2, 3])?;
+            client.read(0, 3)?;
+            client.flush()?;
+            Ok(())
+        })?;
+        // the last request is the disconnect
+        assert_eq!(handles, [100, 101, 102, 103]);
+        Ok(())
+    }
+
+    #[test]
+    fn distinct_handles() -> Result<()> {
+        let handles = request_handles(None, |client| {
+            client.read(0, 3)?;
+            client.read(0, 3)?;
+            Ok(())
+        })?;
+        assert_eq!(handles.len(), 3);
+        assert!(handles[0] != handles[1] && handles[1] != handles[2]);
+        Ok(())
+    }
+
+    /// A stream whose reads fail with a connection reset once `reset` is set.
+    struct ResettableStream<IO> {
+        inner: IO,
+        reset: Arc<AtomicBool>,
+    }
+
+    impl<IO: Read> Read for ResettableStream<IO> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            if self.reset.load(Ordering::SeqCst) {
+                return Err(std::io::ErrorKind::ConnectionReset.into());
+            }
+            self.inner.read(buf)
+        }
+    }
+
+    impl<IO: Write> Write for ResettableStream<IO> {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.inner.write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            self.inner.flush()
+        }
+    }
+
+    #[test]
+    fn client_errors() -> Result<()> {
+        let (server, stream) = start_server_stream(Server::new(MemBlocks::new(vec![0u8; 1024])));
+        let reset = Arc::new(AtomicBool::new(false));
+        let stream = ResettableStream {
+            inner: stream,
+            reset: reset.clone(),
+        };
+        let mut client = Client::new(stream)?;
+
+        // errors from the server carry the NBD error
+        let err = client.read(1020, 10).unwrap_err();
+        match err.downcast_ref::<NbdError>() {
+            Some(NbdError::Server { command, errno }) => {
+                assert_eq!(command, "READ");
+                assert_eq!(*errno, 22);
+            }
+            _ => panic!("unexpected error {err:?}"),
+        }
+
+        reset.store(true, Ordering::SeqCst);
+        let err = client.read(0, 10).unwrap_err();
+        match err.downcast_ref::<NbdError>() {
+            Some(NbdError::Io(err)) => {
+                assert_eq!(err.kind(), std::io::ErrorKind::ConnectionReset)
+            }
+            _ => panic!("unexpected error {err:?}"),
+        }
+        // the server may fail to send its last reply
+        drop(client);
+        let _ = server.join().unwrap();
+        Ok(())
+    }
+
+    #[test]
+    fn reconnecting_client() -> Result<()> {
+        let blocks = Arc::new(MemBlocks::new(vec![0u8; 1024]));
+        // the reset flag for each connection
+        let resets = Arc::new(Mutex::new(vec![]));
+        let connect = {
+            let resets = resets.clone();
+            move || {
+                let server = Server::new(blocks.clone()).op_log_level(None);
+                let (_, stream) = start_server_stream(server);
+                let reset = Arc::new(AtomicBool::new(false));
+                resets.lock().unwrap().push(reset.clone());
+                Client::new(ResettableStream {
+                    inner: stream,
+                    reset,
+                })
+            }
+        };
+        let mut client = ReconnectingClient::with_connector(connect)?.retry_delay(Duration::ZERO);
+        let drop_connection = || {
+            let resets = resets.lock().unwrap();
+            resets.last().unwrap().store(true, Ordering::SeqCst);
+        };
+        let connections = || resets.lock().unwrap().len();
+
+        client.write(0, &[1, 2, 3])?;
+        drop_connection();
+        assert_eq!(client.read(0, 3)?, [1, 2, 3]);
+        assert_eq!(connections(), 2);
+
+        // the write reaches the server, but its reply is lost, so it fails
+        // rather than being sent again
+        drop_connection();
+        let err = client.write(3, &[4]).unwrap_err();
+        assert!(
+            matches!(err.downcast_ref::<NbdError>(), Some(NbdError::Io(_))),
+            "unexpected error {err:?}"
+        );
+        assert_eq!(connections(), 2);
+        // the next operation reconnects
+        assert_eq!(client.read(0, 4)?, [1, 2, 3, 4]);
+        assert_eq!(connections(), 3);
+
+        let mut client = client.retry_writes(true);
+        drop_connection();
+        client.write(4, &[5])?;
+        client.flush()?;
+        assert_eq!(connections(), 4);
+        client.disconnect()?;
+        Ok(())
+    }
+
+    #[test]
+    fn single_writer() -> Result<()> {
+        let server = Server::new(MemBlocks::new(vec![0u8; 1024])).single_writer(true);
+        let mut writer = start_server_client_with(server.clone())?;
+        let mut reader = start_server_client_with(server.clone())?;
+        assert!(writer.client.capabilities().write);
+        assert!(!reader.client.capabilities().write);
+
+        writer.client.write(0, &[1, 2, 3])?;
+        let err = reader.client.write(0, &[4]).unwrap_err();
+        match err.downcast_ref::<NbdError>() {
+            Some(NbdError::Server { command, errno }) => {
+                assert_eq!(command, "WRITE");
+                assert_eq!(*errno, 1, "expected EPERM");
+            }
+            _ => panic!("unexpected error {err:?}"),
+        }
+        assert_eq!(reader.client.read(0, 3)?, [1, 2, 3]);
+
+        // once the writer disconnects, a new connection can write
+        writer.shutdown()?;
+        let mut next = start_server_client_with(server)?;
+        next.client.write(0, &[5])?;
+        next.shutdown()?;
+        reader.shutdown()?;
+        Ok(())
+    }
+
+    /// Create a client for a fake server, which sends a handshake for a
+    /// 1024-byte export and then `replies`.
+    fn scripted_client(replies: &[u8]) -> Result<Client<impl Read + Write + '_>> {
+        scripted_client_opts(replies, false)
+    }
+
+    /// Like [`scripted_client`], but the fake server also agrees to
+    /// structured replies if `structured_replies` is set.
+    fn scripted_client_opts(
+        replies: &[u8],
+        structured_replies: bool,
+    ) -> Result<Client<impl Read + Write + '_>> {
+        use crate::proto::*;
+        use byteorder::{WriteBytesExt, BE};
+
+        let mut input = vec![];
+        input.write_u64::<BE>(MAGIC)?;
+        input.write_u64::<BE>(IHAVEOPT)?;
+        input
+            .write_u16::<BE>((HandshakeFlags::FIXED_NEWSTYLE | HandshakeFlags::NO_ZEROES).bits())?;
+        if structured_replies {
+            OptReply::ack(OptType::STRUCTURED_REPLY).put(&mut input)?;
+        }
+        // fall back to NBD_OPT_EXPORT_NAME
+        OptReply::new(OptType::GO, ReplyType::ERR_UNSUP, vec![]).put(&mut input)?;
+        input.write_u64::<BE>(1024)?;
+        input.write_u16::<BE>(TransmitFlags::HAS_FLAGS.bits())?;
+        let stream = std::io::Cursor::new(input).chain(replies);
+        let opts = ClientOptions {
+            structured_replies,
+            ..Default::default()
+        };
+        Client::with_options(ReadWrite::new(stream, std::io::sink()), opts)
+    }
+
+    #[test]
+    #[cfg(feature = "tls")]
+    fn starttls_refused() -> Result<()> {
+        use crate::proto::*;
+        use byteorder::{WriteBytesExt, BE};
+
+        let (_, client_config) = tls_configs();
+
+        let cases = [
+            (ReplyType::ERR_POLICY, "server refused TLS: no TLS for you"),
+            (ReplyType::ERR_TLS_REQD, "ERR_TLS_REQD: no TLS for you"),
+        ];
+        for (reply_type, expected) in cases {
+            let mut input = vec![];
+            input.write_u64::<BE>(MAGIC)?;
+            input.write_u64::<BE>(IHAVEOPT)?;
+            input.write_u16::<BE>(
+                (HandshakeFlags::FIXED_NEWSTYLE | HandshakeFlags::NO_ZEROES).bits(),
+            )?;
+            OptReply::new(OptType::STARTTLS, reply_type, b"no TLS for you".to_vec())
+                .put(&mut input)?;
+            let stream = ReadWrite::new(std::io::Cursor::new(input), std::io::sink());
+            match Client::new_tls(stream, client_config.clone(), "localhost") {
+                Ok(_) => panic!("client connected without TLS"),
+                Err(err) => assert!(
+                    format!("{err}").contains(expected),
+                    "unexpected error {err:?}"
+                ),
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn client_timeout() -> Result<()> {
+        use crate::proto::*;
+        use byteorder::{WriteBytesExt, BE};
+        use std::os::unix::net::UnixStream;
+
+        // a server that completes the handshake and then never replies
+        let (stream, mut server) = UnixStream::pair()?;
+        server.write_u64::<BE>(MAGIC)?;
+        server.write_u64::<BE>(IHAVEOPT)?;
+        server
+            .write_u16::<BE>((HandshakeFlags::FIXED_NEWSTYLE | HandshakeFlags::NO_ZEROES).bits())?;
+        OptReply::new(OptType::GO, ReplyType::ERR_UNSUP, vec![]).put(&mut server)?;
+        server.write_u64::<BE>(1024)?;
+        server.write_u16::<BE>(TransmitFlags::HAS_FLAGS.bits())?;
+        let mut client = Client::new(stream)?;
+
+        client.set_timeout(Some(Duration::from_millis(10)))?;
+        let err = client.read(0, 4).unwrap_err();
+        assert!(
+            matches!(err.downcast_ref::<NbdError>(), Some(NbdError::TimedOut)),
+            "unexpected error {err:?}"
+        );
+        // a late reply would be out of sync
+        let err = client.read(0, 4).unwrap_err();
+        assert!(format!("{err}").contains("a command timed out"), "{err}");
+        Ok(())
+    }
+
+    #[test]
+    fn client_trim_unsupported() -> Result<()> {
+        // the scripted server does not advertise SEND_TRIM, and has no reply
+        // to give
+        let mut client = scripted_client(&[])?;
+        let err = client.trim(0, 512).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "server does not support trim for this export"
+        );
+        Ok(())
+    }
+
+    /// A simple reply header.
+    fn simple_reply(magic: u32, err: u32, handle: u64) -> Vec<u8> {
+        [
+            &magic.to_be_bytes()[..],
+            &err.to_be_bytes(),
+            &handle.to_be_bytes(),
+        ]
+        .concat()
+    }
+
+    /// A structured reply chunk.
+    fn chunk(flags: u16, typ: u16, handle: u64, payload: &[u8]) -> Vec<u8> {
+        use crate::proto::STRUCTURED_REPLY_MAGIC;
+
+        [
+            &STRUCTURED_REPLY_MAGIC.to_be_bytes()[..],
+            &flags.to_be_bytes(),
+            &typ.to_be_bytes(),
+            &handle.to_be_bytes(),
+            &(payload.len() as u32).to_be_bytes(),
+            payload,
+        ]
+        .concat()
+    }
+
+    #[test]
+    fn client_error_chunk_not_done() -> Result<()> {
+        use crate::proto::{ChunkFlags, ChunkType, SIMPLE_REPLY_MAGIC};
+
+        // EIO with an empty message, and then more chunks for the same read
+        let error = [&5u32.to_be_bytes()[..], &0u16.to_be_bytes()].concat();
+        let data = [&0u64.to_be_bytes()[..], &[1; 4]].concat();
+        let replies = [
+            chunk(0, ChunkType::ERROR.into(), 0, &error),
+            chunk(0, ChunkType::OFFSET_DATA.into(), 0, &data),
+            chunk(ChunkFlags::DONE.bits(), ChunkType::NONE.into(), 0, &[]),
+            simple_reply(SIMPLE_REPLY_MAGIC, 0, 1),
+        ]
+        .concat();
+        let mut client = scripted_client_opts(&replies, true)?;
+        assert!(client.capabilities().structured_replies);
+        let err = client.read(0, 4).unwrap_err();
+        assert!(
+            matches!(
+                err.downcast_ref::<NbdError>(),
+                Some(NbdError::Server { errno: 5, .. })
+            ),
+            "unexpected error {err:?}"
+        );
+        // the rest of the reply was consumed, so the next command is in sync
+        client.flush()?;
+        Ok(())
+    }
+
+    #[test]
+    fn client_malformed_replies() -> Result<()> {
+        use crate::proto::SIMPLE_REPLY_MAGIC;
+
+        let cases = [
+            (simple_reply(0x12345678, 0, 0), "wrong reply magic"),
+            (
+                simple_reply(SIMPLE_REPLY_MAGIC, 1000, 0),
+                "unknown error value 1000",
+            ),
+            (simple_reply(SIMPLE_REPLY_MAGIC, 0, 7), "wrong handle 7"),
+        ];
+        for (reply, expected) in cases {
+            // a valid reply follows, which the client must not read
+            let replies = [reply, simple_reply(SIMPLE_REPLY_MAGIC, 0, 1)].concat();
+            let mut client = scripted_client(&replies)?;
+            let err = client.flush().unwrap_err();
+            match err.downcast_ref::<NbdError>() {
+                Some(NbdError::Protocol(msg)) => assert!(msg.contains(expected), "{msg}"),
+                _ => panic!("unexpected error {err:?}"),
+            }
+            let err = client.flush().unwrap_err();
+            assert!(
+                matches!(err.downcast_ref::<NbdError>(), Some(NbdError::Protocol(_))),
+                "{err:?}"
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn tcp_bind_address() -> Result<()> {
+        // find a free port
+        let port = std::net::TcpListener::bind("127.0.0.1:0")?
+            .local_addr()?
+            .port();
+        let server = Server::new(MemBlocks::new(vec![0u8; 1024]));
+        thread::spawn(move || server.start_on(("127.0.0.1", port)));
+        let url = format!("nbd://127.0.0.1:{port}/default");
+        let mut client = loop {
+            match Client::connect_url(&url) {
+                Ok(client) => break client,
+                Err(_) => thread::sleep(Duration::from_millis(1)),
+            }
+        };
+        client.write(10, &[1, 2, 3])?;
+        assert_eq!(client.read(9, 5)?, [0, 1, 2, 3, 0]);
+        client.disconnect()?;
+
+        // the port is in use
+        let other = Server::new(MemBlocks::new(vec![0u8; 1024]));
+        assert!(other.start_on(("127.0.0.1", port)).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn single_threaded_server() -> Result<()> {
+        let port = std::net::TcpListener::bind("127.0.0.1:0")?
+            .local_addr()?
+            .port();
+        let server = Server::new(MemBlocks::new(vec![0u8; 1024]));
+        thread::spawn(move || server.start_single_threaded_on(("127.0.0.1", port)));
+        let url = format!("nbd://127.0.0.1:{port}/default");
+        let mut first = loop {
+            match Client::connect_url(&url) {
+                Ok(client) => break client,
+                Err(_) => thread::sleep(Duration::from_millis(1)),
+            }
+        };
+        first.write(0, &[1, 2, 3])?;
+
+        // the second connection is not served while the first is open
+        let (tx, rx) = std::sync::mpsc::channel();
+        let second = thread::spawn(move || -> Result<Vec<u8>> {
+            let mut client = Client::connect_url(&url)?;
+            tx.send(()).unwrap();
+            let data = client.read(0, 3)?;
+            client.disconnect()?;
+            Ok(data)
+        });
+        assert!(rx.recv_timeout(Duration::from_millis(100)).is_err());
+        first.disconnect()?;
+        rx.recv_timeout(Duration::from_secs(5))?;
+        assert_eq!(second.join().unwrap()?, [1, 2, 3]);
+        Ok(())
+    }
+
+    #[test]
+    fn shutdown_server() -> Result<()> {
+        let blocks = Arc::new(MemBlocks::new(vec![0u8; 1024]));
+        let handle = Server::new(blocks.clone()).spawn_on("127.0.0.1:0")?;
+        let url = format!("nbd://{}/default", handle.local_addr());
+        let mut client = Client::connect_url(&url)?;
+        let mut idle = Client::connect_url(&url)?;
+        client.write(10, &[1, 2, 3])?;
+        idle.read(0, 10)?;
+
+        handle.shutdown()?;
+        // the write was finished, but the connections are closed
+        let mut buf = [0u8; 3];
+        blocks.read_at(&mut buf, 10)?;
+        assert_eq!(buf, [1, 2, 3]);
+        assert!(client.read(0, 10).is_err());
+        assert!(idle.read(0, 10).is_err());
+        // and no new connections are accepted
+        assert!(Client::connect_url(&url).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn unix_socket() -> Result<()> {
+        let path = std::env::temp_dir().join(format!("nbd-{}.sock", rand::random::<u64>()));
+        let server = Server::new(MemBlocks::new(vec![0u8; 1024]));
+        {
+            let path = path.clone();
+            thread::spawn(move || server.start_unix(path));
+        }
+        while !path.exists() {
+            thread::sleep(Duration::from_millis(1));
+        }
+        let url = format!("nbd+unix:///default?socket={}", path.display());
+        let mut client = Client::connect_url(&url)?;
+        client.write(10, &[1, 2, 3])?;
+        assert_eq!(client.read(9, 5)?, [0, 1, 2, 3, 0]);
+        client.disconnect()?;
+
+        // the path is in use
+        let other = Server::new(MemBlocks::new(vec![0u8; 1024]));
+        assert!(other.start_unix(&path).is_err());
+        // the server is still running, so it won't remove the socket
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn shutdown_unix_server() -> Result<()> {
+        let path = std::env::temp_dir().join(format!("nbd-{}.sock", rand::random::<u64>()));
+        let blocks = Arc::new(MemBlocks::new(vec![0u8; 1024]));
+        let handle = Server::new(blocks.clone()).spawn_unix(&path)?;
+        assert_eq!(handle.path(), path);
+        let url = format!("nbd+unix:///default?socket={}", path.display());
+        let mut client = Client::connect_url(&url)?;
+        client.write(10, &[1, 2, 3])?;
+
+        handle.shutdown()?;
+        let mut buf = [0u8; 3];
+        blocks.read_at(&mut buf, 10)?;
+        assert_eq!(buf, [1, 2, 3]);
+        assert!(client.read(0, 10).is_err());
+        // the socket is removed, so the path can be reused
+        assert!(!path.exists());
+        let handle = Server::new(blocks).spawn_unix(&path)?;
+        handle.shutdown()?;
+        assert!(!path.exists());
+        Ok(())
+    }
+
+    #[test]
+    fn copy_to_sparse_file() -> Result<()> {
+        use std::os::unix::fs::MetadataExt;
+        use std::os::unix::net::UnixStream;
+
+        const SIZE: u64 = 1 << 20;
+        let blocks = Arc::new(SparseMemBlocks::new(SIZE));
+        blocks.write_at(&[1u8; 5000], 4096 * 3 + 10)?;
+        blocks.write_at(&[2u8; 10], 300_000)?;
+        let mut expected = vec![0u8; SIZE as usize];
+        blocks.read_at(&mut expected, 0)?;
+
+        for (structured_replies, block_status) in [(false, false), (true, false), (true, true)] {
+            // a socket rather than a pipe, so the client can send requests
+            // while the server is replying
+            let (s1, s2) = UnixStream::pair()?;
+            let server = Server::new(blocks.clone()).op_log_level(None);
+            let server = thread::spawn(move || server.handle_client(s1));
+            let opts = ClientOptions {
+                structured_replies,
+                block_status,
+                ..Default::default()
+            };
+            let mut client = Client::with_options(s2, opts)?;
+
+            let path = std::env::temp_dir().join(format!("nbd-copy-{}", rand::random::<u64>()));
+            let mut file = std::fs::File::create(&path)?;
+            client.copy_to(&mut file, 4, 1 << 30)?;
+            client.disconnect()?;
+            server.join().unwrap()?;
+
+            let copy = std::fs::read(&path)?;
+            let allocated = file.metadata()?.blocks() * 512;
+            std::fs::remove_file(&path)?;
+            assert!(copy == expected, "copy differs");
+            assert!(
+                allocated < SIZE / 4,
+                "copy is not sparse ({allocated} bytes)"
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn copy_to_status_window() -> Result<()> {
+        use crate::server::{Command, RequestInfo, ServerObserver};
+        use std::os::unix::net::UnixStream;
+        use std::sync::atomic::AtomicUsize;
+
+        #[derive(Default)]
+        struct StatusCounter(AtomicUsize);
+
+        impl ServerObserver for StatusCounter {
+            fn on_request(&self, req: &RequestInfo) {
+                if req.command == Command::BlockStatus {
+                    self.0.fetch_add(1, Ordering::SeqCst);
+                }
+            }
+        }
+
+        // large enough that querying it in small windows takes many requests
+        const SIZE: u64 = 8 << 30;
+        let blocks = Arc::new(SparseMemBlocks::new(SIZE));
+        blocks.write_at(&[1u8; 4096], 4096 * 10)?;
+        blocks.write_at(&[2u8; 4096], SIZE / 2)?;
+
+        for window in [1 << 30, u32::MAX - 4095] {
+            let counter = Arc::new(StatusCounter::default());
+            let server = Server::new(blocks.clone())
+                .op_log_level(None)
+                .observer(counter.clone());
+            // a socket rather than a pipe, so the copy can pipeline reads
+            let (s1, s2) = UnixStream::pair()?;
+            let server = thread::spawn(move || server.handle_client(s1));
+            let opts = ClientOptions {
+                structured_replies: true,
+                block_status: true,
+                ..Default::default()
+            };
+            let mut client = Client::with_options(s2, opts)?;
+            assert_eq!(
+                client.allocated_extents(0, SIZE, window)?,
+                [(4096 * 10, 4096 * 11), (SIZE / 2, SIZE / 2 + 4096)]
+            );
+            let queried = counter.0.swap(0, Ordering::SeqCst) as u64;
+            assert_eq!(queried, SIZE.div_ceil(window as u64));
+
+            let path = std::env::temp_dir().join(format!("nbd-copy-{}", rand::random::<u64>()));
+            let mut file = std::fs::File::create(&path)?;
+            let r = client.copy_to(&mut file, 4, window);
+            let len = file.metadata()?.len();
+            std::fs::remove_file(&path)?;
+            r?;
+            client.disconnect()?;
+            server.join().unwrap()?;
+            assert_eq!(len, SIZE);
+            let queried = counter.0.load(Ordering::SeqCst) as u64;
+            assert!(
+                queried <= SIZE.div_ceil(window as u64),
+                "{queried} block status requests with a window of {window}"
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn copy_from_sparse() -> Result<()> {
+        const SIZE: u64 = 1 << 20;
+        let mut data = vec![0u8; SIZE as usize];
+        data[4096 * 3 + 10..4096 * 3 + 5010].fill(1);
+        data[300_000..300_010].fill(2);
+        // shorter than the export, and not a multiple of the block size
+        let data = &data[..SIZE as usize - 100];
+
+        for sparse in [false, true] {
+            let blocks = Arc::new(SparseMemBlocks::new(SIZE));
+            // data that the copy must overwrite, including with zeros
+            blocks.write_at(&[3u8; 4096 * 4], 4096 * 100)?;
+            let mut sc = start_server_client_with(Server::new(blocks.clone()))?;
+            sc.client.copy_from(&mut &data[..], sparse)?;
+            sc.shutdown()?;
+
+            let mut copy = vec![0u8; data.len()];
+            blocks.read_at(&mut copy, 0)?;
+            assert!(copy == data, "copy differs");
+            if sparse {
+                assert!(blocks.allocated_bytes() <= 4096 * 4);
+            } else {
+                assert_eq!(blocks.allocated_bytes(), SIZE);
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn client_wrong_protocol() {
+        let mut oldstyle = MAGIC.to_be_bytes().to_vec();
+        oldstyle.extend(OLDSTYLE_MAGIC.to_be_bytes());
+        let inputs: [(&[u8], &str); 4] = [
+            (b"HTTP/1.1 400 Bad Request\r\n", "looks like HTTP"),
+            (b"SSH-2.0-OpenSSH_9.6\r\n", "looks like SSH"),
+            (&[0x15, 0x03, 0x03, 0, 2, 2, 50, 0], "looks like TLS"),
+            (&oldstyle, "oldstyle handshake"),
+        ];
+        for (input, expected) in inputs {
+            let stream = ReadWrite::new(input, std::io::sink());
+            let err = Client::new(stream).map(|_| ()).unwrap_err();
+            let msg = format!("{err:?}");
+            assert!(msg.contains(expected), "unexpected error {msg}");
+        }
+    }
+
+    #[test]
+    fn bad_sectors() -> Result<()> {
+        let blocks = BadSectorBlocks::new(MemBlocks::new(vec![1u8; 4096])).bad_sectors(1..2);
+        let mut sc = start_server_client_with(Server::new(blocks).op_log_level(None))?;
+        let client = &mut sc.client;
+        assert_eq!(
+            client.read(0, SECTOR_SIZE as u32)?,
+            [1u8; SECTOR_SIZE as usize]
+        );
+        let err = client.read(SECTOR_SIZE - 10, 20).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<NbdError>(),
+            Some(NbdError::Server { errno: 5, .. })
+        ));
+        assert!(client.write(SECTOR_SIZE, &[2u8; 10]).is_err());
+        client.write(2 * SECTOR_SIZE, &[2u8; 10])?;
+        assert_eq!(client.read(2 * SECTOR_SIZE, 10)?, [2u8; 10]);
+        sc.shutdown()?;
+        Ok(())
+    }
+
+    #[test]
+    fn growing_file_size() -> Result<()> {
+        let path = std::env::temp_dir().join(format!("nbd-grow-{}", rand::random::<u64>()));
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&path)?;
+        file.set_len(4096)?;
+        let server = Server::new(file);
+        let old = start_server_client_with(server.clone())?;
+        assert_eq!(old.client.size(), 4096);
+
+        // another process appends to the file
+        let mut appender = std::fs::OpenOptions::new().append(true).open(&path)?;
+        appender.write_all(&[1u8; 4096])?;
+
+        // a new connection sees the new size, and can read the new data
+        let mut new = start_server_client_with(server)?;
+        std::fs::remove_file(&path)?;
+        assert_eq!(new.client.size(), 8192);
+        assert_eq!(new.client.read(8192 - 3, 3)?, [1, 1, 1]);
+        assert_eq!(old.client.size(), 4096);
+        new.shutdown()?;
+        old.shutdown()?;
+        Ok(())
+    }
+
+    #[test]
+    fn read_len_limit() -> Result<()> {
+        let data = vec![1u8; 1 << 20];
+        let mut sc = start_server_client(data.clone())?;
+        // the server's maximum block size is 256 KiB
+        assert!(sc.client.read(0, 1 << 20).is_err());
+        assert!(sc.client.read(0, u32::MAX).is_err());
+        // the connection is still usable
+        assert_eq!(sc.client.read(0, 1 << 17)?.len(), 1 << 17);
+        sc.shutdown()?;
+
+        let opts = ClientOptions {
+            max_read_len: Some(4096),
+            ..Default::default()
+        };
+        let mut sc = start_server_client_opts(Server::new(MemBlocks::new(data)), opts)?;
+        assert!(sc.client.read(0, 4097).is_err());
+        assert_eq!(sc.client.read(0, 4096)?.len(), 4096);
+        sc.shutdown()?;
+        Ok(())
+    }
+
+    #[test]
+    fn detect_zero_writes() -> Result<()> {
+        let blocks = Arc::new(SparseMemBlocks::new(4096 * 4));
+        let server = Server::new(blocks.clone()).detect_zero_writes(true);
+        let mut sc = start_server_client_with(server)?;
+        sc.client.write(0, &[0u8; 4096 * 2])?;
+        assert_eq!(blocks.allocated_bytes(), 0);
+        assert_eq!(sc.client.read(0, 4096)?, [0u8; 4096]);
+
+        // zeros over existing data still overwrite it
+        sc.client.write(4096, &[1u8; 10])?;
+        sc.client.write(4096, &[0u8; 10])?;
+        assert_eq!(sc.client.read(4096, 10)?, [0u8; 10]);
+        sc.shutdown()?;
+        Ok(())
+    }
+
+    #[test]
+    fn trim_range() -> Result<()> {
+        // much larger than the server's maximum request size
+        const SIZE: u64 = 4096 * 1024;
+        let blocks = Arc::new(SparseMemBlocks::new(SIZE));
+        let mut sc = start_server_client_with(Server::new(blocks.clone()))?;
+        assert!(sc.client.capabilities().trim);
+        for off in (0..SIZE).step_by(4096 * 100) {
+            sc.client.write(off, &[1u8; 10])?;
+        }
+        sc.client.write(SIZE - 4096, &[1u8; 4096])?;
+        assert!(blocks.allocated_bytes() > 0);
+        sc.client.trim_range(0, SIZE)?;
+        assert_eq!(blocks.allocated_bytes(), 0);
+        // rejected without contacting the server
+        assert!(sc.client.trim_range(1, u64::MAX).is_err());
+        sc.shutdown()?;
+        Ok(())
+    }
+
+    #[test]
+    fn client_write_too_large() -> Result<()> {
+        let mut sc = start_server_client(vec![0u8; 1024 * 1024])?;
+        let (_, _, max) = sc.client.block_sizes();
+        sc.client.write(0, &vec![1u8; max as usize])?;
+        // rejected without contacting the server, rather than truncated
+        assert!(sc.client.write(0, &vec![1u8; max as usize + 1]).is_err());
+        assert!(sc
+            .client
+            .write_fua(0, &vec![1u8; max as usize + 1])
+            .is_err());
+        assert_eq!(sc.client.read(0, 4)?, [1u8; 4]);
+        sc.shutdown()?;
+        Ok(())
+    }
+
+    /// Check accesses at the end of an export of `size` bytes.
+    fn check_end_of_export<IO: Read + Write>(client: &mut Client<IO>, size: u64) {
+        // zero-length accesses are invalid anywhere, including the end
+        assert!(client.read(size, 0).is_err());
+        assert!(client.write(size, &[]).is_err());
+        assert!(client.write_zeroes(size, 0, false).is_err());
+        assert!(client.read(0, 0).is_err());
+        // and one byte at the end is out of bounds
+        assert!(client.read(size, 1).is_err());
+        assert!(client.write(size, &[1]).is_err());
+        assert!(client.read(size - 1, 1).is_ok());
+    }
+
+    #[test]
+    fn zero_length_at_end() -> Result<()> {
+        let mut sc = start_server_client(vec![1u8; 1024])?;
+        check_end_of_export(&mut sc.client, 1024);
+        sc.shutdown()?;
+
+        let path = std::env::temp_dir().join(format!("nbd-end-{}", rand::random::<u64>()));
+        std::fs::write(&path, vec![1u8; 1024])?;
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&path)?;
+        let mut sc = start_server_client_with(Server::new(file))?;
+        check_end_of_export(&mut sc.client, 1024);
+        sc.shutdown()?;
+        let len = std::fs::metadata(&path)?.len();
+        std::fs::remove_file(&path)?;
+        assert_eq!(len, 1024, "write past the end extended the file");
+        Ok(())
+    }
+
+    /// A backend that cannot report its size.
+    struct NoSizeBlocks;
+
+    impl Blocks for NoSizeBlocks {
+        fn read_at(&self, _buf: &mut [u8], _off: u64) -> std::io::Result<()> {
+            Ok(())
+        }
+
+        fn write_at(&self, _buf: &[u8], _off: u64) -> std::io::Result<()> {
+            Ok(())
+        }
+
+        fn size(&self) -> std::io::Result<u64> {
+            Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "streaming backend",
+            ))
+        }
+
+        fn flush(&self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn backend_without_size() -> Result<()> {
+        match start_server_client_with(Server::new(NoSizeBlocks)) {
+            Ok(_) => panic!("client connected to backend without a size"),
+            Err(err) => assert!(
+                format!("{err}").contains("does not have a fixed size"),
+                "unexpected error {err:?}"
+            ),
+        }
+        Ok(())
+    }
+
+    /// A backend whose reads and writes alternately return WouldBlock and
+    /// succeed.
+    struct FlakyBlocks {
+        mem: MemBlocks,
+        fail: AtomicBool,
+    }
+
+    impl FlakyBlocks {
+        fn check(&self) -> std::io::Result<()> {
+            if self.fail.fetch_xor(true, Ordering::SeqCst) {
+                return Err(std::io::ErrorKind::WouldBlock.into());
+            }
+            Ok(())
+        }
+    }
+
+    impl Blocks for FlakyBlocks {
+        fn read_at(&self, buf: &mut [u8], off: u64) -> std::io::Result<()> {
+            self.check()?;
+            self.mem.read_at(buf, off)
+        }
+
+        fn write_at(&self, buf: &[u8], off: u64) -> std::io::Result<()> {
+            self.check()?;
+            self.mem.write_at(buf, off)
+        }
+
+        fn size(&self) -> std::io::Result<u64> {
+            self.mem.size()
+        }
+
+        fn flush(&self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn backend_would_block() -> Result<()> {
+        let blocks = FlakyBlocks {
+            mem: MemBlocks::new(vec![0u8; 1024]),
+            fail: AtomicBool::new(true),
+        };
+        let mut sc = start_server_client_with(Server::new(blocks))?;
+        sc.client.write(0, &[1, 2, 3])?;
+        assert_eq!(sc.client.read(0, 3)?, [1, 2, 3]);
+        sc.shutdown()?;
+        Ok(())
+    }
+
+    /// A backend that records readahead hints.
+    struct ReadaheadBlocks {
+        mem: MemBlocks,
+        hints: Mutex<Vec<(u64, u64)>>,
+    }
+
+    impl Blocks for ReadaheadBlocks {
+        fn read_at(&self, buf: &mut [u8], off: u64) -> std::io::Result<()> {
+            self.mem.read_at(buf, off)
+        }
+
+        fn write_at(&self, buf: &[u8], off: u64) -> std::io::Result<()> {
+            self.mem.write_at(buf, off)
+        }
+
+        fn size(&self) -> std::io::Result<u64> {
+            self.mem.size()
+        }
+
+        fn flush(&self) -> std::io::Result<()> {
+            Ok(())
+        }
+
+        fn readahead(&self, off: u64, len: u64) -> std::io::Result<()> {
+            self.hints.lock().unwrap().push((off, len));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn readahead_sequential() -> Result<()> {
+        let blocks = Arc::new(ReadaheadBlocks {
+            mem: MemBlocks::new(vec![0u8; 1000]),
+            hints: Mutex::new(vec![]),
+        });
+        let server = Server::new(blocks.clone()).readahead(true);
+        let mut sc = start_server_client_with(server)?;
+        sc.client.read(0, 100)?;
+        sc.client.read(100, 100)?;
+        sc.client.read(200, 100)?;
+        // not sequential
+        sc.client.read(500, 100)?;
+        // the hint is clamped to the end of the export
+        sc.client.read(600, 300)?;
+        sc.shutdown()?;
+        assert_eq!(
+            *blocks.hints.lock().unwrap(),
+            [(200, 100), (300, 100), (900, 100)]
+        );
+        Ok(())
+    }
+
     #[test]
     fn run_client_server_read_write() -> Result<()> {
         let data = vec![1u8; 1024 * 10];
@@ -8,7 +8,6 @@
 use color_eyre::eyre::{bail, ensure, WrapErr};
 use color_eyre::Result;
 use log::{error, warn};
-use rand::Rng;
 use std::error::Error;
 use std::fmt;
 use std::io::{self, prelude::*, ErrorKind};
@@ -17,18 +16,24 @@ use bitflags::bitflags;
 use byteorder::{ReadBytesExt, WriteBytesExt, BE};
 use num_enum::{IntoPrimitive, TryFromPrimitive};
 
+#[cfg(feature = "tokio")]
+mod async_io;
+
 pub(crate) const TCP_PORT: u16 = 10809;
 
 pub(crate) const MAGIC: u64 = 0x4e42444d41474943; // b"NBDMAGIC"
 pub(crate) const IHAVEOPT: u64 = 0x49484156454F5054; // b"IHAVEOPT"
+/// Sent instead of IHAVEOPT by servers using the oldstyle handshake.
+pub(crate) const OLDSTYLE_MAGIC: u64 = 0x00420281861253;
 pub(crate) const REPLY_MAGIC: u64 = 0x3e889045565a9;
 
 // transmission constants
 pub(crate) const REQUEST_MAGIC: u32 = 0x25609513;
 pub(crate) const SIMPLE_REPLY_MAGIC: u32 = 0x67446698;
+pub(crate) const STRUCTURED_REPLY_MAGIC: u32 = 0x668e33ef;
 
 #[derive(Debug, Clone)]
-pub(crate) struct ProtocolError(String);
+pub(crate) struct ProtocolError(pub(crate) String);
 
 impl ProtocolError {
     pub fn new<S: AsRef<str>>(s: S) -> Self {
@@ -45,6 +50,82 @@ impl fmt::Display for ProtocolError {
 
 impl Error for ProtocolError {}
 
+/// A request for a command the server does not know. Its header was received
+/// in full (and such a command has no payload), so the stream is still in sync
+/// and the server can reply with an error and keep serving.
+#[derive(Debug, Clone)]
+pub(crate) struct UnknownCommand {
+    pub handle: u64,
+    pub typ: u16,
+}
+
+impl fmt::Display for UnknownCommand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "unexpected command {}", self.typ)
+    }
+}
+
+impl Error for UnknownCommand {}
+
+impl UnknownCommand {
+    /// The ENOTSUP reply to the request.
+    pub fn reply(&self) -> SimpleReply<'static> {
+        SimpleReply {
+            err: ErrorType::ENOTSUP,
+            handle: self.handle,
+            data: &[],
+        }
+    }
+}
+
+/// An option whose data is too large to process, which was skipped so the
+/// server can reply with NBD_REP_ERR_TOO_BIG and continue negotiating.
+#[derive(Debug, Clone)]
+pub(crate) struct OptTooBig {
+    pub typ: OptType,
+    pub len: u32,
+}
+
+impl fmt::Display for OptTooBig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "option {:?} of length {} is too large",
+            self.typ, self.len
+        )
+    }
+}
+
+impl Error for OptTooBig {}
+
+/// Recognize the start of a conversation in a protocol other than NBD, to
+/// explain an unexpected magic number.
+pub(crate) fn identify_protocol(prefix: &[u8]) -> Option<&'static str> {
+    const HTTP: [&[u8]; 6] = [b"GET ", b"POST", b"PUT ", b"HEAD", b"OPTI", b"HTTP"];
+    if HTTP.iter().any(|p| prefix.starts_with(p)) {
+        return Some("this looks like HTTP, not NBD");
+    }
+    if prefix.starts_with(b"SSH-") {
+        return Some("this looks like SSH, not NBD");
+    }
+    // a TLS record starts with its type (alert or handshake) and major
+    // version 3
+    if let [0x15 | 0x16, 0x03, ..] = prefix {
+        return Some("this looks like TLS, not NBD (NBD negotiates TLS with NBD_OPT_STARTTLS)");
+    }
+    None
+}
+
+/// Report an unexpected value at the start of the handshake, with a
+/// diagnosis if it is from another protocol.
+pub(crate) fn unexpected_start(what: &str, bytes: &[u8]) -> ProtocolError {
+    let value = bytes.iter().map(|b| format!("{b:02x}")).collect::<String>();
+    match identify_protocol(bytes) {
+        Some(diagnosis) => ProtocolError::new(format!("unexpected {what} 0x{value}: {diagnosis}")),
+        None => ProtocolError::new(format!("unexpected {what} 0x{value}")),
+    }
+}
+
 bitflags! {
   #[derive(Copy, Clone, Debug)]
   pub(crate) struct HandshakeFlags: u16 {
@@ -85,6 +166,9 @@ pub(crate) enum OptType {
     STARTTLS = 5,
     INFO = 6,
     GO = 7,
+    STRUCTURED_REPLY = 8,
+    LIST_META_CONTEXT = 9,
+    SET_META_CONTEXT = 10,
 }
 
 #[derive(IntoPrimitive, TryFromPrimitive, Debug, Copy, Clone, PartialEq, Eq)]
@@ -102,6 +186,7 @@ pub(crate) enum ReplyType {
     ACK = 1,
     SERVER = 2,
     INFO = 3,
+    META_CONTEXT = 4,
     ERR_UNSUP = (1 << 31) + 1,
     ERR_POLICY = (1 << 31) + 2,
     ERR_INVALID = (1 << 31) + 3,
@@ -114,13 +199,18 @@ pub(crate) enum ReplyType {
 
 /// Builder for replying to an option
 #[must_use]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub(crate) struct OptReply {
-    opt: OptType,
-    reply_type: ReplyType,
-    data: Vec<u8>,
+    pub opt: OptType,
+    pub reply_type: ReplyType,
+    pub data: Vec<u8>,
 }
 
 impl OptReply {
+    /// The size of a reply's header, before its data.
+    #[cfg(feature = "tokio")]
+    const HEADER_LEN: usize = 20;
+
     pub fn ack(opt: OptType) -> Self {
         Self {
             opt,
@@ -153,6 +243,40 @@ impl OptReply {
         stream.flush()?;
         Ok(())
     }
+
+    pub fn get<IO: Read>(stream: &mut IO) -> Result<Self> {
+        let (opt, reply_type, len) = Self::get_header(stream)?;
+        let mut data = vec![0u8; len as usize];
+        stream
+            .read_exact(&mut data)
+            .wrap_err_with(|| format!("reading reply to {opt:?} of size {len}"))?;
+        Ok(Self {
+            opt,
+            reply_type,
+            data,
+        })
+    }
+
+    /// Read a reply's header, returning its option, type, and the length of
+    /// its data.
+    fn get_header<IO: Read>(stream: &mut IO) -> Result<(OptType, ReplyType, u32)> {
+        let magic = stream.read_u64::<BE>()?;
+        if magic != REPLY_MAGIC {
+            bail!(ProtocolError(format!("unexpected reply magic {magic}")));
+        }
+        let opt = stream.read_u32::<BE>()?;
+        let opt = OptType::try_from(opt)
+            .map_err(|_| ProtocolError(format!("reply to unexpected option {opt}")))?;
+        let reply_type = stream.read_u32::<BE>()?;
+        let reply_type = ReplyType::try_from(reply_type)
+            .map_err(|_| ProtocolError(format!("unexpected reply type {reply_type}")))?;
+        let len = stream.read_u32::<BE>()?;
+        ensure!(
+            len < 10_000,
+            ProtocolError(format!("option reply length {len} is too large"))
+        );
+        Ok((opt, reply_type, len))
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -162,7 +286,42 @@ pub(crate) struct Opt {
 }
 
 impl Opt {
+    /// The size of an option's header, before its data.
+    #[cfg(feature = "tokio")]
+    const HEADER_LEN: usize = 16;
+
+    /// Options with this much data or more are rejected with [`OptTooBig`].
+    const MAX_LEN: u32 = 10_000;
+
+    /// Get reads the next option from a client.
+    ///
+    /// An option with more data than the server handles fails with an
+    /// [`OptTooBig`] after skipping the data, so the stream stays in sync.
     pub fn get<IO: Read>(stream: &mut IO) -> Result<Self> {
+        let (typ, option_len) = Self::get_header(stream)?;
+        if option_len >= Self::MAX_LEN {
+            let skipped = io::copy(
+                &mut stream.by_ref().take(option_len as u64),
+                &mut io::sink(),
+            )?;
+            if skipped < option_len as u64 {
+                bail!(io::Error::from(io::ErrorKind::UnexpectedEof));
+            }
+            bail!(OptTooBig {
+                typ,
+                len: option_len
+            });
+        }
+        let mut data = vec![0u8; option_len as usize];
+        stream
+            .read_exact(&mut data)
+            .wrap_err_with(|| format!("reading option {:?} of size {option_len}", typ))?;
+        Ok(Self { typ, data })
+    }
+
+    /// Read an option's header, returning its type and the length of its
+    /// data.
+    fn get_header<IO: Read>(stream: &mut IO) -> Result<(OptType, u32)> {
         // C: 64 bits, 0x49484156454F5054 (ASCII 'IHAVEOPT') (note same newstyle handshake's magic number)
         // C: 32 bits, option
         // C: 32 bits, length of option data (unsigned)
@@ -175,15 +334,7 @@ impl Opt {
         let typ = OptType::try_from(option)
             .map_err(|_| ProtocolError(format!("unexpected option {option}")))?;
         let option_len = stream.read_u32::<BE>()?;
-        ensure!(
-            option_len < 10_000,
-            ProtocolError(format!("option length {option_len} is too large"))
-        );
-        let mut data = vec![0u8; option_len as usize];
-        stream
-            .read_exact(&mut data)
-            .wrap_err_with(|| format!("reading option {:?} of size {option_len}", typ))?;
-        Ok(Self { typ, data })
+        Ok((typ, option_len))
     }
 
     pub fn put<IO: Write>(self, stream: &mut IO) -> Result<()> {
@@ -216,16 +367,14 @@ impl ExportList {
             data.write_u32::<BE>(name.len() as u32)?;
             data.write_all(name.as_bytes())?;
             OptReply::new(OptType::LIST, ReplyType::SERVER, data).put(stream)?;
-            OptReply::ack(OptType::LIST).put(stream)?;
         }
+        OptReply::ack(OptType::LIST).put(stream)?;
         Ok(())
     }
 }
 
 #[derive(Debug, Clone)]
 pub(crate) struct InfoRequest {
-    // we just ignore the requested export names in general
-    #[allow(dead_code)]
     pub name: String,
     pub typs: Vec<InfoType>,
 }
@@ -241,12 +390,103 @@ impl InfoRequest {
         let mut typs = vec![];
         for _ in 0..num_requests {
             let typ = stream.read_u16::<BE>()?;
-            let typ = InfoType::try_from(typ)
-                .map_err(|_| ProtocolError::new("invalid info type {typ}"))?;
-            typs.push(typ);
+            // the server should ignore information requests it does not
+            // understand
+            match InfoType::try_from(typ) {
+                Ok(typ) => typs.push(typ),
+                Err(_) => warn!("ignoring unknown info type {typ}"),
+            }
         }
         Ok(InfoRequest { name, typs })
     }
+
+    pub fn put<IO: Write>(&self, stream: &mut IO) -> Result<()> {
+        // C: 32 bits, length of name (unsigned); MUST be no larger than the option data length - 6
+        // C: String: name of the export
+        // C: 16 bits, number of information requests
+        // C: 16 bits x n - list of NBD_INFO information requests
+        stream.write_u32::<BE>(self.name.len() as u32)?;
+        stream.write_all(self.name.as_bytes())?;
+        stream.write_u16::<BE>(self.typs.len() as u16)?;
+        for typ in &self.typs {
+            stream.write_u16::<BE>((*typ).into())?;
+        }
+        Ok(())
+    }
+}
+
+/// The only metadata context this crate supports, which describes holes and
+/// zeroed ranges.
+pub(crate) const ALLOCATION_CONTEXT: &str = "base:allocation";
+
+/// base:allocation status flag: the extent is not allocated.
+pub(crate) const STATE_HOLE: u32 = 1 << 0;
+/// base:allocation status flag: the extent reads as zeros.
+pub(crate) const STATE_ZERO: u32 = 1 << 1;
+
+/// Data for NBD_OPT_LIST_META_CONTEXT and NBD_OPT_SET_META_CONTEXT.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct MetaContextRequest {
+    pub name: String,
+    pub queries: Vec<String>,
+}
+
+impl MetaContextRequest {
+    pub fn get<IO: Read>(stream: &mut IO) -> Result<Self> {
+        let read_string = |stream: &mut IO| -> Result<String> {
+            let len = stream.read_u32::<BE>()?;
+            ensure!(
+                len < 10_000,
+                ProtocolError(format!("string of length {len} is too long"))
+            );
+            let mut buf = vec![0; len as usize];
+            stream.read_exact(&mut buf)?;
+            String::from_utf8(buf).wrap_err(ProtocolError::new("invalid UTF-8 in string"))
+        };
+        let name = read_string(stream)?;
+        let num_queries = stream.read_u32::<BE>()?;
+        let mut queries = vec![];
+        for _ in 0..num_queries {
+            queries.push(read_string(stream)?);
+        }
+        Ok(Self { name, queries })
+    }
+
+    pub fn put<IO: Write>(&self, stream: &mut IO) -> Result<()> {
+        // C: 32 bits, length of export name.
+        // C: String, name of export for which we wish to list metadata contexts.
+        // C: 32 bits, number of queries
+        // C: 32 bits, length of query
+        // C: String, query to list a subset of the available metadata contexts.
+        stream.write_u32::<BE>(self.name.len() as u32)?;
+        stream.write_all(self.name.as_bytes())?;
+        stream.write_u32::<BE>(self.queries.len() as u32)?;
+        for query in &self.queries {
+            stream.write_u32::<BE>(query.len() as u32)?;
+            stream.write_all(query.as_bytes())?;
+        }
+        Ok(())
+    }
+}
+
+/// Block size constraints, as sent in an NBD_INFO_BLOCK_SIZE reply.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct BlockSize {
+    pub minimum: u32,
+    pub preferred: u32,
+    pub maximum: u32,
+}
+
+impl Default for BlockSize {
+    fn default() -> Self {
+        // defaults from the "Block size constraints" section of the spec for a
+        // server that does not send NBD_INFO_BLOCK_SIZE
+        Self {
+            minimum: 1,
+            preferred: 4096,
+            maximum: 32 * 1024 * 1024,
+        }
+    }
 }
 
 // -------------------
@@ -305,6 +545,8 @@ impl fmt::Debug for Request {
             || self.typ == Cmd::WRITE
             || self.typ == Cmd::TRIM
             || self.typ == Cmd::CACHE
+            || self.typ == Cmd::WRITE_ZEROES
+            || self.typ == Cmd::RESIZE
         {
             f = f.field("offset", &self.offset);
         }
@@ -316,8 +558,18 @@ impl fmt::Debug for Request {
 }
 
 impl Request {
+    /// The size of a request before its data.
+    #[cfg(feature = "tokio")]
+    const HEADER_LEN: usize = 28;
+
+    /// Create a request with a random handle, for tests that send requests
+    /// without a client.
+    #[cfg(test)]
     pub fn new(typ: Cmd, offset: u64, len: u32) -> Self {
-        let handle = rand::thread_rng().gen::<u64>();
+        Self::with_handle(rand::random::<u64>(), typ, offset, len)
+    }
+
+    pub fn with_handle(handle: u64, typ: Cmd, offset: u64, len: u32) -> Self {
         let data_len = if typ == Cmd::WRITE { len as usize } else { 0 };
         Request {
             flags: CmdFlags::empty(),
@@ -351,7 +603,30 @@ impl Request {
     }
 
     /// Get reads the next request, storing the data for a write request in buf.
+    ///
+    /// A write larger than buf has its data discarded, leaving `data_len` less
+    /// than `len`, so the stream stays in sync and the server can reject it.
+    /// Unknown flags are kept for the server to reject. An unknown command
+    /// fails with an [`UnknownCommand`], after which the stream is still in
+    /// sync; other failures leave the stream out of sync.
     pub fn get<IO: Read>(stream: &mut IO, buf: &mut [u8]) -> Result<Self> {
+        let mut req = Self::get_header(stream)?;
+        if req.typ == Cmd::WRITE && req.len as usize > buf.len() {
+            let skipped = io::copy(&mut stream.by_ref().take(req.len as u64), &mut io::sink())?;
+            if skipped < req.len as u64 {
+                bail!(io::Error::from(io::ErrorKind::UnexpectedEof));
+            }
+        } else if req.typ == Cmd::WRITE {
+            req.data_len = req.len as usize;
+            stream
+                .read_exact(&mut buf[..req.data_len])
+                .wrap_err_with(|| format!("parsing write request of length {}", req.data_len))?;
+        }
+        Ok(req)
+    }
+
+    /// Read a request up to its data, if any, leaving `data_len` 0.
+    fn get_header<IO: Read>(stream: &mut IO) -> Result<Self> {
         // C: 32 bits, 0x25609513, magic (NBD_REQUEST_MAGIC)
         // C: 16 bits, command flags
         // C: 16 bits, type
@@ -363,35 +638,47 @@ impl Request {
         if magic != REQUEST_MAGIC {
             bail!(ProtocolError(format!("wrong request magic {}", magic)));
         }
-        let flags = stream.read_u16::<BE>()?;
-        let flags = CmdFlags::from_bits(flags)
-            .ok_or_else(|| ProtocolError(format!("unexpected command flags {}", flags)))?;
+        let flags = CmdFlags::from_bits_retain(stream.read_u16::<BE>()?);
         let typ = stream.read_u16::<BE>()?;
-        let typ =
-            Cmd::try_from(typ).map_err(|_| ProtocolError(format!("unexpected command {}", typ)))?;
         let handle = stream.read_u64::<BE>()?;
         let offset = stream.read_u64::<BE>()?;
         let len = stream.read_u32::<BE>()?;
-        let data_len;
-        if typ == Cmd::WRITE {
-            data_len = (len as usize).min(buf.len());
-            stream
-                .read_exact(&mut buf[..data_len])
-                .wrap_err_with(|| format!("parsing write request of length {data_len}"))?;
-        } else {
-            data_len = 0;
-        };
+        let typ = Cmd::try_from(typ).map_err(|_| UnknownCommand { handle, typ })?;
         Ok(Self {
             flags,
             typ,
             handle,
             offset,
             len,
-            data_len,
+            data_len: 0,
+        })
+    }
+
+    /// Parse the request at the start of `buf` without consuming it, if `buf`
+    /// holds a complete and valid request with no payload (that is, anything
+    /// but a write).
+    pub fn peek(mut buf: &[u8]) -> Option<Self> {
+        if buf.read_u32::<BE>().ok()? != REQUEST_MAGIC {
+            return None;
+        }
+        let flags = CmdFlags::from_bits(buf.read_u16::<BE>().ok()?)?;
+        let typ = Cmd::try_from(buf.read_u16::<BE>().ok()?).ok()?;
+        if typ == Cmd::WRITE {
+            return None;
+        }
+        Some(Self {
+            flags,
+            typ,
+            handle: buf.read_u64::<BE>().ok()?,
+            offset: buf.read_u64::<BE>().ok()?,
+            len: buf.read_u32::<BE>().ok()?,
+            data_len: 0,
         })
     }
 }
 
+/// Error values allowed on the wire by the spec (which happen to match the
+/// Linux errno values).
 #[derive(IntoPrimitive, TryFromPrimitive, Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u32)]
 pub(crate) enum ErrorType {
@@ -409,15 +696,73 @@ pub(crate) enum ErrorType {
 impl ErrorType {
     pub fn from_io_kind(kind: io::ErrorKind) -> Self {
         match kind {
-            ErrorKind::PermissionDenied => Self::EPERM,
-            ErrorKind::InvalidInput => Self::EOVERFLOW,
-            ErrorKind::UnexpectedEof => Self::EOVERFLOW,
+            ErrorKind::PermissionDenied | ErrorKind::ReadOnlyFilesystem => Self::EPERM,
+            ErrorKind::OutOfMemory => Self::ENOMEM,
+            // out-of-bounds accesses are reported as invalid input (by
+            // MemBlocks) or an unexpected EOF (by files)
+            ErrorKind::InvalidInput | ErrorKind::UnexpectedEof => Self::EINVAL,
+            // the spec says to map EDQUOT and EFBIG to ENOSPC, and a write that
+            // makes no progress is most likely out of space
+            ErrorKind::StorageFull
+            | ErrorKind::QuotaExceeded
+            | ErrorKind::FileTooLarge
+            | ErrorKind::WriteZero => Self::ENOSPC,
+            ErrorKind::Unsupported => Self::ENOTSUP,
             _ => {
                 warn!("unexpected error {}", kind);
                 Self::EIO
             }
         }
     }
+
+    /// Convert an I/O error to the closest error allowed by the protocol,
+    /// preferring the OS error code if there is one.
+    pub fn from_io_error(err: &io::Error) -> Self {
+        use nix::errno::Errno;
+        if let Some(errno) = err.raw_os_error() {
+            match Errno::from_raw(errno) {
+                Errno::EPERM | Errno::EACCES | Errno::EROFS => return Self::EPERM,
+                Errno::EIO => return Self::EIO,
+                Errno::ENOMEM => return Self::ENOMEM,
+                Errno::EINVAL => return Self::EINVAL,
+                Errno::ENOSPC | Errno::EDQUOT | Errno::EFBIG => return Self::ENOSPC,
+                Errno::EOVERFLOW => return Self::EOVERFLOW,
+                Errno::ENOTSUP => return Self::ENOTSUP,
+                Errno::ESHUTDOWN => return Self::ESHUTDOWN,
+                _ => {}
+            }
+        }
+        Self::from_io_kind(err.kind())
+    }
+
+    /// Parse an error from the wire. The spec says clients should treat
+    /// unexpected values as EINVAL.
+    pub fn from_wire(err: u32) -> Self {
+        Self::try_from(err).unwrap_or_else(|_| {
+            warn!("unexpected error value {err}");
+            Self::EINVAL
+        })
+    }
+
+    fn description(&self) -> &'static str {
+        match self {
+            Self::OK => "success",
+            Self::EPERM => "operation not permitted",
+            Self::EIO => "input/output error",
+            Self::ENOMEM => "cannot allocate memory",
+            Self::EINVAL => "invalid argument",
+            Self::ENOSPC => "no space left on device",
+            Self::EOVERFLOW => "value too large",
+            Self::ENOTSUP => "operation not supported",
+            Self::ESHUTDOWN => "server is shutting down",
+        }
+    }
+}
+
+impl fmt::Display for ErrorType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?} ({})", self, self.description())
+    }
 }
 
 #[derive(Debug)]
@@ -437,10 +782,6 @@ impl<'a> SimpleReply<'a> {
         }
     }
 
-    pub fn ok(req: &Request) -> Self {
-        Self::data(req, &[])
-    }
-
     pub fn err(err: ErrorType, req: &Request) -> Self {
         SimpleReply {
             err,
@@ -449,20 +790,11 @@ impl<'a> SimpleReply<'a> {
         }
     }
 
+    #[cfg(test)]
     pub fn get<IO: Read>(stream: &mut IO, buf: &'a mut [u8]) -> Result<Self> {
-        let mut magic_buf = [0u8; 4];
-        let n = stream.read(&mut magic_buf)?;
-        if n == 0 {
-            error!("socket is closed for reading");
-        }
-        let magic = u32::from_be_bytes(magic_buf);
-        if magic != SIMPLE_REPLY_MAGIC {
-            bail!(ProtocolError::new(format!("wrong reply magic {magic}")));
-        }
-        let err = stream.read_u32::<BE>()?;
-        let err = ErrorType::try_from(err)
-            .map_err(|_| ProtocolError::new(format!("invalid error type {err}")))?;
-        let handle = stream.read_u64::<BE>()?;
+        let ReplyHeader::Simple { err, handle } = ReplyHeader::get(stream)? else {
+            bail!(ProtocolError::new("unexpected structured reply"));
+        };
         stream.read_exact(buf)?;
         Ok(Self {
             err,
@@ -486,6 +818,113 @@ impl<'a> SimpleReply<'a> {
     }
 }
 
+/// The header of either kind of reply in the transmission phase.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum ReplyHeader {
+    /// A simple reply, followed by data for a successful read.
+    Simple { err: ErrorType, handle: u64 },
+    /// One chunk of a structured reply, followed by its payload.
+    Structured(ChunkHeader),
+}
+
+impl ReplyHeader {
+    pub fn get<IO: Read>(stream: &mut IO) -> Result<Self> {
+        let mut magic_buf = [0u8; 4];
+        let n = stream.read(&mut magic_buf)?;
+        if n == 0 {
+            error!("socket is closed for reading");
+        }
+        stream.read_exact(&mut magic_buf[n..])?;
+        let magic = u32::from_be_bytes(magic_buf);
+        match magic {
+            SIMPLE_REPLY_MAGIC => {
+                let err = stream.read_u32::<BE>()?;
+                let err = ErrorType::try_from(err)
+                    .map_err(|_| ProtocolError(format!("unknown error value {err} in reply")))?;
+                let handle = stream.read_u64::<BE>()?;
+                Ok(Self::Simple { err, handle })
+            }
+            STRUCTURED_REPLY_MAGIC => Ok(Self::Structured(ChunkHeader::get(stream)?)),
+            _ => bail!(ProtocolError::new(format!("wrong reply magic {magic}"))),
+        }
+    }
+}
+
+#[derive(IntoPrimitive, TryFromPrimitive, Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u16)]
+pub(crate) enum ChunkType {
+    NONE = 0,
+    OFFSET_DATA = 1,
+    OFFSET_HOLE = 2,
+    BLOCK_STATUS = 5,
+    ERROR = (1 << 15) + 1,
+    ERROR_OFFSET = (1 << 15) + 2,
+}
+
+bitflags! {
+    #[derive(Copy, Clone, Debug, PartialEq, Eq)]
+    pub(crate) struct ChunkFlags: u16 {
+        const DONE = 1 << 0;
+    }
+}
+
+/// Header for one chunk of a structured reply.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct ChunkHeader {
+    pub flags: ChunkFlags,
+    /// Chunk type, kept as a number since clients must tolerate unknown types.
+    pub typ: u16,
+    pub handle: u64,
+    /// Length of the payload that follows.
+    pub len: u32,
+}
+
+impl ChunkHeader {
+    /// Parse the rest of a chunk header, after the magic.
+    fn get<IO: Read>(stream: &mut IO) -> Result<Self> {
+        // S: 32 bits, 0x668e33ef, magic (NBD_STRUCTURED_REPLY_MAGIC)
+        // S: 16 bits, flags
+        // S: 16 bits, type
+        // S: 64 bits, handle
+        // S: 32 bits, length of payload (unsigned)
+        // S: length bytes of payload data (if length is nonzero)
+        let flags = ChunkFlags::from_bits_truncate(stream.read_u16::<BE>()?);
+        let typ = stream.read_u16::<BE>()?;
+        let handle = stream.read_u64::<BE>()?;
+        let len = stream.read_u32::<BE>()?;
+        Ok(Self {
+            flags,
+            typ,
+            handle,
+            len,
+        })
+    }
+
+    /// Send a chunk of a structured reply, with the payload split into parts.
+    pub fn put<IO: Write>(
+        stream: &mut IO,
+        flags: ChunkFlags,
+        typ: ChunkType,
+        handle: u64,
+        payload: &[&[u8]],
+    ) -> Result<()> {
+        let len: usize = payload.iter().map(|p| p.len()).sum();
+        stream.write_u32::<BE>(STRUCTURED_REPLY_MAGIC)?;
+        stream.write_u16::<BE>(flags.bits())?;
+        stream.write_u16::<BE>(typ.into())?;
+        stream.write_u64::<BE>(handle)?;
+        stream.write_u32::<BE>(len as u32)?;
+        for part in payload {
+            stream.write_all(part)?;
+        }
+        Ok(())
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.flags.contains(ChunkFlags::DONE)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -502,6 +941,133 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_opt_reply_get_put() -> Result<()> {
+        let reply = OptReply::new(OptType::GO, ReplyType::INFO, vec![0, 3, 1, 2]);
+        let mut buf = vec![];
+        reply.clone().put(&mut buf)?;
+        assert_eq!(OptReply::get(&mut &buf[..])?, reply);
+        Ok(())
+    }
+
+    #[test]
+    fn test_info_request_get_put() -> Result<()> {
+        let req = InfoRequest {
+            name: "default".to_string(),
+            typs: vec![InfoType::BLOCK_SIZE, InfoType::NAME],
+        };
+        let mut buf = vec![];
+        req.put(&mut buf)?;
+        let parsed = InfoRequest::get(&mut &buf[..])?;
+        assert_eq!(parsed.name, req.name);
+        assert_eq!(parsed.typs, req.typs);
+        Ok(())
+    }
+
+    #[test]
+    fn test_error_from_io_kind() {
+        for (kind, err) in [
+            (ErrorKind::PermissionDenied, ErrorType::EPERM),
+            (ErrorKind::ReadOnlyFilesystem, ErrorType::EPERM),
+            (ErrorKind::OutOfMemory, ErrorType::ENOMEM),
+            (ErrorKind::InvalidInput, ErrorType::EINVAL),
+            (ErrorKind::UnexpectedEof, ErrorType::EINVAL),
+            (ErrorKind::StorageFull, ErrorType::ENOSPC),
+            (ErrorKind::QuotaExceeded, ErrorType::ENOSPC),
+            (ErrorKind::FileTooLarge, ErrorType::ENOSPC),
+            (ErrorKind::WriteZero, ErrorType::ENOSPC),
+            (ErrorKind::Unsupported, ErrorType::ENOTSUP),
+            (ErrorKind::Other, ErrorType::EIO),
+        ] {
+            assert_eq!(ErrorType::from_io_kind(kind), err, "mapping {kind:?}");
+        }
+    }
+
+    #[test]
+    fn test_error_from_io_error() {
+        use nix::errno::Errno;
+        for (errno, err) in [
+            (Errno::EPERM, ErrorType::EPERM),
+            (Errno::EACCES, ErrorType::EPERM),
+            (Errno::EROFS, ErrorType::EPERM),
+            (Errno::EIO, ErrorType::EIO),
+            (Errno::ENOMEM, ErrorType::ENOMEM),
+            (Errno::EINVAL, ErrorType::EINVAL),
+            (Errno::ENOSPC, ErrorType::ENOSPC),
+            (Errno::EDQUOT, ErrorType::ENOSPC),
+            (Errno::EFBIG, ErrorType::ENOSPC),
+            (Errno::EOVERFLOW, ErrorType::EOVERFLOW),
+            (Errno::ENOTSUP, ErrorType::ENOTSUP),
+            (Errno::ESHUTDOWN, ErrorType::ESHUTDOWN),
+            (Errno::EBADF, ErrorType::EIO),
+        ] {
+            let io_err = io::Error::from_raw_os_error(errno as i32);
+            assert_eq!(ErrorType::from_io_error(&io_err), err, "mapping {errno:?}");
+        }
+        let io_err = io::Error::new(ErrorKind::InvalidInput, "out-of-bounds read");
+        assert_eq!(ErrorType::from_io_error(&io_err), ErrorType::EINVAL);
+    }
+
+    #[test]
+    fn test_error_from_wire() {
+        assert_eq!(ErrorType::from_wire(28), ErrorType::ENOSPC);
+        assert_eq!(ErrorType::from_wire(27), ErrorType::EINVAL);
+        assert_eq!(
+            ErrorType::ENOSPC.to_string(),
+            "ENOSPC (no space left on device)"
+        );
+    }
+
+    #[test]
+    fn test_info_request_unknown_type() -> Result<()> {
+        let mut buf = vec![];
+        buf.write_u32::<BE>(0)?;
+        buf.write_u16::<BE>(3)?;
+        buf.write_u16::<BE>(InfoType::BLOCK_SIZE.into())?;
+        buf.write_u16::<BE>(1234)?;
+        buf.write_u16::<BE>(InfoType::NAME.into())?;
+        let req = InfoRequest::get(&mut &buf[..])?;
+        assert_eq!(req.typs, [InfoType::BLOCK_SIZE, InfoType::NAME]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_chunk_get_put() -> Result<()> {
+        let mut buf = vec![];
+        ChunkHeader::put(
+            &mut buf,
+            ChunkFlags::DONE,
+            ChunkType::OFFSET_DATA,
+            1234,
+            &[&[1, 2], &[3]],
+        )?;
+        let mut stream = &buf[..];
+        let header = ReplyHeader::get(&mut stream)?;
+        assert_eq!(
+            header,
+            ReplyHeader::Structured(ChunkHeader {
+                flags: ChunkFlags::DONE,
+                typ: ChunkType::OFFSET_DATA.into(),
+                handle: 1234,
+                len: 3,
+            })
+        );
+        assert_eq!(stream, [1, 2, 3]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_meta_context_request_get_put() -> Result<()> {
+        let req = MetaContextRequest {
+            name: "default".to_string(),
+            queries: vec![ALLOCATION_CONTEXT.to_string(), "base:".to_string()],
+        };
+        let mut buf = vec![];
+        req.put(&mut buf)?;
+        assert_eq!(MetaContextRequest::get(&mut &buf[..])?, req);
+        Ok(())
+    }
+
     #[test]
     fn test_request_get_put_read() -> Result<()> {
         let req = Request {
@@ -518,6 +1084,42 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_identify_protocol() {
+        assert_eq!(
+            identify_protocol(b"GET / HT"),
+            Some("this looks like HTTP, not NBD")
+        );
+        assert_eq!(
+            identify_protocol(b"HTTP/1.1"),
+            Some("this looks like HTTP, not NBD")
+        );
+        assert_eq!(
+            identify_protocol(b"SSH-2.0-"),
+            Some("this looks like SSH, not NBD")
+        );
+        assert!(identify_protocol(&[0x16, 0x03, 0x01, 0x02])
+            .unwrap()
+            .contains("TLS"));
+        assert_eq!(identify_protocol(&MAGIC.to_be_bytes()), None);
+        assert_eq!(identify_protocol(&[0x16]), None);
+    }
+
+    #[test]
+    fn test_request_peek() -> Result<()> {
+        let req = Request::with_handle(7, Cmd::READ, 4096, 512);
+        let mut buf = vec![];
+        req.put(&[], &mut buf)?;
+        assert_eq!(Request::peek(&buf), Some(req));
+        // incomplete
+        assert_eq!(Request::peek(&buf[..buf.len() - 1]), None);
+        // writes have a payload, so they are not peeked
+        let mut buf = vec![];
+        Request::with_handle(8, Cmd::WRITE, 0, 1).put(&[1], &mut buf)?;
+        assert_eq!(Request::peek(&buf), None);
+        Ok(())
+    }
+
     #[test]
     fn test_request_get_put_write() -> Result<()> {
         let req = Request {
@@ -536,4 +1138,23 @@ mod tests {
         assert_eq!(data, data_read);
         Ok(())
     }
+
+    #[test]
+    fn test_request_get_oversized_write() -> Result<()> {
+        let mut stream = vec![];
+        Request::with_handle(1, Cmd::WRITE, 0, 20).put(&[7; 20], &mut stream)?;
+        let read = Request::with_handle(2, Cmd::READ, 0, 20);
+        read.put(&[], &mut stream)?;
+
+        let mut stream = &stream[..];
+        let mut buf = [0u8; 10];
+        let req = Request::get(&mut stream, &mut buf)?;
+        // the data is skipped, not truncated into buf
+        assert_eq!((req.handle, req.len, req.data_len), (1, 20, 0));
+        assert_eq!(buf, [0u8; 10]);
+        // and the next request still parses
+        assert_eq!(Request::get(&mut stream, &mut buf)?, read);
+        assert!(stream.is_empty());
+        Ok(())
+    }
 }
@@ -0,0 +1,98 @@
+//! Reading messages from async streams, for the tokio server and client.
+//!
+//! Each message's fixed-size header is read into a buffer and parsed by the
+//! same code as the blocking versions; only the variable-length data is read
+//! separately. Messages are sent by writing them to a buffer first.
+
+use std::io;
+
+use color_eyre::eyre::{bail, WrapErr};
+use color_eyre::Result;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use super::*;
+
+/// Read and discard `len` bytes from `stream`.
+async fn skip<IO: AsyncRead + Unpin>(stream: &mut IO, len: u64) -> Result<()> {
+    let skipped = tokio::io::copy(&mut stream.take(len), &mut tokio::io::sink()).await?;
+    if skipped < len {
+        bail!(io::Error::from(io::ErrorKind::UnexpectedEof));
+    }
+    Ok(())
+}
+
+impl Opt {
+    /// Like [`Opt::get`], but from an async stream.
+    pub async fn get_async<IO: AsyncRead + Unpin>(stream: &mut IO) -> Result<Self> {
+        let mut header = [0u8; Self::HEADER_LEN];
+        stream.read_exact(&mut header).await?;
+        let (typ, option_len) = Self::get_header(&mut &header[..])?;
+        if option_len >= Self::MAX_LEN {
+            skip(stream, option_len as u64).await?;
+            bail!(OptTooBig {
+                typ,
+                len: option_len
+            });
+        }
+        let mut data = vec![0u8; option_len as usize];
+        stream
+            .read_exact(&mut data)
+            .await
+            .wrap_err_with(|| format!("reading option {:?} of size {option_len}", typ))?;
+        Ok(Self { typ, data })
+    }
+}
+
+impl Request {
+    /// Like [`Request::get`], but from an async stream.
+    pub async fn get_async<IO: AsyncRead + Unpin>(stream: &mut IO, buf: &mut [u8]) -> Result<Self> {
+        let mut header = [0u8; Self::HEADER_LEN];
+        stream.read_exact(&mut header).await?;
+        let mut req = Self::get_header(&mut &header[..])?;
+        if req.typ == Cmd::WRITE && req.len as usize > buf.len() {
+            skip(stream, req.len as u64).await?;
+        } else if req.typ == Cmd::WRITE {
+            req.data_len = req.len as usize;
+            stream
+                .read_exact(&mut buf[..req.data_len])
+                .await
+                .wrap_err_with(|| format!("parsing write request of length {}", req.data_len))?;
+        }
+        Ok(req)
+    }
+}
+
+impl OptReply {
+    /// Like [`OptReply::get`], but from an async stream.
+    pub async fn get_async<IO: AsyncRead + Unpin>(stream: &mut IO) -> Result<Self> {
+        let mut header = [0u8; Self::HEADER_LEN];
+        stream.read_exact(&mut header).await?;
+        let (opt, reply_type, len) = Self::get_header(&mut &header[..])?;
+        let mut data = vec![0u8; len as usize];
+        stream
+            .read_exact(&mut data)
+            .await
+            .wrap_err_with(|| format!("reading reply to {opt:?} of size {len}"))?;
+        Ok(Self {
+            opt,
+            reply_type,
+            data,
+        })
+    }
+}
+
+impl ReplyHeader {
+    /// Like [`ReplyHeader::get`], but from an async stream.
+    pub async fn get_async<IO: AsyncRead + Unpin>(stream: &mut IO) -> Result<Self> {
+        // the magic determines the length of the rest of the header
+        let mut header = [0u8; 20];
+        stream.read_exact(&mut header[..4]).await?;
+        let len = match u32::from_be_bytes([header[0], header[1], header[2], header[3]]) {
+            SIMPLE_REPLY_MAGIC => 16,
+            STRUCTURED_REPLY_MAGIC => 20,
+            magic => bail!(ProtocolError::new(format!("wrong reply magic {magic}"))),
+        };
+        stream.read_exact(&mut header[4..len]).await?;
+        Self::get(&mut &header[..len])
+    }
+}
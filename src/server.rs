@@ -1,45 +1,298 @@
 //! Network Block Device server, exporting an underlying file.
 //!
-//! Implements the most basic parts of the protocol: a single export,
-//! read/write/flush commands, and no other flags (eg, read-only or TLS
-//! support).
+//! Implements the fixed newstyle handshake with named exports (NBD_OPT_GO,
+//! NBD_OPT_INFO, and NBD_OPT_LIST), structured replies, the base:allocation
+//! metadata context, and upgrading to TLS with NBD_OPT_STARTTLS. Exports can
+//! be read-only, and support read, write, flush, trim, write zeroes, cache,
+//! and block status commands (with FUA), as well as the resize extension for
+//! backends that support it.
 //!
 //! See <https://github.com/NetworkBlockDevice/nbd/blob/master/doc/proto.md> for
 //! the protocol description.
 
 #![deny(missing_docs)]
 use std::fs::File;
-use std::io::{self, prelude::*};
-use std::net::TcpListener;
+use std::io::{self, prelude::*, BufReader, IoSlice};
+use std::net::{TcpListener, ToSocketAddrs};
 use std::os::unix::fs::FileExt;
-use std::sync::{Arc, Mutex};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
 use std::thread;
+use std::time::{Duration, Instant};
 
 use byteorder::{ReadBytesExt, WriteBytesExt, BE};
-use color_eyre::eyre::{bail, WrapErr};
+use color_eyre::eyre::{bail, eyre, Report, WrapErr};
 use color_eyre::Result;
-use log::{info, warn};
+use log::{debug, info, log, warn, Level};
 
 use crate::proto::*;
 
+#[cfg(feature = "tokio")]
+mod async_server;
+#[cfg(any(test, feature = "testutil"))]
+mod bad_sectors;
+#[cfg(any(test, feature = "testutil"))]
+mod chaos;
+#[cfg(feature = "compress")]
+mod compress;
+mod control;
+#[cfg(feature = "encrypt")]
+mod encrypt;
+mod handle;
+mod locks;
+mod meta;
+mod observer;
+mod overlay;
+mod shm;
+mod snapshot;
+mod sparse;
+mod sub;
+mod tls;
+mod trigger;
+mod workers;
+#[cfg(feature = "tokio")]
+pub use async_server::AsyncServer;
+#[cfg(any(test, feature = "testutil"))]
+pub use bad_sectors::{BadSectorBlocks, SECTOR_SIZE};
+#[cfg(any(test, feature = "testutil"))]
+pub use chaos::ChaosCommand;
+#[cfg(feature = "compress")]
+pub use compress::{Codec, CompressedBlocks, Deflate};
+#[cfg(feature = "encrypt")]
+pub use encrypt::{EncryptedBlocks, ENCRYPTION_SECTOR_SIZE};
+use handle::{CloseRead, Connections};
+pub use handle::{ServerHandle, UnixServerHandle};
+pub use locks::{LockedBlocks, RangeLock, RangeLocks};
+use observer::Observer;
+pub use observer::{Command, RequestInfo, ServerObserver};
+pub use overlay::OverlayBlocks;
+pub use shm::ShmBlocks;
+pub use snapshot::SnapshotBlocks;
+pub use sparse::SparseMemBlocks;
+pub use sub::SubBlocks;
+use tls::{Stream, TlsUpgrade};
+use trigger::WriteTrigger;
+pub use trigger::{WriteAction, WriteCondition};
+use workers::SplitStream;
+
+/// Identifies a point-in-time snapshot taken with [`Blocks::snapshot`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SnapshotId(pub u64);
+
+/// A run of bytes with the same allocation status, as reported by
+/// [`Blocks::extent_status`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Extent {
+    /// Length of the run in bytes.
+    pub len: u64,
+    /// The range is not allocated in the backend.
+    pub hole: bool,
+    /// The range reads as zeros.
+    pub zero: bool,
+}
+
 /// Blocks is a byte array that can be exported by this server, with a basic
 /// read/write API that works on arbitrary offsets.
 ///
 /// Blocks is implemented for unix files (using the underlying `pread` and
 /// `pwrite` system calls) and for [`MemBlocks`] for exporting an in-memory byte
 /// array.
+///
+/// The server retries operations that fail with
+/// [`io::ErrorKind::WouldBlock`] or [`io::ErrorKind::Interrupted`] a few
+/// times before reporting an error to the client.
 pub trait Blocks {
     /// Fill buf starting from off (reading `buf.len()` bytes)
     fn read_at(&self, buf: &mut [u8], off: u64) -> io::Result<()>;
 
     /// Write data from buf to self starting at off (writing `buf.len()` bytes)
+    ///
+    /// The server rejects zero-length reads and writes with EINVAL before
+    /// calling the backend, so `buf` is never empty for NBD requests.
     fn write_at(&self, buf: &[u8], off: u64) -> io::Result<()>;
 
+    /// Write the concatenation of `bufs` to self starting at off.
+    ///
+    /// The default implementation calls [`Blocks::write_at`] for each
+    /// buffer; backends can override this to issue fewer system calls.
+    fn write_vectored_at(&self, bufs: &[IoSlice], off: u64) -> io::Result<()> {
+        let mut pos = off;
+        for buf in bufs.iter().filter(|buf| !buf.is_empty()) {
+            self.write_at(buf, pos)?;
+            pos += buf.len() as u64;
+        }
+        Ok(())
+    }
+
     /// Get the size of this array (in bytes)
+    ///
+    /// NBD requires a fixed size when a client connects, so an export whose
+    /// backend returns an error here fails negotiation.
+    ///
+    /// The size is queried each time a client negotiates an export, so if
+    /// the backend grows (for example, a file that another process appends
+    /// to), clients that connect afterward see the new size. NBD has no way
+    /// to notify connected clients of a size change; they keep the size from
+    /// their handshake until they reconnect. In between, the server caches
+    /// the size to check requests against, updating it after a resize.
     fn size(&self) -> io::Result<u64>;
 
     /// Flush any outstanding writes to stable storage.
     fn flush(&self) -> io::Result<()>;
+
+    /// Flush outstanding writes to `[off, off+len)` to stable storage, for a
+    /// request with the FUA flag.
+    ///
+    /// Backends that can persist part of their data more cheaply than all of
+    /// it should override this. The default implementation calls
+    /// [`Blocks::flush`].
+    fn flush_range(&self, off: u64, len: u64) -> io::Result<()> {
+        let _ = (off, len);
+        self.flush()
+    }
+
+    /// Whether clients can safely use several connections to this export at
+    /// once, in which case the server advertises NBD_FLAG_CAN_MULTI_CONN.
+    ///
+    /// A backend that returns true must make writes visible to reads from
+    /// every connection as soon as they complete, and a [`Blocks::flush`]
+    /// must persist writes that completed on any connection, not just those
+    /// issued through the same one. The kernel client relies on this to send
+    /// a flush on just one of its connections. The default is false.
+    fn supports_multi_conn(&self) -> bool {
+        false
+    }
+
+    /// Set `len` bytes starting at off to zero.
+    ///
+    /// Unless `no_hole` is set, the implementation may deallocate the range
+    /// (punch a hole) rather than writing zeros. The default implementation
+    /// always writes zeros.
+    fn write_zeroes(&self, off: u64, len: u64, no_hole: bool) -> io::Result<()> {
+        let _ = no_hole;
+        write_zero_bufs(self, off, len)
+    }
+
+    /// Discard `len` bytes starting at off, which the client no longer
+    /// needs; afterward the range may read back as anything.
+    ///
+    /// The default implementation does nothing, since TRIM is only a hint.
+    fn trim(&self, off: u64, len: u64) -> io::Result<()> {
+        let _ = (off, len);
+        Ok(())
+    }
+
+    /// Whether [`Blocks::trim`] actually frees space, in which case the
+    /// server advertises NBD_FLAG_SEND_TRIM. Backends that override `trim`
+    /// should return true. The default is false.
+    fn supports_trim(&self) -> bool {
+        false
+    }
+
+    /// Change the size of this array to `size` bytes, zero-filling if it
+    /// grows.
+    ///
+    /// The default implementation does not support resizing.
+    fn resize(&self, size: u64) -> io::Result<()> {
+        let _ = size;
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "resize is not supported",
+        ))
+    }
+
+    /// Whether [`Blocks::resize`] is implemented, in which case the server
+    /// advertises NBD_FLAG_SEND_RESIZE. Backends that override `resize`
+    /// should return true. The default is false.
+    fn supports_resize(&self) -> bool {
+        false
+    }
+
+    /// Describe the allocation status of `[off, off+len)` as a sequence of
+    /// extents whose lengths sum to `len`.
+    ///
+    /// The default implementation reports a single allocated, non-zero
+    /// extent.
+    fn extent_status(&self, off: u64, len: u64) -> io::Result<Vec<Extent>> {
+        let _ = off;
+        Ok(vec![Extent {
+            len,
+            hole: false,
+            zero: false,
+        }])
+    }
+
+    /// Hint that `[off, off+len)` is likely to be read soon, for example
+    /// because the client is reading sequentially.
+    ///
+    /// The default implementation does nothing.
+    fn readahead(&self, off: u64, len: u64) -> io::Result<()> {
+        let _ = (off, len);
+        Ok(())
+    }
+
+    /// Bring `[off, off+len)` into the cache, for NBD_CMD_CACHE, so that
+    /// later reads of it are fast.
+    ///
+    /// Unlike [`Blocks::readahead`], the client asked for this explicitly and
+    /// waits for it to finish. The default implementation reads the range
+    /// into a throwaway buffer, which for a file populates the page cache.
+    fn cache(&self, off: u64, len: u64) -> io::Result<()> {
+        let mut buf = vec![0u8; len.min(4096 * 32) as usize];
+        let end = off + len;
+        let mut pos = off;
+        while pos < end {
+            let n = (end - pos).min(buf.len() as u64);
+            self.read_at(&mut buf[..n as usize], pos)?;
+            pos += n;
+        }
+        Ok(())
+    }
+
+    /// Capture a consistent point-in-time view of the current contents,
+    /// which later writes do not affect.
+    ///
+    /// How a snapshot is read depends on the backend (see
+    /// [`SnapshotBlocks::read_snapshot`]). The default implementation does not
+    /// support snapshots.
+    fn snapshot(&self) -> io::Result<SnapshotId> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "snapshots are not supported",
+        ))
+    }
+}
+
+/// Implement write_zeroes by writing buffers of zeros.
+fn write_zero_bufs<F: Blocks + ?Sized>(blocks: &F, off: u64, len: u64) -> io::Result<()> {
+    let zeros = vec![0u8; len.min(4096 * 32) as usize];
+    let end = off + len;
+    let mut pos = off;
+    while pos < end {
+        let n = (end - pos).min(zeros.len() as u64);
+        blocks.write_at(&zeros[..n as usize], pos)?;
+        pos += n;
+    }
+    Ok(())
+}
+
+/// Get the size of a block device (such as a partition), which its metadata
+/// reports as 0.
+#[cfg(target_os = "linux")]
+fn block_device_size(file: &File) -> io::Result<u64> {
+    use std::os::unix::io::AsRawFd;
+
+    // BLKGETSIZE64 is declared with a size_t argument, but returns a u64
+    nix::ioctl_read_bad!(
+        blkgetsize64,
+        nix::request_code_read!(0x12, 114, std::mem::size_of::<usize>()),
+        u64
+    );
+    let mut size = 0;
+    // SAFETY: BLKGETSIZE64 writes a u64 to size
+    unsafe { blkgetsize64(file.as_raw_fd(), &mut size) }?;
+    Ok(size)
 }
 
 impl Blocks for File {
@@ -51,16 +304,179 @@ impl Blocks for File {
         FileExt::write_all_at(self, buf, off)
     }
 
+    fn write_vectored_at(&self, bufs: &[IoSlice], off: u64) -> io::Result<()> {
+        use nix::sys::uio::pwritev;
+
+        let mut bufs = bufs.to_vec();
+        let mut bufs = &mut bufs[..];
+        let mut pos = off;
+        while !bufs.is_empty() {
+            match pwritev(self, bufs, pos as i64) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(n) => {
+                    IoSlice::advance_slices(&mut bufs, n);
+                    pos += n as u64;
+                }
+                Err(nix::errno::Errno::EINTR) => {}
+                Err(err) => return Err(err.into()),
+            }
+        }
+        Ok(())
+    }
+
     fn size(&self) -> io::Result<u64> {
-        self.metadata().map(|m| m.len())
+        let meta = self.metadata()?;
+        #[cfg(target_os = "linux")]
+        if std::os::unix::fs::FileTypeExt::is_block_device(&meta.file_type()) {
+            return block_device_size(self);
+        }
+        Ok(meta.len())
     }
 
     fn flush(&self) -> io::Result<()> {
-        self.sync_all()?;
+        // fdatasync skips metadata such as timestamps, but still persists the
+        // size, so a resize is durable. sync_file_range would be cheaper for
+        // flush_range, but it neither persists metadata nor flushes the
+        // drive's write cache, so it cannot implement FUA.
+        self.sync_data()?;
+        Ok(())
+    }
+
+    fn supports_multi_conn(&self) -> bool {
+        // fsync covers writes from every file descriptor
+        true
+    }
+
+    fn resize(&self, size: u64) -> io::Result<()> {
+        self.set_len(size)
+    }
+
+    fn supports_resize(&self) -> bool {
+        // block devices have a fixed size
+        self.metadata().is_ok_and(|m| m.is_file())
+    }
+
+    #[cfg(target_os = "linux")]
+    fn write_zeroes(&self, off: u64, len: u64, no_hole: bool) -> io::Result<()> {
+        use nix::errno::Errno;
+        use nix::fcntl::{fallocate, FallocateFlags};
+        use std::os::unix::io::AsRawFd;
+
+        // ZERO_RANGE keeps the range allocated, as required by NO_HOLE
+        let mode = if no_hole {
+            FallocateFlags::FALLOC_FL_ZERO_RANGE
+        } else {
+            FallocateFlags::FALLOC_FL_PUNCH_HOLE | FallocateFlags::FALLOC_FL_KEEP_SIZE
+        };
+        match fallocate(self.as_raw_fd(), mode, off as i64, len as i64) {
+            Ok(()) => Ok(()),
+            // not every file system supports these modes
+            Err(Errno::EOPNOTSUPP) => write_zero_bufs(self, off, len),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    fn trim(&self, off: u64, len: u64) -> io::Result<()> {
+        use nix::errno::Errno;
+        use nix::fcntl::{fallocate, FallocateFlags};
+        use std::os::unix::io::AsRawFd;
+
+        let mode = FallocateFlags::FALLOC_FL_PUNCH_HOLE | FallocateFlags::FALLOC_FL_KEEP_SIZE;
+        match fallocate(self.as_raw_fd(), mode, off as i64, len as i64) {
+            Ok(()) => Ok(()),
+            // trim is only a hint, so there's no need to fall back to writing
+            Err(Errno::EOPNOTSUPP) => Ok(()),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    fn supports_trim(&self) -> bool {
+        true
+    }
+
+    #[cfg(target_os = "linux")]
+    fn readahead(&self, off: u64, len: u64) -> io::Result<()> {
+        use nix::fcntl::{posix_fadvise, PosixFadviseAdvice};
+        use std::os::unix::io::AsRawFd;
+
+        posix_fadvise(
+            self.as_raw_fd(),
+            off as i64,
+            len as i64,
+            PosixFadviseAdvice::POSIX_FADV_WILLNEED,
+        )?;
         Ok(())
     }
 }
 
+impl<F: Blocks + ?Sized> Blocks for Arc<F> {
+    fn read_at(&self, buf: &mut [u8], off: u64) -> io::Result<()> {
+        (**self).read_at(buf, off)
+    }
+
+    fn write_at(&self, buf: &[u8], off: u64) -> io::Result<()> {
+        (**self).write_at(buf, off)
+    }
+
+    fn write_vectored_at(&self, bufs: &[IoSlice], off: u64) -> io::Result<()> {
+        (**self).write_vectored_at(bufs, off)
+    }
+
+    fn readahead(&self, off: u64, len: u64) -> io::Result<()> {
+        (**self).readahead(off, len)
+    }
+
+    fn cache(&self, off: u64, len: u64) -> io::Result<()> {
+        (**self).cache(off, len)
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        (**self).size()
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        (**self).flush()
+    }
+
+    fn flush_range(&self, off: u64, len: u64) -> io::Result<()> {
+        (**self).flush_range(off, len)
+    }
+
+    fn supports_multi_conn(&self) -> bool {
+        (**self).supports_multi_conn()
+    }
+
+    fn write_zeroes(&self, off: u64, len: u64, no_hole: bool) -> io::Result<()> {
+        (**self).write_zeroes(off, len, no_hole)
+    }
+
+    fn trim(&self, off: u64, len: u64) -> io::Result<()> {
+        (**self).trim(off, len)
+    }
+
+    fn supports_trim(&self) -> bool {
+        (**self).supports_trim()
+    }
+
+    fn resize(&self, size: u64) -> io::Result<()> {
+        (**self).resize(size)
+    }
+
+    fn supports_resize(&self) -> bool {
+        (**self).supports_resize()
+    }
+
+    fn extent_status(&self, off: u64, len: u64) -> io::Result<Vec<Extent>> {
+        (**self).extent_status(off, len)
+    }
+
+    fn snapshot(&self) -> io::Result<SnapshotId> {
+        (**self).snapshot()
+    }
+}
+
 /// MemBlocks is a convenience for an in-memory implementation of Blocks using
 /// an array of bytes.
 #[derive(Debug, Clone)]
@@ -71,6 +487,23 @@ impl MemBlocks {
     pub fn new(data: Vec<u8>) -> Self {
         MemBlocks(Arc::new(Mutex::new(data)))
     }
+
+    /// Create a new MemBlocks initialized with the contents of a file.
+    pub fn load(file: &File) -> io::Result<Self> {
+        let mut data = vec![0u8; file.metadata()?.len() as usize];
+        file.read_exact_at(&mut data, 0)?;
+        Ok(Self::new(data))
+    }
+
+    /// Write the contents of this MemBlocks to a file, replacing its
+    /// contents.
+    pub fn save(&self, file: &File) -> io::Result<()> {
+        let data = self.0.lock().unwrap();
+        file.write_all_at(&data, 0)?;
+        file.set_len(data.len() as u64)?;
+        file.sync_all()?;
+        Ok(())
+    }
 }
 
 impl Blocks for MemBlocks {
@@ -108,13 +541,123 @@ impl Blocks for MemBlocks {
     fn flush(&self) -> io::Result<()> {
         Ok(())
     }
+
+    fn supports_multi_conn(&self) -> bool {
+        true
+    }
+
+    fn cache(&self, _off: u64, _len: u64) -> io::Result<()> {
+        // already in memory
+        Ok(())
+    }
+
+    fn trim(&self, off: u64, len: u64) -> io::Result<()> {
+        // the memory cannot be freed, but zeroing the range means it does not
+        // keep stale data
+        let mut data = self.0.lock().unwrap();
+        let range = off as usize..(off + len) as usize;
+        match data.get_mut(range) {
+            Some(range) => range.fill(0),
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "out-of-bounds trim",
+                ))
+            }
+        }
+        Ok(())
+    }
+
+    fn supports_trim(&self) -> bool {
+        true
+    }
+
+    fn resize(&self, size: u64) -> io::Result<()> {
+        let mut data = self.0.lock().unwrap();
+        data.resize(size as usize, 0);
+        Ok(())
+    }
+
+    fn supports_resize(&self) -> bool {
+        true
+    }
+}
+
+/// PersistentMemBlocks serves an image loaded into memory from a file, writing
+/// the whole image back to the file on every flush.
+///
+/// This makes for a fast ramdisk seeded from a real image that still persists
+/// data the client has flushed.
+#[derive(Debug)]
+pub struct PersistentMemBlocks {
+    mem: MemBlocks,
+    file: File,
+}
+
+impl PersistentMemBlocks {
+    /// Load the contents of `file` into memory.
+    pub fn load(file: File) -> io::Result<Self> {
+        let mem = MemBlocks::load(&file)?;
+        Ok(Self { mem, file })
+    }
+}
+
+impl Blocks for PersistentMemBlocks {
+    fn read_at(&self, buf: &mut [u8], off: u64) -> io::Result<()> {
+        self.mem.read_at(buf, off)
+    }
+
+    fn write_at(&self, buf: &[u8], off: u64) -> io::Result<()> {
+        self.mem.write_at(buf, off)
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        self.mem.size()
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        self.mem.save(&self.file)
+    }
+
+    fn flush_range(&self, off: u64, len: u64) -> io::Result<()> {
+        let data = self.mem.0.lock().unwrap();
+        let range = off as usize..(off + len) as usize;
+        if self.file.metadata()?.len() != data.len() as u64 || range.end > data.len() {
+            // resized since the last flush
+            drop(data);
+            return self.flush();
+        }
+        self.file.write_all_at(&data[range], off)?;
+        self.file.sync_data()?;
+        Ok(())
+    }
+
+    fn supports_multi_conn(&self) -> bool {
+        // every flush saves the whole image
+        true
+    }
+
+    fn resize(&self, size: u64) -> io::Result<()> {
+        self.mem.resize(size)
+    }
+
+    fn supports_resize(&self) -> bool {
+        true
+    }
 }
 
 #[cfg(test)]
 mod tests {
+    use byteorder::{ReadBytesExt, WriteBytesExt, BE};
     use color_eyre::Result;
+    use readwrite::ReadWrite;
+    use std::io::{prelude::*, IoSlice};
+    use std::thread;
 
-    use super::{Blocks, MemBlocks};
+    use super::{Blocks, MemBlocks, PersistentMemBlocks, Server, SparseMemBlocks, SubBlocks};
+    use crate::proto::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
 
     #[test]
     fn test_mem_blocks() -> Result<()> {
@@ -131,77 +674,1852 @@ mod tests {
         assert_eq!(buf, [1, 3, 4]);
         Ok(())
     }
-}
 
-/// Wrap a Blocks and implement the core NBD operations using its operations.
-#[derive(Debug)]
-struct Export<F: Blocks>(F);
+    #[test]
+    fn test_persistent_mem_blocks() -> Result<()> {
+        let path = std::env::temp_dir().join(format!("nbd-mem-{}", rand::random::<u64>()));
+        std::fs::write(&path, [5u8; 100])?;
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&path)?;
+        let blocks = PersistentMemBlocks::load(file)?;
+        assert_eq!(blocks.size()?, 100);
 
-impl<F: Blocks> Export<F> {
-    /// Name returns the name of the single, default export, for listing purposes.
-    ///
-    /// The server ignores all export names anyway so this name is not important.
-    fn name(&self) -> String {
-        "default".to_string()
+        let mut buf = [0u8; 3];
+        blocks.read_at(&mut buf, 97)?;
+        assert_eq!(buf, [5u8; 3]);
+
+        blocks.write_at(&[6, 7], 10)?;
+        blocks.write_at(&[8], 50)?;
+        // not persisted until flushed
+        assert_eq!(std::fs::read(&path)?[10], 5);
+        // flushing a range persists only that range
+        blocks.flush_range(50, 1)?;
+        assert_eq!(std::fs::read(&path)?[10], 5);
+        assert_eq!(std::fs::read(&path)?[50], 8);
+        blocks.flush()?;
+        let data = std::fs::read(&path)?;
+        std::fs::remove_file(&path)?;
+        assert_eq!(data.len(), 100);
+        assert_eq!(data[9..13], [5, 6, 7, 5]);
+        Ok(())
     }
 
-    fn read<'a>(
-        &self,
-        off: u64,
-        len: u32,
-        buf: &'a mut [u8],
-    ) -> core::result::Result<&'a mut [u8], ErrorType> {
-        let len = len as usize;
-        if buf.len() < len {
-            return Err(ErrorType::EOVERFLOW);
+    #[test]
+    fn test_file_trim() -> Result<()> {
+        let path = std::env::temp_dir().join(format!("nbd-trim-{}", rand::random::<u64>()));
+        std::fs::write(&path, [1u8; 4096 * 4])?;
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&path)?;
+        Blocks::trim(&file, 4096, 4096 * 2)?;
+        let data = std::fs::read(&path)?;
+        std::fs::remove_file(&path)?;
+        assert_eq!(data.len(), 4096 * 4);
+        // the punched hole reads back as zeros
+        assert!(data[4096..4096 * 3].iter().all(|&b| b == 0));
+        assert!(data[..4096].iter().all(|&b| b == 1));
+        assert!(data[4096 * 3..].iter().all(|&b| b == 1));
+        Ok(())
+    }
+
+    #[test]
+    fn test_write_vectored() -> Result<()> {
+        let bufs = [
+            IoSlice::new(&[1, 2]),
+            IoSlice::new(&[]),
+            IoSlice::new(&[3u8; 5000]),
+            IoSlice::new(&[4]),
+        ];
+        let mut expected = vec![0u8; 10];
+        expected.extend([1, 2]);
+        expected.extend([3u8; 5000]);
+        expected.extend([4]);
+        expected.resize(6000, 0);
+
+        let path = std::env::temp_dir().join(format!("nbd-vec-{}", rand::random::<u64>()));
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&path)?;
+        file.set_len(6000)?;
+        Blocks::write_vectored_at(&file, &bufs, 10)?;
+        let data = std::fs::read(&path)?;
+        std::fs::remove_file(&path)?;
+        assert_eq!(data, expected);
+
+        // the default implementation
+        let mem = MemBlocks::new(vec![0u8; 6000]);
+        mem.write_vectored_at(&bufs, 10)?;
+        let mut data = vec![0u8; 6000];
+        mem.read_at(&mut data, 0)?;
+        assert_eq!(data, expected);
+        Ok(())
+    }
+
+    /// Start handling a connection with `server` in a new thread, and run the
+    /// client side of the initial handshake on the returned stream.
+    fn start_server<F: Blocks + Sync + Send + 'static>(
+        server: Server<F>,
+    ) -> Result<(thread::JoinHandle<Result<()>>, impl Read + Write)> {
+        let (r1, w1) = pipe::pipe();
+        let (r2, w2) = pipe::pipe();
+        start_server_on(server, ReadWrite::new(r1, w2), ReadWrite::new(r2, w1))
+    }
+
+    /// Like [`start_server`], but over a connection with ends `server_end`
+    /// and `stream`.
+    fn start_server_on<
+        F: Blocks + Sync + Send + 'static,
+        S: Read + Write + Send + 'static,
+        C: Read + Write,
+    >(
+        server: Server<F>,
+        server_end: S,
+        mut stream: C,
+    ) -> Result<(thread::JoinHandle<Result<()>>, C)> {
+        let server = thread::spawn(move || server.handle_client(server_end));
+
+        assert_eq!(stream.read_u64::<BE>()?, MAGIC);
+        assert_eq!(stream.read_u64::<BE>()?, IHAVEOPT);
+        stream.read_u16::<BE>()?;
+        let flags = ClientHandshakeFlags::C_FIXED_NEWSTYLE | ClientHandshakeFlags::C_NO_ZEROES;
+        stream.write_u32::<BE>(flags.bits())?;
+        Ok((server, stream))
+    }
+
+    fn send_opt(stream: &mut impl Write, typ: OptType, data: Vec<u8>) -> Result<()> {
+        Opt { typ, data }.put(stream)?;
+        Ok(())
+    }
+
+    fn info_request(typs: Vec<InfoType>) -> Result<Vec<u8>> {
+        named_info_request("default", typs)
+    }
+
+    fn named_info_request(name: &str, typs: Vec<InfoType>) -> Result<Vec<u8>> {
+        let mut data = vec![];
+        InfoRequest {
+            name: name.to_string(),
+            typs,
         }
-        let buf = &mut buf[..len];
-        match Blocks::read_at(&self.0, buf, off) {
-            Ok(_) => Ok(buf),
-            Err(err) => Err(ErrorType::from_io_kind(err.kind())),
+        .put(&mut data)?;
+        Ok(data)
+    }
+
+    /// Get the next reply, checking the option and reply type.
+    fn expect_reply(stream: &mut impl Read, opt: OptType, typ: ReplyType) -> Result<Vec<u8>> {
+        let reply = OptReply::get(stream)?;
+        assert_eq!(reply.opt, opt);
+        assert_eq!(reply.reply_type, typ, "unexpected reply to {opt:?}");
+        Ok(reply.data)
+    }
+
+    #[test]
+    fn test_wrong_protocol() {
+        let server = Server::new(MemBlocks::new(vec![0u8; 1024]));
+        let inputs: [(&[u8], &str); 3] = [
+            (b"GET / HTTP/1.1\r\n\r\n", "looks like HTTP"),
+            (b"SSH-2.0-OpenSSH_9.6\r\n", "looks like SSH"),
+            // the start of a TLS ClientHello
+            (&[0x16, 0x03, 0x01, 0x02, 0x00, 0x01], "looks like TLS"),
+        ];
+        for (input, expected) in inputs {
+            let stream = ReadWrite::new(input, std::io::sink());
+            let err = server.handle_client(stream).unwrap_err();
+            let msg = format!("{err:?}");
+            assert!(msg.contains(expected), "unexpected error {msg}");
         }
     }
 
-    fn write(&self, off: u64, len: usize, data: &[u8]) -> core::result::Result<(), ErrorType> {
-        if len > data.len() {
-            return Err(ErrorType::EOVERFLOW);
+    #[test]
+    fn test_read_only() -> Result<()> {
+        let server = Server::new(MemBlocks::new(vec![1u8; 1024])).read_only(true);
+        let (server, mut stream) = start_server(server.op_log_level(None))?;
+
+        send_opt(&mut stream, OptType::INFO, info_request(vec![])?)?;
+        let data = expect_reply(&mut stream, OptType::INFO, ReplyType::INFO)?;
+        let mut data = &data[..];
+        assert_eq!(data.read_u16::<BE>()?, InfoType::EXPORT.into());
+        assert_eq!(data.read_u64::<BE>()?, 1024);
+        let flags = TransmitFlags::from_bits_retain(data.read_u16::<BE>()?);
+        assert!(flags.contains(TransmitFlags::READ_ONLY));
+        expect_reply(&mut stream, OptType::INFO, ReplyType::INFO)?;
+        expect_reply(&mut stream, OptType::INFO, ReplyType::ACK)?;
+
+        send_opt(&mut stream, OptType::EXPORT_NAME, b"default".to_vec())?;
+        assert_eq!(stream.read_u64::<BE>()?, 1024);
+        let flags = TransmitFlags::from_bits_retain(stream.read_u16::<BE>()?);
+        assert!(flags.contains(TransmitFlags::READ_ONLY));
+
+        for (typ, data) in [
+            (Cmd::WRITE, &[2u8; 10][..]),
+            (Cmd::WRITE_ZEROES, &[]),
+            (Cmd::TRIM, &[]),
+        ] {
+            let req = Request::new(typ, 0, 10);
+            req.put(data, &mut stream)?;
+            let reply = SimpleReply::get(&mut stream, &mut [])?;
+            assert_eq!(reply.err, ErrorType::EPERM, "{typ:?}");
         }
-        let data = &data[..len];
-        Blocks::write_at(&self.0, data, off).map_err(|err| ErrorType::from_io_kind(err.kind()))?;
+        Request::new(Cmd::READ, 0, 10).put(&[], &mut stream)?;
+        let mut buf = [0u8; 10];
+        assert_eq!(SimpleReply::get(&mut stream, &mut buf)?.err, ErrorType::OK);
+        assert_eq!(buf, [1u8; 10]);
+
+        Request::new(Cmd::DISCONNECT, 0, 0).put(&[], &mut stream)?;
+        server.join().unwrap()?;
         Ok(())
     }
 
-    fn flush(&self) -> io::Result<()> {
-        self.0.flush()?;
+    #[test]
+    fn test_list_info_go() -> Result<()> {
+        let (server, mut stream) = start_server(Server::new(MemBlocks::new(vec![0u8; 1024])))?;
+
+        send_opt(&mut stream, OptType::LIST, vec![])?;
+        let data = expect_reply(&mut stream, OptType::LIST, ReplyType::SERVER)?;
+        assert_eq!(data, b"\0\0\0\x07default");
+        expect_reply(&mut stream, OptType::LIST, ReplyType::ACK)?;
+
+        send_opt(
+            &mut stream,
+            OptType::INFO,
+            info_request(vec![InfoType::BLOCK_SIZE])?,
+        )?;
+        let data = expect_reply(&mut stream, OptType::INFO, ReplyType::INFO)?;
+        let mut data = &data[..];
+        assert_eq!(data.read_u16::<BE>()?, InfoType::EXPORT.into());
+        assert_eq!(data.read_u64::<BE>()?, 1024);
+        let data = expect_reply(&mut stream, OptType::INFO, ReplyType::INFO)?;
+        assert_eq!((&data[..]).read_u16::<BE>()?, InfoType::BLOCK_SIZE.into());
+        expect_reply(&mut stream, OptType::INFO, ReplyType::ACK)?;
+
+        send_opt(&mut stream, OptType::GO, info_request(vec![])?)?;
+        expect_reply(&mut stream, OptType::GO, ReplyType::INFO)?;
+        // block size constraints are sent even when not requested
+        let data = expect_reply(&mut stream, OptType::GO, ReplyType::INFO)?;
+        let mut data = &data[..];
+        assert_eq!(data.read_u16::<BE>()?, InfoType::BLOCK_SIZE.into());
+        let _minimum = data.read_u32::<BE>()?;
+        let _preferred = data.read_u32::<BE>()?;
+        assert_eq!(data.read_u32::<BE>()?, 4096 * 64);
+        expect_reply(&mut stream, OptType::GO, ReplyType::ACK)?;
+
+        // now in the transmission phase
+        Request::new(Cmd::DISCONNECT, 0, 0).put(&[], &mut stream)?;
+        server.join().unwrap()?;
         Ok(())
     }
 
-    fn size(&self) -> io::Result<u64> {
-        self.0.size()
+    #[test]
+    #[cfg(feature = "tls")]
+    fn test_starttls() -> Result<()> {
+        let (server_config, client_config) = crate::tests::tls_configs();
+        let server = Server::new(MemBlocks::new(vec![0u8; 1024]))
+            .require_tls(true)
+            .starttls(server_config);
+        // a socket rather than a pipe, since both sides of a TLS handshake
+        // write at once and a pipe's writes wait for the other side to read
+        let (s1, s2) = std::os::unix::net::UnixStream::pair()?;
+        let (server, mut stream) = start_server_on(server, s1, s2)?;
+
+        send_opt(&mut stream, OptType::GO, info_request(vec![])?)?;
+        expect_reply(&mut stream, OptType::GO, ReplyType::ERR_TLS_REQD)?;
+        send_opt(&mut stream, OptType::STARTTLS, vec![1])?;
+        expect_reply(&mut stream, OptType::STARTTLS, ReplyType::ERR_INVALID)?;
+        send_opt(&mut stream, OptType::STARTTLS, vec![])?;
+        expect_reply(&mut stream, OptType::STARTTLS, ReplyType::ACK)?;
+        let conn = rustls::ClientConnection::new(client_config, "localhost".try_into()?)?;
+        let mut stream = rustls::StreamOwned::new(conn, stream);
+
+        // now over TLS, which can only be started once
+        send_opt(&mut stream, OptType::STARTTLS, vec![])?;
+        expect_reply(&mut stream, OptType::STARTTLS, ReplyType::ERR_INVALID)?;
+        send_opt(&mut stream, OptType::GO, info_request(vec![])?)?;
+        expect_reply(&mut stream, OptType::GO, ReplyType::INFO)?;
+        expect_reply(&mut stream, OptType::GO, ReplyType::INFO)?;
+        expect_reply(&mut stream, OptType::GO, ReplyType::ACK)?;
+
+        Request::new(Cmd::DISCONNECT, 0, 0).put(&[], &mut stream)?;
+        server.join().unwrap()?;
+        Ok(())
     }
-}
 
-#[derive(Debug)]
-struct ServerInner<F: Blocks> {
-    export: Export<F>,
-}
+    #[test]
+    fn test_go_info_once() -> Result<()> {
+        let (server, mut stream) = start_server(Server::new(MemBlocks::new(vec![0u8; 1024])))?;
+        let typs = vec![
+            InfoType::EXPORT,
+            InfoType::BLOCK_SIZE,
+            InfoType::NAME,
+            InfoType::EXPORT,
+        ];
+        send_opt(&mut stream, OptType::GO, info_request(typs)?)?;
+        let mut infos = vec![];
+        loop {
+            let reply = OptReply::get(&mut stream)?;
+            if reply.reply_type == ReplyType::ACK {
+                break;
+            }
+            assert_eq!(reply.reply_type, ReplyType::INFO);
+            let typ = (&reply.data[..]).read_u16::<BE>()?;
+            infos.push(InfoType::try_from(typ).unwrap());
+            if infos.last() == Some(&InfoType::NAME) {
+                assert_eq!(&reply.data[2..], b"default");
+            }
+        }
+        assert_eq!(
+            infos,
+            [InfoType::EXPORT, InfoType::NAME, InfoType::BLOCK_SIZE]
+        );
 
-impl<F: Blocks> ServerInner<F> {
-    // fake constant for the server's supported operations
-    #[allow(non_snake_case)]
-    fn TRANSMIT_FLAGS() -> TransmitFlags {
-        TransmitFlags::HAS_FLAGS | TransmitFlags::SEND_FLUSH | TransmitFlags::SEND_FUA
+        Request::new(Cmd::DISCONNECT, 0, 0).put(&[], &mut stream)?;
+        server.join().unwrap()?;
+        Ok(())
     }
 
-    // Agree on basic negotiation flags.
-    fn initial_handshake<IO: Read + Write>(stream: &mut IO) -> Result<HandshakeFlags> {
-        stream.write_u64::<BE>(MAGIC)?;
+    #[test]
+    fn test_go_unknown_info_type() -> Result<()> {
+        let (server, mut stream) = start_server(Server::new(MemBlocks::new(vec![0u8; 1024])))?;
+        let mut data = vec![];
+        data.write_u32::<BE>(0)?;
+        data.write_u16::<BE>(2)?;
+        data.write_u16::<BE>(InfoType::BLOCK_SIZE.into())?;
+        // not a known info type
+        data.write_u16::<BE>(0x7fff)?;
+        send_opt(&mut stream, OptType::GO, data)?;
+        expect_reply(&mut stream, OptType::GO, ReplyType::INFO)?;
+        expect_reply(&mut stream, OptType::GO, ReplyType::INFO)?;
+        expect_reply(&mut stream, OptType::GO, ReplyType::ACK)?;
+
+        Request::new(Cmd::DISCONNECT, 0, 0).put(&[], &mut stream)?;
+        server.join().unwrap()?;
+        Ok(())
+    }
+
+    /// Get the size of an export using NBD_OPT_INFO.
+    fn info_size(stream: &mut (impl Read + Write), name: &str) -> Result<u64> {
+        send_opt(stream, OptType::INFO, named_info_request(name, vec![])?)?;
+        let data = expect_reply(stream, OptType::INFO, ReplyType::INFO)?;
+        expect_reply(stream, OptType::INFO, ReplyType::INFO)?;
+        expect_reply(stream, OptType::INFO, ReplyType::ACK)?;
+        let mut data = &data[..];
+        assert_eq!(data.read_u16::<BE>()?, InfoType::EXPORT.into());
+        Ok(data.read_u64::<BE>()?)
+    }
+
+    #[test]
+    fn test_sub_range_exports() -> Result<()> {
+        let mem = Arc::new(MemBlocks::new(vec![0u8; 1000]));
+        let server = Server::with_export("p1", SubBlocks::new(mem.clone(), 0, 600))
+            .add_export("p2", SubBlocks::new(mem.clone(), 600, 400));
+        let (server, mut stream) = start_server(server)?;
+
+        send_opt(&mut stream, OptType::LIST, vec![])?;
+        let data = expect_reply(&mut stream, OptType::LIST, ReplyType::SERVER)?;
+        assert_eq!(&data[4..], b"p1");
+        let data = expect_reply(&mut stream, OptType::LIST, ReplyType::SERVER)?;
+        assert_eq!(&data[4..], b"p2");
+        expect_reply(&mut stream, OptType::LIST, ReplyType::ACK)?;
+
+        assert_eq!(info_size(&mut stream, "p1")?, 600);
+        assert_eq!(info_size(&mut stream, "p2")?, 400);
+        // the empty name is the default (first) export
+        assert_eq!(info_size(&mut stream, "")?, 600);
+        send_opt(
+            &mut stream,
+            OptType::INFO,
+            named_info_request("p3", vec![])?,
+        )?;
+        expect_reply(&mut stream, OptType::INFO, ReplyType::ERR_UNKNOWN)?;
+
+        send_opt(&mut stream, OptType::GO, named_info_request("p2", vec![])?)?;
+        expect_reply(&mut stream, OptType::GO, ReplyType::INFO)?;
+        expect_reply(&mut stream, OptType::GO, ReplyType::INFO)?;
+        expect_reply(&mut stream, OptType::GO, ReplyType::ACK)?;
+
+        // writes are relative to the start of p2
+        let req = Request::new(Cmd::WRITE, 0, 2);
+        req.put(&[7, 7], &mut stream)?;
+        assert_eq!(SimpleReply::get(&mut stream, &mut [])?.err, ErrorType::OK);
+        let mut buf = [0u8; 2];
+        mem.read_at(&mut buf, 600)?;
+        assert_eq!(buf, [7, 7]);
+
+        // and cannot go past the end of p2
+        let req = Request::new(Cmd::WRITE, 399, 2);
+        req.put(&[7, 7], &mut stream)?;
+        assert_eq!(
+            SimpleReply::get(&mut stream, &mut [])?.err,
+            ErrorType::ENOSPC
+        );
+
+        Request::new(Cmd::DISCONNECT, 0, 0).put(&[], &mut stream)?;
+        server.join().unwrap()?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_info_then_go_other_export() -> Result<()> {
+        let server = Server::with_export("a", MemBlocks::new(vec![1u8; 100]))
+            .add_export("b", MemBlocks::new(vec![2u8; 200]));
+        let (server, mut stream) = start_server(server)?;
+
+        assert_eq!(info_size(&mut stream, "a")?, 100);
+        send_opt(&mut stream, OptType::GO, named_info_request("b", vec![])?)?;
+        let data = expect_reply(&mut stream, OptType::GO, ReplyType::INFO)?;
+        let mut data = &data[..];
+        assert_eq!(data.read_u16::<BE>()?, InfoType::EXPORT.into());
+        assert_eq!(data.read_u64::<BE>()?, 200);
+        expect_reply(&mut stream, OptType::GO, ReplyType::INFO)?;
+        expect_reply(&mut stream, OptType::GO, ReplyType::ACK)?;
+
+        // transmission uses b, not the export from INFO
+        Request::new(Cmd::READ, 150, 3).put(&[], &mut stream)?;
+        let mut buf = [0u8; 3];
+        let reply = SimpleReply::get(&mut stream, &mut buf)?;
+        assert_eq!(reply.err, ErrorType::OK);
+        assert_eq!(buf, [2u8; 3]);
+
+        Request::new(Cmd::DISCONNECT, 0, 0).put(&[], &mut stream)?;
+        server.join().unwrap()?;
+        Ok(())
+    }
+
+    /// Get the transmission flags the server advertises in reply to INFO.
+    fn info_transmit_flags<F: Blocks + Sync + Send + 'static>(
+        server: Server<F>,
+    ) -> Result<TransmitFlags> {
+        let (server, mut stream) = start_server(server.op_log_level(None))?;
+        send_opt(&mut stream, OptType::INFO, info_request(vec![])?)?;
+        let data = expect_reply(&mut stream, OptType::INFO, ReplyType::INFO)?;
+        let flags = TransmitFlags::from_bits_retain((&data[10..]).read_u16::<BE>()?);
+        expect_reply(&mut stream, OptType::INFO, ReplyType::INFO)?;
+        expect_reply(&mut stream, OptType::INFO, ReplyType::ACK)?;
+        send_opt(&mut stream, OptType::ABORT, vec![])?;
+        server.join().unwrap()?;
+        Ok(flags)
+    }
+
+    #[test]
+    fn test_multi_conn_flag() -> Result<()> {
+        let flags = info_transmit_flags(Server::new(MemBlocks::new(vec![0u8; 1024])))?;
+        assert!(flags.contains(TransmitFlags::CAN_MULTI_CONN));
+        let flags = info_transmit_flags(Server::new(CountingBlocks {
+            mem: MemBlocks::new(vec![0u8; 1024]),
+            reads: AtomicUsize::new(0),
+        }))?;
+        assert!(!flags.contains(TransmitFlags::CAN_MULTI_CONN));
+        Ok(())
+    }
+
+    #[test]
+    fn test_trim_flag() -> Result<()> {
+        let flags = info_transmit_flags(Server::new(MemBlocks::new(vec![0u8; 1024])))?;
+        assert!(flags.contains(TransmitFlags::SEND_TRIM));
+        // a backend that does not implement trim
+        let flags = info_transmit_flags(Server::new(CountingBlocks {
+            mem: MemBlocks::new(vec![0u8; 1024]),
+            reads: AtomicUsize::new(0),
+        }))?;
+        assert!(!flags.contains(TransmitFlags::SEND_TRIM));
+        Ok(())
+    }
+
+    /// A backend that counts calls to read_at.
+    struct CountingBlocks {
+        mem: MemBlocks,
+        reads: AtomicUsize,
+    }
+
+    impl Blocks for CountingBlocks {
+        fn read_at(&self, buf: &mut [u8], off: u64) -> std::io::Result<()> {
+            self.reads.fetch_add(1, Ordering::SeqCst);
+            self.mem.read_at(buf, off)
+        }
+
+        fn write_at(&self, buf: &[u8], off: u64) -> std::io::Result<()> {
+            self.mem.write_at(buf, off)
+        }
+
+        fn size(&self) -> std::io::Result<u64> {
+            self.mem.size()
+        }
+
+        fn flush(&self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Start a server and finish the handshake with GO, returning a stream in
+    /// the transmission phase.
+    fn start_transmission<F: Blocks + Sync + Send + 'static>(
+        server: Server<F>,
+    ) -> Result<(thread::JoinHandle<Result<()>>, impl Read + Write)> {
+        let (server, mut stream) = start_server(server)?;
+        send_opt(&mut stream, OptType::GO, info_request(vec![])?)?;
+        // export info and block size
+        expect_reply(&mut stream, OptType::GO, ReplyType::INFO)?;
+        expect_reply(&mut stream, OptType::GO, ReplyType::INFO)?;
+        expect_reply(&mut stream, OptType::GO, ReplyType::ACK)?;
+        Ok((server, stream))
+    }
+
+    #[test]
+    fn test_coalesce_reads() -> Result<()> {
+        let data: Vec<u8> = (0..2000).map(|i| i as u8).collect();
+        let blocks = Arc::new(CountingBlocks {
+            mem: MemBlocks::new(data.clone()),
+            reads: AtomicUsize::new(0),
+        });
+        let server = Server::new(blocks.clone())
+            .op_log_level(None)
+            .coalesce_reads(true);
+        let (server, mut stream) = start_transmission(server)?;
+
+        // adjacent, overlapping, and then a separate read, all sent at once
+        let reqs = [
+            Request::with_handle(1, Cmd::READ, 0, 100),
+            Request::with_handle(2, Cmd::READ, 100, 100),
+            Request::with_handle(3, Cmd::READ, 150, 100),
+            Request::with_handle(4, Cmd::READ, 1000, 10),
+        ];
+        let mut input = vec![];
+        for req in &reqs {
+            req.put(&[], &mut input)?;
+        }
+        stream.write_all(&input)?;
+
+        for req in &reqs {
+            let mut buf = vec![0u8; req.len as usize];
+            let reply = SimpleReply::get(&mut stream, &mut buf)?;
+            assert_eq!(reply.err, ErrorType::OK);
+            assert_eq!(reply.handle, req.handle);
+            let off = req.offset as usize;
+            assert_eq!(buf, data[off..off + req.len as usize]);
+        }
+        assert_eq!(blocks.reads.load(Ordering::SeqCst), 2);
+
+        Request::new(Cmd::DISCONNECT, 0, 0).put(&[], &mut stream)?;
+        server.join().unwrap()?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_negotiate_then_disconnect() -> Result<()> {
+        let server = Server::new(MemBlocks::new(vec![0u8; 1024])).op_log_level(None);
+        // like a probe checking that the export is up, without doing any I/O
+        for _ in 0..3 {
+            let (thread, mut stream) = start_transmission(server.clone())?;
+            Request::new(Cmd::DISCONNECT, 0, 0).put(&[], &mut stream)?;
+            thread.join().unwrap()?;
+        }
+        // every connection reused the same request buffer
+        assert_eq!(server.0.buffers.lock().unwrap().len(), 1);
+        Ok(())
+    }
+
+    /// A backend whose flushes fail.
+    struct FailingFlushBlocks(MemBlocks);
+
+    impl Blocks for FailingFlushBlocks {
+        fn read_at(&self, buf: &mut [u8], off: u64) -> std::io::Result<()> {
+            self.0.read_at(buf, off)
+        }
+
+        fn write_at(&self, buf: &[u8], off: u64) -> std::io::Result<()> {
+            self.0.write_at(buf, off)
+        }
+
+        fn size(&self) -> std::io::Result<u64> {
+            self.0.size()
+        }
+
+        fn flush(&self) -> std::io::Result<()> {
+            Err(std::io::Error::from_raw_os_error(libc::EIO))
+        }
+    }
+
+    #[test]
+    fn test_request_errors_keep_connection() -> Result<()> {
+        let server = Server::new(FailingFlushBlocks(MemBlocks::new(vec![0u8; 1024])));
+        let (server, mut stream) = start_transmission(server.op_log_level(None))?;
+        let mut expect_reply = |req: Request, data: &[u8], err: ErrorType| -> Result<()> {
+            req.put(data, &mut stream)?;
+            let reply = SimpleReply::get(&mut stream, &mut [])?;
+            assert_eq!((reply.handle, reply.err), (req.handle, err), "{req:?}");
+            Ok(())
+        };
+
+        // backend errors
+        expect_reply(
+            Request::with_handle(1, Cmd::FLUSH, 0, 0),
+            &[],
+            ErrorType::EIO,
+        )?;
+        let mut req = Request::with_handle(2, Cmd::WRITE, 0, 3);
+        req.flags |= CmdFlags::FUA;
+        expect_reply(req, &[1, 2, 3], ErrorType::EIO)?;
+        // requests the server rejects
+        let mut req = Request::with_handle(3, Cmd::READ, 0, 3);
+        req.flags = CmdFlags::from_bits_retain(1 << 15);
+        expect_reply(req, &[], ErrorType::ENOTSUP)?;
+        let len = 4096 * 64 + 1;
+        let req = Request::with_handle(4, Cmd::WRITE, 0, len);
+        expect_reply(req, &vec![9u8; len as usize], ErrorType::EOVERFLOW)?;
+
+        // the connection still works, and only the first write happened
+        Request::with_handle(5, Cmd::READ, 0, 4).put(&[], &mut stream)?;
+        let mut buf = [0u8; 4];
+        let reply = SimpleReply::get(&mut stream, &mut buf)?;
+        assert_eq!((reply.handle, reply.err), (5, ErrorType::OK));
+        assert_eq!(buf, [1, 2, 3, 0]);
+
+        Request::new(Cmd::DISCONNECT, 0, 0).put(&[], &mut stream)?;
+        server.join().unwrap()?;
+        Ok(())
+    }
+
+    /// A backend on a full disk, whose writes past the first 512 bytes fail
+    /// like a File's would.
+    struct FullDiskBlocks(MemBlocks);
+
+    impl Blocks for FullDiskBlocks {
+        fn read_at(&self, buf: &mut [u8], off: u64) -> std::io::Result<()> {
+            self.0.read_at(buf, off)
+        }
+
+        fn write_at(&self, buf: &[u8], off: u64) -> std::io::Result<()> {
+            if off + buf.len() as u64 > 512 {
+                return Err(std::io::Error::from_raw_os_error(libc::ENOSPC));
+            }
+            self.0.write_at(buf, off)
+        }
+
+        fn size(&self) -> std::io::Result<u64> {
+            self.0.size()
+        }
+
+        fn flush(&self) -> std::io::Result<()> {
+            self.0.flush()
+        }
+    }
+
+    #[test]
+    fn test_write_enospc() -> Result<()> {
+        let server = Server::new(FullDiskBlocks(MemBlocks::new(vec![0u8; 1024])));
+        let (server, mut stream) = start_transmission(server.op_log_level(None))?;
+        for (handle, off, err) in [(1, 0, ErrorType::OK), (2, 600, ErrorType::ENOSPC)] {
+            let req = Request::with_handle(handle, Cmd::WRITE, off, 4);
+            req.put(&[1, 2, 3, 4], &mut stream)?;
+            let reply = SimpleReply::get(&mut stream, &mut [])?;
+            assert_eq!((reply.handle, reply.err), (handle, err));
+        }
+        Request::new(Cmd::DISCONNECT, 0, 0).put(&[], &mut stream)?;
+        server.join().unwrap()?;
+        Ok(())
+    }
+
+    /// A backend whose reads at offset 0 wait until a read at another offset
+    /// has finished.
+    struct OrderedReadBlocks {
+        mem: MemBlocks,
+        other_read: std::sync::Mutex<bool>,
+        cond: std::sync::Condvar,
+    }
+
+    impl Blocks for OrderedReadBlocks {
+        fn read_at(&self, buf: &mut [u8], off: u64) -> std::io::Result<()> {
+            let mut other_read = self.other_read.lock().unwrap();
+            if off == 0 {
+                let timeout = std::time::Duration::from_secs(5);
+                (other_read, _) = self
+                    .cond
+                    .wait_timeout_while(other_read, timeout, |done| !*done)
+                    .unwrap();
+                assert!(*other_read, "reads were not concurrent");
+            } else {
+                *other_read = true;
+                self.cond.notify_all();
+            }
+            self.mem.read_at(buf, off)
+        }
+
+        fn write_at(&self, buf: &[u8], off: u64) -> std::io::Result<()> {
+            self.mem.write_at(buf, off)
+        }
+
+        fn size(&self) -> std::io::Result<u64> {
+            self.mem.size()
+        }
+
+        fn flush(&self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_workers_reply_out_of_order() -> Result<()> {
+        let data: Vec<u8> = (0..8192).map(|i| (i / 4096) as u8 + 1).collect();
+        let blocks = OrderedReadBlocks {
+            mem: MemBlocks::new(data),
+            other_read: std::sync::Mutex::new(false),
+            cond: std::sync::Condvar::new(),
+        };
+        let server = Server::new(blocks).op_log_level(None).workers(2);
+        let (r1, w1) = pipe::pipe();
+        let (r2, w2) = pipe::pipe();
+        let server = thread::spawn(move || server.0.handle_split_client(r1, w2));
+        let mut stream = ReadWrite::new(r2, w1);
+        assert_eq!(stream.read_u64::<BE>()?, MAGIC);
+        assert_eq!(stream.read_u64::<BE>()?, IHAVEOPT);
+        stream.read_u16::<BE>()?;
+        let flags = ClientHandshakeFlags::C_FIXED_NEWSTYLE | ClientHandshakeFlags::C_NO_ZEROES;
+        stream.write_u32::<BE>(flags.bits())?;
+        send_opt(&mut stream, OptType::GO, info_request(vec![])?)?;
+        expect_reply(&mut stream, OptType::GO, ReplyType::INFO)?;
+        expect_reply(&mut stream, OptType::GO, ReplyType::INFO)?;
+        expect_reply(&mut stream, OptType::GO, ReplyType::ACK)?;
+
+        // the first read only finishes after the second
+        Request::with_handle(1, Cmd::READ, 0, 10).put(&[], &mut stream)?;
+        Request::with_handle(2, Cmd::READ, 4096, 10).put(&[], &mut stream)?;
+        let mut buf = [0u8; 10];
+        let reply = SimpleReply::get(&mut stream, &mut buf)?;
+        assert_eq!(
+            (reply.handle, reply.err, buf),
+            (2, ErrorType::OK, [2u8; 10])
+        );
+        let reply = SimpleReply::get(&mut stream, &mut buf)?;
+        assert_eq!(
+            (reply.handle, reply.err, buf),
+            (1, ErrorType::OK, [1u8; 10])
+        );
+
+        Request::new(Cmd::DISCONNECT, 0, 0).put(&[], &mut stream)?;
+        server.join().unwrap()?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_max_block_size() -> Result<()> {
+        const MAX: u32 = 4096 * 64;
+        let blocks = Arc::new(MemBlocks::new(vec![0u8; MAX as usize * 2]));
+        let server = Server::new(blocks.clone()).op_log_level(None);
+        let (server, mut stream) = start_transmission(server)?;
+
+        // a client that respects the advertised maximum
+        Request::with_handle(1, Cmd::WRITE, 0, MAX).put(&vec![1u8; MAX as usize], &mut stream)?;
+        assert_eq!(SimpleReply::get(&mut stream, &mut [])?.err, ErrorType::OK);
+        Request::with_handle(2, Cmd::READ, 0, MAX).put(&[], &mut stream)?;
+        let mut buf = vec![0u8; MAX as usize];
+        assert_eq!(SimpleReply::get(&mut stream, &mut buf)?.err, ErrorType::OK);
+        assert!(buf.iter().all(|&b| b == 1));
+
+        // and one that does not
+        let len = MAX + 1;
+        Request::with_handle(3, Cmd::WRITE, 0, len).put(&vec![2u8; len as usize], &mut stream)?;
+        let reply = SimpleReply::get(&mut stream, &mut [])?;
+        assert_eq!((reply.handle, reply.err), (3, ErrorType::EOVERFLOW));
+        Request::with_handle(4, Cmd::READ, 0, len).put(&[], &mut stream)?;
+        let reply = SimpleReply::get(&mut stream, &mut [])?;
+        assert_eq!((reply.handle, reply.err), (4, ErrorType::EOVERFLOW));
+
+        // the oversized write was not applied, even in part
+        let mut data = vec![0u8; len as usize];
+        blocks.read_at(&mut data, 0)?;
+        assert!(data[..MAX as usize].iter().all(|&b| b == 1));
+        assert_eq!(data[MAX as usize], 0);
+
+        Request::new(Cmd::DISCONNECT, 0, 0).put(&[], &mut stream)?;
+        server.join().unwrap()?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_configured_max_block_size() -> Result<()> {
+        const MAX: u32 = 4096 * 4;
+        let blocks = MemBlocks::new(vec![0u8; MAX as usize * 2]);
+        let server = Server::new(blocks).op_log_level(None).max_block_size(MAX);
+        let (server, mut stream) = start_server(server)?;
+
+        // the maximum is advertised
+        send_opt(
+            &mut stream,
+            OptType::GO,
+            info_request(vec![InfoType::BLOCK_SIZE])?,
+        )?;
+        expect_reply(&mut stream, OptType::GO, ReplyType::INFO)?;
+        let info = expect_reply(&mut stream, OptType::GO, ReplyType::INFO)?;
+        assert_eq!(info[10..14], MAX.to_be_bytes());
+        expect_reply(&mut stream, OptType::GO, ReplyType::ACK)?;
+
+        for (handle, len, err) in [(1, MAX, ErrorType::OK), (2, MAX + 1, ErrorType::EOVERFLOW)] {
+            Request::with_handle(handle, Cmd::WRITE, 0, len)
+                .put(&vec![1u8; len as usize], &mut stream)?;
+            let reply = SimpleReply::get(&mut stream, &mut [])?;
+            assert_eq!((reply.handle, reply.err), (handle, err));
+        }
+
+        Request::new(Cmd::DISCONNECT, 0, 0).put(&[], &mut stream)?;
+        server.join().unwrap()?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_option_too_big() -> Result<()> {
+        let server = Server::new(MemBlocks::new(vec![0u8; 1024]));
+        let (server, mut stream) = start_server(server)?;
+        send_opt(&mut stream, OptType::INFO, vec![0u8; 20_000])?;
+        expect_reply(&mut stream, OptType::INFO, ReplyType::ERR_TOO_BIG)?;
+        // negotiation continues
+        send_opt(&mut stream, OptType::INFO, info_request(vec![])?)?;
+        expect_reply(&mut stream, OptType::INFO, ReplyType::INFO)?;
+        expect_reply(&mut stream, OptType::INFO, ReplyType::INFO)?;
+        expect_reply(&mut stream, OptType::INFO, ReplyType::ACK)?;
+        send_opt(&mut stream, OptType::ABORT, vec![])?;
+        server.join().unwrap()?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_unknown_command_keeps_connection() -> Result<()> {
+        use byteorder::{WriteBytesExt, BE};
+
+        let server = Server::new(MemBlocks::new(vec![1u8; 1024])).op_log_level(None);
+        let (server, mut stream) = start_transmission(server)?;
+        stream.write_u32::<BE>(REQUEST_MAGIC)?;
+        stream.write_u16::<BE>(0)?; // flags
+        stream.write_u16::<BE>(0x99)?; // type
+        stream.write_u64::<BE>(7)?; // handle
+        stream.write_u64::<BE>(0)?; // offset
+        stream.write_u32::<BE>(0)?; // length
+        let reply = SimpleReply::get(&mut stream, &mut [])?;
+        assert_eq!((reply.handle, reply.err), (7, ErrorType::ENOTSUP));
+
+        // the same connection still serves requests
+        let req = Request::new(Cmd::READ, 0, 4);
+        req.put(&[], &mut stream)?;
+        let mut buf = [0u8; 4];
+        let reply = SimpleReply::get(&mut stream, &mut buf)?;
+        assert_eq!((reply.handle, reply.err), (req.handle, ErrorType::OK));
+        assert_eq!(buf, [1; 4]);
+        Request::new(Cmd::DISCONNECT, 0, 0).put(&[], &mut stream)?;
+        server.join().unwrap()?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_malformed_request_closes_connection() -> Result<()> {
+        // bad magic means the stream is out of sync, so there's no reply
+        let server = Server::new(MemBlocks::new(vec![0u8; 1024]));
+        let (server, mut stream) = start_transmission(server)?;
+        stream.write_all(&[0xab; 28])?;
+        assert!(server.join().unwrap().is_err());
+        let mut rest = vec![];
+        stream.read_to_end(&mut rest)?;
+        assert!(rest.is_empty());
+        Ok(())
+    }
+
+    /// Compare many small pipelined adjacent reads with and without
+    /// coalescing. Run with `cargo test --release bench_coalesce_reads --
+    /// --ignored --nocapture`.
+    #[test]
+    #[ignore]
+    fn bench_coalesce_reads() -> Result<()> {
+        const READS: u64 = 100_000;
+        const LEN: u32 = 512;
+        for coalesce in [false, true] {
+            let blocks = Arc::new(CountingBlocks {
+                mem: MemBlocks::new(vec![0u8; 64 * 1024 * 1024]),
+                reads: AtomicUsize::new(0),
+            });
+            let server = Server::new(blocks.clone())
+                .op_log_level(None)
+                .coalesce_reads(coalesce);
+            let (server, mut stream) = start_transmission(server)?;
+
+            let start = std::time::Instant::now();
+            let mut buf = vec![0u8; LEN as usize];
+            // keep a window of requests in flight
+            for chunk in 0..READS / 64 {
+                let mut input = vec![];
+                for i in 0..64 {
+                    let off = (chunk * 64 + i) * LEN as u64 % (64 * 1024 * 1024);
+                    Request::with_handle(i, Cmd::READ, off, LEN).put(&[], &mut input)?;
+                }
+                stream.write_all(&input)?;
+                for _ in 0..64 {
+                    assert_eq!(SimpleReply::get(&mut stream, &mut buf)?.err, ErrorType::OK);
+                }
+            }
+            let elapsed = start.elapsed();
+            println!(
+                "coalesce={coalesce}: {:.0} reads/s, {} backend reads",
+                (READS / 64 * 64) as f64 / elapsed.as_secs_f64(),
+                blocks.reads.load(Ordering::SeqCst)
+            );
+            Request::new(Cmd::DISCONNECT, 0, 0).put(&[], &mut stream)?;
+            server.join().unwrap()?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_max_list_exports() -> Result<()> {
+        let mut server = Server::new(MemBlocks::new(vec![0u8; 10]));
+        for i in 0..10 {
+            server = server.add_export(format!("e{i}"), MemBlocks::new(vec![0u8; 100 + i]));
+        }
+        let (server, mut stream) = start_server(server.max_list_exports(Some(3)))?;
+
+        send_opt(&mut stream, OptType::LIST, vec![])?;
+        for name in ["default", "e0", "e1"] {
+            let data = expect_reply(&mut stream, OptType::LIST, ReplyType::SERVER)?;
+            assert_eq!(&data[4..], name.as_bytes());
+        }
+        expect_reply(&mut stream, OptType::LIST, ReplyType::ACK)?;
+
+        // unlisted exports are still available
+        assert_eq!(info_size(&mut stream, "e9")?, 109);
+
+        send_opt(&mut stream, OptType::ABORT, vec![])?;
+        server.join().unwrap()?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_minimum_block_size() -> Result<()> {
+        let server = Server::new(MemBlocks::new(vec![0u8; 4096])).minimum_block_size(512);
+        let (server, mut stream) = start_server(server)?;
+        send_opt(
+            &mut stream,
+            OptType::GO,
+            info_request(vec![InfoType::BLOCK_SIZE])?,
+        )?;
+        expect_reply(&mut stream, OptType::GO, ReplyType::INFO)?;
+        let data = expect_reply(&mut stream, OptType::GO, ReplyType::INFO)?;
+        let mut data = &data[..];
+        assert_eq!(data.read_u16::<BE>()?, InfoType::BLOCK_SIZE.into());
+        assert_eq!(data.read_u32::<BE>()?, 512);
+        expect_reply(&mut stream, OptType::GO, ReplyType::ACK)?;
+
+        let reply_err = |stream: &mut _| -> Result<ErrorType> {
+            let ReplyHeader::Simple { err, .. } = ReplyHeader::get(stream)? else {
+                panic!("expected a simple reply");
+            };
+            Ok(err)
+        };
+
+        Request::new(Cmd::WRITE, 512, 1024).put(&[1u8; 1024], &mut stream)?;
+        assert_eq!(reply_err(&mut stream)?, ErrorType::OK);
+        Request::new(Cmd::READ, 1024, 512).put(&[], &mut stream)?;
+        assert_eq!(reply_err(&mut stream)?, ErrorType::OK);
+        let mut buf = [0u8; 512];
+        stream.read_exact(&mut buf)?;
+        assert_eq!(buf, [1u8; 512]);
+
+        // unaligned offset
+        Request::new(Cmd::WRITE, 100, 512).put(&[2u8; 512], &mut stream)?;
+        assert_eq!(reply_err(&mut stream)?, ErrorType::EINVAL);
+        // unaligned length
+        Request::new(Cmd::READ, 0, 100).put(&[], &mut stream)?;
+        assert_eq!(reply_err(&mut stream)?, ErrorType::EINVAL);
+        Request::new(Cmd::WRITE_ZEROES, 0, 10).put(&[], &mut stream)?;
+        assert_eq!(reply_err(&mut stream)?, ErrorType::EINVAL);
+
+        Request::new(Cmd::DISCONNECT, 0, 0).put(&[], &mut stream)?;
+        server.join().unwrap()?;
+        Ok(())
+    }
+
+    /// Serve `req` (with data for a write), returning the error and any data
+    /// in the reply.
+    fn serve<F: Blocks + Sync + Send + 'static>(
+        server: &Server<F>,
+        req: Request,
+        data: &[u8],
+    ) -> Result<(ErrorType, Vec<u8>)> {
+        let mut request = vec![];
+        req.put(data, &mut request)?;
+        let reply = server.serve_request(&request)?;
+        let mut reply = &reply[..];
+        let ReplyHeader::Simple { err, handle } = ReplyHeader::get(&mut reply)? else {
+            panic!("expected a simple reply");
+        };
+        assert_eq!(handle, req.handle);
+        Ok((err, reply.to_vec()))
+    }
+
+    #[test]
+    fn test_snapshot_unsupported() {
+        let server = Server::new(MemBlocks::new(vec![0u8; 1024]));
+        let err = server.snapshot("default").unwrap_err();
+        let err = err.root_cause().downcast_ref::<std::io::Error>().unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::Unsupported);
+        assert!(server.snapshot("other").is_err());
+    }
+
+    #[test]
+    fn test_serve_read_write() -> Result<()> {
+        let server = Server::new(MemBlocks::new(vec![1u8; 1024]));
+        assert_eq!(
+            serve(&server, Request::new(Cmd::READ, 10, 3), &[])?,
+            (ErrorType::OK, vec![1, 1, 1])
+        );
+        assert_eq!(
+            serve(&server, Request::new(Cmd::WRITE, 11, 2), &[2, 3])?,
+            (ErrorType::OK, vec![])
+        );
+        assert_eq!(
+            serve(&server, Request::new(Cmd::READ, 10, 3), &[])?,
+            (ErrorType::OK, vec![1, 2, 3])
+        );
+        // errors have no data
+        assert_eq!(
+            serve(&server, Request::new(Cmd::READ, 1023, 2), &[])?,
+            (ErrorType::EINVAL, vec![])
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_serve_write_zeroes() -> Result<()> {
+        let server = Server::new(MemBlocks::new(vec![1u8; 1024]));
+        let mut req = Request::new(Cmd::WRITE_ZEROES, 1, 2);
+        req.flags |= CmdFlags::FUA;
+        assert_eq!(serve(&server, req, &[])?.0, ErrorType::OK);
+        assert_eq!(
+            serve(&server, Request::new(Cmd::READ, 0, 4), &[])?.1,
+            [1, 0, 0, 1]
+        );
+        let mut req = Request::new(Cmd::WRITE_ZEROES, 2, 4);
+        req.flags |= CmdFlags::NO_HOLE;
+        assert_eq!(serve(&server, req, &[])?.0, ErrorType::OK);
+        assert_eq!(
+            serve(&server, Request::new(Cmd::READ, 0, 8), &[])?.1,
+            [1, 0, 0, 0, 0, 0, 1, 1]
+        );
+        assert_eq!(
+            serve(&server, Request::new(Cmd::WRITE_ZEROES, 1000, 100), &[])?.0,
+            ErrorType::ENOSPC
+        );
+        // the range wraps around
+        assert_eq!(
+            serve(
+                &server,
+                Request::new(Cmd::WRITE_ZEROES, u64::MAX - 1, 10),
+                &[]
+            )?
+            .0,
+            ErrorType::EOVERFLOW
+        );
+        assert_eq!(
+            serve(&server, Request::new(Cmd::WRITE, u64::MAX, 2), &[1, 2])?.0,
+            ErrorType::EOVERFLOW
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_serve_flush_trim_resize() -> Result<()> {
+        let server = Server::new(MemBlocks::new(vec![1u8; 1024]));
+        for req in [
+            Request::new(Cmd::FLUSH, 0, 0),
+            Request::new(Cmd::TRIM, 0, 512),
+            Request::new(Cmd::RESIZE, 2048, 0),
+        ] {
+            let typ = req.typ;
+            assert_eq!(
+                serve(&server, req, &[])?,
+                (ErrorType::OK, vec![]),
+                "{typ:?}"
+            );
+        }
+        assert_eq!(server.0.exports[0].size()?, 2048);
+        // MemBlocks zeroes a trimmed range
+        assert_eq!(
+            serve(&server, Request::new(Cmd::READ, 510, 4), &[])?.1,
+            [0, 0, 1, 1]
+        );
+        // writes are checked against the new size
+        assert_eq!(
+            serve(&server, Request::new(Cmd::WRITE, 2000, 48), &[2u8; 48])?.0,
+            ErrorType::OK
+        );
+        assert_eq!(
+            serve(&server, Request::new(Cmd::WRITE, 2000, 49), &[2u8; 49])?.0,
+            ErrorType::ENOSPC
+        );
+        Ok(())
+    }
+
+    /// A backend that counts calls to size.
+    struct SizeCountingBlocks {
+        mem: MemBlocks,
+        sizes: AtomicUsize,
+    }
+
+    impl Blocks for SizeCountingBlocks {
+        fn read_at(&self, buf: &mut [u8], off: u64) -> std::io::Result<()> {
+            self.mem.read_at(buf, off)
+        }
+
+        fn write_at(&self, buf: &[u8], off: u64) -> std::io::Result<()> {
+            self.mem.write_at(buf, off)
+        }
+
+        fn size(&self) -> std::io::Result<u64> {
+            self.sizes.fetch_add(1, Ordering::SeqCst);
+            self.mem.size()
+        }
+
+        fn flush(&self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_size_cached() -> Result<()> {
+        let blocks = Arc::new(SizeCountingBlocks {
+            mem: MemBlocks::new(vec![0u8; 4096]),
+            sizes: AtomicUsize::new(0),
+        });
+        let server = Server::new(blocks.clone()).op_log_level(None);
+        for off in [0, 1024, 2048] {
+            let req = Request::new(Cmd::WRITE, off, 512);
+            assert_eq!(serve(&server, req, &[1u8; 512])?.0, ErrorType::OK);
+            let req = Request::new(Cmd::TRIM, off, 512);
+            assert_eq!(serve(&server, req, &[])?.0, ErrorType::OK);
+        }
+        assert_eq!(blocks.sizes.load(Ordering::SeqCst), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_serve_cache() -> Result<()> {
+        let blocks = Arc::new(CountingBlocks {
+            mem: MemBlocks::new(vec![1u8; 1024 * 1024]),
+            reads: AtomicUsize::new(0),
+        });
+        let server = Server::new(blocks.clone()).op_log_level(None);
+        // the default implementation reads the range in chunks
+        assert_eq!(
+            serve(&server, Request::new(Cmd::CACHE, 0, 1024 * 256), &[])?,
+            (ErrorType::OK, vec![])
+        );
+        assert_eq!(blocks.reads.load(Ordering::SeqCst), 2);
+        assert_eq!(
+            serve(&server, Request::new(Cmd::CACHE, 1024 * 1024 - 1, 2), &[])?.0,
+            ErrorType::EINVAL
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_serve_unsupported() -> Result<()> {
+        let server = Server::new(MemBlocks::new(vec![1u8; 1024]));
+        let mut req = Request::new(Cmd::READ, 0, 1);
+        req.flags |= CmdFlags::DF;
+        assert_eq!(serve(&server, req, &[])?.0, ErrorType::ENOTSUP);
+        // disconnect has no reply
+        let mut request = vec![];
+        Request::new(Cmd::DISCONNECT, 0, 0).put(&[], &mut request)?;
+        assert!(server.serve_request(&request)?.is_empty());
+        Ok(())
+    }
+
+    /// Get the next structured reply chunk header and its payload.
+    fn get_chunk(stream: &mut impl Read) -> Result<(ChunkHeader, Vec<u8>)> {
+        let ReplyHeader::Structured(chunk) = ReplyHeader::get(stream)? else {
+            panic!("expected a structured reply");
+        };
+        let mut payload = vec![0u8; chunk.len as usize];
+        stream.read_exact(&mut payload)?;
+        Ok((chunk, payload))
+    }
+
+    #[test]
+    fn test_meta_context_negotiation() -> Result<()> {
+        let meta_request = |name: &str, queries: &[&str]| -> Result<Vec<u8>> {
+            let mut data = vec![];
+            MetaContextRequest {
+                name: name.to_string(),
+                queries: queries.iter().map(|q| q.to_string()).collect(),
+            }
+            .put(&mut data)?;
+            Ok(data)
+        };
+        let (server, mut stream) = start_server(Server::new(SparseMemBlocks::new(4096 * 2)))?;
+
+        // metadata contexts need structured replies
+        send_opt(
+            &mut stream,
+            OptType::SET_META_CONTEXT,
+            meta_request("", &[ALLOCATION_CONTEXT])?,
+        )?;
+        expect_reply(
+            &mut stream,
+            OptType::SET_META_CONTEXT,
+            ReplyType::ERR_INVALID,
+        )?;
+        send_opt(&mut stream, OptType::STRUCTURED_REPLY, vec![])?;
+        expect_reply(&mut stream, OptType::STRUCTURED_REPLY, ReplyType::ACK)?;
+
+        for (typ, name, queries, listed) in [
+            (OptType::LIST_META_CONTEXT, "", &[][..], true),
+            (OptType::LIST_META_CONTEXT, "default", &["base:"][..], true),
+            (
+                OptType::LIST_META_CONTEXT,
+                "",
+                &["qemu:dirty-bitmap:a"][..],
+                false,
+            ),
+            (
+                OptType::SET_META_CONTEXT,
+                "",
+                &["base:", "other:x"][..],
+                false,
+            ),
+            (
+                OptType::SET_META_CONTEXT,
+                "",
+                &[ALLOCATION_CONTEXT][..],
+                true,
+            ),
+        ] {
+            send_opt(&mut stream, typ, meta_request(name, queries)?)?;
+            if listed {
+                let data = expect_reply(&mut stream, typ, ReplyType::META_CONTEXT)?;
+                assert_eq!(&data[4..], ALLOCATION_CONTEXT.as_bytes());
+            }
+            expect_reply(&mut stream, typ, ReplyType::ACK)?;
+        }
+        send_opt(
+            &mut stream,
+            OptType::LIST_META_CONTEXT,
+            meta_request("nope", &[])?,
+        )?;
+        expect_reply(
+            &mut stream,
+            OptType::LIST_META_CONTEXT,
+            ReplyType::ERR_UNKNOWN,
+        )?;
+
+        send_opt(&mut stream, OptType::GO, info_request(vec![])?)?;
+        expect_reply(&mut stream, OptType::GO, ReplyType::INFO)?;
+        expect_reply(&mut stream, OptType::GO, ReplyType::INFO)?;
+        expect_reply(&mut stream, OptType::GO, ReplyType::ACK)?;
+
+        // the whole export is a hole, so REQ_ONE gets a single extent
+        let mut req = Request::new(Cmd::BLOCK_STATUS, 0, 4096 * 2);
+        req.flags = CmdFlags::REQ_ONE;
+        req.put(&[], &mut stream)?;
+        let (chunk, payload) = get_chunk(&mut stream)?;
+        assert_eq!(chunk.typ, u16::from(ChunkType::BLOCK_STATUS));
+        assert!(chunk.is_done());
+        let mut payload = &payload[..];
+        payload.read_u32::<BE>()?;
+        assert_eq!(payload.read_u32::<BE>()?, 4096 * 2);
+        assert_eq!(payload.read_u32::<BE>()?, STATE_HOLE | STATE_ZERO);
+        assert!(payload.is_empty());
+
+        Request::new(Cmd::DISCONNECT, 0, 0).put(&[], &mut stream)?;
+        server.join().unwrap()?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_structured_read_hole() -> Result<()> {
+        let blocks = SparseMemBlocks::new(4096 * 2);
+        blocks.write_at(&[1u8; 10], 0)?;
+        let (server, mut stream) = start_server(Server::new(blocks))?;
+
+        send_opt(&mut stream, OptType::STRUCTURED_REPLY, vec![])?;
+        expect_reply(&mut stream, OptType::STRUCTURED_REPLY, ReplyType::ACK)?;
+        send_opt(&mut stream, OptType::GO, info_request(vec![])?)?;
+        expect_reply(&mut stream, OptType::GO, ReplyType::INFO)?;
+        expect_reply(&mut stream, OptType::GO, ReplyType::INFO)?;
+        expect_reply(&mut stream, OptType::GO, ReplyType::ACK)?;
+
+        Request::new(Cmd::READ, 0, 4096 * 2).put(&[], &mut stream)?;
+        let (chunk, payload) = get_chunk(&mut stream)?;
+        assert_eq!(chunk.typ, u16::from(ChunkType::OFFSET_DATA));
+        assert!(!chunk.is_done());
+        assert_eq!(payload.len(), 8 + 4096);
+        let (chunk, payload) = get_chunk(&mut stream)?;
+        assert_eq!(chunk.typ, u16::from(ChunkType::OFFSET_HOLE));
+        assert!(chunk.is_done());
+        let mut payload = &payload[..];
+        assert_eq!(payload.read_u64::<BE>()?, 4096);
+        assert_eq!(payload.read_u32::<BE>()?, 4096);
+
+        // errors are a single chunk with a message
+        Request::new(Cmd::READ, 0, 0).put(&[], &mut stream)?;
+        let (chunk, payload) = get_chunk(&mut stream)?;
+        assert_eq!(chunk.typ, u16::from(ChunkType::ERROR));
+        assert!(chunk.is_done());
+        let mut payload = &payload[..];
+        assert_eq!(payload.read_u32::<BE>()?, ErrorType::EINVAL.into());
+        let msg_len = payload.read_u16::<BE>()?;
+        assert_eq!(payload.len(), msg_len as usize);
+        assert_eq!(
+            std::str::from_utf8(payload)?,
+            format!("READ failed: {}", ErrorType::EINVAL)
+        );
+
+        Request::new(Cmd::DISCONNECT, 0, 0).put(&[], &mut stream)?;
+        server.join().unwrap()?;
+        Ok(())
+    }
+}
+
+/// Wrap a Blocks and implement the core NBD operations using its operations.
+#[derive(Debug)]
+struct Export<F: Blocks> {
+    name: String,
+    description: Option<String>,
+    blocks: F,
+    /// The size of `blocks`, or [`Export::UNKNOWN_SIZE`] until it is first
+    /// needed, cached so that checking each request's bounds does not query
+    /// the backend.
+    size: AtomicU64,
+    /// A connection has write access (see [`Server::single_writer`]).
+    has_writer: AtomicBool,
+}
+
+/// Check that `[off, off+len)` is within an export of `size` bytes, failing
+/// with `past_end` if not, or EOVERFLOW if the range wraps around.
+fn check_in_bounds(
+    off: u64,
+    len: u64,
+    size: u64,
+    past_end: ErrorType,
+) -> core::result::Result<(), ErrorType> {
+    match off.checked_add(len) {
+        None => Err(ErrorType::EOVERFLOW),
+        Some(end) if end > size => Err(past_end),
+        Some(_) => Ok(()),
+    }
+}
+
+/// Check that `[off, off+len)` is within a device of `size` bytes, for
+/// [`Blocks`] wrappers that check accesses themselves.
+pub(super) fn check_bounds(size: u64, off: u64, len: u64) -> io::Result<()> {
+    match off.checked_add(len) {
+        Some(end) if end <= size => Ok(()),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "out-of-bounds access",
+        )),
+    }
+}
+
+/// Reject zero-length accesses, which the spec leaves unspecified, so that
+/// they get the same error (regardless of offset) from every backend.
+fn check_nonempty(len: usize) -> core::result::Result<(), ErrorType> {
+    if len == 0 {
+        return Err(ErrorType::EINVAL);
+    }
+    Ok(())
+}
+
+/// Run a backend operation, retrying (a bounded number of times) if the
+/// backend reports a transient error.
+///
+/// Nonblocking backends may return [`io::ErrorKind::WouldBlock`] when they are
+/// temporarily unavailable; rather than failing the request, wait briefly and
+/// try again. Interrupted operations are retried immediately.
+fn retry<T>(mut op: impl FnMut() -> io::Result<T>) -> io::Result<T> {
+    const MAX_RETRIES: u32 = 10;
+    let mut retries = 0;
+    loop {
+        match op() {
+            Err(err) if retries < MAX_RETRIES => match err.kind() {
+                io::ErrorKind::WouldBlock => {
+                    retries += 1;
+                    thread::sleep(Duration::from_millis(retries as u64));
+                }
+                io::ErrorKind::Interrupted => retries += 1,
+                _ => return Err(err),
+            },
+            r => return r,
+        }
+    }
+}
+
+impl<F: Blocks> Export<F> {
+    const UNKNOWN_SIZE: u64 = u64::MAX;
+
+    fn new(name: String, blocks: F) -> Self {
+        Self {
+            name,
+            description: None,
+            blocks,
+            size: AtomicU64::new(Self::UNKNOWN_SIZE),
+            has_writer: AtomicBool::new(false),
+        }
+    }
+
+    fn read<'a>(
+        &self,
+        off: u64,
+        len: u32,
+        buf: &'a mut [u8],
+    ) -> core::result::Result<&'a mut [u8], ErrorType> {
+        let len = len as usize;
+        check_nonempty(len)?;
+        if buf.len() < len {
+            return Err(ErrorType::EOVERFLOW);
+        }
+        let buf = &mut buf[..len];
+        match retry(|| Blocks::read_at(&self.blocks, buf, off)) {
+            Ok(_) => Ok(buf),
+            Err(err) => Err(ErrorType::from_io_error(&err)),
+        }
+    }
+
+    /// Read for a structured reply: get the extents covering `[off,
+    /// off+len)`, reading data only for those not known to be zero.
+    fn read_extents<'a>(
+        &self,
+        off: u64,
+        len: u32,
+        buf: &'a mut [u8],
+    ) -> core::result::Result<(&'a [u8], Vec<Extent>), ErrorType> {
+        let len = len as usize;
+        check_nonempty(len)?;
+        if buf.len() < len {
+            return Err(ErrorType::EOVERFLOW);
+        }
+        let mut extents = retry(|| Blocks::extent_status(&self.blocks, off, len as u64))
+            .map_err(|err| ErrorType::from_io_error(&err))?;
+        extents.retain(|e| e.len > 0);
+        let mut pos = 0;
+        for extent in &extents {
+            let end = pos + extent.len as usize;
+            if end > len {
+                warn!("extents for {off}+{len} are too long");
+                return Err(ErrorType::EIO);
+            }
+            if !extent.zero {
+                retry(|| Blocks::read_at(&self.blocks, &mut buf[pos..end], off + pos as u64))
+                    .map_err(|err| ErrorType::from_io_error(&err))?;
+            }
+            pos = end;
+        }
+        if pos != len {
+            warn!("extents for {off}+{len} are too short");
+            return Err(ErrorType::EIO);
+        }
+        Ok((&buf[..len], extents))
+    }
+
+    /// Write data, or if `detect_zeroes` is set and the data is all zeros,
+    /// write zeroes (which may leave a hole) instead.
+    fn write(
+        &self,
+        off: u64,
+        len: usize,
+        data: &[u8],
+        detect_zeroes: bool,
+    ) -> core::result::Result<(), ErrorType> {
+        check_nonempty(len)?;
+        if len > data.len() {
+            return Err(ErrorType::EOVERFLOW);
+        }
+        // backends like File would otherwise grow to fit the write
+        let size = self.size().map_err(|err| ErrorType::from_io_error(&err))?;
+        check_in_bounds(off, len as u64, size, ErrorType::ENOSPC)?;
+        let data = &data[..len];
+        let zeroes = detect_zeroes && data.iter().all(|&b| b == 0);
+        retry(|| {
+            if zeroes {
+                Blocks::write_zeroes(&self.blocks, off, len as u64, false)
+            } else {
+                Blocks::write_at(&self.blocks, data, off)
+            }
+        })
+        .map_err(|err| ErrorType::from_io_error(&err))?;
+        Ok(())
+    }
+
+    fn write_zeroes(
+        &self,
+        off: u64,
+        len: u32,
+        no_hole: bool,
+    ) -> core::result::Result<(), ErrorType> {
+        check_nonempty(len as usize)?;
+        let size = self.size().map_err(|err| ErrorType::from_io_error(&err))?;
+        check_in_bounds(off, len as u64, size, ErrorType::ENOSPC)?;
+        retry(|| Blocks::write_zeroes(&self.blocks, off, len as u64, no_hole))
+            .map_err(|err| ErrorType::from_io_error(&err))?;
+        Ok(())
+    }
+
+    fn trim(&self, off: u64, len: u32) -> core::result::Result<(), ErrorType> {
+        let size = self.size().map_err(|err| ErrorType::from_io_error(&err))?;
+        check_in_bounds(off, len as u64, size, ErrorType::EINVAL)?;
+        retry(|| Blocks::trim(&self.blocks, off, len as u64))
+            .map_err(|err| ErrorType::from_io_error(&err))
+    }
+
+    fn cache(&self, off: u64, len: u32) -> core::result::Result<(), ErrorType> {
+        check_nonempty(len as usize)?;
+        let size = self.size().map_err(|err| ErrorType::from_io_error(&err))?;
+        check_in_bounds(off, len as u64, size, ErrorType::EINVAL)?;
+        retry(|| Blocks::cache(&self.blocks, off, len as u64))
+            .map_err(|err| ErrorType::from_io_error(&err))
+    }
+
+    fn resize(&self, size: u64) -> core::result::Result<(), ErrorType> {
+        let r = retry(|| Blocks::resize(&self.blocks, size));
+        // even a failed resize may have changed the size
+        self.size.store(Self::UNKNOWN_SIZE, Ordering::Relaxed);
+        r.map_err(|err| ErrorType::from_io_error(&err))
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        retry(|| self.blocks.flush())?;
+        Ok(())
+    }
+
+    fn flush_range(&self, off: u64, len: u64) -> io::Result<()> {
+        retry(|| self.blocks.flush_range(off, len))?;
+        Ok(())
+    }
+
+    /// The export's size, from the cache if it is known.
+    fn size(&self) -> io::Result<u64> {
+        match self.size.load(Ordering::Relaxed) {
+            Self::UNKNOWN_SIZE => self.refresh_size(),
+            size => Ok(size),
+        }
+    }
+
+    /// Get the export's size from the backend, updating the cache.
+    fn refresh_size(&self) -> io::Result<u64> {
+        let size = retry(|| self.blocks.size())?;
+        self.size.store(size, Ordering::Relaxed);
+        Ok(size)
+    }
+
+    /// Hint the backend to prefetch `[off, off+len)`, clamped to the export.
+    /// Failures are only logged, since this is just an optimization.
+    fn readahead(&self, off: u64, len: u64) {
+        let Ok(size) = self.size() else { return };
+        let len = len.min(size.saturating_sub(off));
+        if len == 0 {
+            return;
+        }
+        if let Err(err) = self.blocks.readahead(off, len) {
+            debug!(target: "nbd", "readahead of {off}+{len} failed: {err}");
+        }
+    }
+
+    /// Flush and then snapshot the export, so the snapshot includes all
+    /// completed writes.
+    fn snapshot(&self) -> io::Result<SnapshotId> {
+        self.flush()?;
+        retry(|| self.blocks.snapshot())
+    }
+
+    /// Get the size to advertise for this export during negotiation, which
+    /// is always fresh from the backend.
+    fn export_size(&self) -> Result<u64> {
+        self.refresh_size().wrap_err_with(|| {
+            format!(
+                "backend for export {:?} does not have a fixed size and cannot be exported",
+                self.name
+            )
+        })
+    }
+}
+
+/// Logs a warning when an operation has been running for longer than a
+/// threshold, from a background thread.
+struct Watchdog {
+    state: Arc<(Mutex<WatchState>, Condvar)>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+#[derive(Default)]
+struct WatchState {
+    /// Description and start time of the running operation.
+    current: Option<(String, Instant)>,
+    /// A warning has been logged for the current operation.
+    warned: bool,
+    done: bool,
+}
+
+impl Watchdog {
+    fn new(threshold: Duration) -> Self {
+        let state = Arc::new((Mutex::new(WatchState::default()), Condvar::new()));
+        let thread = thread::spawn({
+            let state = state.clone();
+            move || {
+                let (lock, cvar) = &*state;
+                let mut st = lock.lock().unwrap();
+                while !st.done {
+                    let mut timeout = threshold;
+                    if let Some((desc, start)) = &st.current {
+                        let elapsed = start.elapsed();
+                        if elapsed >= threshold {
+                            if !st.warned {
+                                warn!(target: "nbd", "operation {desc} has been running for {elapsed:?}");
+                                st.warned = true;
+                            }
+                        } else {
+                            timeout = threshold - elapsed;
+                        }
+                    }
+                    st = cvar.wait_timeout(st, timeout).unwrap().0;
+                }
+            }
+        });
+        Self {
+            state,
+            thread: Some(thread),
+        }
+    }
+
+    fn start(&self, desc: String) {
+        let (lock, cvar) = &*self.state;
+        let mut st = lock.lock().unwrap();
+        st.current = Some((desc, Instant::now()));
+        st.warned = false;
+        cvar.notify_one();
+    }
+
+    fn finish(&self) {
+        self.state.0.lock().unwrap().current = None;
+    }
+}
+
+impl Drop for Watchdog {
+    fn drop(&mut self) {
+        let (lock, cvar) = &*self.state;
+        lock.lock().unwrap().done = true;
+        cvar.notify_one();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// A stream with buffered reads, which exposes what has been received but
+/// not yet read. Writes are not buffered.
+struct BufStream<IO>(BufReader<IO>);
+
+impl<IO: Read> Read for BufStream<IO> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl<IO: Write> Write for BufStream<IO> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.get_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.get_mut().flush()
+    }
+}
+
+/// A request buffer borrowed from a server's pool, which goes back to the
+/// pool when dropped.
+struct PooledBuf<'a> {
+    pool: &'a Mutex<Vec<Vec<u8>>>,
+    buf: Vec<u8>,
+}
+
+impl std::ops::Deref for PooledBuf<'_> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.buf
+    }
+}
+
+impl std::ops::DerefMut for PooledBuf<'_> {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.buf
+    }
+}
+
+impl Drop for PooledBuf<'_> {
+    fn drop(&mut self) {
+        let mut pool = self.pool.lock().unwrap();
+        if pool.len() < MAX_POOLED_BUFFERS {
+            pool.push(std::mem::take(&mut self.buf));
+        }
+    }
+}
+
+/// The most request buffers a server keeps for reuse.
+const MAX_POOLED_BUFFERS: usize = 16;
+
+/// The end of the range covered by a batch of reads.
+fn batch_end(batch: &[Request]) -> u64 {
+    batch
+        .iter()
+        .map(|req| req.offset.saturating_add(req.len as u64))
+        .max()
+        .unwrap_or(0)
+}
+
+/// The options a client has negotiated so far in the handshake.
+struct Haggle<F: Blocks> {
+    flags: HandshakeFlags,
+    /// The connection is already running over TLS.
+    tls_active: bool,
+    /// NBD_OPT_STARTTLS can be accepted.
+    starttls: bool,
+    structured_replies: bool,
+    /// The export base:allocation was selected for.
+    allocation: Option<Arc<Export<F>>>,
+}
+
+/// The outcome of one round of option haggling.
+enum Haggled<F: Blocks> {
+    /// The client selected an export.
+    Export(Session<F>),
+    /// The client asked to upgrade to TLS, and the server agreed.
+    StartTls,
+    /// The client ended the handshake.
+    Abort,
+}
+
+/// How a connection continues after the handshake.
+enum Negotiated<'s, F: Blocks, IO> {
+    /// Transmission happens over the original connection.
+    Plain(Session<F>, IO),
+    /// Transmission happens over TLS.
+    Tls(Session<F>, Box<dyn Stream + 's>),
+    /// The client ended the handshake.
+    Abort,
+}
+
+/// State negotiated for one connection during the handshake.
+#[derive(Debug)]
+struct Session<F: Blocks> {
+    export: Arc<Export<F>>,
+    /// The client negotiated NBD_OPT_STRUCTURED_REPLY.
+    structured_replies: bool,
+    /// The client may not modify the export.
+    read_only: bool,
+    /// The client selected the base:allocation metadata context for this
+    /// export, so it can use NBD_CMD_BLOCK_STATUS.
+    block_status: bool,
+    /// This connection holds the export's writer slot, which it releases
+    /// when it ends.
+    writer: bool,
+}
+
+impl<F: Blocks> Drop for Session<F> {
+    fn drop(&mut self) {
+        if self.writer {
+            self.export.has_writer.store(false, Ordering::SeqCst);
+        }
+    }
+}
+
+#[derive(Debug)]
+struct ServerInner<F: Blocks> {
+    /// The first export is the default, used when the client requests an
+    /// empty name.
+    exports: Vec<Arc<Export<F>>>,
+    /// Level at which each request is logged (None disables per-op logging).
+    op_log_level: Option<Level>,
+    /// Block size advertised as minimum in NBD_INFO_BLOCK_SIZE. Requests not
+    /// aligned to it are rejected.
+    minimum_block_size: u32,
+    /// Block size advertised as preferred in NBD_INFO_BLOCK_SIZE.
+    preferred_block_size: u32,
+    /// Block size advertised as maximum in NBD_INFO_BLOCK_SIZE, which is the
+    /// size of each request buffer. Larger requests fail with EOVERFLOW.
+    max_block_size: u32,
+    /// Refuse to negotiate an export until TLS is set up (FORCEDTLS mode).
+    require_tls: bool,
+    /// Runs the TLS handshake after NBD_OPT_STARTTLS (None if TLS is not
+    /// supported).
+    tls_upgrade: Option<TlsUpgrade>,
+    /// Turn writes of all zeros into write zeroes.
+    detect_zero_writes: bool,
+    /// Warn about operations that take longer than this.
+    slow_op_threshold: Option<Duration>,
+    /// Prefetch ahead of clients that read sequentially.
+    readahead: bool,
+    /// Merge pipelined adjacent reads into one backend read.
+    coalesce_reads: bool,
+    /// Give write access to only one connection per export at a time.
+    single_writer: bool,
+    /// Never allow writes to any export.
+    read_only: bool,
+    /// Limit on the number of exports in a LIST reply.
+    max_list_exports: Option<usize>,
+    /// Number of threads per connection handling requests concurrently (0
+    /// handles them one at a time).
+    workers: usize,
+    /// Callback to run once clients have written enough.
+    write_trigger: Option<WriteTrigger>,
+    /// Called for every request and reply.
+    observer: Option<Observer>,
+    /// Errors to inject into random requests.
+    #[cfg(any(test, feature = "testutil"))]
+    chaos: chaos::Chaos,
+    /// Number of clients currently connected.
+    connections: AtomicUsize,
+    /// The connections this server accepted itself, which the control socket
+    /// can list and close.
+    conns: Connections,
+    /// Request buffers from finished connections, so that short connections
+    /// (such as probes that disconnect right after the handshake) do not each
+    /// allocate one.
+    buffers: Mutex<Vec<Vec<u8>>>,
+}
+
+impl<F: Blocks> ServerInner<F> {
+    // fake constant for the server's supported operations
+    #[allow(non_snake_case)]
+    fn TRANSMIT_FLAGS() -> TransmitFlags {
+        TransmitFlags::HAS_FLAGS
+            | TransmitFlags::SEND_FLUSH
+            | TransmitFlags::SEND_FUA
+            | TransmitFlags::SEND_WRITE_ZEROES
+            | TransmitFlags::SEND_CACHE
+    }
+
+    // the default for the largest read or write the server accepts
+    const DEFAULT_MAX_BLOCK_SIZE: u32 = 4096 * 64;
+
+    // Agree on basic negotiation flags.
+    fn initial_handshake<IO: Read + Write>(stream: &mut IO) -> Result<HandshakeFlags> {
+        Self::put_greeting(stream)?;
+        let client_flags = stream.read_u32::<BE>()?;
+        Self::handshake_flags(client_flags)
+    }
+
+    /// Send the server's half of the initial handshake.
+    fn put_greeting<IO: Write>(stream: &mut IO) -> Result<()> {
+        stream.write_u64::<BE>(MAGIC)?;
         stream.write_u64::<BE>(IHAVEOPT)?;
         stream
             .write_u16::<BE>((HandshakeFlags::FIXED_NEWSTYLE | HandshakeFlags::NO_ZEROES).bits())?;
-        let client_flags = stream.read_u32::<BE>()?;
+        Ok(())
+    }
+
+    /// Check the flags the client replied to the greeting with, returning
+    /// the flags for the rest of the handshake.
+    fn handshake_flags(client_flags: u32) -> Result<HandshakeFlags> {
         let client_flags = ClientHandshakeFlags::from_bits(client_flags)
-            .ok_or_else(|| ProtocolError::new(format!("unexpected client flags {client_flags}")))?;
+            .ok_or_else(|| unexpected_start("client flags", &client_flags.to_be_bytes()))?;
         if !client_flags.contains(ClientHandshakeFlags::C_FIXED_NEWSTYLE) {
             bail!(ProtocolError::new("client does not support FIXED_NEWSTYLE"));
         }
@@ -212,13 +2530,76 @@ impl<F: Blocks> ServerInner<F> {
         Ok(flags)
     }
 
-    fn send_export_list<IO: Write>(&self, stream: &mut IO) -> Result<()> {
-        ExportList::new(vec![self.export.name()]).put(stream)?;
-        Ok(())
+    fn send_export_list<IO: Write>(&self, stream: &mut IO) -> Result<()> {
+        let mut exports = &self.exports[..];
+        if let Some(max) = self.max_list_exports {
+            if exports.len() > max {
+                info!(
+                    target: "nbd",
+                    "listing only {max} of {} exports",
+                    exports.len()
+                );
+                exports = &exports[..max];
+            }
+        }
+        let names = exports.iter().map(|e| e.name.clone()).collect();
+        ExportList::new(names).put(stream)?;
+        Ok(())
+    }
+
+    /// Start a session using `export`, which is read-only if the server is,
+    /// or if it only allows a single writer and another connection already
+    /// has it.
+    fn new_session(&self, export: &Arc<Export<F>>, structured_replies: bool) -> Session<F> {
+        let writer = self.single_writer
+            && !self.read_only
+            && export
+                .has_writer
+                .compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst)
+                .is_ok();
+        Session {
+            export: export.clone(),
+            structured_replies,
+            read_only: self.read_only || (self.single_writer && !writer),
+            block_status: false,
+            writer,
+        }
+    }
+
+    /// The transmission flags to advertise for a connection to `export`.
+    fn transmit_flags(export: &Export<F>, read_only: bool) -> TransmitFlags {
+        let mut flags = Self::TRANSMIT_FLAGS();
+        if read_only {
+            flags |= TransmitFlags::READ_ONLY;
+        }
+        if export.blocks.supports_trim() {
+            flags |= TransmitFlags::SEND_TRIM;
+        }
+        if export.blocks.supports_resize() {
+            flags |= TransmitFlags::SEND_RESIZE;
+        }
+        if export.blocks.supports_multi_conn() {
+            flags |= TransmitFlags::CAN_MULTI_CONN;
+        }
+        flags
+    }
+
+    /// Find an export by name, where the empty name refers to the default
+    /// export.
+    fn find_export(&self, name: &str) -> Option<&Arc<Export<F>>> {
+        if name.is_empty() {
+            return self.exports.first();
+        }
+        self.exports.iter().find(|e| e.name == name)
     }
 
     /// Send export info at the end of newstyle negotiation, when client sends NBD_OPT_EXPORT_NAME.
-    fn send_export_info<IO: Write>(&self, stream: &mut IO, flags: HandshakeFlags) -> Result<()> {
+    fn send_export_info<IO: Write>(
+        &self,
+        session: &Session<F>,
+        stream: &mut IO,
+        flags: HandshakeFlags,
+    ) -> Result<()> {
         // If the value of the option field is `NBD_OPT_EXPORT_NAME` and the
         // server is willing to allow the export, the server replies with
         // information about the used export:
@@ -226,8 +2607,8 @@ impl<F: Blocks> ServerInner<F> {
         // S: 64 bits, size of the export in bytes (unsigned)
         // S: 16 bits, transmission flags
         // S: 124 bytes, zeroes (reserved) (unless `NBD_FLAG_C_NO_ZEROES` was negotiated by the client)
-        stream.write_u64::<BE>(self.export.size()?)?;
-        let transmit = Self::TRANSMIT_FLAGS();
+        stream.write_u64::<BE>(session.export.export_size()?)?;
+        let transmit = Self::transmit_flags(&session.export, session.read_only);
         stream.write_u16::<BE>(transmit.bits())?;
         if !flags.contains(HandshakeFlags::NO_ZEROES) {
             stream.write_all(&[0u8; 124])?;
@@ -236,13 +2617,41 @@ impl<F: Blocks> ServerInner<F> {
         Ok(())
     }
 
+    /// Reply to an INFO or GO option.
+    ///
+    /// Sends each requested info type the server supports exactly once (in the
+    /// order of [`InfoType`]), always including the mandatory
+    /// NBD_INFO_EXPORT, followed by a single ACK.
     fn info_responses<IO: Write>(
         &self,
+        export: &Export<F>,
         opt_typ: OptType,
         info_req: InfoRequest,
+        read_only: bool,
         stream: &mut IO,
     ) -> Result<()> {
-        for typ in info_req.typs.iter().chain([InfoType::EXPORT].iter()) {
+        let size = match export.export_size() {
+            Ok(size) => size,
+            Err(err) => {
+                warn!("{err:?}");
+                // the spec allows an error reply to carry a message
+                let msg = err.to_string().into_bytes();
+                OptReply::new(opt_typ, ReplyType::ERR_UNKNOWN, msg).put(stream)?;
+                return Err(err);
+            }
+        };
+        let order = [
+            InfoType::EXPORT,
+            InfoType::NAME,
+            InfoType::DESCRIPTION,
+            InfoType::BLOCK_SIZE,
+        ];
+        // block size constraints are always sent, so that clients which do
+        // not ask for them still learn the maximum request size
+        let typs = order.into_iter().filter(|typ| {
+            matches!(typ, InfoType::EXPORT | InfoType::BLOCK_SIZE) || info_req.typs.contains(typ)
+        });
+        for typ in typs {
             match typ {
                 InfoType::EXPORT => {
                     // Mandatory information before a successful completion of
@@ -258,8 +2667,8 @@ impl<F: Blocks> ServerInner<F> {
                     // - 16 bits, transmission flags
                     let mut buf = vec![];
                     buf.write_u16::<BE>(InfoType::EXPORT.into())?;
-                    buf.write_u64::<BE>(self.export.size()?)?;
-                    buf.write_u16::<BE>(Self::TRANSMIT_FLAGS().bits())?;
+                    buf.write_u64::<BE>(size)?;
+                    buf.write_u16::<BE>(Self::transmit_flags(export, read_only).bits())?;
                     OptReply::new(opt_typ, ReplyType::INFO, buf).put(stream)?;
                 }
                 InfoType::BLOCK_SIZE => {
@@ -284,16 +2693,34 @@ impl<F: Blocks> ServerInner<F> {
                     //  -  32 bits, preferred block size
                     //  -  32 bits, maximum block size
 
+                    // the preferred size must be at least the minimum
+                    let preferred = self.preferred_block_size.max(self.minimum_block_size);
                     let mut buf = vec![];
                     buf.write_u16::<BE>(InfoType::BLOCK_SIZE.into())?;
-                    buf.write_u32::<BE>(1)?; // minimum
-                    buf.write_u32::<BE>(4096)?; // preferred
-                    buf.write_u32::<BE>(4096 * 32)?; // maximum
+                    buf.write_u32::<BE>(self.minimum_block_size)?; // minimum
+                    buf.write_u32::<BE>(preferred)?; // preferred
+                    buf.write_u32::<BE>(self.max_block_size)?; // maximum
+                    OptReply::new(opt_typ, ReplyType::INFO, buf).put(stream)?;
+                }
+                InfoType::NAME => {
+                    // - 16 bits, NBD_INFO_NAME
+                    // - String: name of the export
+                    let mut buf = vec![];
+                    buf.write_u16::<BE>(InfoType::NAME.into())?;
+                    buf.write_all(export.name.as_bytes())?;
                     OptReply::new(opt_typ, ReplyType::INFO, buf).put(stream)?;
                 }
-                InfoType::NAME | InfoType::DESCRIPTION => {
-                    OptReply::new(opt_typ, ReplyType::ERR_UNSUP, vec![]).put(stream)?;
-                    return Ok(());
+                InfoType::DESCRIPTION => {
+                    // - 16 bits, NBD_INFO_DESCRIPTION
+                    // - String: description of the export
+                    //
+                    // the server may omit info it does not have
+                    if let Some(description) = &export.description {
+                        let mut buf = vec![];
+                        buf.write_u16::<BE>(InfoType::DESCRIPTION.into())?;
+                        buf.write_all(description.as_bytes())?;
+                        OptReply::new(opt_typ, ReplyType::INFO, buf).put(stream)?;
+                    }
                 }
             }
         }
@@ -308,151 +2735,1080 @@ impl<F: Blocks> ServerInner<F> {
         &self,
         stream: &mut IO,
         flags: HandshakeFlags,
-    ) -> Result<Option<&Export<F>>> {
+        tls_active: bool,
+    ) -> Result<Haggled<F>> {
+        let mut haggle = Haggle {
+            flags,
+            tls_active,
+            starttls: self.tls_upgrade.is_some(),
+            structured_replies: false,
+            allocation: None,
+        };
         loop {
-            let opt = Opt::get(stream)?;
+            let opt = match Opt::get(stream) {
+                Ok(opt) => opt,
+                Err(err) => {
+                    Self::reject_option(err, stream)?;
+                    continue;
+                }
+            };
+            if let Some(haggled) = self.haggle_option(&mut haggle, opt, stream)? {
+                return Ok(haggled);
+            }
+        }
+    }
+
+    /// Handle a failure to read an option: an option that was too big to
+    /// read gets an error reply, after which haggling continues, while any
+    /// other error is returned.
+    fn reject_option<IO: Write>(err: Report, stream: &mut IO) -> Result<()> {
+        match err.downcast_ref::<OptTooBig>() {
+            // there is no way to send an error for EXPORT_NAME
+            Some(too_big) if too_big.typ != OptType::EXPORT_NAME => {
+                warn!(target: "nbd", "rejecting option: {too_big}");
+                OptReply::new(too_big.typ, ReplyType::ERR_TOO_BIG, vec![]).put(stream)?;
+                Ok(())
+            }
+            _ => Err(err),
+        }
+    }
+
+    /// Reply to one option, returning how the handshake ends if this option
+    /// ends it.
+    fn haggle_option<IO: Write>(
+        &self,
+        haggle: &mut Haggle<F>,
+        opt: Opt,
+        stream: &mut IO,
+    ) -> Result<Option<Haggled<F>>> {
+        if self.require_tls && !haggle.tls_active {
             match opt.typ {
+                OptType::STARTTLS | OptType::ABORT => {}
                 OptType::EXPORT_NAME => {
-                    let _export: String = String::from_utf8(opt.data)
-                        .wrap_err(ProtocolError::new("non-UTF8 export name"))?;
-                    // requested export name is currently ignored since there is
-                    // only a single export
-                    self.send_export_info(stream, flags)?;
-                    return Ok(Some(&self.export));
-                }
-                OptType::LIST => {
-                    self.send_export_list(stream)?;
-                }
-                // the only difference between INFO and GO is that on success,
-                // GO starts the transmission phase
-                OptType::INFO => {
-                    let info_req = InfoRequest::get(&mut &opt.data[..])?;
-                    self.info_responses(opt.typ, info_req, stream)?;
-                }
-                OptType::GO => {
-                    let info_req = InfoRequest::get(&mut &opt.data[..])?;
-                    self.info_responses(opt.typ, info_req, stream)?;
-                    return Ok(Some(&self.export));
-                }
-                OptType::ABORT => {
-                    return Ok(None);
+                    // there is no way to send an error for EXPORT_NAME
+                    bail!(ProtocolError::new("client requested export without TLS"));
                 }
                 _ => {
-                    warn!("got unsupported option {:?}", opt);
-                    OptReply::new(opt.typ, ReplyType::ERR_UNSUP, vec![]).put(stream)?;
+                    OptReply::new(opt.typ, ReplyType::ERR_TLS_REQD, vec![]).put(stream)?;
+                    return Ok(None);
+                }
+            }
+        }
+        let selects_allocation = |export: &Arc<Export<F>>| {
+            haggle
+                .allocation
+                .as_ref()
+                .is_some_and(|e| Arc::ptr_eq(e, export))
+        };
+        match opt.typ {
+            OptType::EXPORT_NAME => {
+                let name = String::from_utf8(opt.data)
+                    .wrap_err(ProtocolError::new("non-UTF8 export name"))?;
+                let Some(export) = self.find_export(&name) else {
+                    // there is no way to send an error for EXPORT_NAME
+                    bail!(ProtocolError::new(format!("unknown export {name:?}")));
+                };
+                let mut session = self.new_session(export, haggle.structured_replies);
+                session.block_status = selects_allocation(export);
+                self.send_export_info(&session, stream, haggle.flags)?;
+                return Ok(Some(Haggled::Export(session)));
+            }
+            OptType::LIST => {
+                self.send_export_list(stream)?;
+            }
+            // the only difference between INFO and GO is that on success,
+            // GO starts the transmission phase
+            OptType::INFO | OptType::GO => {
+                let info_req = InfoRequest::get(&mut &opt.data[..])?;
+                let Some(export) = self.find_export(&info_req.name) else {
+                    warn!("client requested unknown export {:?}", info_req.name);
+                    OptReply::new(opt.typ, ReplyType::ERR_UNKNOWN, vec![]).put(stream)?;
+                    return Ok(None);
+                };
+                if opt.typ == OptType::GO {
+                    let mut session = self.new_session(export, haggle.structured_replies);
+                    session.block_status = selects_allocation(export);
+                    self.info_responses(export, opt.typ, info_req, session.read_only, stream)?;
+                    return Ok(Some(Haggled::Export(session)));
+                }
+                // report what GO would get now
+                let read_only = self.read_only
+                    || (self.single_writer && export.has_writer.load(Ordering::SeqCst));
+                self.info_responses(export, opt.typ, info_req, read_only, stream)?;
+            }
+            OptType::STRUCTURED_REPLY => {
+                if !opt.data.is_empty() {
+                    OptReply::new(opt.typ, ReplyType::ERR_INVALID, vec![]).put(stream)?;
+                    return Ok(None);
+                }
+                haggle.structured_replies = true;
+                OptReply::ack(opt.typ).put(stream)?;
+            }
+            OptType::LIST_META_CONTEXT => {
+                self.meta_context_option(&opt, haggle.structured_replies, stream)?;
+            }
+            OptType::SET_META_CONTEXT => {
+                haggle.allocation =
+                    self.meta_context_option(&opt, haggle.structured_replies, stream)?;
+            }
+            OptType::STARTTLS if haggle.starttls => {
+                if haggle.tls_active || !opt.data.is_empty() {
+                    OptReply::new(opt.typ, ReplyType::ERR_INVALID, vec![]).put(stream)?;
+                    return Ok(None);
                 }
+                OptReply::ack(opt.typ).put(stream)?;
+                return Ok(Some(Haggled::StartTls));
+            }
+            OptType::ABORT => {
+                return Ok(Some(Haggled::Abort));
+            }
+            _ => {
+                warn!("got unsupported option {:?}", opt);
+                OptReply::new(opt.typ, ReplyType::ERR_UNSUP, vec![]).put(stream)?;
             }
         }
+        Ok(None)
     }
 
-    fn handle_ops<IO: Read + Write>(export: &Export<F>, stream: &mut IO) -> Result<()> {
-        let mut buf = vec![0u8; 4096 * 64];
-        loop {
-            assert_eq!(buf.len(), 4096 * 64);
-            let req = Request::get(stream, &mut buf)?;
-            info!(target: "nbd", "{:?}", req);
-            // only FUA is supported
-            if req.flags.intersects(CmdFlags::FUA.complement()) {
-                warn!(target: "nbd", "unexpected flags {:?}", req.flags);
-                SimpleReply::err(ErrorType::ENOTSUP, &req).put(stream)?;
-                continue;
-            }
-            match req.typ {
-                Cmd::READ => match export.read(req.offset, req.len, &mut buf) {
-                    Ok(data) => SimpleReply::data(&req, data).put(stream)?,
+    /// Reply to a read with structured reply chunks, sending extents known to
+    /// be zero as holes rather than zero-filled data.
+    fn put_read_chunks<IO: Write>(
+        req: &Request,
+        data: &[u8],
+        extents: &[Extent],
+        stream: &mut IO,
+    ) -> Result<()> {
+        if extents.is_empty() {
+            ChunkHeader::put(stream, ChunkFlags::DONE, ChunkType::NONE, req.handle, &[])?;
+            return Ok(());
+        }
+        let mut pos = 0;
+        for (i, extent) in extents.iter().enumerate() {
+            let flags = if i == extents.len() - 1 {
+                ChunkFlags::DONE
+            } else {
+                ChunkFlags::empty()
+            };
+            let offset = (req.offset + pos as u64).to_be_bytes();
+            let end = pos + extent.len as usize;
+            if extent.zero {
+                // S: 64 bits: offset (unsigned)
+                // S: 32 bits: hole size (unsigned, MUST be nonzero)
+                let len = (extent.len as u32).to_be_bytes();
+                ChunkHeader::put(
+                    stream,
+                    flags,
+                    ChunkType::OFFSET_HOLE,
+                    req.handle,
+                    &[&offset, &len],
+                )?;
+            } else {
+                // S: 64 bits: offset (unsigned)
+                // S: length - 8 bytes: data
+                ChunkHeader::put(
+                    stream,
+                    flags,
+                    ChunkType::OFFSET_DATA,
+                    req.handle,
+                    &[&offset, &data[pos..end]],
+                )?;
+            }
+            pos = end;
+        }
+        Ok(())
+    }
+
+    /// Send an error as the final chunk of a structured reply, with a
+    /// description of the error as its message, returning the error.
+    fn put_error_chunk<IO: Write>(
+        err: ErrorType,
+        req: &Request,
+        stream: &mut IO,
+    ) -> Result<ErrorType> {
+        // S: 32 bits: error (MUST be nonzero)
+        // S: 16 bits: message length (no more than header length - 6)
+        // S: message length bytes: optional string
+        let msg = format!("{:?} failed: {err}", req.typ);
+        let msg_len = (msg.len() as u16).to_be_bytes();
+        let errno = u32::from(err).to_be_bytes();
+        ChunkHeader::put(
+            stream,
+            ChunkFlags::DONE,
+            ChunkType::ERROR,
+            req.handle,
+            &[&errno, &msg_len, msg.as_bytes()],
+        )?;
+        Ok(err)
+    }
+
+    /// Check that a request that accesses data is aligned to the minimum block
+    /// size.
+    fn is_aligned(&self, req: &Request) -> bool {
+        let min = self.minimum_block_size;
+        match req.typ {
+            Cmd::READ | Cmd::WRITE | Cmd::WRITE_ZEROES | Cmd::TRIM => {
+                req.offset.is_multiple_of(min as u64) && req.len.is_multiple_of(min)
+            }
+            _ => true,
+        }
+    }
+
+    /// Handle one request, whose data (for a write) is in `buf`, sending the
+    /// reply to `stream`.
+    ///
+    /// Returns false if the connection should be closed. The request is
+    /// well-framed, so any problem with it (including backend errors) is
+    /// reported in the reply and the connection stays open; errors are only
+    /// returned for failures sending the reply.
+    fn handle_request<IO: Write>(
+        &self,
+        session: &Session<F>,
+        req: &Request,
+        buf: &mut [u8],
+        stream: &mut IO,
+    ) -> Result<bool> {
+        let Some(observer) = &self.observer else {
+            return Ok(self.reply(session, req, buf, stream)?.is_some());
+        };
+        let info = RequestInfo::new(req);
+        observer.on_request(&info);
+        let start = Instant::now();
+        let err = self.reply(session, req, buf, stream)?;
+        if let Some(err) = err {
+            observer.on_reply(&info, err, start.elapsed());
+        }
+        Ok(err.is_some())
+    }
+
+    /// Handle one request and send its reply, returning the error it was
+    /// sent with (None for a disconnect, which gets no reply).
+    fn reply<IO: Write>(
+        &self,
+        session: &Session<F>,
+        req: &Request,
+        buf: &mut [u8],
+        stream: &mut IO,
+    ) -> Result<Option<ErrorType>> {
+        let export = &*session.export;
+        // only FUA and NO_HOLE are supported, and REQ_ONE for block status
+        let mut supported = CmdFlags::FUA | CmdFlags::NO_HOLE;
+        if req.typ == Cmd::BLOCK_STATUS {
+            supported |= CmdFlags::REQ_ONE;
+        }
+        if !supported.contains(req.flags) {
+            warn!(target: "nbd", "unexpected flags {:?}", req.flags);
+            return Self::put_simple_reply(ErrorType::ENOTSUP, req, stream).map(Some);
+        }
+        let writes_stopped = self.write_trigger.as_ref().is_some_and(|t| t.stopped());
+        if (session.read_only || writes_stopped)
+            && matches!(
+                req.typ,
+                Cmd::WRITE | Cmd::WRITE_ZEROES | Cmd::TRIM | Cmd::RESIZE
+            )
+        {
+            warn!(target: "nbd", "{:?} on a read-only connection", req.typ);
+            return Self::put_simple_reply(ErrorType::EPERM, req, stream).map(Some);
+        }
+        if !self.is_aligned(req) {
+            warn!(target: "nbd", "unaligned request {:?}", req);
+            // reads must get a structured reply
+            return if req.typ == Cmd::READ && session.structured_replies {
+                Self::put_error_chunk(ErrorType::EINVAL, req, stream).map(Some)
+            } else {
+                Self::put_simple_reply(ErrorType::EINVAL, req, stream).map(Some)
+            };
+        }
+        #[cfg(any(test, feature = "testutil"))]
+        if let Some(err) = self.chaos.inject(req.typ) {
+            return if req.typ == Cmd::READ && session.structured_replies {
+                Self::put_error_chunk(err, req, stream).map(Some)
+            } else {
+                Self::put_simple_reply(err, req, stream).map(Some)
+            };
+        }
+        let err = match req.typ {
+            Cmd::READ if session.structured_replies => {
+                match export.read_extents(req.offset, req.len, buf) {
+                    Ok((data, extents)) => {
+                        Self::put_read_chunks(req, data, &extents, stream)?;
+                        ErrorType::OK
+                    }
                     Err(err) => {
                         warn!(target: "nbd", "read error {:?}", err);
-                        SimpleReply::err(err, &req).put(stream)?;
+                        Self::put_error_chunk(err, req, stream)?
                     }
-                },
-                Cmd::WRITE => match export.write(req.offset, req.data_len, &buf) {
-                    Ok(_) => {
-                        if req.flags.contains(CmdFlags::FUA) {
-                            export.flush()?;
-                        }
-                        SimpleReply::ok(&req).put(stream)?;
+                }
+            }
+            Cmd::READ => match export.read(req.offset, req.len, buf) {
+                Ok(data) => {
+                    SimpleReply::data(req, data).put(stream)?;
+                    ErrorType::OK
+                }
+                Err(err) => {
+                    warn!(target: "nbd", "read error {:?}", err);
+                    Self::put_simple_reply(err, req, stream)?
+                }
+            },
+            Cmd::WRITE if req.data_len < req.len as usize => {
+                // Request::get discarded the data
+                warn!(target: "nbd", "write of {} bytes is too large", req.len);
+                Self::put_simple_reply(ErrorType::EOVERFLOW, req, stream)?
+            }
+            Cmd::WRITE => {
+                let r = export.write(req.offset, req.data_len, buf, self.detect_zero_writes);
+                if r.is_ok() {
+                    self.record_write(export, req);
+                }
+                match r {
+                    Ok(_) if req.flags.contains(CmdFlags::FUA) => {
+                        Self::put_flush_reply(export, req, stream)?
                     }
+                    Ok(_) => Self::put_simple_reply(ErrorType::OK, req, stream)?,
                     Err(err) => {
                         warn!(target: "nbd", "write error {:?}", err);
-                        SimpleReply::err(err, &req).put(stream)?;
+                        Self::put_simple_reply(err, req, stream)?
                     }
-                },
-                Cmd::DISCONNECT => {
-                    // don't send a reply - RFC says server can send an ACK, but
-                    // Linux client closes the connection immediately
-                    return Ok(());
                 }
-                Cmd::FLUSH => {
-                    export.flush()?;
-                    SimpleReply::ok(&req).put(stream)?;
+            }
+            Cmd::WRITE_ZEROES => {
+                let no_hole = req.flags.contains(CmdFlags::NO_HOLE);
+                let r = export.write_zeroes(req.offset, req.len, no_hole);
+                if r.is_ok() {
+                    self.record_write(export, req);
                 }
-                Cmd::TRIM => {
-                    SimpleReply::ok(&req).put(stream)?;
+                match r {
+                    Ok(_) if req.flags.contains(CmdFlags::FUA) => {
+                        Self::put_flush_reply(export, req, stream)?
+                    }
+                    Ok(_) => Self::put_simple_reply(ErrorType::OK, req, stream)?,
+                    Err(err) => {
+                        warn!(target: "nbd", "write zeroes error {:?}", err);
+                        Self::put_simple_reply(err, req, stream)?
+                    }
                 }
-                _ => {
-                    SimpleReply::err(ErrorType::ENOTSUP, &req).put(stream)?;
-                    return Ok(());
+            }
+            Cmd::CACHE => match export.cache(req.offset, req.len) {
+                Ok(_) => Self::put_simple_reply(ErrorType::OK, req, stream)?,
+                Err(err) => {
+                    warn!(target: "nbd", "cache error {:?}", err);
+                    Self::put_simple_reply(err, req, stream)?
+                }
+            },
+            Cmd::RESIZE => {
+                // the new size is sent in the offset field
+                match export.resize(req.offset) {
+                    Ok(_) => Self::put_simple_reply(ErrorType::OK, req, stream)?,
+                    Err(err) => {
+                        warn!(target: "nbd", "resize error {:?}", err);
+                        Self::put_simple_reply(err, req, stream)?
+                    }
+                }
+            }
+            Cmd::DISCONNECT => {
+                // don't send a reply - RFC says server can send an ACK, but
+                // Linux client closes the connection immediately
+                return Ok(None);
+            }
+            Cmd::FLUSH => Self::put_flush_reply(export, req, stream)?,
+            Cmd::BLOCK_STATUS => Self::put_block_status(session, req, stream)?,
+            Cmd::TRIM => match export.trim(req.offset, req.len) {
+                Ok(_) if req.flags.contains(CmdFlags::FUA) => {
+                    Self::put_flush_reply(export, req, stream)?
+                }
+                Ok(_) => Self::put_simple_reply(ErrorType::OK, req, stream)?,
+                Err(err) => {
+                    warn!(target: "nbd", "trim error {:?}", err);
+                    Self::put_simple_reply(err, req, stream)?
+                }
+            },
+        };
+        Ok(Some(err))
+    }
+
+    /// Send a simple reply without data to `req`, returning its error.
+    fn put_simple_reply<IO: Write>(
+        err: ErrorType,
+        req: &Request,
+        stream: &mut IO,
+    ) -> Result<ErrorType> {
+        SimpleReply::err(err, req).put(stream)?;
+        Ok(err)
+    }
+
+    /// Count a successful write for [`Server::when_written`].
+    fn record_write(&self, export: &Export<F>, req: &Request) {
+        if let Some(trigger) = &self.write_trigger {
+            trigger.record(export, req.offset, req.len as u64);
+        }
+    }
+
+    /// Flush the export (or just the range written, for a FUA request) and
+    /// reply to `req` with the result.
+    fn put_flush_reply<IO: Write>(
+        export: &Export<F>,
+        req: &Request,
+        stream: &mut IO,
+    ) -> Result<ErrorType> {
+        // a FUA request only needs its own range to be durable
+        let r = if req.typ == Cmd::FLUSH {
+            export.flush()
+        } else {
+            export.flush_range(req.offset, req.len as u64)
+        };
+        match r {
+            Ok(_) => Self::put_simple_reply(ErrorType::OK, req, stream),
+            Err(err) => {
+                warn!(target: "nbd", "flush error {:?}", err);
+                Self::put_simple_reply(ErrorType::from_io_error(&err), req, stream)
+            }
+        }
+    }
+
+    fn handle_ops<IO: Read + Write>(&self, session: &Session<F>, stream: &mut IO) -> Result<()> {
+        // buffering lets us look at pipelined requests that have already
+        // arrived, to coalesce reads
+        let mut stream = BufStream(BufReader::with_capacity(64 * 1024, stream));
+        let mut buf = self.request_buf();
+        let watchdog = self.slow_op_threshold.map(Watchdog::new);
+        // where the next read starts if the client is reading sequentially
+        let mut next_read = None;
+        loop {
+            assert_eq!(buf.len(), self.max_block_size as usize);
+            let req = match Request::get(&mut stream, &mut buf) {
+                Ok(req) => req,
+                Err(err) => {
+                    // an unknown command has no payload, so the next request
+                    // still parses
+                    if let Some(unknown) = err.downcast_ref::<UnknownCommand>() {
+                        warn!(target: "nbd", "{unknown}");
+                        unknown.reply().put(&mut stream)?;
+                        continue;
+                    }
+                    // otherwise the stream may be out of sync, so the
+                    // connection must close
+                    return Err(err);
+                }
+            };
+            if let Some(level) = self.op_log_level {
+                log!(target: "nbd", level, "{:?}", req);
+            }
+            let mut batch = vec![req];
+            if self.coalesce_reads && !session.structured_replies {
+                self.take_adjacent_reads(&mut batch, &mut stream, buf.len())?;
+            }
+            let req = &batch[0];
+            if self.readahead && req.typ == Cmd::READ {
+                let end = batch_end(&batch);
+                if next_read == Some(req.offset) {
+                    // prefetch the next read of the same size
+                    let len = batch[batch.len() - 1].len;
+                    session.export.readahead(end, len as u64);
+                }
+                next_read = Some(end);
+            }
+            if let Some(watchdog) = &watchdog {
+                let mut desc = format!("{:?} (handle {})", req, req.handle);
+                if batch.len() > 1 {
+                    desc += &format!(" and {} coalesced reads", batch.len() - 1);
+                }
+                watchdog.start(desc);
+            }
+            let more = if batch.len() > 1 {
+                self.handle_coalesced_reads(session, &batch, &mut buf, &mut stream)?;
+                true
+            } else {
+                self.handle_request(session, req, &mut buf, &mut stream)?
+            };
+            if let Some(watchdog) = &watchdog {
+                watchdog.finish();
+            }
+            if !more {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Get a buffer of `max_block_size` bytes for handling requests,
+    /// reusing one from the pool if possible.
+    fn request_buf(&self) -> PooledBuf<'_> {
+        let buf = self.buffers.lock().unwrap().pop();
+        PooledBuf {
+            pool: &self.buffers,
+            buf: buf.unwrap_or_else(|| vec![0u8; self.max_block_size as usize]),
+        }
+    }
+
+    /// Check if a request is a read that can be merged with others.
+    fn can_coalesce(&self, req: &Request) -> bool {
+        req.typ == Cmd::READ && req.flags.is_empty() && req.len > 0 && self.is_aligned(req)
+    }
+
+    /// Extend `batch`, which holds one request, with the reads that follow it
+    /// in the input that has already arrived, as long as each starts within
+    /// the range covered so far (so the batch reads one contiguous range of at
+    /// most `max_len` bytes).
+    fn take_adjacent_reads<IO: Read>(
+        &self,
+        batch: &mut Vec<Request>,
+        stream: &mut BufStream<IO>,
+        max_len: usize,
+    ) -> Result<()> {
+        if !self.can_coalesce(&batch[0]) {
+            return Ok(());
+        }
+        let start = batch[0].offset;
+        let mut end = batch_end(batch);
+        while let Some(next) = Request::peek(stream.0.buffer()) {
+            if !self.can_coalesce(&next) || next.offset < start || next.offset > end {
+                break;
+            }
+            let Some(next_end) = next.offset.checked_add(next.len as u64) else {
+                break;
+            };
+            let next_end = next_end.max(end);
+            if next_end - start > max_len as u64 {
+                break;
+            }
+            // consume the request we peeked
+            let next = Request::get(stream, &mut [])?;
+            if let Some(level) = self.op_log_level {
+                log!(target: "nbd", level, "{:?}", next);
+            }
+            batch.push(next);
+            end = next_end;
+        }
+        Ok(())
+    }
+
+    /// Reply to a batch of reads covering one contiguous range with a single
+    /// backend read, falling back to handling them one at a time if that
+    /// fails (so each gets its own error).
+    fn handle_coalesced_reads<IO: Write>(
+        &self,
+        session: &Session<F>,
+        batch: &[Request],
+        buf: &mut [u8],
+        stream: &mut IO,
+    ) -> Result<()> {
+        let start = batch[0].offset;
+        let len = (batch_end(batch) - start) as u32;
+        debug!(target: "nbd", "coalesced {} reads into {start}+{len}", batch.len());
+        let started = Instant::now();
+        match session.export.read(start, len, buf) {
+            Ok(data) => {
+                for req in batch {
+                    let off = (req.offset - start) as usize;
+                    SimpleReply::data(req, &data[off..off + req.len as usize]).put(stream)?;
+                }
+                // each read took as long as the whole batch
+                if let Some(observer) = &self.observer {
+                    for req in batch {
+                        let info = RequestInfo::new(req);
+                        observer.on_request(&info);
+                        observer.on_reply(&info, ErrorType::OK, started.elapsed());
+                    }
+                }
+            }
+            Err(_) => {
+                for req in batch {
+                    self.handle_request(session, req, buf, stream)?;
                 }
             }
         }
+        Ok(())
+    }
+
+    /// Handle a single request (in wire format) for the default export,
+    /// without a handshake, returning the reply.
+    #[cfg(any(test, feature = "testutil"))]
+    fn serve_request(&self, mut request: &[u8]) -> Result<Vec<u8>> {
+        let mut buf = vec![0u8; self.max_block_size as usize];
+        let req = Request::get(&mut request, &mut buf)?;
+        let session = self.new_session(&self.exports[0], false);
+        let mut reply = vec![];
+        self.handle_request(&session, &req, &mut buf, &mut reply)?;
+        Ok(reply)
     }
 
     /// Handle a single client, and return on disconnect.
-    fn handle_client<IO: Read + Write>(&self, mut stream: IO) -> Result<()> {
+    fn handle_client<IO: Read + Write>(&self, stream: IO) -> Result<()> {
+        self.connections.fetch_add(1, Ordering::SeqCst);
+        let r = self.serve_client(stream);
+        self.connections.fetch_sub(1, Ordering::SeqCst);
+        r
+    }
+
+    fn serve_client<IO: Read + Write>(&self, stream: IO) -> Result<()> {
+        let r = match self.negotiate(stream)? {
+            Negotiated::Plain(session, mut stream) => self.handle_ops(&session, &mut stream),
+            Negotiated::Tls(session, mut stream) => self.handle_ops(&session, &mut stream),
+            Negotiated::Abort => return Ok(()),
+        };
+        allow_disconnect(r.wrap_err("handling client operations"))
+    }
+
+    /// Run the handshake, upgrading the connection to TLS if the client asks
+    /// to, and return the session for the export the client selected along
+    /// with the stream to serve it on.
+    fn negotiate<'s, IO: Read + Write + 's>(
+        &self,
+        mut stream: IO,
+    ) -> Result<Negotiated<'s, F, IO>> {
         let flags = Self::initial_handshake(&mut stream).wrap_err("initial handshake failed")?;
-        if let Some(export) = self
-            .handshake_haggle(&mut stream, flags)
+        let negotiated = match self
+            .handshake_haggle(&mut stream, flags, false)
             .wrap_err("handshake haggling failed")?
         {
-            info!("handshake finished with {:?}", flags);
-            let r = Self::handle_ops(export, &mut stream).wrap_err("handling client operations");
-            if let Err(err) = r {
-                // if the error is due to UnexpectedEof, then the client closed
-                // the connection, which the server should allow gracefully
-                if let Some(err) = err.root_cause().downcast_ref::<io::Error>() {
-                    if err.kind() == io::ErrorKind::UnexpectedEof {
-                        return Ok(());
-                    }
+            Haggled::Export(session) => Negotiated::Plain(session, stream),
+            Haggled::StartTls => {
+                let upgrade = self.tls_upgrade.as_ref().expect("STARTTLS without TLS");
+                let mut stream = upgrade
+                    .upgrade(Box::new(stream))
+                    .wrap_err("TLS handshake failed")?;
+                info!("TLS handshake finished");
+                match self
+                    .handshake_haggle(&mut stream, flags, true)
+                    .wrap_err("handshake haggling over TLS failed")?
+                {
+                    Haggled::Export(session) => Negotiated::Tls(session, stream),
+                    Haggled::StartTls | Haggled::Abort => Negotiated::Abort,
                 }
-                return Err(err);
+            }
+            Haggled::Abort => Negotiated::Abort,
+        };
+        if !matches!(negotiated, Negotiated::Abort) {
+            info!("handshake finished with {:?}", flags);
+        }
+        Ok(negotiated)
+    }
+}
+
+/// Treat the client closing the connection (an UnexpectedEof error) as a
+/// graceful disconnect.
+fn allow_disconnect(r: Result<()>) -> Result<()> {
+    if let Err(err) = &r {
+        if let Some(err) = err.root_cause().downcast_ref::<io::Error>() {
+            if err.kind() == io::ErrorKind::UnexpectedEof {
+                return Ok(());
             }
         }
-        Ok(())
     }
+    r
 }
 
-/// Server implements the NBD protocol, with a single export.
+/// Server implements the NBD protocol, serving one or more named exports.
+///
+/// Cloning a Server gives another handle to the same server (for example, to
+/// take snapshots while [`Server::start`] runs in another thread), after which
+/// it can no longer be configured.
 #[derive(Debug)]
 pub struct Server<F: Blocks>(Arc<ServerInner<F>>);
 
+impl<F: Blocks> Clone for Server<F> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
 impl<F: Blocks + Sync + Send + 'static> Server<F> {
-    /// Create a Server that exports blocks.
+    /// Create a Server that exports blocks, with the name "default".
     pub fn new(blocks: F) -> Self {
-        let export = Export(blocks);
-        Self(Arc::new(ServerInner { export }))
+        Self::with_export("default", blocks)
+    }
+
+    /// Create a Server with a single export named `name`.
+    ///
+    /// This export is also the default export, which clients get by
+    /// requesting the empty name.
+    pub fn with_export<S: Into<String>>(name: S, blocks: F) -> Self {
+        let export = Export::new(name.into(), blocks);
+        Self(Arc::new(ServerInner {
+            exports: vec![Arc::new(export)],
+            op_log_level: Some(Level::Info),
+            minimum_block_size: 1,
+            preferred_block_size: 4096,
+            max_block_size: ServerInner::<F>::DEFAULT_MAX_BLOCK_SIZE,
+            require_tls: false,
+            tls_upgrade: None,
+            detect_zero_writes: false,
+            slow_op_threshold: None,
+            readahead: false,
+            coalesce_reads: false,
+            single_writer: false,
+            read_only: false,
+            max_list_exports: None,
+            workers: 0,
+            write_trigger: None,
+            observer: None,
+            #[cfg(any(test, feature = "testutil"))]
+            chaos: Default::default(),
+            connections: AtomicUsize::new(0),
+            conns: Connections::default(),
+            buffers: Mutex::new(vec![]),
+        }))
+    }
+
+    fn inner_mut(&mut self) -> &mut ServerInner<F> {
+        Arc::get_mut(&mut self.0).expect("server configured after it started")
+    }
+
+    /// Add another export, which clients select by name.
+    ///
+    /// Panics if there is already an export with this name.
+    pub fn add_export<S: Into<String>>(mut self, name: S, blocks: F) -> Self {
+        let name = name.into();
+        let exports = &mut self.inner_mut().exports;
+        assert!(
+            exports.iter().all(|e| e.name != name),
+            "duplicate export {name:?}"
+        );
+        exports.push(Arc::new(Export::new(name, blocks)));
+        self
+    }
+
+    /// Set a human-readable description for the export `name`, which clients
+    /// can request with NBD_INFO_DESCRIPTION.
+    ///
+    /// Panics if there is no export with this name.
+    pub fn export_description<S: Into<String>>(mut self, name: &str, description: S) -> Self {
+        let export = self
+            .inner_mut()
+            .exports
+            .iter_mut()
+            .find(|e| e.name == name)
+            .unwrap_or_else(|| panic!("no export {name:?}"));
+        Arc::get_mut(export)
+            .expect("server configured after it started")
+            .description = Some(description.into());
+        self
+    }
+
+    /// Set the level at which each request is logged (to the `nbd` target).
+    ///
+    /// The default is [`Level::Info`]; `None` disables per-request logging
+    /// entirely while keeping connection-level logs.
+    pub fn op_log_level(mut self, level: Option<Level>) -> Self {
+        self.inner_mut().op_log_level = level;
+        self
+    }
+
+    /// Set the preferred block size advertised to clients (the default is
+    /// 4096).
+    ///
+    /// Clients that request block size information (including this crate's
+    /// client when setting up a kernel device) adopt this size.
+    ///
+    /// Panics if `size` is not a power of two between 512 and the maximum
+    /// block size (see [`Server::max_block_size`]).
+    pub fn preferred_block_size(mut self, size: u32) -> Self {
+        let inner = self.inner_mut();
+        assert!(
+            size.is_power_of_two() && (512..=inner.max_block_size).contains(&size),
+            "invalid preferred block size {size}"
+        );
+        inner.preferred_block_size = size;
+        self
+    }
+
+    /// Set the largest read or write the server accepts, which is advertised
+    /// to clients as the maximum block size (the default is 256 KiB).
+    ///
+    /// Each connection holds a request buffer of this size. Requests larger
+    /// than this fail with EOVERFLOW; their data is discarded rather than
+    /// partially written.
+    ///
+    /// Panics if `size` is not a power of two between 4 KiB and 32 MiB, or is
+    /// less than the preferred or minimum block size.
+    pub fn max_block_size(mut self, size: u32) -> Self {
+        let inner = self.inner_mut();
+        assert!(
+            size.is_power_of_two()
+                && (4096..=32 * 1024 * 1024).contains(&size)
+                && size >= inner.preferred_block_size
+                && size >= inner.minimum_block_size,
+            "invalid maximum block size {size}"
+        );
+        inner.max_block_size = size;
+        self
+    }
+
+    /// Require reads and writes to be aligned to `size`, which is advertised
+    /// to clients as the minimum block size (the default of 1 allows any
+    /// access).
+    ///
+    /// This protects backends that only support aligned access (for example,
+    /// files opened with O_DIRECT); unaligned requests fail with EINVAL.
+    ///
+    /// Panics if `size` is not a power of two of at most 64 KiB.
+    pub fn minimum_block_size(mut self, size: u32) -> Self {
+        assert!(
+            size.is_power_of_two() && size <= 64 * 1024,
+            "invalid minimum block size {size}"
+        );
+        self.inner_mut().minimum_block_size = size;
+        self
+    }
+
+    /// Detect writes whose data is all zeros and handle them as write zeroes,
+    /// which backends like [`SparseMemBlocks`] and files can implement by
+    /// leaving a hole (the default is false).
+    ///
+    /// This saves space for clients that write zeros without using
+    /// NBD_CMD_WRITE_ZEROES, at the cost of scanning each write.
+    pub fn detect_zero_writes(mut self, detect: bool) -> Self {
+        self.inner_mut().detect_zero_writes = detect;
+        self
+    }
+
+    /// Log a warning (to the `nbd` target) for any operation that has been
+    /// running for longer than `threshold`, to help diagnose stuck or slow
+    /// backends (the default of `None` disables this).
+    ///
+    /// Each connection gets a background thread that watches its operations.
+    pub fn slow_op_warning(mut self, threshold: Option<Duration>) -> Self {
+        self.inner_mut().slow_op_threshold = threshold;
+        self
+    }
+
+    /// Prefetch data for clients that read sequentially (consecutive READs
+    /// where each starts where the previous one ended), using
+    /// [`Blocks::readahead`]. Disabled by default.
+    pub fn readahead(mut self, enable: bool) -> Self {
+        self.inner_mut().readahead = enable;
+        self
+    }
+
+    /// Serve adjacent reads that a client has pipelined with a single backend
+    /// read (disabled by default).
+    ///
+    /// When a READ arrives and the requests after it have already been
+    /// received, any run of READs that each start within the range covered
+    /// so far (up to the maximum block size in total) is merged into one call to
+    /// [`Blocks::read_at`], and the result is split into a reply for each
+    /// request. The server never waits for more requests to arrive, but the
+    /// reply to the first read in a batch is only sent after the whole
+    /// merged read finishes, which adds latency to it in exchange for fewer
+    /// backend calls. Only clients using simple replies (not structured
+    /// replies) get coalesced reads.
+    pub fn coalesce_reads(mut self, enable: bool) -> Self {
+        self.inner_mut().coalesce_reads = enable;
+        self
+    }
+
+    /// Allow only one connection at a time to write to each export (disabled
+    /// by default).
+    ///
+    /// The first connection to an export gets read-write access, and
+    /// connections made while it is open get read-only access: they are
+    /// advertised NBD_FLAG_READ_ONLY, and their writes, write zeroes, trims,
+    /// and resizes fail with EPERM. Once the writer disconnects, the next new
+    /// connection becomes the writer. This suits setups with one primary
+    /// client and read-only replicas of a backend that is not coherent
+    /// across writers.
+    pub fn single_writer(mut self, enable: bool) -> Self {
+        self.inner_mut().single_writer = enable;
+        self
+    }
+
+    /// Handle up to `workers` requests from each connection at once, in a
+    /// pool of threads per connection (the default of 0 handles requests one
+    /// at a time).
+    ///
+    /// Each reply is sent as soon as its request finishes, so a slow read
+    /// does not hold up the requests after it, and replies can arrive in a
+    /// different order than the requests (which the protocol allows, since
+    /// replies are matched to requests by handle). This applies to
+    /// connections accepted by [`Server::start`], [`Server::start_on`],
+    /// [`Server::spawn_on`], and [`Server::start_unix`], but not
+    /// [`Server::serve_connection`], whose stream cannot be split into a
+    /// reader and a writer. These connections do not coalesce reads, read
+    /// ahead, or warn about slow operations.
+    pub fn workers(mut self, workers: usize) -> Self {
+        self.inner_mut().workers = workers;
+        self
+    }
+
+    /// Export everything read-only (disabled by default).
+    ///
+    /// Every connection is advertised NBD_FLAG_READ_ONLY (so the Linux kernel
+    /// makes the device read-only), and writes, write zeroes, trims, and
+    /// resizes fail with EPERM.
+    pub fn read_only(mut self, enable: bool) -> Self {
+        self.inner_mut().read_only = enable;
+        self
+    }
+
+    /// Limit how many exports are sent in reply to NBD_OPT_LIST (the default
+    /// of `None` lists them all), to keep the reply small for servers with
+    /// many exports.
+    ///
+    /// Exports past the limit are not listed, but clients can still select
+    /// them by name.
+    pub fn max_list_exports(mut self, max: Option<usize>) -> Self {
+        self.inner_mut().max_list_exports = max;
+        self
+    }
+
+    /// Require clients to set up TLS before negotiating an export (the
+    /// spec's FORCEDTLS mode).
+    ///
+    /// Until STARTTLS completes, every option other than NBD_OPT_STARTTLS and
+    /// NBD_OPT_ABORT is rejected with NBD_REP_ERR_TLS_REQD. Clients can only
+    /// get past this if TLS is set up with [`Server::starttls`].
+    pub fn require_tls(mut self, require: bool) -> Self {
+        self.inner_mut().require_tls = require;
+        self
+    }
+
+    /// Serve a client over an established connection: run the handshake,
+    /// then process commands until the client disconnects.
+    ///
+    /// The connection can be any duplex byte stream, which is how to embed
+    /// the server in a process that already has a channel to its client (a
+    /// vsock, a pty, a tunnel, or a pair of pipes). [`Server::start`] is
+    /// just a loop that accepts TCP connections and serves each one in its
+    /// own thread; to serve several connections concurrently over a custom
+    /// transport, do the same with a clone of the Server per thread. See
+    /// `examples/duplex.rs`.
+    ///
+    /// The stream should be unbuffered or flush on its own, since replies
+    /// are written without an explicit flush.
+    ///
+    /// Returns Ok(()) when client gracefully disconnects.
+    pub fn serve_connection<IO: Read + Write>(&self, stream: IO) -> Result<()> {
+        self.0.handle_client(stream)
     }
 
     /// Handshake and communicate with a client on a single connection.
     ///
+    /// This is the same as [`Server::serve_connection`].
+    ///
     /// Returns Ok(()) when client gracefully disconnects.
     pub fn handle_client<IO: Read + Write>(&self, stream: IO) -> Result<()> {
         self.0.handle_client(stream)
     }
 
-    /// Start accepting connections from clients and processing commands.
+    /// Take a snapshot of the export `name` (see [`Blocks::snapshot`]), after
+    /// flushing it.
+    ///
+    /// Fails if there is no such export or its backend does not support
+    /// snapshots.
+    pub fn snapshot(&self, name: &str) -> Result<SnapshotId> {
+        let export = self
+            .0
+            .find_export(name)
+            .ok_or_else(|| eyre!("no export {name:?}"))?;
+        let id = export
+            .snapshot()
+            .wrap_err_with(|| format!("snapshotting export {name:?}"))?;
+        info!("took snapshot {id:?} of export {name:?}");
+        Ok(id)
+    }
+
+    /// Handle a single transmission-phase request for the default export,
+    /// skipping the handshake, and return the reply.
+    ///
+    /// `request` is in wire format (including the data for a write), and the
+    /// reply is a simple reply, or empty if the command has no reply (like
+    /// NBD_CMD_DISCONNECT). This is intended for unit testing commands in
+    /// isolation.
+    ///
+    /// Only available with the `testutil` feature.
+    #[cfg(any(test, feature = "testutil"))]
+    pub fn serve_request(&self, request: &[u8]) -> Result<Vec<u8>> {
+        self.0.serve_request(request)
+    }
+
+    /// Start accepting connections from clients and processing commands, on
+    /// the standard NBD port on localhost.
     pub fn start(self) -> Result<()> {
-        let addr = ("127.0.0.1", TCP_PORT);
-        let listener = TcpListener::bind(addr)?;
+        self.start_on(("127.0.0.1", TCP_PORT))
+    }
+
+    /// Like [`Server::start`], but listen on `addr`, such as `("0.0.0.0",
+    /// 10809)` to accept connections from other machines.
+    ///
+    /// This returns once a `shutdown` command on the control socket (see
+    /// [`Server::control_socket`]) stops the server and its clients are
+    /// served, or when accepting a connection fails; use [`Server::spawn_on`]
+    /// for a server that can be shut down from the same process.
+    pub fn start_on<A: ToSocketAddrs>(self, addr: A) -> Result<()> {
+        self.spawn_on(addr)?.wait()
+    }
+
+    /// Like [`Server::start`], but serve one connection at a time in the
+    /// calling thread, without spawning a thread per connection.
+    ///
+    /// A client that connects while another is being served waits until that
+    /// client disconnects, so requests are handled strictly sequentially.
+    pub fn start_single_threaded(self) -> Result<()> {
+        self.start_single_threaded_on(("127.0.0.1", TCP_PORT))
+    }
+
+    /// Like [`Server::start_single_threaded`], but listen on `addr`.
+    pub fn start_single_threaded_on<A: ToSocketAddrs>(self, addr: A) -> Result<()> {
+        let listener = TcpListener::bind(addr).wrap_err("binding TCP listener")?;
         for stream in listener.incoming() {
             let stream = stream?;
             stream.set_nodelay(true)?;
             info!(target: "nbd", "client connected");
-            let server = self.0.clone();
-            thread::spawn(move || match server.handle_client(stream) {
+            match self.handle_client(stream) {
                 Ok(_) => info!(target: "nbd", "client disconnected"),
                 Err(err) => eprintln!("error handling client:\n{:?}", err),
-            });
+            }
         }
         Ok(())
     }
+
+    /// Like [`Server::start`], but accept connections on a Unix socket at
+    /// `path` instead of TCP.
+    ///
+    /// Fails if `path` already exists. The socket file is removed when the
+    /// server stops accepting connections, which happens on a `shutdown`
+    /// control command (as for [`Server::start_on`]) or if accepting a
+    /// connection fails; use [`Server::spawn_unix`] for a server that can be
+    /// shut down from the same process.
+    pub fn start_unix<P: AsRef<Path>>(self, path: P) -> Result<()> {
+        self.spawn_unix(path)?.wait()
+    }
+
+    /// Handle a newly connected client in a new thread, tracking it by
+    /// connection id while it is connected.
+    fn spawn_client<IO: SplitStream + CloseRead>(&self, stream: IO) -> thread::JoinHandle<()> {
+        let server = self.0.clone();
+        thread::spawn(move || {
+            let id = match server.conns.add(&stream) {
+                Ok(id) => id,
+                Err(err) => {
+                    eprintln!("error tracking client connection: {err}");
+                    return;
+                }
+            };
+            info!(target: "nbd", "client {id} connected");
+            let r = if server.workers > 0 {
+                stream
+                    .try_split()
+                    .wrap_err("splitting connection")
+                    .and_then(|writer| server.handle_split_client(stream, writer))
+            } else {
+                server.handle_client(stream)
+            };
+            server.conns.remove(id);
+            match r {
+                Ok(_) => info!(target: "nbd", "client {id} disconnected"),
+                Err(err) => eprintln!("error handling client {id}:\n{:?}", err),
+            }
+        })
+    }
+}
+
+/// Removes a file (such as a socket) when dropped.
+struct RemoveOnDrop(PathBuf);
+
+impl Drop for RemoveOnDrop {
+    fn drop(&mut self) {
+        if let Err(err) = std::fs::remove_file(&self.0) {
+            warn!(target: "nbd", "removing {}: {err}", self.0.display());
+        }
+    }
 }
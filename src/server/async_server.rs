@@ -0,0 +1,281 @@
+//! Serving clients from a tokio runtime, with a task per connection.
+
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+use color_eyre::eyre::WrapErr;
+use color_eyre::Result;
+use log::{info, log, warn};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, ToSocketAddrs};
+use tokio::task;
+
+use super::{allow_disconnect, Blocks, Haggle, Haggled, Server, ServerInner, Session};
+use crate::proto::*;
+
+/// AsyncServer serves the exports of a [`Server`] from a tokio runtime,
+/// handling each connection in a task rather than a thread, so that it scales
+/// to many (mostly idle) clients.
+///
+/// Reading options and requests and sending replies are asynchronous, while
+/// the handling of each option and request runs on tokio's blocking thread
+/// pool (see [`task::spawn_blocking`]), since [`Blocks`] operations block.
+/// Requests on a connection are handled one at a time. The Server's other
+/// settings apply as usual, except for those that are about connection
+/// threads ([`Server::workers`], [`Server::coalesce_reads`],
+/// [`Server::readahead`], and [`Server::slow_op_warning`]), which are ignored.
+/// STARTTLS is not supported.
+///
+/// Only available with the `tokio` feature.
+#[derive(Debug)]
+pub struct AsyncServer<F: Blocks>(Arc<ServerInner<F>>);
+
+impl<F: Blocks> Clone for AsyncServer<F> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+impl<F: Blocks + Sync + Send + 'static> AsyncServer<F> {
+    /// Serve the exports of `server`, with its configuration.
+    pub fn new(server: Server<F>) -> Self {
+        Self(server.0)
+    }
+
+    /// Start accepting connections from clients and processing commands, on
+    /// the standard NBD port on localhost.
+    pub async fn start(self) -> Result<()> {
+        self.start_on(("127.0.0.1", TCP_PORT)).await
+    }
+
+    /// Like [`AsyncServer::start`], but listen on `addr`.
+    pub async fn start_on<A: ToSocketAddrs>(self, addr: A) -> Result<()> {
+        let listener = TcpListener::bind(addr)
+            .await
+            .wrap_err("binding TCP listener")?;
+        self.serve(listener).await
+    }
+
+    /// Accept connections from `listener`, serving each one in its own task.
+    ///
+    /// This never returns unless accepting a connection fails.
+    pub async fn serve(self, listener: TcpListener) -> Result<()> {
+        loop {
+            let (stream, _) = listener.accept().await?;
+            stream.set_nodelay(true)?;
+            info!(target: "nbd", "client connected");
+            let server = self.clone();
+            tokio::spawn(async move {
+                match server.handle_client(stream).await {
+                    Ok(_) => info!(target: "nbd", "client disconnected"),
+                    Err(err) => eprintln!("error handling client:\n{:?}", err),
+                }
+            });
+        }
+    }
+
+    /// Handshake and communicate with a client on a single connection.
+    ///
+    /// Returns Ok(()) when client gracefully disconnects.
+    pub async fn handle_client<IO: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        stream: IO,
+    ) -> Result<()> {
+        self.0.connections.fetch_add(1, Ordering::SeqCst);
+        let r = self.serve_client(stream).await;
+        self.0.connections.fetch_sub(1, Ordering::SeqCst);
+        r
+    }
+
+    async fn serve_client<IO: AsyncRead + AsyncWrite + Unpin>(&self, mut stream: IO) -> Result<()> {
+        let Some(session) = self.negotiate(&mut stream).await? else {
+            return Ok(());
+        };
+        let r = self.handle_ops(session, &mut stream).await;
+        allow_disconnect(r.wrap_err("handling client operations"))
+    }
+
+    /// Run the handshake, returning the session for the export the client
+    /// selected (or None if it ended the handshake).
+    async fn negotiate<IO: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        stream: &mut IO,
+    ) -> Result<Option<Session<F>>> {
+        let flags = async {
+            let mut greeting = vec![];
+            ServerInner::<F>::put_greeting(&mut greeting)?;
+            stream.write_all(&greeting).await?;
+            stream.flush().await?;
+            ServerInner::<F>::handshake_flags(stream.read_u32().await?)
+        }
+        .await
+        .wrap_err("initial handshake failed")?;
+        let mut haggle = Haggle {
+            flags,
+            tls_active: false,
+            starttls: false,
+            structured_replies: false,
+            allocation: None,
+        };
+        let haggled = loop {
+            let r = self.haggle_option(haggle, stream).await;
+            let (next, haggled) = r.wrap_err("handshake haggling failed")?;
+            if let Some(haggled) = haggled {
+                break haggled;
+            }
+            haggle = next;
+        };
+        match haggled {
+            Haggled::Export(session) => {
+                info!("handshake finished with {:?}", flags);
+                Ok(Some(session))
+            }
+            Haggled::StartTls => unreachable!("STARTTLS accepted without TLS"),
+            Haggled::Abort => Ok(None),
+        }
+    }
+
+    /// Read the next option and reply to it, returning the state of the
+    /// handshake afterward and how it ends, if this option ends it.
+    async fn haggle_option<IO: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        mut haggle: Haggle<F>,
+        stream: &mut IO,
+    ) -> Result<(Haggle<F>, Option<Haggled<F>>)> {
+        let mut reply = vec![];
+        let opt = match Opt::get_async(stream).await {
+            Ok(opt) => opt,
+            Err(err) => {
+                ServerInner::<F>::reject_option(err, &mut reply)?;
+                stream.write_all(&reply).await?;
+                stream.flush().await?;
+                return Ok((haggle, None));
+            }
+        };
+        let inner = self.0.clone();
+        let (haggle, reply, r) = task::spawn_blocking(move || {
+            let r = inner.haggle_option(&mut haggle, opt, &mut reply);
+            (haggle, reply, r)
+        })
+        .await?;
+        stream.write_all(&reply).await?;
+        stream.flush().await?;
+        Ok((haggle, r?))
+    }
+
+    /// Handle requests one at a time until the client disconnects.
+    async fn handle_ops<IO: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        session: Session<F>,
+        stream: &mut IO,
+    ) -> Result<()> {
+        let session = Arc::new(session);
+        let mut buf = vec![0u8; self.0.max_block_size as usize];
+        loop {
+            let req = match Request::get_async(stream, &mut buf).await {
+                Ok(req) => req,
+                Err(err) => {
+                    // an unknown command has no payload, so the next request
+                    // still parses
+                    if let Some(unknown) = err.downcast_ref::<UnknownCommand>() {
+                        warn!(target: "nbd", "{unknown}");
+                        let mut reply = vec![];
+                        unknown.reply().put(&mut reply)?;
+                        stream.write_all(&reply).await?;
+                        continue;
+                    }
+                    return Err(err);
+                }
+            };
+            if let Some(level) = self.0.op_log_level {
+                log!(target: "nbd", level, "{:?}", req);
+            }
+            let inner = self.0.clone();
+            let session = session.clone();
+            let (more, reply, request_buf) = task::spawn_blocking(move || {
+                let mut reply = vec![];
+                let more = inner.handle_request(&session, &req, &mut buf, &mut reply);
+                (more, reply, buf)
+            })
+            .await?;
+            buf = request_buf;
+            stream.write_all(&reply).await?;
+            stream.flush().await?;
+            if !more? {
+                return Ok(());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpStream;
+    use std::sync::Arc;
+
+    use color_eyre::Result;
+    use tokio::net::TcpListener;
+    use tokio::task;
+
+    use super::AsyncServer;
+    use crate::client::{Client, ClientOptions};
+    use crate::server::{Blocks, MemBlocks, Server};
+
+    #[tokio::test]
+    async fn test_async_server() -> Result<()> {
+        let blocks = Arc::new(MemBlocks::new(vec![0u8; 1 << 20]));
+        let server = AsyncServer::new(Server::new(blocks.clone()).op_log_level(None));
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let serving = tokio::spawn(server.clone().serve(listener));
+
+        // clients on their own threads, so they use the blocking client
+        let clients: Vec<_> = (0..8u8)
+            .map(|i| {
+                task::spawn_blocking(move || -> Result<()> {
+                    let mut client = Client::new(TcpStream::connect(addr)?)?;
+                    assert_eq!(client.size(), 1 << 20);
+                    let off = i as u64 * 4096;
+                    client.write(off, &[i + 1; 4096])?;
+                    assert_eq!(client.read(off, 4096)?, [i + 1; 4096]);
+                    client.flush()?;
+                    client.disconnect()?;
+                    Ok(())
+                })
+            })
+            .collect();
+        for client in clients {
+            client.await??;
+        }
+        let mut buf = [0u8; 1];
+        blocks.read_at(&mut buf, 7 * 4096)?;
+        assert_eq!(buf, [8]);
+
+        // a connection handled directly, over a Unix socket
+        let (client_end, server_end) = tokio::net::UnixStream::pair()?;
+        let handling = tokio::spawn(async move { server.handle_client(server_end).await });
+        let client_end = client_end.into_std()?;
+        client_end.set_nonblocking(false)?;
+        task::spawn_blocking(move || -> Result<()> {
+            // options negotiated in earlier steps of the handshake carry over
+            let opts = ClientOptions {
+                structured_replies: true,
+                block_status: true,
+                ..Default::default()
+            };
+            let mut client = Client::with_options(client_end, opts)?;
+            assert!(client.capabilities().block_status);
+            assert!(!client.block_status(0, 4096)?[0].is_hole());
+            assert_eq!(client.read(4096, 2)?, [2, 2]);
+            // out of bounds, but the connection stays usable
+            assert!(client.read(1 << 20, 1).is_err());
+            assert_eq!(client.read(0, 1)?, [1]);
+            client.disconnect()?;
+            Ok(())
+        })
+        .await??;
+        handling.await??;
+        serving.abort();
+        Ok(())
+    }
+}
@@ -0,0 +1,163 @@
+//! Simulate a disk with bad sectors, for testing how clients handle media
+//! errors.
+
+use std::io;
+use std::ops::Range;
+
+use log::debug;
+use nix::errno::Errno;
+
+use super::{Blocks, Extent, SnapshotId};
+
+/// The sector size used to describe bad ranges.
+pub const SECTOR_SIZE: u64 = 512;
+
+/// BadSectorBlocks wraps another Blocks and fails accesses to some sectors
+/// with EIO, like a failing disk.
+///
+/// Reads fail if they touch any sector marked bad for reads, and writes
+/// (including write zeroes) fail if they touch any sector marked bad for
+/// writes. Other accesses go to the inner Blocks unchanged.
+///
+/// Only available with the `testutil` feature.
+#[derive(Debug)]
+pub struct BadSectorBlocks<F> {
+    inner: F,
+    bad_reads: Vec<Range<u64>>,
+    bad_writes: Vec<Range<u64>>,
+}
+
+impl<F: Blocks> BadSectorBlocks<F> {
+    /// Wrap `inner`, with no bad sectors.
+    pub fn new(inner: F) -> Self {
+        Self {
+            inner,
+            bad_reads: vec![],
+            bad_writes: vec![],
+        }
+    }
+
+    /// Fail reads of the sectors in `sectors` (in units of [`SECTOR_SIZE`]).
+    pub fn bad_reads(mut self, sectors: Range<u64>) -> Self {
+        self.bad_reads.push(sectors);
+        self
+    }
+
+    /// Fail writes to the sectors in `sectors` (in units of [`SECTOR_SIZE`]).
+    pub fn bad_writes(mut self, sectors: Range<u64>) -> Self {
+        self.bad_writes.push(sectors);
+        self
+    }
+
+    /// Fail both reads and writes of the sectors in `sectors`.
+    pub fn bad_sectors(self, sectors: Range<u64>) -> Self {
+        self.bad_reads(sectors.clone()).bad_writes(sectors)
+    }
+
+    /// Fail with EIO if `[off, off+len)` touches any sector in `bad`.
+    fn check(bad: &[Range<u64>], off: u64, len: u64) -> io::Result<()> {
+        if len == 0 {
+            return Ok(());
+        }
+        let first = off / SECTOR_SIZE;
+        let end = off.saturating_add(len).div_ceil(SECTOR_SIZE);
+        if bad.iter().any(|r| r.start < end && first < r.end) {
+            debug!(target: "nbd", "simulated bad sector in {off}+{len}");
+            return Err(Errno::EIO.into());
+        }
+        Ok(())
+    }
+}
+
+impl<F: Blocks> Blocks for BadSectorBlocks<F> {
+    fn read_at(&self, buf: &mut [u8], off: u64) -> io::Result<()> {
+        Self::check(&self.bad_reads, off, buf.len() as u64)?;
+        self.inner.read_at(buf, off)
+    }
+
+    fn write_at(&self, buf: &[u8], off: u64) -> io::Result<()> {
+        Self::check(&self.bad_writes, off, buf.len() as u64)?;
+        self.inner.write_at(buf, off)
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        self.inner.size()
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        self.inner.flush()
+    }
+
+    fn flush_range(&self, off: u64, len: u64) -> io::Result<()> {
+        self.inner.flush_range(off, len)
+    }
+
+    fn supports_multi_conn(&self) -> bool {
+        self.inner.supports_multi_conn()
+    }
+
+    fn write_zeroes(&self, off: u64, len: u64, no_hole: bool) -> io::Result<()> {
+        Self::check(&self.bad_writes, off, len)?;
+        self.inner.write_zeroes(off, len, no_hole)
+    }
+
+    fn trim(&self, off: u64, len: u64) -> io::Result<()> {
+        self.inner.trim(off, len)
+    }
+
+    fn supports_trim(&self) -> bool {
+        self.inner.supports_trim()
+    }
+
+    fn resize(&self, size: u64) -> io::Result<()> {
+        self.inner.resize(size)
+    }
+
+    fn supports_resize(&self) -> bool {
+        self.inner.supports_resize()
+    }
+
+    fn extent_status(&self, off: u64, len: u64) -> io::Result<Vec<Extent>> {
+        self.inner.extent_status(off, len)
+    }
+
+    fn readahead(&self, off: u64, len: u64) -> io::Result<()> {
+        self.inner.readahead(off, len)
+    }
+
+    fn cache(&self, off: u64, len: u64) -> io::Result<()> {
+        self.inner.cache(off, len)
+    }
+
+    fn snapshot(&self) -> io::Result<SnapshotId> {
+        self.inner.snapshot()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use color_eyre::Result;
+
+    use super::*;
+    use crate::server::MemBlocks;
+
+    #[test]
+    fn test_bad_sectors() -> Result<()> {
+        let blocks = BadSectorBlocks::new(MemBlocks::new(vec![0u8; 4096]))
+            .bad_reads(2..3)
+            .bad_writes(4..6);
+        let mut buf = [0u8; 10];
+        // the last bytes before and first bytes after the bad read sector
+        blocks.read_at(&mut buf, 2 * SECTOR_SIZE - 10)?;
+        blocks.read_at(&mut buf, 3 * SECTOR_SIZE)?;
+        let err = blocks.read_at(&mut buf, 2 * SECTOR_SIZE - 5).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(Errno::EIO as i32));
+
+        // writes to a sector that is only bad for reads succeed
+        blocks.write_at(&[1; 10], 2 * SECTOR_SIZE)?;
+        assert!(blocks.write_at(&[1; 10], 6 * SECTOR_SIZE - 1).is_err());
+        assert!(blocks.write_zeroes(0, 8 * SECTOR_SIZE, false).is_err());
+        blocks.read_at(&mut buf, 4 * SECTOR_SIZE)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,169 @@
+//! Inject errors into random commands, for testing how clients handle and
+//! retry failures.
+
+use std::sync::Mutex;
+
+use log::debug;
+use nix::errno::Errno;
+
+use super::{Blocks, Server};
+use crate::proto::{Cmd, ErrorType};
+
+/// A kind of command that can be made to fail with
+/// [`Server::inject_errors`].
+///
+/// Only available with the `testutil` feature.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChaosCommand {
+    /// NBD_CMD_READ
+    Read,
+    /// NBD_CMD_WRITE
+    Write,
+    /// NBD_CMD_FLUSH
+    Flush,
+    /// NBD_CMD_TRIM
+    Trim,
+    /// NBD_CMD_WRITE_ZEROES
+    WriteZeroes,
+    /// NBD_CMD_RESIZE
+    Resize,
+}
+
+impl ChaosCommand {
+    fn matches(self, cmd: Cmd) -> bool {
+        let target = match self {
+            Self::Read => Cmd::READ,
+            Self::Write => Cmd::WRITE,
+            Self::Flush => Cmd::FLUSH,
+            Self::Trim => Cmd::TRIM,
+            Self::WriteZeroes => Cmd::WRITE_ZEROES,
+            Self::Resize => Cmd::RESIZE,
+        };
+        cmd == target
+    }
+}
+
+/// Errors to inject, and the random state that decides when.
+#[derive(Debug)]
+pub(super) struct Chaos {
+    rules: Vec<(ChaosCommand, f64, Errno)>,
+    /// splitmix64 state, shared by all connections
+    rng: Mutex<u64>,
+}
+
+impl Default for Chaos {
+    fn default() -> Self {
+        Self {
+            rules: vec![],
+            rng: Mutex::new(0),
+        }
+    }
+}
+
+impl Chaos {
+    /// A uniformly random number in [0, 1).
+    fn next_f64(&self) -> f64 {
+        let mut state = self.rng.lock().unwrap();
+        *state = state.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = *state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^= z >> 31;
+        (z >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /// Decide whether `cmd` should fail, and with what error.
+    pub(super) fn inject(&self, cmd: Cmd) -> Option<ErrorType> {
+        for &(target, probability, errno) in &self.rules {
+            if target.matches(cmd) && self.next_f64() < probability {
+                debug!(target: "nbd", "injecting {errno} into {cmd:?}");
+                return Some(ErrorType::from_io_error(&errno.into()));
+            }
+        }
+        None
+    }
+}
+
+impl<F: Blocks + Sync + Send + 'static> Server<F> {
+    /// Fail each `cmd` request with `errno` with the given `probability`
+    /// (between 0 and 1), before it reaches the backend.
+    ///
+    /// This is chaos testing for clients: unlike [`super::BadSectorBlocks`],
+    /// which fails fixed ranges, it fails random requests of any kind, for
+    /// example 1% of flushes with EIO and 0.1% of writes with ENOSPC. Rules
+    /// are checked in the order they were added, and the first that fires
+    /// decides the error. The errno is reported as the closest NBD error
+    /// (EIO for errnos the protocol has no value for). Use
+    /// [`Server::chaos_seed`] to choose which requests fail. Reads merged by
+    /// [`Server::coalesce_reads`] are not affected.
+    ///
+    /// Only available with the `testutil` feature.
+    pub fn inject_errors(mut self, cmd: ChaosCommand, probability: f64, errno: Errno) -> Self {
+        assert!(
+            (0.0..=1.0).contains(&probability),
+            "invalid probability {probability}"
+        );
+        self.inner_mut().chaos.rules.push((cmd, probability, errno));
+        self
+    }
+
+    /// Seed the random choices of [`Server::inject_errors`] (the default seed
+    /// is 0), so a run with the same requests fails the same ones.
+    ///
+    /// Only available with the `testutil` feature.
+    pub fn chaos_seed(mut self, seed: u64) -> Self {
+        self.inner_mut().chaos.rng = Mutex::new(seed);
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use color_eyre::Result;
+    use nix::errno::Errno;
+
+    use super::ChaosCommand;
+    use crate::proto::*;
+    use crate::server::{MemBlocks, Server};
+
+    /// The errors of the replies to `n` flushes and then `n` writes.
+    fn run(server: &Server<MemBlocks>, n: usize) -> Result<Vec<ErrorType>> {
+        let mut errs = vec![];
+        for (i, typ) in [(0, Cmd::FLUSH), (n, Cmd::WRITE)] {
+            for handle in i..i + n {
+                let len = if typ == Cmd::WRITE { 1 } else { 0 };
+                let mut request = vec![];
+                Request::with_handle(handle as u64, typ, 0, len).put(&[1], &mut request)?;
+                let reply = server.serve_request(&request)?;
+                errs.push(SimpleReply::get(&mut &reply[..], &mut [])?.err);
+            }
+        }
+        Ok(errs)
+    }
+
+    #[test]
+    fn test_inject_errors() -> Result<()> {
+        let new_server = |seed| {
+            Server::new(MemBlocks::new(vec![0u8; 1024]))
+                .op_log_level(None)
+                .inject_errors(ChaosCommand::Flush, 0.1, Errno::EIO)
+                .inject_errors(ChaosCommand::Write, 0.01, Errno::ENOSPC)
+                .chaos_seed(seed)
+        };
+        const N: usize = 2000;
+        let errs = run(&new_server(1), N)?;
+        let count = |errs: &[ErrorType], err| errs.iter().filter(|&&e| e == err).count();
+        let (flushes, writes) = errs.split_at(N);
+        let failed_flushes = count(flushes, ErrorType::EIO);
+        assert_eq!(failed_flushes + count(flushes, ErrorType::OK), N);
+        assert!((140..=260).contains(&failed_flushes), "{failed_flushes}");
+        let failed_writes = count(writes, ErrorType::ENOSPC);
+        assert_eq!(failed_writes + count(writes, ErrorType::OK), N);
+        assert!((5..=40).contains(&failed_writes), "{failed_writes}");
+
+        // the seed decides which requests fail
+        assert_eq!(run(&new_server(1), N)?, errs);
+        assert_ne!(run(&new_server(2), N)?, errs);
+        Ok(())
+    }
+}
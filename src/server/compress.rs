@@ -0,0 +1,382 @@
+//! Transparent compression of an export, stored block by block in another
+//! Blocks.
+
+use std::fmt;
+use std::io;
+use std::sync::Mutex;
+
+use byteorder::{ByteOrder, ReadBytesExt, WriteBytesExt, BE};
+
+use super::{check_bounds, Blocks, Extent};
+
+/// Identifies the header of a compressed image.
+const MAGIC: &[u8; 8] = b"NBDCOMP1";
+
+/// Size of the header: magic, export size (64 bits), block size (32 bits).
+const HEADER_LEN: u64 = 20;
+
+/// A compression algorithm for [`CompressedBlocks`].
+///
+/// Implement this to use a codec other than [`Deflate`], such as zstd. An
+/// image has to be reopened with the same codec it was written with.
+pub trait Codec: fmt::Debug + Send + Sync {
+    /// Compress `data`.
+    fn compress(&self, data: &[u8]) -> Vec<u8>;
+
+    /// Decompress `data`, which must produce exactly `out.len()` bytes.
+    fn decompress(&self, data: &[u8], out: &mut [u8]) -> io::Result<()>;
+}
+
+/// Raw DEFLATE compression (as in gzip and zlib), with a level from 0 (no
+/// compression) to 10 (best).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Deflate {
+    /// Compression level.
+    pub level: u8,
+}
+
+impl Default for Deflate {
+    fn default() -> Self {
+        Self { level: 6 }
+    }
+}
+
+impl Codec for Deflate {
+    fn compress(&self, data: &[u8]) -> Vec<u8> {
+        miniz_oxide::deflate::compress_to_vec(data, self.level)
+    }
+
+    fn decompress(&self, data: &[u8], out: &mut [u8]) -> io::Result<()> {
+        let data = miniz_oxide::inflate::decompress_to_vec_with_limit(data, out.len())
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, format!("{err:?}")))?;
+        if data.len() != out.len() {
+            return Err(invalid_data("decompressed block has the wrong size"));
+        }
+        out.copy_from_slice(&data);
+        Ok(())
+    }
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// CompressedBlocks exports an image stored compressed in another Blocks
+/// (typically a sparse file), so that it takes up less space than its
+/// logical size.
+///
+/// The export is split into blocks of a fixed size, each compressed on its
+/// own into a slot of the same size in the backing Blocks, with an index of
+/// each block's compressed length at the start. Only the compressed bytes of
+/// each slot are written, so the rest stays a hole in a sparse file. Blocks
+/// that have never been written (or are all zeros) take no space at all, and
+/// blocks that do not compress are stored as is.
+///
+/// Writes that cover part of a block read, decompress, and recompress the
+/// whole block, so a block size that matches clients' typical writes (such
+/// as 64KiB) works best.
+///
+/// Only available with the `compress` feature.
+pub struct CompressedBlocks<B, C = Deflate> {
+    inner: B,
+    codec: C,
+    size: u64,
+    block_size: u64,
+    /// Where the slot for block 0 starts in `inner`.
+    data_start: u64,
+    /// Compressed length of each block (0 for all zeros). The lock also
+    /// serializes all operations, so a block's data and length are updated
+    /// together.
+    lens: Mutex<Vec<u32>>,
+}
+
+impl<B: fmt::Debug, C: fmt::Debug> fmt::Debug for CompressedBlocks<B, C> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("CompressedBlocks")
+            .field("inner", &self.inner)
+            .field("codec", &self.codec)
+            .field("size", &self.size)
+            .field("block_size", &self.block_size)
+            .finish()
+    }
+}
+
+/// The offset of the first slot, after the header and index.
+fn data_start(size: u64, block_size: u64) -> u64 {
+    let index_len = size.div_ceil(block_size) * 4;
+    (HEADER_LEN + index_len).next_multiple_of(4096)
+}
+
+impl<B: Blocks, C: Codec> CompressedBlocks<B, C> {
+    /// The size `inner` needs to be to hold an export of `size` bytes in
+    /// blocks of `block_size` bytes (if it cannot grow on its own, as files
+    /// do).
+    pub fn backing_size(size: u64, block_size: u64) -> u64 {
+        data_start(size, block_size) + size.div_ceil(block_size) * block_size
+    }
+
+    /// Create a new, all-zero export of `size` bytes in `inner`, compressing
+    /// each `block_size` bytes with `codec`.
+    ///
+    /// `inner` should be empty (or all zeros): only the header is written.
+    pub fn create(inner: B, size: u64, block_size: u32, codec: C) -> io::Result<Self> {
+        if block_size == 0 || !block_size.is_multiple_of(512) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "block size must be a nonzero multiple of 512",
+            ));
+        }
+        let mut header = vec![];
+        header.extend_from_slice(MAGIC);
+        header.write_u64::<BE>(size)?;
+        header.write_u32::<BE>(block_size)?;
+        inner.write_at(&header, 0)?;
+        let block_size = block_size as u64;
+        let num_blocks = size.div_ceil(block_size) as usize;
+        Ok(Self {
+            inner,
+            codec,
+            size,
+            block_size,
+            data_start: data_start(size, block_size),
+            lens: Mutex::new(vec![0; num_blocks]),
+        })
+    }
+
+    /// Open an export previously set up with [`CompressedBlocks::create`],
+    /// which must have used the same codec.
+    pub fn open(inner: B, codec: C) -> io::Result<Self> {
+        let mut header = [0u8; HEADER_LEN as usize];
+        inner.read_at(&mut header, 0)?;
+        if &header[..8] != MAGIC {
+            return Err(invalid_data("not a compressed image"));
+        }
+        let mut fields = &header[8..];
+        let size = fields.read_u64::<BE>()?;
+        let block_size = fields.read_u32::<BE>()? as u64;
+        if block_size == 0 {
+            return Err(invalid_data("invalid block size 0"));
+        }
+        let num_blocks = size.div_ceil(block_size) as usize;
+        let mut index = vec![0u8; num_blocks * 4];
+        inner.read_at(&mut index, HEADER_LEN)?;
+        let lens: Vec<u32> = index.chunks(4).map(BE::read_u32).collect();
+        if lens.iter().any(|&len| len as u64 > block_size) {
+            return Err(invalid_data("compressed block is larger than a block"));
+        }
+        Ok(Self {
+            inner,
+            codec,
+            size,
+            block_size,
+            data_start: data_start(size, block_size),
+            lens: Mutex::new(lens),
+        })
+    }
+
+    /// The length of block `block` (only the last block may be short).
+    fn block_len(&self, block: u64) -> usize {
+        (self.size - block * self.block_size).min(self.block_size) as usize
+    }
+
+    /// Read and decompress block `block`, whose compressed length is `len`,
+    /// into `out`.
+    fn load(&self, block: u64, len: u32, out: &mut [u8]) -> io::Result<()> {
+        if len == 0 {
+            out.fill(0);
+            return Ok(());
+        }
+        let mut data = vec![0u8; len as usize];
+        self.inner
+            .read_at(&mut data, self.data_start + block * self.block_size)?;
+        if len as usize == out.len() {
+            // stored uncompressed
+            out.copy_from_slice(&data);
+            return Ok(());
+        }
+        self.codec.decompress(&data, out)
+    }
+
+    /// Compress and store `data` as block `block`, updating its length in
+    /// `lens`.
+    fn store(&self, lens: &mut [u32], block: u64, data: &[u8]) -> io::Result<()> {
+        let slot = self.data_start + block * self.block_size;
+        let len = if data.iter().all(|&b| b == 0) {
+            self.inner.trim(slot, self.block_size)?;
+            0
+        } else {
+            let compressed = self.codec.compress(data);
+            // a block that does not compress is stored as is, which is how
+            // load tells them apart
+            let stored = if compressed.len() < data.len() {
+                &compressed[..]
+            } else {
+                data
+            };
+            self.inner.write_at(stored, slot)?;
+            stored.len() as u32
+        };
+        if lens[block as usize] != len {
+            self.inner
+                .write_at(&len.to_be_bytes(), HEADER_LEN + block * 4)?;
+            lens[block as usize] = len;
+        }
+        Ok(())
+    }
+}
+
+impl<B: Blocks, C: Codec> Blocks for CompressedBlocks<B, C> {
+    fn read_at(&self, buf: &mut [u8], off: u64) -> io::Result<()> {
+        check_bounds(self.size, off, buf.len() as u64)?;
+        let lens = self.lens.lock().unwrap();
+        let mut block_buf = vec![0u8; self.block_size as usize];
+        let mut pos = 0;
+        while pos < buf.len() {
+            let start = off + pos as u64;
+            let block = start / self.block_size;
+            let block_off = (start % self.block_size) as usize;
+            let block_data = &mut block_buf[..self.block_len(block)];
+            self.load(block, lens[block as usize], block_data)?;
+            let n = (block_data.len() - block_off).min(buf.len() - pos);
+            buf[pos..pos + n].copy_from_slice(&block_data[block_off..block_off + n]);
+            pos += n;
+        }
+        Ok(())
+    }
+
+    fn write_at(&self, buf: &[u8], off: u64) -> io::Result<()> {
+        check_bounds(self.size, off, buf.len() as u64)?;
+        let mut lens = self.lens.lock().unwrap();
+        let mut block_buf = vec![0u8; self.block_size as usize];
+        let mut pos = 0;
+        while pos < buf.len() {
+            let start = off + pos as u64;
+            let block = start / self.block_size;
+            let block_off = (start % self.block_size) as usize;
+            let block_len = self.block_len(block);
+            let n = (block_len - block_off).min(buf.len() - pos);
+            if n == block_len {
+                self.store(&mut lens, block, &buf[pos..pos + n])?;
+            } else {
+                // read-modify-write a partial block
+                let block_data = &mut block_buf[..block_len];
+                self.load(block, lens[block as usize], block_data)?;
+                block_data[block_off..block_off + n].copy_from_slice(&buf[pos..pos + n]);
+                self.store(&mut lens, block, block_data)?;
+            }
+            pos += n;
+        }
+        Ok(())
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.size)
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        self.inner.flush()
+    }
+
+    fn supports_multi_conn(&self) -> bool {
+        self.inner.supports_multi_conn()
+    }
+
+    fn extent_status(&self, off: u64, len: u64) -> io::Result<Vec<Extent>> {
+        check_bounds(self.size, off, len)?;
+        let lens = self.lens.lock().unwrap();
+        let mut extents: Vec<Extent> = vec![];
+        let end = off + len;
+        let mut start = off;
+        while start < end {
+            let block = start / self.block_size;
+            let block_end = ((block + 1) * self.block_size).min(end);
+            let zero = lens[block as usize] == 0;
+            match extents.last_mut() {
+                Some(last) if last.zero == zero => last.len += block_end - start,
+                _ => extents.push(Extent {
+                    len: block_end - start,
+                    hole: zero,
+                    zero,
+                }),
+            }
+            start = block_end;
+        }
+        Ok(extents)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use color_eyre::Result;
+
+    use super::*;
+    use crate::server::{MemBlocks, SparseMemBlocks};
+
+    const SIZE: u64 = 10 * 4096 + 100;
+
+    #[test]
+    fn test_compressed_round_trip() -> Result<()> {
+        let backing = SparseMemBlocks::new(CompressedBlocks::<SparseMemBlocks>::backing_size(
+            SIZE, 4096,
+        ));
+        let blocks = CompressedBlocks::create(backing, SIZE, 4096, Deflate::default())?;
+        let mut expected = vec![0u8; SIZE as usize];
+
+        // compressible text across a block boundary, incompressible data
+        // within a block, and a write into the short last block
+        let text: Vec<u8> = b"hello, compressed world! ".repeat(400);
+        let noise: Vec<u8> = (0..3000u32)
+            .map(|i| (i.wrapping_mul(2654435761) >> 24) as u8)
+            .collect();
+        for (off, data) in [
+            (1000, &text[..]),
+            (5 * 4096 + 7, &noise[..]),
+            (SIZE - 50, &[9u8; 50][..]),
+        ] {
+            blocks.write_at(data, off)?;
+            expected[off as usize..off as usize + data.len()].copy_from_slice(data);
+        }
+        let mut buf = vec![0u8; SIZE as usize];
+        blocks.read_at(&mut buf, 0)?;
+        assert!(buf == expected, "read does not match writes");
+
+        // the text compresses, and untouched blocks take no space
+        let lens = blocks.lens.lock().unwrap().clone();
+        assert!(
+            lens[0] > 0 && lens[0] < 1000,
+            "block 0 is {} bytes",
+            lens[0]
+        );
+        assert_eq!(lens[4], 0);
+        let extents = blocks.extent_status(0, SIZE)?;
+        assert!(extents.iter().any(|e| e.hole));
+
+        // overwriting with zeros frees the block
+        blocks.write_at(&[0u8; 3000], 5 * 4096 + 7)?;
+        assert_eq!(blocks.lens.lock().unwrap()[5], 0);
+        expected[5 * 4096 + 7..][..3000].fill(0);
+
+        // reopening sees the same data
+        let blocks = CompressedBlocks::open(blocks.inner, Deflate::default())?;
+        let mut buf = vec![0u8; SIZE as usize];
+        blocks.read_at(&mut buf, 0)?;
+        assert!(buf == expected, "reopened image does not match");
+        Ok(())
+    }
+
+    #[test]
+    fn test_compressed_bounds() -> Result<()> {
+        let backing = MemBlocks::new(vec![0u8; 100]);
+        assert!(CompressedBlocks::open(backing, Deflate::default()).is_err());
+
+        let backing = SparseMemBlocks::new(CompressedBlocks::<SparseMemBlocks>::backing_size(
+            SIZE, 4096,
+        ));
+        let blocks = CompressedBlocks::create(backing, SIZE, 4096, Deflate::default())?;
+        assert_eq!(blocks.size()?, SIZE);
+        assert!(blocks.write_at(&[1u8; 10], SIZE - 5).is_err());
+        let mut buf = [0u8; 10];
+        assert!(blocks.read_at(&mut buf, SIZE).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,206 @@
+//! A control socket for managing a running server.
+
+use std::fmt::Write as _;
+use std::io::{self, prelude::*, BufReader};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::sync::atomic::Ordering;
+use std::thread;
+
+use color_eyre::eyre::{eyre, WrapErr};
+use color_eyre::Result;
+use log::{info, warn};
+
+use super::{Blocks, Server};
+
+impl<F: Blocks + Sync + Send + 'static> Server<F> {
+    /// Listen for control commands on a Unix socket at `path`, in a
+    /// background thread. This is separate from the NBD protocol.
+    ///
+    /// Each line sent to the socket is a command, and the server answers each
+    /// with one line, starting with `ok` on success or `error` followed by a
+    /// message on failure. The commands are:
+    ///
+    /// - `status`: reply with `ok connections=N`, followed by
+    ///   `export=NAME:SIZE` for each export and `conn=ID` for each connected
+    ///   client.
+    /// - `flush NAME`: flush the export `NAME`.
+    /// - `disconnect ID`: close the connection `ID` (as listed by `status`)
+    ///   for reading, so the server finishes the requests it has received
+    ///   and then disconnects the client.
+    /// - `shutdown`: stop accepting connections and disconnect every client
+    ///   this way, so that [`Server::start`] returns (or
+    ///   [`super::ServerHandle::wait`] for a spawned server).
+    ///
+    /// Connection ids are assigned to the connections the server accepts
+    /// itself, with [`Server::start`], [`Server::start_on`],
+    /// [`Server::start_unix`], and their spawn equivalents; connections
+    /// served with [`Server::serve_connection`] are counted in
+    /// `connections`, but cannot be listed or disconnected.
+    ///
+    /// Fails if `path` already exists.
+    pub fn control_socket<P: AsRef<Path>>(&self, path: P) -> Result<thread::JoinHandle<()>> {
+        let path = path.as_ref();
+        let listener = UnixListener::bind(path)
+            .wrap_err_with(|| format!("binding control socket {}", path.display()))?;
+        let server = self.clone();
+        Ok(thread::spawn(move || {
+            for stream in listener.incoming() {
+                let server = server.clone();
+                match stream {
+                    Ok(stream) => {
+                        thread::spawn(move || {
+                            if let Err(err) = server.handle_control(stream) {
+                                warn!(target: "nbd", "control connection failed: {err}");
+                            }
+                        });
+                    }
+                    Err(err) => warn!(target: "nbd", "accepting control connection: {err}"),
+                }
+            }
+        }))
+    }
+
+    fn handle_control(&self, stream: UnixStream) -> io::Result<()> {
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut stream = stream;
+        let mut line = String::new();
+        while reader.read_line(&mut line)? > 0 {
+            let reply = match self.control_command(line.trim()) {
+                Ok(reply) => reply,
+                Err(err) => format!("error {err}"),
+            };
+            writeln!(stream, "{reply}")?;
+            line.clear();
+        }
+        Ok(())
+    }
+
+    /// Run one control command, returning the reply.
+    fn control_command(&self, line: &str) -> Result<String> {
+        info!(target: "nbd", "control command {line:?}");
+        let (cmd, arg) = line.split_once(' ').unwrap_or((line, ""));
+        let inner = &self.0;
+        match cmd {
+            "status" => {
+                let mut reply = format!(
+                    "ok connections={}",
+                    inner.connections.load(Ordering::SeqCst)
+                );
+                for export in &inner.exports {
+                    write!(reply, " export={}:{}", export.name, export.size()?)?;
+                }
+                for id in inner.conns.ids() {
+                    write!(reply, " conn={id}")?;
+                }
+                Ok(reply)
+            }
+            "flush" => {
+                let export = inner
+                    .find_export(arg)
+                    .ok_or_else(|| eyre!("no export {arg:?}"))?;
+                export.flush()?;
+                Ok("ok".to_string())
+            }
+            "disconnect" => {
+                let id = arg
+                    .parse()
+                    .map_err(|_| eyre!("invalid connection id {arg:?}"))?;
+                inner.conns.disconnect(id)?;
+                Ok("ok".to_string())
+            }
+            "shutdown" => {
+                inner.conns.shutdown();
+                Ok("ok".to_string())
+            }
+            _ => Err(eyre!("unknown command {cmd:?}")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{prelude::*, BufReader};
+    use std::os::unix::net::UnixStream;
+    use std::path::Path;
+
+    use color_eyre::Result;
+
+    use crate::client::Client;
+    use crate::server::{MemBlocks, Server};
+
+    /// Connect to the control socket at `path`, returning a function that
+    /// runs a command and returns the reply.
+    fn control(path: &Path) -> Result<impl FnMut(&str) -> Result<String>> {
+        let stream = UnixStream::connect(path)?;
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut stream = stream;
+        Ok(move |cmd: &str| -> Result<String> {
+            writeln!(stream, "{cmd}")?;
+            let mut line = String::new();
+            reader.read_line(&mut line)?;
+            Ok(line.trim_end().to_string())
+        })
+    }
+
+    fn control_path() -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("nbd-control-{}", rand::random::<u64>()))
+    }
+
+    #[test]
+    fn test_control_status() -> Result<()> {
+        let server = Server::new(MemBlocks::new(vec![0u8; 1024]))
+            .add_export("other", MemBlocks::new(vec![0u8; 10]));
+        let path = control_path();
+        server.control_socket(&path)?;
+        let mut command = control(&path)?;
+        std::fs::remove_file(&path)?;
+
+        let status = command("status")?;
+        let fields: Vec<&str> = status.split(' ').collect();
+        assert_eq!(
+            fields,
+            [
+                "ok",
+                "connections=0",
+                "export=default:1024",
+                "export=other:10"
+            ]
+        );
+        assert_eq!(command("flush other")?, "ok");
+        assert!(command("flush missing")?.starts_with("error"));
+        assert_eq!(command("bogus")?, "error unknown command \"bogus\"");
+        Ok(())
+    }
+
+    #[test]
+    fn test_control_disconnect_shutdown() -> Result<()> {
+        let server = Server::new(MemBlocks::new(vec![0u8; 1024])).op_log_level(None);
+        let path = control_path();
+        server.control_socket(&path)?;
+        let mut command = control(&path)?;
+        std::fs::remove_file(&path)?;
+        let handle = server.spawn_on("127.0.0.1:0")?;
+        let addr = handle.local_addr();
+
+        // the handshake is done, so both connections have been registered
+        let mut first = Client::new(std::net::TcpStream::connect(addr)?)?;
+        let mut second = Client::new(std::net::TcpStream::connect(addr)?)?;
+        assert_eq!(
+            command("status")?,
+            "ok connections=2 export=default:1024 conn=0 conn=1"
+        );
+
+        assert_eq!(command("disconnect 0")?, "ok");
+        assert!(first.read(0, 1).is_err());
+        assert_eq!(second.read(0, 1)?, [0]);
+        assert_eq!(command("disconnect 0")?, "error no connection 0");
+        assert!(command("disconnect x")?.starts_with("error invalid connection id"));
+
+        assert_eq!(command("shutdown")?, "ok");
+        handle.wait()?;
+        assert!(second.read(0, 1).is_err());
+        assert!(std::net::TcpStream::connect(addr).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,392 @@
+//! Encryption at rest of an export, sector by sector in another Blocks.
+
+use std::fmt;
+use std::io;
+use std::sync::Mutex;
+
+use aes::cipher::KeyInit;
+use aes::{Aes128, Aes256};
+use xts_mode::{get_tweak_default, Xts128};
+
+use super::{check_bounds, Blocks};
+
+/// The size of the sectors that are encrypted as a unit.
+pub const ENCRYPTION_SECTOR_SIZE: u64 = 512;
+
+/// EncryptedBlocks exports an image stored encrypted in another Blocks, so
+/// that the backing storage (say, a file on disk) never sees plaintext.
+///
+/// Each 512-byte sector is encrypted with AES-XTS, using the sector number
+/// as the tweak, as in dm-crypt's `aes-xts-plain64`. Reads and writes that
+/// do not cover whole sectors decrypt the surrounding sectors and re-encrypt
+/// them. Like any disk encryption, this protects confidentiality but not
+/// integrity: modified ciphertext decrypts to garbage rather than giving an
+/// error.
+///
+/// The ciphertext of an all-zero sector is not zero, so a fresh backing file
+/// reads as garbage until it is written; use [`EncryptedBlocks::format`] to
+/// zero it first.
+///
+/// Only available with the `encrypt` feature.
+pub struct EncryptedBlocks<B> {
+    inner: B,
+    xts: Xts,
+    size: u64,
+    // serializes writes, so read-modify-write of partial sectors is atomic
+    rmw: Mutex<()>,
+}
+
+impl<B: fmt::Debug> fmt::Debug for EncryptedBlocks<B> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // leave out the key
+        f.debug_struct("EncryptedBlocks")
+            .field("inner", &self.inner)
+            .field("size", &self.size)
+            .finish_non_exhaustive()
+    }
+}
+
+/// AES-XTS with a 128- or 256-bit key, from the `aes` and `xts-mode` crates.
+enum Xts {
+    // the expanded keys are large, so both are boxed
+    Aes128(Box<Xts128<Aes128>>),
+    Aes256(Box<Xts128<Aes256>>),
+}
+
+impl Xts {
+    /// Set up XTS with `key`, the data key followed by the tweak key, which
+    /// must be 32 or 64 bytes.
+    fn new(key: &[u8]) -> Self {
+        let (data, tweak) = key.split_at(key.len() / 2);
+        match key.len() {
+            32 => Self::Aes128(Box::new(Xts128::new(
+                Aes128::new_from_slice(data).unwrap(),
+                Aes128::new_from_slice(tweak).unwrap(),
+            ))),
+            64 => Self::Aes256(Box::new(Xts128::new(
+                Aes256::new_from_slice(data).unwrap(),
+                Aes256::new_from_slice(tweak).unwrap(),
+            ))),
+            len => panic!("invalid XTS key length {len}"),
+        }
+    }
+
+    /// Encrypt `buf` in place as data unit (sector) number `unit`.
+    fn encrypt(&self, unit: u64, buf: &mut [u8]) {
+        let tweak = get_tweak_default(unit as u128);
+        match self {
+            Self::Aes128(xts) => xts.encrypt_sector(buf, tweak),
+            Self::Aes256(xts) => xts.encrypt_sector(buf, tweak),
+        }
+    }
+
+    /// Decrypt `buf` in place as data unit (sector) number `unit`.
+    fn decrypt(&self, unit: u64, buf: &mut [u8]) {
+        let tweak = get_tweak_default(unit as u128);
+        match self {
+            Self::Aes128(xts) => xts.decrypt_sector(buf, tweak),
+            Self::Aes256(xts) => xts.decrypt_sector(buf, tweak),
+        }
+    }
+}
+
+impl<B: Blocks> EncryptedBlocks<B> {
+    /// Export `inner` (with its current size, which must be a whole number
+    /// of sectors) encrypted with `key`.
+    ///
+    /// The key is the concatenation of the XTS data and tweak keys: 32
+    /// bytes for AES-128 or 64 bytes for AES-256.
+    pub fn new(inner: B, key: &[u8]) -> io::Result<Self> {
+        if key.len() != 32 && key.len() != 64 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "key must be 32 or 64 bytes",
+            ));
+        }
+        if key[..key.len() / 2] == key[key.len() / 2..] {
+            // IEEE 1619 requires distinct keys
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "data and tweak keys must differ",
+            ));
+        }
+        let size = inner.size()?;
+        if !size.is_multiple_of(ENCRYPTION_SECTOR_SIZE) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("size {size} is not a multiple of {ENCRYPTION_SECTOR_SIZE} bytes"),
+            ));
+        }
+        Ok(Self {
+            inner,
+            xts: Xts::new(key),
+            size,
+            rmw: Mutex::new(()),
+        })
+    }
+
+    /// Fill the whole export with (encrypted) zeros.
+    pub fn format(&self) -> io::Result<()> {
+        const CHUNK: u64 = 1024 * 1024;
+        let zeros = vec![0u8; CHUNK as usize];
+        let mut off = 0;
+        while off < self.size {
+            let len = CHUNK.min(self.size - off) as usize;
+            self.write_at(&zeros[..len], off)?;
+            off += len as u64;
+        }
+        Ok(())
+    }
+
+    /// Read and decrypt the sector-aligned range starting at `off` into
+    /// `buf`.
+    fn read_sectors(&self, buf: &mut [u8], off: u64) -> io::Result<()> {
+        self.inner.read_at(buf, off)?;
+        for (i, sector) in buf.chunks_mut(ENCRYPTION_SECTOR_SIZE as usize).enumerate() {
+            self.xts
+                .decrypt(off / ENCRYPTION_SECTOR_SIZE + i as u64, sector);
+        }
+        Ok(())
+    }
+
+    /// Encrypt `buf` in place and write it to the sector-aligned `off`.
+    fn write_sectors(&self, buf: &mut [u8], off: u64) -> io::Result<()> {
+        for (i, sector) in buf.chunks_mut(ENCRYPTION_SECTOR_SIZE as usize).enumerate() {
+            self.xts
+                .encrypt(off / ENCRYPTION_SECTOR_SIZE + i as u64, sector);
+        }
+        self.inner.write_at(buf, off)
+    }
+
+    /// The sector-aligned range covering `[off, off+len)`.
+    fn align(off: u64, len: u64) -> (u64, u64) {
+        let start = off - off % ENCRYPTION_SECTOR_SIZE;
+        let end = (off + len).next_multiple_of(ENCRYPTION_SECTOR_SIZE);
+        (start, end)
+    }
+}
+
+impl<B: Blocks> Blocks for EncryptedBlocks<B> {
+    fn read_at(&self, buf: &mut [u8], off: u64) -> io::Result<()> {
+        check_bounds(self.size, off, buf.len() as u64)?;
+        let (start, end) = Self::align(off, buf.len() as u64);
+        if (start, end) == (off, off + buf.len() as u64) {
+            return self.read_sectors(buf, off);
+        }
+        let mut sectors = vec![0u8; (end - start) as usize];
+        self.read_sectors(&mut sectors, start)?;
+        let skip = (off - start) as usize;
+        buf.copy_from_slice(&sectors[skip..skip + buf.len()]);
+        Ok(())
+    }
+
+    fn write_at(&self, buf: &[u8], off: u64) -> io::Result<()> {
+        check_bounds(self.size, off, buf.len() as u64)?;
+        let (start, end) = Self::align(off, buf.len() as u64);
+        // aligned writes take the lock too, so they cannot land in between
+        // the read and write of a partial sector and then be undone
+        let _rmw = self.rmw.lock().unwrap();
+        if (start, end) == (off, off + buf.len() as u64) {
+            return self.write_sectors(&mut buf.to_vec(), off);
+        }
+        let mut sectors = vec![0u8; (end - start) as usize];
+        // only the first and last sectors are partially overwritten
+        let last = end - ENCRYPTION_SECTOR_SIZE;
+        self.read_sectors(&mut sectors[..ENCRYPTION_SECTOR_SIZE as usize], start)?;
+        if last != start {
+            self.read_sectors(&mut sectors[(last - start) as usize..], last)?;
+        }
+        let skip = (off - start) as usize;
+        sectors[skip..skip + buf.len()].copy_from_slice(buf);
+        self.write_sectors(&mut sectors, start)
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.size)
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        self.inner.flush()
+    }
+
+    fn flush_range(&self, off: u64, len: u64) -> io::Result<()> {
+        // sectors map to the same offsets in inner
+        let (start, end) = Self::align(off, len);
+        self.inner.flush_range(start, end - start)
+    }
+
+    fn supports_multi_conn(&self) -> bool {
+        self.inner.supports_multi_conn()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{mpsc, Arc};
+    use std::thread;
+    use std::time::Duration;
+
+    use color_eyre::Result;
+
+    use super::*;
+    use crate::server::MemBlocks;
+
+    const KEY: [u8; 32] = [
+        0x27, 0x18, 0x28, 0x18, 0x28, 0x45, 0x90, 0x45, 0x23, 0x53, 0x60, 0x28, 0x74, 0x71, 0x35,
+        0x26, 0x31, 0x41, 0x59, 0x26, 0x53, 0x58, 0x97, 0x93, 0x23, 0x84, 0x62, 0x64, 0x33, 0x83,
+        0x27, 0x95,
+    ];
+
+    const SIZE: u64 = 8 * ENCRYPTION_SECTOR_SIZE;
+
+    #[test]
+    fn test_encrypted_round_trip() -> Result<()> {
+        let blocks = EncryptedBlocks::new(MemBlocks::new(vec![0u8; SIZE as usize]), &KEY)?;
+        blocks.format()?;
+        let mut expected = vec![0u8; SIZE as usize];
+
+        // aligned, within one sector, and across several sectors
+        for (off, len, val) in [(0, 512, 1u8), (700, 10, 2), (1000, 1500, 3)] {
+            blocks.write_at(&vec![val; len], off)?;
+            expected[off as usize..off as usize + len].fill(val);
+        }
+        let mut buf = vec![0u8; SIZE as usize];
+        blocks.read_at(&mut buf, 0)?;
+        assert!(buf == expected, "read does not match writes");
+        let mut buf = vec![0u8; 100];
+        blocks.read_at(&mut buf, 950)?;
+        assert!(buf[..] == expected[950..1050]);
+
+        // the backing bytes are all different from the plaintext, including
+        // the zeros
+        let mut backing = vec![0u8; SIZE as usize];
+        blocks.inner.read_at(&mut backing, 0)?;
+        for (sector, plain) in backing.chunks(512).zip(expected.chunks(512)) {
+            assert!(sector != plain, "sector stored as plaintext");
+        }
+        // identical sectors encrypt differently at different offsets
+        assert!(backing[5 * 512..6 * 512] != backing[6 * 512..7 * 512]);
+
+        // reopening with the same key sees the same data
+        let blocks = EncryptedBlocks::new(blocks.inner, &KEY)?;
+        let mut buf = vec![0u8; SIZE as usize];
+        blocks.read_at(&mut buf, 0)?;
+        assert!(buf == expected, "reopened image does not match");
+        Ok(())
+    }
+
+    /// Backing storage that pauses the first read until told to go on.
+    struct PausedRead {
+        inner: MemBlocks,
+        paused: Mutex<Option<(mpsc::Sender<()>, mpsc::Receiver<()>)>>,
+    }
+
+    impl Blocks for PausedRead {
+        fn read_at(&self, buf: &mut [u8], off: u64) -> io::Result<()> {
+            if let Some((reading, resume)) = self.paused.lock().unwrap().take() {
+                reading.send(()).unwrap();
+                resume.recv().unwrap();
+            }
+            self.inner.read_at(buf, off)
+        }
+
+        fn write_at(&self, buf: &[u8], off: u64) -> io::Result<()> {
+            self.inner.write_at(buf, off)
+        }
+
+        fn size(&self) -> io::Result<u64> {
+            self.inner.size()
+        }
+
+        fn flush(&self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_encrypted_aligned_write_during_rmw() -> Result<()> {
+        let (reading_tx, reading) = mpsc::channel();
+        let (resume, resume_rx) = mpsc::channel();
+        let backing = PausedRead {
+            inner: MemBlocks::new(vec![0u8; SIZE as usize]),
+            paused: Mutex::new(None),
+        };
+        let blocks = Arc::new(EncryptedBlocks::new(backing, &KEY)?);
+        blocks.format()?;
+        *blocks.inner.paused.lock().unwrap() = Some((reading_tx, resume_rx));
+
+        // a partial write to sector 0 stops after reading the old sector
+        let partial = thread::spawn({
+            let blocks = blocks.clone();
+            move || blocks.write_at(&[1u8; 10], 0)
+        });
+        reading.recv()?;
+        // overwriting the whole sector must wait for it rather than be undone
+        let aligned = thread::spawn({
+            let blocks = blocks.clone();
+            move || blocks.write_at(&[2u8; 512], 0)
+        });
+        thread::sleep(Duration::from_millis(50));
+        resume.send(())?;
+        partial.join().unwrap()?;
+        aligned.join().unwrap()?;
+
+        let mut buf = [0u8; 512];
+        blocks.read_at(&mut buf, 0)?;
+        assert_eq!(buf, [2u8; 512]);
+        Ok(())
+    }
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_xts_ieee1619() {
+        // vectors 1 and 2 from IEEE 1619-2007
+        for (key, unit, plaintext, ciphertext) in [
+            (
+                "00".repeat(32),
+                0,
+                "00".repeat(32),
+                "917cf69ebd68b2ec9b9fe9a3eadda692cd43d2f59598ed858c02c2652fbf922e".to_string(),
+            ),
+            (
+                "11".repeat(16) + &"22".repeat(16),
+                0x3333333333,
+                "44".repeat(32),
+                "c454185e6a16936e39334038acef838bfb186fff7480adc4289382ecd6d394f0".to_string(),
+            ),
+        ] {
+            let xts = Xts::new(&hex(&key));
+            let mut buf = hex(&plaintext);
+            xts.encrypt(unit, &mut buf);
+            assert_eq!(buf, hex(&ciphertext));
+            xts.decrypt(unit, &mut buf);
+            assert_eq!(buf, hex(&plaintext));
+        }
+
+        // AES-256 keys round trip too
+        let key: Vec<u8> = KEY.iter().chain(KEY.iter().rev()).copied().collect();
+        let xts = Xts::new(&key);
+        let mut buf = [5u8; 512];
+        xts.encrypt(7, &mut buf);
+        assert!(buf != [5u8; 512]);
+        xts.decrypt(7, &mut buf);
+        assert_eq!(buf, [5u8; 512]);
+    }
+
+    #[test]
+    fn test_encrypted_invalid() {
+        let backing = || MemBlocks::new(vec![0u8; SIZE as usize]);
+        assert!(EncryptedBlocks::new(backing(), &KEY[..16]).is_err());
+        assert!(EncryptedBlocks::new(backing(), &[7u8; 32]).is_err());
+        assert!(EncryptedBlocks::new(MemBlocks::new(vec![0u8; 1000]), &KEY).is_err());
+
+        let blocks = EncryptedBlocks::new(backing(), &KEY).unwrap();
+        assert!(blocks.write_at(&[1u8; 10], SIZE - 5).is_err());
+    }
+}
@@ -0,0 +1,342 @@
+//! Running a server in the background so it can be shut down.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io;
+use std::net::{
+    IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs,
+};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+use color_eyre::eyre::{bail, eyre, WrapErr};
+use color_eyre::Result;
+use log::info;
+
+use super::workers::SplitStream;
+use super::{Blocks, RemoveOnDrop, Server};
+
+/// A connection that can be closed for reading, to make the thread serving
+/// it finish up.
+pub(super) trait CloseRead: fmt::Debug + Send {
+    fn close_read(&self) -> io::Result<()>;
+
+    /// Close the connection in both directions, so the client sees it closed
+    /// even while other handles to it remain open.
+    fn close(&self) -> io::Result<()>;
+}
+
+impl CloseRead for TcpStream {
+    fn close_read(&self) -> io::Result<()> {
+        self.shutdown(Shutdown::Read)
+    }
+
+    fn close(&self) -> io::Result<()> {
+        self.shutdown(Shutdown::Both)
+    }
+}
+
+impl CloseRead for UnixStream {
+    fn close_read(&self) -> io::Result<()> {
+        self.shutdown(Shutdown::Read)
+    }
+
+    fn close(&self) -> io::Result<()> {
+        self.shutdown(Shutdown::Both)
+    }
+}
+
+/// A connected client: a handle to its socket, and the thread serving it.
+type ClientThread = (Box<dyn CloseRead>, JoinHandle<()>);
+
+/// How to wake an accept loop blocked in `accept`: by connecting to it.
+#[derive(Debug)]
+enum Wake {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+/// An accept loop running in the background, which stops once `shutdown` is
+/// set and it is woken.
+#[derive(Debug)]
+struct Accepting {
+    shutdown: AtomicBool,
+    wake: Wake,
+}
+
+impl Accepting {
+    fn stop(&self) {
+        self.shutdown.store(true, Ordering::SeqCst);
+        // if this fails, the accept loop has already stopped
+        match &self.wake {
+            Wake::Tcp(addr) => {
+                let _ = TcpStream::connect(addr);
+            }
+            Wake::Unix(path) => {
+                let _ = UnixStream::connect(path);
+            }
+        }
+    }
+}
+
+/// The connections a server accepted itself (with [`Server::start`] and
+/// friends, or the spawn equivalents), numbered so that they can be listed
+/// and closed from the control socket, along with the accept loops to stop
+/// on shutdown.
+#[derive(Debug, Default)]
+pub(super) struct Connections {
+    next_id: AtomicU64,
+    /// Each connected client's socket, by connection id.
+    open: Mutex<BTreeMap<u64, Box<dyn CloseRead>>>,
+    accepting: Mutex<Vec<Arc<Accepting>>>,
+}
+
+impl Connections {
+    /// Track the connection `stream`, returning its id.
+    pub(super) fn add<S: SplitStream + CloseRead>(&self, stream: &S) -> io::Result<u64> {
+        let conn = stream.try_split()?;
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.open.lock().unwrap().insert(id, Box::new(conn));
+        Ok(id)
+    }
+
+    /// Stop tracking connection `id` once the server is done with it,
+    /// closing it (the background server keeps another handle to it).
+    pub(super) fn remove(&self, id: u64) {
+        let conn = self.open.lock().unwrap().remove(&id);
+        if let Some(conn) = conn {
+            // the client may have already disconnected
+            let _ = conn.close();
+        }
+    }
+
+    /// The ids of the connected clients, in the order they connected.
+    pub(super) fn ids(&self) -> Vec<u64> {
+        self.open.lock().unwrap().keys().copied().collect()
+    }
+
+    /// Close connection `id` for reading, so the server finishes the
+    /// requests it has received and then disconnects it.
+    pub(super) fn disconnect(&self, id: u64) -> Result<()> {
+        let open = self.open.lock().unwrap();
+        let Some(conn) = open.get(&id) else {
+            bail!("no connection {id}");
+        };
+        conn.close_read()?;
+        Ok(())
+    }
+
+    /// Stop every accept loop, and close every connection for reading.
+    pub(super) fn shutdown(&self) {
+        for accepting in self.accepting.lock().unwrap().iter() {
+            accepting.stop();
+        }
+        for conn in self.open.lock().unwrap().values() {
+            // the client may have already disconnected
+            let _ = conn.close_read();
+        }
+    }
+}
+
+/// The accept loop and client threads of a background server.
+#[derive(Debug)]
+struct Background {
+    accepting: Arc<Accepting>,
+    accept: JoinHandle<Result<()>>,
+    clients: Arc<Mutex<Vec<ClientThread>>>,
+}
+
+impl Background {
+    /// Stop the accept loop, then close each connection for reading and wait
+    /// for its thread.
+    fn stop(self) -> Result<()> {
+        self.accepting.stop();
+        self.wait()
+    }
+
+    /// Wait for the accept loop to stop, and then for the clients to be
+    /// served. If accepting failed, return the error without waiting for the
+    /// clients.
+    fn wait(self) -> Result<()> {
+        self.accept
+            .join()
+            .map_err(|_| eyre!("accept thread panicked"))??;
+        let clients = std::mem::take(&mut *self.clients.lock().unwrap());
+        for (conn, thread) in clients {
+            // the client may have already disconnected
+            let _ = conn.close_read();
+            thread.join().map_err(|_| eyre!("client thread panicked"))?;
+        }
+        Ok(())
+    }
+}
+
+/// A server accepting connections in a background thread, started with
+/// [`Server::spawn_on`].
+#[derive(Debug)]
+pub struct ServerHandle {
+    addr: SocketAddr,
+    background: Background,
+}
+
+/// A server accepting connections on a Unix socket in a background thread,
+/// started with [`Server::spawn_unix`].
+#[derive(Debug)]
+pub struct UnixServerHandle {
+    path: PathBuf,
+    background: Background,
+}
+
+impl<F: Blocks + Sync + Send + 'static> Server<F> {
+    /// Like [`Server::start_on`], but accept connections in a background
+    /// thread, returning a handle to shut the server down.
+    ///
+    /// The listener is bound before this returns, so clients can connect
+    /// right away (bind port 0 and use [`ServerHandle::local_addr`] to pick a
+    /// free port).
+    pub fn spawn_on<A: ToSocketAddrs>(self, addr: A) -> Result<ServerHandle> {
+        let listener = TcpListener::bind(addr).wrap_err("binding TCP listener")?;
+        let addr = listener.local_addr()?;
+        let mut wake = addr;
+        if wake.ip().is_unspecified() {
+            wake.set_ip(match wake.ip() {
+                IpAddr::V4(_) => Ipv4Addr::LOCALHOST.into(),
+                IpAddr::V6(_) => Ipv6Addr::LOCALHOST.into(),
+            });
+        }
+        let background = self.spawn_accept(Wake::Tcp(wake), move || {
+            let (stream, _) = listener.accept()?;
+            stream.set_nodelay(true)?;
+            Ok(stream)
+        });
+        Ok(ServerHandle { addr, background })
+    }
+
+    /// Like [`Server::start_unix`], but accept connections in a background
+    /// thread, returning a handle to shut the server down.
+    ///
+    /// The socket is bound before this returns, so clients can connect right
+    /// away. Fails if `path` already exists. The socket file is removed when
+    /// the server stops accepting connections.
+    pub fn spawn_unix<P: AsRef<Path>>(self, path: P) -> Result<UnixServerHandle> {
+        let path = path.as_ref().to_path_buf();
+        let listener = UnixListener::bind(&path)
+            .wrap_err_with(|| format!("binding socket {}", path.display()))?;
+        let socket = RemoveOnDrop(path.clone());
+        let background = self.spawn_accept(Wake::Unix(path.clone()), move || {
+            // keep the socket file for as long as the accept loop runs
+            let _ = &socket;
+            let (stream, _) = listener.accept()?;
+            Ok(stream)
+        });
+        Ok(UnixServerHandle { path, background })
+    }
+
+    /// Serve connections from `accept` in a background thread, until it is
+    /// stopped (and woken with `wake`) or `accept` fails.
+    fn spawn_accept<S: SplitStream + CloseRead>(
+        self,
+        wake: Wake,
+        accept: impl FnMut() -> io::Result<S> + Send + 'static,
+    ) -> Background {
+        let accepting = Arc::new(Accepting {
+            shutdown: AtomicBool::new(false),
+            wake,
+        });
+        self.0
+            .conns
+            .accepting
+            .lock()
+            .unwrap()
+            .push(accepting.clone());
+        let clients: Arc<Mutex<Vec<ClientThread>>> = Arc::new(Mutex::new(vec![]));
+        let accept = thread::spawn({
+            let accepting = accepting.clone();
+            let clients = clients.clone();
+            move || -> Result<()> {
+                let r = self.accept_loop(&accepting, &clients, accept);
+                let mut all = self.0.conns.accepting.lock().unwrap();
+                all.retain(|other| !Arc::ptr_eq(other, &accepting));
+                r
+            }
+        });
+        Background {
+            accepting,
+            accept,
+            clients,
+        }
+    }
+
+    /// Serve connections from `accept` until `accepting` is stopped.
+    fn accept_loop<S: SplitStream + CloseRead>(
+        &self,
+        accepting: &Accepting,
+        clients: &Mutex<Vec<ClientThread>>,
+        mut accept: impl FnMut() -> io::Result<S>,
+    ) -> Result<()> {
+        loop {
+            let stream = accept();
+            if accepting.shutdown.load(Ordering::SeqCst) {
+                return Ok(());
+            }
+            let stream = stream?;
+            let conn = stream.try_split()?;
+            let thread = self.spawn_client(stream);
+            let mut clients = clients.lock().unwrap();
+            clients.retain(|(_, thread)| !thread.is_finished());
+            clients.push((Box::new(conn), thread));
+        }
+    }
+}
+
+impl ServerHandle {
+    /// The address the server is listening on.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Stop accepting connections, and wait for the connected clients to be
+    /// served.
+    ///
+    /// Each connection is closed for reading, so the server finishes the
+    /// request it is handling (and any it has already received) and then
+    /// disconnects. Returns an error if accepting connections had failed.
+    pub fn shutdown(self) -> Result<()> {
+        info!(target: "nbd", "shutting down server on {}", self.addr);
+        self.background.stop()
+    }
+
+    /// Wait for the server to stop accepting connections, which happens
+    /// after a `shutdown` command on the control socket (see
+    /// [`Server::control_socket`]), and then for the connected clients to be
+    /// served.
+    ///
+    /// Returns an error, without waiting for clients, if accepting
+    /// connections failed.
+    pub fn wait(self) -> Result<()> {
+        self.background.wait()
+    }
+}
+
+impl UnixServerHandle {
+    /// The path of the socket the server is listening on.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Stop accepting connections, remove the socket file, and wait for the
+    /// connected clients to be served, as in [`ServerHandle::shutdown`].
+    pub fn shutdown(self) -> Result<()> {
+        info!(target: "nbd", "shutting down server on {}", self.path.display());
+        self.background.stop()
+    }
+
+    /// Wait for the server to stop accepting connections and for its clients
+    /// to be served, as in [`ServerHandle::wait`].
+    pub fn wait(self) -> Result<()> {
+        self.background.wait()
+    }
+}
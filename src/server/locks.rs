@@ -0,0 +1,210 @@
+//! Advisory byte-range locks for coordinating writers to a shared export.
+
+use std::collections::BTreeMap;
+use std::io;
+use std::ops::Range;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use super::Blocks;
+
+#[derive(Debug, Default)]
+struct LockTable {
+    next_id: u64,
+    ranges: BTreeMap<u64, Range<u64>>,
+}
+
+impl LockTable {
+    fn conflicts(&self, range: &Range<u64>, except: Option<u64>) -> bool {
+        self.ranges
+            .iter()
+            .any(|(id, r)| Some(*id) != except && r.start < range.end && range.start < r.end)
+    }
+}
+
+/// RangeLocks is a table of advisory locks on byte ranges of an export.
+///
+/// NBD has no notion of locking, so these locks are only advisory: they are
+/// taken out-of-band by cooperating writers (eg, threads in the process
+/// embedding the server), and only writes that go through a [`LockedBlocks`]
+/// sharing this table are checked against them. Nothing stops a client from
+/// writing the underlying storage some other way.
+///
+/// Cloning a RangeLocks gives another handle to the same table.
+#[derive(Debug, Clone, Default)]
+pub struct RangeLocks(Arc<Mutex<LockTable>>);
+
+impl RangeLocks {
+    /// Create an empty lock table.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Lock `range`, or return None if it overlaps an existing lock.
+    ///
+    /// The lock is released when the returned guard is dropped.
+    pub fn try_lock(&self, range: Range<u64>) -> Option<RangeLock> {
+        let mut table = self.0.lock().unwrap();
+        if range.is_empty() || table.conflicts(&range, None) {
+            return None;
+        }
+        let id = table.next_id;
+        table.next_id += 1;
+        table.ranges.insert(id, range.clone());
+        Some(RangeLock {
+            locks: self.clone(),
+            id,
+            range,
+        })
+    }
+
+    /// Check if any part of `range` is locked.
+    pub fn is_locked(&self, range: Range<u64>) -> bool {
+        self.0.lock().unwrap().conflicts(&range, None)
+    }
+}
+
+/// A held lock on a byte range, released on drop.
+#[derive(Debug)]
+pub struct RangeLock {
+    locks: RangeLocks,
+    id: u64,
+    range: Range<u64>,
+}
+
+impl RangeLock {
+    /// The locked range.
+    pub fn range(&self) -> Range<u64> {
+        self.range.clone()
+    }
+}
+
+impl Drop for RangeLock {
+    fn drop(&mut self) {
+        self.locks.0.lock().unwrap().ranges.remove(&self.id);
+    }
+}
+
+/// LockedBlocks wraps a Blocks to reject writes that conflict with advisory
+/// [`RangeLocks`].
+///
+/// Writes through the [`Blocks`] interface (that is, from NBD clients) that
+/// overlap any locked range fail with [`io::ErrorKind::PermissionDenied`],
+/// which clients see as EPERM. The holder of a lock writes within its range
+/// using [`LockedBlocks::write_locked`]. Reads are never blocked.
+#[derive(Debug)]
+pub struct LockedBlocks<F> {
+    inner: F,
+    locks: RangeLocks,
+}
+
+impl<F: Blocks> LockedBlocks<F> {
+    /// Check writes to `inner` against `locks`.
+    pub fn new(inner: F, locks: RangeLocks) -> Self {
+        Self { inner, locks }
+    }
+
+    /// Write on behalf of the holder of `lock`, which must cover the written
+    /// range. Other locks are still respected.
+    pub fn write_locked(&self, lock: &RangeLock, buf: &[u8], off: u64) -> io::Result<()> {
+        let range = off..off + buf.len() as u64;
+        if range.start < lock.range.start || range.end > lock.range.end {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "write outside of locked range",
+            ));
+        }
+        let _table = self.check_write(&range, Some(lock.id))?;
+        self.inner.write_at(buf, off)
+    }
+
+    /// Check that a write is allowed, returning the lock table guard so that
+    /// no conflicting lock can be taken until the write finishes.
+    fn check_write(
+        &self,
+        range: &Range<u64>,
+        except: Option<u64>,
+    ) -> io::Result<MutexGuard<'_, LockTable>> {
+        let table = self.locks.0.lock().unwrap();
+        if table.conflicts(range, except) {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "write to locked range",
+            ));
+        }
+        Ok(table)
+    }
+}
+
+impl<F: Blocks> Blocks for LockedBlocks<F> {
+    fn read_at(&self, buf: &mut [u8], off: u64) -> io::Result<()> {
+        self.inner.read_at(buf, off)
+    }
+
+    fn write_at(&self, buf: &[u8], off: u64) -> io::Result<()> {
+        let _table = self.check_write(&(off..off + buf.len() as u64), None)?;
+        self.inner.write_at(buf, off)
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        self.inner.size()
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        self.inner.flush()
+    }
+
+    fn flush_range(&self, off: u64, len: u64) -> io::Result<()> {
+        self.inner.flush_range(off, len)
+    }
+
+    fn supports_multi_conn(&self) -> bool {
+        self.inner.supports_multi_conn()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use color_eyre::Result;
+
+    use super::*;
+    use crate::server::MemBlocks;
+
+    #[test]
+    fn test_conflicting_locks() {
+        let locks = RangeLocks::new();
+        let lock = locks.try_lock(10..20).unwrap();
+        assert!(locks.try_lock(15..25).is_none());
+        assert!(locks.try_lock(0..11).is_none());
+        assert!(locks.is_locked(19..30));
+        drop(lock);
+        assert!(!locks.is_locked(0..100));
+        assert!(locks.try_lock(15..25).is_some());
+    }
+
+    #[test]
+    fn test_non_conflicting_locks() {
+        let locks = RangeLocks::new();
+        let _a = locks.try_lock(10..20).unwrap();
+        let _b = locks.try_lock(20..30).unwrap();
+        let _c = locks.try_lock(0..10).unwrap();
+        assert!(!locks.is_locked(30..40));
+    }
+
+    #[test]
+    fn test_locked_writes() -> Result<()> {
+        let locks = RangeLocks::new();
+        let blocks = LockedBlocks::new(MemBlocks::new(vec![0u8; 100]), locks.clone());
+        let lock = locks.try_lock(10..20).unwrap();
+
+        let err = blocks.write_at(&[1; 4], 8).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        blocks.write_at(&[1; 4], 20)?;
+        blocks.write_locked(&lock, &[2; 4], 12)?;
+        assert!(blocks.write_locked(&lock, &[2; 4], 18).is_err());
+
+        let mut buf = [0u8; 4];
+        blocks.read_at(&mut buf, 12)?;
+        assert_eq!(buf, [2; 4]);
+        Ok(())
+    }
+}
@@ -0,0 +1,130 @@
+//! The base:allocation metadata context, which lets clients query holes and
+//! zeroed ranges with NBD_CMD_BLOCK_STATUS.
+
+use std::io::prelude::*;
+use std::sync::Arc;
+
+use byteorder::{WriteBytesExt, BE};
+use color_eyre::Result;
+use log::warn;
+
+use super::{check_in_bounds, check_nonempty, retry, Blocks, Export, Extent, ServerInner, Session};
+use crate::proto::*;
+
+/// The ID the server assigns to the base:allocation context.
+const ALLOCATION_CONTEXT_ID: u32 = 1;
+
+impl<F: Blocks> Export<F> {
+    /// Get the allocation status of `[off, off+len)`.
+    fn block_status(&self, off: u64, len: u32) -> core::result::Result<Vec<Extent>, ErrorType> {
+        check_nonempty(len as usize)?;
+        let size = self.size().map_err(|err| ErrorType::from_io_error(&err))?;
+        check_in_bounds(off, len as u64, size, ErrorType::EINVAL)?;
+        let mut extents = retry(|| Blocks::extent_status(&self.blocks, off, len as u64))
+            .map_err(|err| ErrorType::from_io_error(&err))?;
+        extents.retain(|e| e.len > 0);
+        if extents.is_empty() || extents.iter().map(|e| e.len).sum::<u64>() != len as u64 {
+            warn!("extents for {off}+{len} do not cover the range");
+            return Err(ErrorType::EIO);
+        }
+        Ok(extents)
+    }
+}
+
+impl<F: Blocks> ServerInner<F> {
+    /// Reply to NBD_OPT_LIST_META_CONTEXT or NBD_OPT_SET_META_CONTEXT.
+    ///
+    /// For SET, returns the export base:allocation is now selected for, if
+    /// any; every SET replaces the previous selection.
+    pub(super) fn meta_context_option<IO: Write>(
+        &self,
+        opt: &Opt,
+        structured_replies: bool,
+        stream: &mut IO,
+    ) -> Result<Option<Arc<Export<F>>>> {
+        // metadata is only sent in structured replies
+        if !structured_replies {
+            warn!("{:?} without structured replies", opt.typ);
+            OptReply::new(opt.typ, ReplyType::ERR_INVALID, vec![]).put(stream)?;
+            return Ok(None);
+        }
+        let Ok(req) = MetaContextRequest::get(&mut &opt.data[..]) else {
+            OptReply::new(opt.typ, ReplyType::ERR_INVALID, vec![]).put(stream)?;
+            return Ok(None);
+        };
+        let Some(export) = self.find_export(&req.name) else {
+            warn!("client requested unknown export {:?}", req.name);
+            OptReply::new(opt.typ, ReplyType::ERR_UNKNOWN, vec![]).put(stream)?;
+            return Ok(None);
+        };
+        let selected = if opt.typ == OptType::LIST_META_CONTEXT {
+            // with no queries, list every context; "base:" lists the whole
+            // namespace
+            req.queries.is_empty()
+                || req
+                    .queries
+                    .iter()
+                    .any(|q| q == "base:" || q == ALLOCATION_CONTEXT)
+        } else {
+            req.queries.iter().any(|q| q == ALLOCATION_CONTEXT)
+        };
+        if selected {
+            // S: 32 bits, NBD metadata context ID.
+            // S: String, name of the metadata context.
+            let mut data = vec![];
+            data.write_u32::<BE>(ALLOCATION_CONTEXT_ID)?;
+            data.write_all(ALLOCATION_CONTEXT.as_bytes())?;
+            OptReply::new(opt.typ, ReplyType::META_CONTEXT, data).put(stream)?;
+        }
+        OptReply::ack(opt.typ).put(stream)?;
+        let set = opt.typ == OptType::SET_META_CONTEXT && selected;
+        Ok(set.then(|| export.clone()))
+    }
+
+    /// Reply to NBD_CMD_BLOCK_STATUS, returning the error sent.
+    pub(super) fn put_block_status<IO: Write>(
+        session: &Session<F>,
+        req: &Request,
+        stream: &mut IO,
+    ) -> Result<ErrorType> {
+        if !session.block_status {
+            warn!(target: "nbd", "block status without a metadata context");
+            return Self::put_simple_reply(ErrorType::EINVAL, req, stream);
+        }
+        let mut extents = match session.export.block_status(req.offset, req.len) {
+            Ok(extents) => extents,
+            Err(err) => {
+                warn!(target: "nbd", "block status error {:?}", err);
+                return Self::put_error_chunk(err, req, stream);
+            }
+        };
+        if req.flags.contains(CmdFlags::REQ_ONE) {
+            extents.truncate(1);
+        }
+        // S: 32 bits, metadata context ID
+        // S: list of block status descriptors, each:
+        //    32 bits, length of the extent
+        //    32 bits, status flags
+        let mut data = vec![];
+        data.write_u32::<BE>(ALLOCATION_CONTEXT_ID)?;
+        for extent in &extents {
+            let mut flags = 0;
+            if extent.hole {
+                flags |= STATE_HOLE;
+            }
+            if extent.zero {
+                flags |= STATE_ZERO;
+            }
+            data.write_u32::<BE>(extent.len as u32)?;
+            data.write_u32::<BE>(flags)?;
+        }
+        ChunkHeader::put(
+            stream,
+            ChunkFlags::DONE,
+            ChunkType::BLOCK_STATUS,
+            req.handle,
+            &[&data],
+        )?;
+        Ok(ErrorType::OK)
+    }
+}
@@ -0,0 +1,202 @@
+//! Hooks for watching the requests a server handles, for example to collect
+//! metrics on bytes transferred and latency.
+
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+use super::{Blocks, Server};
+use crate::proto::{Cmd, ErrorType, Request};
+
+/// The kind of a request, as seen by a [`ServerObserver`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Command {
+    /// NBD_CMD_READ
+    Read,
+    /// NBD_CMD_WRITE
+    Write,
+    /// NBD_CMD_DISC
+    Disconnect,
+    /// NBD_CMD_FLUSH
+    Flush,
+    /// NBD_CMD_TRIM
+    Trim,
+    /// NBD_CMD_CACHE
+    Cache,
+    /// NBD_CMD_WRITE_ZEROES
+    WriteZeroes,
+    /// NBD_CMD_BLOCK_STATUS
+    BlockStatus,
+    /// NBD_CMD_RESIZE
+    Resize,
+}
+
+impl From<Cmd> for Command {
+    fn from(cmd: Cmd) -> Self {
+        match cmd {
+            Cmd::READ => Self::Read,
+            Cmd::WRITE => Self::Write,
+            Cmd::DISCONNECT => Self::Disconnect,
+            Cmd::FLUSH => Self::Flush,
+            Cmd::TRIM => Self::Trim,
+            Cmd::CACHE => Self::Cache,
+            Cmd::WRITE_ZEROES => Self::WriteZeroes,
+            Cmd::BLOCK_STATUS => Self::BlockStatus,
+            Cmd::RESIZE => Self::Resize,
+        }
+    }
+}
+
+/// A request the server is handling, as passed to a [`ServerObserver`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RequestInfo {
+    /// The handle the client chose for the request.
+    pub handle: u64,
+    /// The kind of request.
+    pub command: Command,
+    /// The offset the request starts at (the new size, for a resize).
+    pub offset: u64,
+    /// The length of the request in bytes.
+    pub len: u32,
+}
+
+impl RequestInfo {
+    pub(super) fn new(req: &Request) -> Self {
+        Self {
+            handle: req.handle,
+            command: req.typ.into(),
+            offset: req.offset,
+            len: req.len,
+        }
+    }
+}
+
+/// Callbacks for the requests a server handles, registered with
+/// [`Server::observer`].
+///
+/// The callbacks run on the thread handling the connection (or a worker, see
+/// [`Server::workers`]), in between requests, so they should be cheap: for
+/// example, update atomic counters or send to a channel rather than doing
+/// I/O. Both do nothing by default.
+pub trait ServerObserver: Send + Sync {
+    /// Called when the server starts handling `req`.
+    fn on_request(&self, req: &RequestInfo) {
+        let _ = req;
+    }
+
+    /// Called once the reply to `req` has been sent (or, with workers, built),
+    /// with the error it was sent with (0 for success), the number of data
+    /// bytes read or written, and how long handling it took.
+    ///
+    /// Disconnect requests get no reply, so this is not called for them.
+    fn on_reply(&self, req: &RequestInfo, errno: u32, bytes: u64, elapsed: Duration) {
+        let _ = (req, errno, bytes, elapsed);
+    }
+}
+
+/// The observer registered with [`Server::observer`].
+#[derive(Clone)]
+pub(super) struct Observer(Arc<dyn ServerObserver>);
+
+impl fmt::Debug for Observer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Observer")
+    }
+}
+
+impl Observer {
+    pub(super) fn on_request(&self, req: &RequestInfo) {
+        self.0.on_request(req);
+    }
+
+    pub(super) fn on_reply(&self, req: &RequestInfo, err: ErrorType, elapsed: Duration) {
+        let bytes = match req.command {
+            Command::Read | Command::Write if err == ErrorType::OK => req.len as u64,
+            _ => 0,
+        };
+        self.0.on_reply(req, err.into(), bytes, elapsed);
+    }
+}
+
+impl<F: Blocks + Sync + Send + 'static> Server<F> {
+    /// Call `observer` for every request the server handles, for example to
+    /// count bytes transferred or record latencies.
+    pub fn observer(mut self, observer: Arc<dyn ServerObserver>) -> Self {
+        self.inner_mut().observer = Some(Observer(observer));
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use color_eyre::Result;
+
+    use super::{Command, RequestInfo, ServerObserver};
+    use crate::proto::*;
+    use crate::server::{MemBlocks, Server};
+
+    #[derive(Default)]
+    struct Recorder {
+        requests: Mutex<Vec<RequestInfo>>,
+        replies: Mutex<Vec<(Command, u32, u64)>>,
+    }
+
+    impl ServerObserver for Recorder {
+        fn on_request(&self, req: &RequestInfo) {
+            self.requests.lock().unwrap().push(*req);
+        }
+
+        fn on_reply(&self, req: &RequestInfo, errno: u32, bytes: u64, _elapsed: Duration) {
+            self.replies
+                .lock()
+                .unwrap()
+                .push((req.command, errno, bytes));
+        }
+    }
+
+    #[test]
+    fn test_observer() -> Result<()> {
+        let recorder = Arc::new(Recorder::default());
+        let server = Server::new(MemBlocks::new(vec![0u8; 4096]))
+            .op_log_level(None)
+            .observer(recorder.clone());
+
+        let reqs = [
+            (Request::new(Cmd::WRITE, 0, 1024), vec![1u8; 1024]),
+            (Request::new(Cmd::READ, 512, 1024), vec![]),
+            // out of bounds
+            (Request::new(Cmd::READ, 4096, 1024), vec![]),
+            (Request::new(Cmd::FLUSH, 0, 0), vec![]),
+        ];
+        for (req, data) in &reqs {
+            let mut request = vec![];
+            req.put(data, &mut request)?;
+            server.serve_request(&request)?;
+        }
+
+        let requests = recorder.requests.lock().unwrap();
+        assert_eq!(requests.len(), 4);
+        assert_eq!(
+            requests[1],
+            RequestInfo {
+                handle: reqs[1].0.handle,
+                command: Command::Read,
+                offset: 512,
+                len: 1024,
+            }
+        );
+        assert_eq!(
+            *recorder.replies.lock().unwrap(),
+            [
+                (Command::Write, 0, 1024),
+                (Command::Read, 0, 1024),
+                (Command::Read, ErrorType::EINVAL.into(), 0),
+                (Command::Flush, 0, 0),
+            ]
+        );
+        Ok(())
+    }
+}
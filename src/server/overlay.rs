@@ -0,0 +1,215 @@
+//! Copy-on-write export of a read-only base, with writes captured in a
+//! separate overlay.
+
+use std::collections::BTreeSet;
+use std::io;
+use std::sync::Mutex;
+
+use super::{check_bounds, Blocks};
+
+/// Granularity at which writes are captured in the overlay.
+const BLOCK_SIZE: u64 = 4096;
+
+/// OverlayBlocks exports a base that is never written, with writes going to
+/// an overlay instead, for example to boot several VMs from one shared image
+/// while keeping each one's changes separate.
+///
+/// Reads of blocks (of 4096 bytes) that have been written come from the
+/// overlay, and reads of the rest fall through to the base. The first write
+/// to part of a block copies the rest of the block from the base, so the
+/// overlay must support writes anywhere in the export's range (a sparse file
+/// or [`super::SparseMemBlocks`] of the base's size works well).
+///
+/// Which blocks have been written is only kept in memory, so unlike
+/// [`super::SnapshotBlocks`] the overlay cannot be reopened later.
+#[derive(Debug)]
+pub struct OverlayBlocks<B, O> {
+    base: B,
+    overlay: O,
+    size: u64,
+    // also serializes all operations, so a block's data and its entry are
+    // updated together
+    written: Mutex<BTreeSet<u64>>,
+}
+
+impl<B: Blocks, O: Blocks> OverlayBlocks<B, O> {
+    /// Export `base` (with its current size), capturing writes in `overlay`.
+    pub fn new(base: B, overlay: O) -> io::Result<Self> {
+        let size = base.size()?;
+        Ok(Self {
+            base,
+            overlay,
+            size,
+            written: Mutex::new(BTreeSet::new()),
+        })
+    }
+
+    /// The number of blocks that have been written to the overlay.
+    pub fn written_blocks(&self) -> usize {
+        self.written.lock().unwrap().len()
+    }
+
+    /// Copy the parts of the blocks partially covered by `[off, off+len)`
+    /// that are outside it from the base to the overlay, if those blocks are
+    /// not in the overlay yet, so the whole blocks can be marked as written.
+    fn copy_edges(&self, written: &BTreeSet<u64>, off: u64, len: u64) -> io::Result<()> {
+        let end = off + len;
+        let first = off / BLOCK_SIZE;
+        let last = (end - 1) / BLOCK_SIZE;
+        // the part of the first block before the range
+        let head = first * BLOCK_SIZE;
+        if head < off && !written.contains(&first) {
+            self.copy_from_base(head, off)?;
+        }
+        // the part of the last block after the range
+        let tail = ((last + 1) * BLOCK_SIZE).min(self.size);
+        if end < tail && !written.contains(&last) {
+            self.copy_from_base(end, tail)?;
+        }
+        Ok(())
+    }
+
+    /// Copy `[start, end)` from the base to the overlay.
+    fn copy_from_base(&self, start: u64, end: u64) -> io::Result<()> {
+        let mut buf = vec![0u8; (end - start) as usize];
+        self.base.read_at(&mut buf, start)?;
+        self.overlay.write_at(&buf, start)
+    }
+
+    /// Run `write` on the overlay for `[off, off+len)`, first copying any
+    /// partially covered blocks from the base, and record the blocks as
+    /// written.
+    fn capture(
+        &self,
+        off: u64,
+        len: u64,
+        write: impl FnOnce(&O) -> io::Result<()>,
+    ) -> io::Result<()> {
+        check_bounds(self.size, off, len)?;
+        if len == 0 {
+            return Ok(());
+        }
+        let mut written = self.written.lock().unwrap();
+        self.copy_edges(&written, off, len)?;
+        write(&self.overlay)?;
+        written.extend(off / BLOCK_SIZE..=(off + len - 1) / BLOCK_SIZE);
+        Ok(())
+    }
+}
+
+impl<B: Blocks, O: Blocks> Blocks for OverlayBlocks<B, O> {
+    fn read_at(&self, buf: &mut [u8], off: u64) -> io::Result<()> {
+        check_bounds(self.size, off, buf.len() as u64)?;
+        let written = self.written.lock().unwrap();
+        let mut pos = 0;
+        while pos < buf.len() {
+            // read a run of blocks that all come from the same place
+            let start = off + pos as u64;
+            let in_overlay = written.contains(&(start / BLOCK_SIZE));
+            let mut end = (start / BLOCK_SIZE + 1) * BLOCK_SIZE;
+            while end < off + buf.len() as u64
+                && written.contains(&(end / BLOCK_SIZE)) == in_overlay
+            {
+                end += BLOCK_SIZE;
+            }
+            let len = (end.min(off + buf.len() as u64) - start) as usize;
+            let piece = &mut buf[pos..pos + len];
+            if in_overlay {
+                self.overlay.read_at(piece, start)?;
+            } else {
+                self.base.read_at(piece, start)?;
+            }
+            pos += len;
+        }
+        Ok(())
+    }
+
+    fn write_at(&self, buf: &[u8], off: u64) -> io::Result<()> {
+        self.capture(off, buf.len() as u64, |overlay| overlay.write_at(buf, off))
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.size)
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        // the base is never written
+        self.overlay.flush()
+    }
+
+    fn flush_range(&self, off: u64, len: u64) -> io::Result<()> {
+        self.overlay.flush_range(off, len)
+    }
+
+    fn supports_multi_conn(&self) -> bool {
+        self.overlay.supports_multi_conn()
+    }
+
+    fn write_zeroes(&self, off: u64, len: u64, no_hole: bool) -> io::Result<()> {
+        self.capture(off, len, |overlay| overlay.write_zeroes(off, len, no_hole))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use color_eyre::Result;
+
+    use super::*;
+    use crate::server::{MemBlocks, SparseMemBlocks};
+
+    fn base_image() -> (MemBlocks, Vec<u8>) {
+        // not a whole number of blocks
+        let data: Vec<u8> = (0..3 * BLOCK_SIZE + 100).map(|i| (i % 251) as u8).collect();
+        (MemBlocks::new(data.clone()), data)
+    }
+
+    #[test]
+    fn test_overlay_read_through() -> Result<()> {
+        let (base, data) = base_image();
+        let blocks = OverlayBlocks::new(base, SparseMemBlocks::new(data.len() as u64))?;
+        assert_eq!(blocks.size()?, data.len() as u64);
+
+        let mut buf = vec![0u8; data.len()];
+        blocks.read_at(&mut buf, 0)?;
+        assert!(buf == data, "read does not match base");
+        let mut buf = vec![0u8; 10];
+        assert!(blocks.read_at(&mut buf, data.len() as u64 - 5).is_err());
+        assert_eq!(blocks.written_blocks(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_overlay_write_read_back() -> Result<()> {
+        let (base, mut expected) = base_image();
+        let blocks = OverlayBlocks::new(base, SparseMemBlocks::new(expected.len() as u64))?;
+
+        // within one block, across a block boundary, and into the short last
+        // block
+        for (off, len, val) in [
+            (10, 20, 1u8),
+            (BLOCK_SIZE - 5, 10, 2),
+            (3 * BLOCK_SIZE + 50, 20, 3),
+        ] {
+            blocks.write_at(&vec![val; len], off)?;
+            expected[off as usize..off as usize + len].fill(val);
+        }
+        blocks.write_zeroes(2 * BLOCK_SIZE + 1, 10, false)?;
+        expected[2 * BLOCK_SIZE as usize + 1..][..10].fill(0);
+        assert_eq!(blocks.written_blocks(), 4);
+
+        let mut buf = vec![0u8; expected.len()];
+        blocks.read_at(&mut buf, 0)?;
+        assert!(buf == expected, "read does not match writes");
+        // unaligned reads that mix overlay and base blocks
+        let mut buf = vec![0u8; 2 * BLOCK_SIZE as usize];
+        blocks.read_at(&mut buf, BLOCK_SIZE / 2)?;
+        assert!(buf[..] == expected[BLOCK_SIZE as usize / 2..][..buf.len()]);
+
+        // the base is unchanged
+        let (_, data) = base_image();
+        let mut buf = vec![0u8; data.len()];
+        blocks.base.read_at(&mut buf, 0)?;
+        assert!(buf == data, "base was modified");
+        Ok(())
+    }
+}
@@ -0,0 +1,177 @@
+//! Backend over a POSIX shared-memory object, shared with other processes.
+
+use std::ffi::c_void;
+use std::fs::File;
+use std::io;
+use std::num::NonZeroUsize;
+use std::ptr::NonNull;
+
+use nix::fcntl::OFlag;
+use nix::sys::mman::{mmap, munmap, shm_open, MapFlags, ProtFlags};
+use nix::sys::stat::Mode;
+
+use super::Blocks;
+
+/// ShmBlocks exports a POSIX shared-memory object (see `shm_open(3)`), mapped
+/// into the server's memory, so that another process that maps the same
+/// object sees writes from NBD clients (and the other way around) without any
+/// copying.
+///
+/// The server does not coordinate with the other process: each side sees the
+/// other's writes as they happen, so they should agree on who writes which
+/// parts of the region.
+#[derive(Debug)]
+pub struct ShmBlocks {
+    name: String,
+    ptr: NonNull<u8>,
+    len: usize,
+}
+
+// The mapping is only accessed through read_at and write_at, which copy in and
+// out of it, like a file accessed with pread and pwrite.
+unsafe impl Send for ShmBlocks {}
+unsafe impl Sync for ShmBlocks {}
+
+impl ShmBlocks {
+    /// Map the first `size` bytes of the shared-memory object `name` (such as
+    /// "/vm-disk"), creating it if it does not exist and growing it to `size`
+    /// if it is smaller.
+    pub fn open(name: &str, size: u64) -> io::Result<Self> {
+        let len = NonZeroUsize::new(size as usize).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "cannot map an empty region")
+        })?;
+        let fd = shm_open(
+            name,
+            OFlag::O_RDWR | OFlag::O_CREAT,
+            Mode::S_IRUSR | Mode::S_IWUSR,
+        )?;
+        let file = File::from(fd);
+        if file.metadata()?.len() < size {
+            file.set_len(size)?;
+        }
+        // Safety: this is a new shared mapping, which does not alias any Rust
+        // memory. The mapping stays valid after the file is closed.
+        let ptr = unsafe {
+            mmap(
+                None,
+                len,
+                ProtFlags::PROT_READ | ProtFlags::PROT_WRITE,
+                MapFlags::MAP_SHARED,
+                &file,
+                0,
+            )?
+        };
+        Ok(Self {
+            name: name.to_string(),
+            ptr: ptr.cast(),
+            len: len.get(),
+        })
+    }
+
+    /// The name of the shared-memory object.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn check_bounds(&self, off: u64, len: usize) -> io::Result<usize> {
+        match (off as usize).checked_add(len) {
+            Some(end) if end <= self.len => Ok(off as usize),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "out-of-bounds access",
+            )),
+        }
+    }
+}
+
+impl Drop for ShmBlocks {
+    fn drop(&mut self) {
+        // Safety: the mapping was created in open and is not used after this.
+        // The object itself is left for other processes (see shm_unlink(3)).
+        let _ = unsafe { munmap(self.ptr.cast::<c_void>(), self.len) };
+    }
+}
+
+impl Blocks for ShmBlocks {
+    fn read_at(&self, buf: &mut [u8], off: u64) -> io::Result<()> {
+        let off = self.check_bounds(off, buf.len())?;
+        // Safety: the range is within the mapping, which never overlaps buf.
+        unsafe {
+            std::ptr::copy_nonoverlapping(self.ptr.as_ptr().add(off), buf.as_mut_ptr(), buf.len());
+        }
+        Ok(())
+    }
+
+    fn write_at(&self, buf: &[u8], off: u64) -> io::Result<()> {
+        let off = self.check_bounds(off, buf.len())?;
+        // Safety: as for read_at.
+        unsafe {
+            std::ptr::copy_nonoverlapping(buf.as_ptr(), self.ptr.as_ptr().add(off), buf.len());
+        }
+        Ok(())
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.len as u64)
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        // shared memory has no backing storage to flush to
+        Ok(())
+    }
+
+    fn supports_multi_conn(&self) -> bool {
+        true
+    }
+
+    fn cache(&self, _off: u64, _len: u64) -> io::Result<()> {
+        // already in memory
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::process;
+
+    use color_eyre::Result;
+    use nix::sys::mman::shm_unlink;
+
+    use super::ShmBlocks;
+    use crate::proto::*;
+    use crate::server::{Blocks, Server};
+
+    #[test]
+    fn test_shm_shared_with_second_mapping() -> Result<()> {
+        let name = format!("/nbd-test-{}", process::id());
+        let r = (|| -> Result<()> {
+            let server = Server::new(ShmBlocks::open(&name, 8192)?).op_log_level(None);
+            // like another process mapping the same object
+            let other = ShmBlocks::open(&name, 8192)?;
+
+            let mut request = vec![];
+            Request::new(Cmd::WRITE, 4096, 4).put(&[1, 2, 3, 4], &mut request)?;
+            let reply = server.serve_request(&request)?;
+            assert_eq!(
+                SimpleReply::get(&mut &reply[..], &mut [])?.err,
+                ErrorType::OK
+            );
+            let mut buf = [0u8; 4];
+            other.read_at(&mut buf, 4096)?;
+            assert_eq!(buf, [1, 2, 3, 4]);
+
+            // and the other way around
+            other.write_at(&[9; 4], 0)?;
+            let mut request = vec![];
+            Request::new(Cmd::READ, 0, 4).put(&[], &mut request)?;
+            let reply = server.serve_request(&request)?;
+            let reply = SimpleReply::get(&mut &reply[..], &mut buf)?;
+            assert_eq!((reply.err, buf), (ErrorType::OK, [9; 4]));
+
+            assert!(other.write_at(&[0; 4], 8190).is_err());
+            Ok(())
+        })();
+        shm_unlink(name.as_str())?;
+        r
+    }
+}
@@ -0,0 +1,379 @@
+//! Two-file snapshot backend: a read-only base image plus an active image
+//! that captures writes.
+
+use std::fs::File;
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::sync::Mutex;
+
+use super::{check_bounds, Blocks, SnapshotId};
+
+/// Granularity at which writes are captured in the active image.
+const BLOCK_SIZE: u64 = 4096;
+
+/// SnapshotBlocks exports a base image with writes redirected to a separate
+/// active image, so the base can be restored by discarding changes.
+///
+/// A sidecar bitmap file records which blocks have been written to the
+/// active image (one bit per 4096-byte block, least-significant bit first);
+/// reads of other blocks fall through to the base. The bitmap is persisted on
+/// every write, so reopening the same three files resumes the snapshot.
+///
+/// Use [`SnapshotBlocks::commit`] to merge the captured writes into the base
+/// and [`SnapshotBlocks::discard`] to drop them.
+///
+/// [`Blocks::snapshot`] commits, after which the base is a point-in-time view
+/// of the export (readable with [`SnapshotBlocks::read_snapshot`]) while new
+/// writes are captured in the active image.
+#[derive(Debug)]
+pub struct SnapshotBlocks {
+    base: File,
+    active: File,
+    bitmap_file: File,
+    size: u64,
+    // also serializes all operations, so that a block's data and bit are
+    // updated together
+    bitmap: Mutex<Vec<u8>>,
+    // incremented whenever the base changes (only while holding the bitmap
+    // lock)
+    generation: Mutex<u64>,
+}
+
+fn is_set(bitmap: &[u8], block: u64) -> bool {
+    bitmap[(block / 8) as usize] & (1 << (block % 8)) != 0
+}
+
+impl SnapshotBlocks {
+    /// Open a snapshot over `base`, using `active` and `bitmap` to store
+    /// writes.
+    ///
+    /// The export has the size of `base`. `active` and `bitmap` should be
+    /// empty files for a new snapshot, or the files from a previous
+    /// snapshot of the same base to resume it.
+    pub fn new(base: File, active: File, bitmap_file: File) -> io::Result<Self> {
+        let size = base.metadata()?.len();
+        let num_blocks = size.div_ceil(BLOCK_SIZE);
+        let bitmap_len = num_blocks.div_ceil(8);
+        if active.metadata()?.len() < size {
+            active.set_len(size)?;
+        }
+        let mut bitmap = vec![0u8; bitmap_len as usize];
+        let existing = bitmap_file.metadata()?.len();
+        if existing > 0 {
+            if existing != bitmap_len {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("snapshot bitmap has size {existing} (expected {bitmap_len})"),
+                ));
+            }
+            bitmap_file.read_exact_at(&mut bitmap, 0)?;
+        } else {
+            bitmap_file.write_all_at(&bitmap, 0)?;
+        }
+        Ok(Self {
+            base,
+            active,
+            bitmap_file,
+            size,
+            bitmap: Mutex::new(bitmap),
+            generation: Mutex::new(0),
+        })
+    }
+
+    /// Number of blocks currently captured in the active image.
+    pub fn dirty_blocks(&self) -> u64 {
+        let bitmap = self.bitmap.lock().unwrap();
+        bitmap.iter().map(|b| b.count_ones() as u64).sum()
+    }
+
+    /// Split [off, off+len) into pieces that each lie within one block.
+    fn chunks(off: u64, len: u64) -> impl Iterator<Item = (u64, u64, u64)> {
+        let end = off + len;
+        let mut pos = off;
+        std::iter::from_fn(move || {
+            if pos >= end {
+                return None;
+            }
+            let block = pos / BLOCK_SIZE;
+            let chunk_end = ((block + 1) * BLOCK_SIZE).min(end);
+            let chunk = (block, pos, chunk_end - pos);
+            pos = chunk_end;
+            Some(chunk)
+        })
+    }
+
+    fn block_len(&self, block: u64) -> u64 {
+        BLOCK_SIZE.min(self.size - block * BLOCK_SIZE)
+    }
+
+    /// Merge the captured writes into the base image and reset the snapshot.
+    ///
+    /// This replaces any snapshot taken with [`Blocks::snapshot`].
+    pub fn commit(&self) -> io::Result<()> {
+        let mut bitmap = self.bitmap.lock().unwrap();
+        self.commit_locked(&mut bitmap)?;
+        *self.generation.lock().unwrap() += 1;
+        Ok(())
+    }
+
+    fn commit_locked(&self, bitmap: &mut [u8]) -> io::Result<()> {
+        let mut buf = vec![0u8; BLOCK_SIZE as usize];
+        for block in 0..self.size.div_ceil(BLOCK_SIZE) {
+            if is_set(bitmap, block) {
+                let buf = &mut buf[..self.block_len(block) as usize];
+                self.active.read_exact_at(buf, block * BLOCK_SIZE)?;
+                self.base.write_all_at(buf, block * BLOCK_SIZE)?;
+            }
+        }
+        self.base.sync_all()?;
+        Self::clear(&self.bitmap_file, bitmap)
+    }
+
+    /// Read from the snapshot `id`, returned by [`Blocks::snapshot`].
+    ///
+    /// Only the latest snapshot can be read, and only until the next
+    /// [`SnapshotBlocks::commit`].
+    pub fn read_snapshot(&self, id: SnapshotId, buf: &mut [u8], off: u64) -> io::Result<()> {
+        check_bounds(self.size, off, buf.len() as u64)?;
+        // prevent a concurrent commit from changing the base
+        let _bitmap = self.bitmap.lock().unwrap();
+        let generation = *self.generation.lock().unwrap();
+        if id != SnapshotId(generation) {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("snapshot {id:?} has been replaced"),
+            ));
+        }
+        self.base.read_exact_at(buf, off)
+    }
+
+    /// Drop the captured writes, restoring the contents of the base image.
+    pub fn discard(&self) -> io::Result<()> {
+        let mut bitmap = self.bitmap.lock().unwrap();
+        Self::clear(&self.bitmap_file, &mut bitmap)
+    }
+
+    fn clear(bitmap_file: &File, bitmap: &mut [u8]) -> io::Result<()> {
+        bitmap.fill(0);
+        bitmap_file.write_all_at(bitmap, 0)?;
+        bitmap_file.sync_all()?;
+        Ok(())
+    }
+}
+
+impl Blocks for SnapshotBlocks {
+    fn read_at(&self, buf: &mut [u8], off: u64) -> io::Result<()> {
+        check_bounds(self.size, off, buf.len() as u64)?;
+        let bitmap = self.bitmap.lock().unwrap();
+        let mut buf_off = 0;
+        for (block, pos, len) in Self::chunks(off, buf.len() as u64) {
+            let chunk = &mut buf[buf_off..buf_off + len as usize];
+            if is_set(&bitmap, block) {
+                self.active.read_exact_at(chunk, pos)?;
+            } else {
+                self.base.read_exact_at(chunk, pos)?;
+            }
+            buf_off += len as usize;
+        }
+        Ok(())
+    }
+
+    fn write_at(&self, buf: &[u8], off: u64) -> io::Result<()> {
+        check_bounds(self.size, off, buf.len() as u64)?;
+        let mut bitmap = self.bitmap.lock().unwrap();
+        let mut buf_off = 0;
+        for (block, pos, len) in Self::chunks(off, buf.len() as u64) {
+            if !is_set(&bitmap, block) && len < self.block_len(block) {
+                // copy the rest of the block from the base before capturing
+                // a partial write
+                let mut data = vec![0u8; self.block_len(block) as usize];
+                self.base.read_exact_at(&mut data, block * BLOCK_SIZE)?;
+                self.active.write_all_at(&data, block * BLOCK_SIZE)?;
+            }
+            self.active
+                .write_all_at(&buf[buf_off..buf_off + len as usize], pos)?;
+            buf_off += len as usize;
+            if !is_set(&bitmap, block) {
+                let idx = (block / 8) as usize;
+                bitmap[idx] |= 1 << (block % 8);
+                self.bitmap_file
+                    .write_all_at(&bitmap[idx..idx + 1], idx as u64)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.size)
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        // data must be durable before the bitmap refers to it
+        self.active.sync_all()?;
+        self.bitmap_file.sync_all()?;
+        Ok(())
+    }
+
+    fn snapshot(&self) -> io::Result<SnapshotId> {
+        let mut bitmap = self.bitmap.lock().unwrap();
+        self.commit_locked(&mut bitmap)?;
+        let mut generation = self.generation.lock().unwrap();
+        *generation += 1;
+        Ok(SnapshotId(*generation))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{self, OpenOptions};
+    use std::path::PathBuf;
+
+    use color_eyre::Result;
+
+    use super::*;
+
+    struct TempFiles(Vec<PathBuf>);
+
+    impl TempFiles {
+        fn new(names: &[&str]) -> Self {
+            let id = rand::random::<u64>();
+            let dir = std::env::temp_dir();
+            Self(
+                names
+                    .iter()
+                    .map(|name| dir.join(format!("nbd-snapshot-{id}-{name}")))
+                    .collect(),
+            )
+        }
+
+        fn open(&self, i: usize) -> io::Result<File> {
+            OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(&self.0[i])
+        }
+
+        fn snapshot(&self) -> io::Result<SnapshotBlocks> {
+            SnapshotBlocks::new(self.open(0)?, self.open(1)?, self.open(2)?)
+        }
+    }
+
+    impl Drop for TempFiles {
+        fn drop(&mut self) {
+            for path in &self.0 {
+                let _ = fs::remove_file(path);
+            }
+        }
+    }
+
+    fn setup() -> Result<TempFiles> {
+        let files = TempFiles::new(&["base", "active", "bitmap"]);
+        let base = files.open(0)?;
+        base.write_all_at(&vec![1u8; 3 * BLOCK_SIZE as usize + 100], 0)?;
+        Ok(files)
+    }
+
+    #[test]
+    fn test_snapshot_read_through() -> Result<()> {
+        let files = setup()?;
+        let snap = files.snapshot()?;
+        assert_eq!(snap.size()?, 3 * BLOCK_SIZE + 100);
+        let mut buf = vec![0u8; 200];
+        snap.read_at(&mut buf, 3 * BLOCK_SIZE - 100)?;
+        assert_eq!(buf, vec![1u8; 200]);
+        assert_eq!(snap.dirty_blocks(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_snapshot_out_of_bounds() -> Result<()> {
+        let files = setup()?;
+        let snap = files.snapshot()?;
+        let mut buf = [0u8; 10];
+        // past the end, and far enough past that off + len overflows
+        for off in [3 * BLOCK_SIZE + 95, u64::MAX - 5] {
+            assert!(snap.read_at(&mut buf, off).is_err());
+            assert!(snap.write_at(&buf, off).is_err());
+        }
+        let id = snap.snapshot()?;
+        assert!(snap.read_snapshot(id, &mut buf, u64::MAX - 5).is_err());
+        assert_eq!(snap.dirty_blocks(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_snapshot_write_capture() -> Result<()> {
+        let files = setup()?;
+        let snap = files.snapshot()?;
+        // spans blocks 0 and 1
+        snap.write_at(&[2u8; 10], BLOCK_SIZE - 5)?;
+        assert_eq!(snap.dirty_blocks(), 2);
+
+        let mut buf = [0u8; 12];
+        snap.read_at(&mut buf, BLOCK_SIZE - 6)?;
+        assert_eq!(buf, [1, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 1]);
+
+        // base is untouched
+        let mut base_buf = [0u8; 10];
+        files
+            .open(0)?
+            .read_exact_at(&mut base_buf, BLOCK_SIZE - 5)?;
+        assert_eq!(base_buf, [1u8; 10]);
+
+        // the bitmap persists across reopening
+        snap.flush()?;
+        drop(snap);
+        let snap = files.snapshot()?;
+        assert_eq!(snap.dirty_blocks(), 2);
+        snap.read_at(&mut buf, BLOCK_SIZE - 6)?;
+        assert_eq!(buf, [1, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 1]);
+
+        snap.discard()?;
+        snap.read_at(&mut buf, BLOCK_SIZE - 6)?;
+        assert_eq!(buf, [1u8; 12]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_snapshot_commit() -> Result<()> {
+        let files = setup()?;
+        let snap = files.snapshot()?;
+        // write to the final, partial block
+        snap.write_at(&[3u8; 4], 3 * BLOCK_SIZE + 96)?;
+        snap.commit()?;
+        assert_eq!(snap.dirty_blocks(), 0);
+
+        let mut buf = [0u8; 6];
+        files
+            .open(0)?
+            .read_exact_at(&mut buf, 3 * BLOCK_SIZE + 94)?;
+        assert_eq!(buf, [1, 1, 3, 3, 3, 3]);
+        snap.read_at(&mut buf, 3 * BLOCK_SIZE + 94)?;
+        assert_eq!(buf, [1, 1, 3, 3, 3, 3]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_snapshot_point_in_time() -> Result<()> {
+        let files = setup()?;
+        let snap = files.snapshot()?;
+        snap.write_at(&[2u8; 10], 0)?;
+        let id = snap.snapshot()?;
+        assert_eq!(snap.dirty_blocks(), 0);
+
+        // writes after the snapshot are visible, but not in the snapshot
+        snap.write_at(&[3u8; 5], 0)?;
+        let mut buf = [0u8; 12];
+        snap.read_at(&mut buf, 0)?;
+        assert_eq!(buf, [3, 3, 3, 3, 3, 2, 2, 2, 2, 2, 1, 1]);
+        snap.read_snapshot(id, &mut buf, 0)?;
+        assert_eq!(buf, [2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 1, 1]);
+
+        // committing replaces the snapshot
+        snap.commit()?;
+        assert!(snap.read_snapshot(id, &mut buf, 0).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,269 @@
+//! Sparse in-memory backend.
+
+use std::collections::BTreeMap;
+use std::io;
+use std::sync::Mutex;
+
+use super::{check_bounds, Blocks, Extent};
+
+/// Granularity at which SparseMemBlocks allocates memory.
+const BLOCK_SIZE: u64 = 4096;
+
+#[derive(Debug)]
+struct SparseData {
+    size: u64,
+    /// Allocated blocks, by block number; every other block reads as zeros.
+    blocks: BTreeMap<u64, Box<[u8]>>,
+}
+
+/// SparseMemBlocks is an in-memory Blocks that only allocates memory for
+/// blocks that have been written, so it can export a large, mostly empty
+/// device.
+///
+/// Unallocated ranges are reported as holes by [`Blocks::extent_status`], and
+/// writing zeros (without NO_HOLE) or trimming deallocates whole blocks.
+#[derive(Debug)]
+pub struct SparseMemBlocks(Mutex<SparseData>);
+
+impl SparseMemBlocks {
+    /// Create an empty (all zero) SparseMemBlocks of `size` bytes.
+    pub fn new(size: u64) -> Self {
+        Self(Mutex::new(SparseData {
+            size,
+            blocks: BTreeMap::new(),
+        }))
+    }
+
+    /// Number of bytes of memory allocated to store data.
+    pub fn allocated_bytes(&self) -> u64 {
+        self.0.lock().unwrap().blocks.len() as u64 * BLOCK_SIZE
+    }
+}
+
+/// Split [off, off+len) into (block, offset within block, length) pieces.
+fn chunks(off: u64, len: u64) -> impl Iterator<Item = (u64, usize, usize)> {
+    let end = off + len;
+    let mut pos = off;
+    std::iter::from_fn(move || {
+        if pos >= end {
+            return None;
+        }
+        let block = pos / BLOCK_SIZE;
+        let chunk_end = ((block + 1) * BLOCK_SIZE).min(end);
+        let chunk = (
+            block,
+            (pos % BLOCK_SIZE) as usize,
+            (chunk_end - pos) as usize,
+        );
+        pos = chunk_end;
+        Some(chunk)
+    })
+}
+
+impl Blocks for SparseMemBlocks {
+    fn read_at(&self, buf: &mut [u8], off: u64) -> io::Result<()> {
+        let data = self.0.lock().unwrap();
+        check_bounds(data.size, off, buf.len() as u64)?;
+        let mut buf_off = 0;
+        for (block, block_off, len) in chunks(off, buf.len() as u64) {
+            let dst = &mut buf[buf_off..buf_off + len];
+            match data.blocks.get(&block) {
+                Some(b) => dst.copy_from_slice(&b[block_off..block_off + len]),
+                None => dst.fill(0),
+            }
+            buf_off += len;
+        }
+        Ok(())
+    }
+
+    fn write_at(&self, buf: &[u8], off: u64) -> io::Result<()> {
+        let mut data = self.0.lock().unwrap();
+        check_bounds(data.size, off, buf.len() as u64)?;
+        let mut buf_off = 0;
+        for (block, block_off, len) in chunks(off, buf.len() as u64) {
+            let b = data
+                .blocks
+                .entry(block)
+                .or_insert_with(|| vec![0u8; BLOCK_SIZE as usize].into_boxed_slice());
+            b[block_off..block_off + len].copy_from_slice(&buf[buf_off..buf_off + len]);
+            buf_off += len;
+        }
+        Ok(())
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.0.lock().unwrap().size)
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn supports_multi_conn(&self) -> bool {
+        true
+    }
+
+    fn cache(&self, _off: u64, _len: u64) -> io::Result<()> {
+        // already in memory
+        Ok(())
+    }
+
+    fn write_zeroes(&self, off: u64, len: u64, no_hole: bool) -> io::Result<()> {
+        let mut data = self.0.lock().unwrap();
+        check_bounds(data.size, off, len)?;
+        for (block, block_off, len) in chunks(off, len) {
+            if !no_hole && len == BLOCK_SIZE as usize {
+                data.blocks.remove(&block);
+                continue;
+            }
+            let b = data
+                .blocks
+                .entry(block)
+                .or_insert_with(|| vec![0u8; BLOCK_SIZE as usize].into_boxed_slice());
+            b[block_off..block_off + len].fill(0);
+        }
+        Ok(())
+    }
+
+    fn trim(&self, off: u64, len: u64) -> io::Result<()> {
+        self.write_zeroes(off, len, false)
+    }
+
+    fn supports_trim(&self) -> bool {
+        true
+    }
+
+    fn resize(&self, size: u64) -> io::Result<()> {
+        let mut data = self.0.lock().unwrap();
+        if size < data.size {
+            // zero the tail of the last block, so growing again reads zeros
+            let last = size / BLOCK_SIZE;
+            if let Some(b) = data.blocks.get_mut(&last) {
+                b[(size % BLOCK_SIZE) as usize..].fill(0);
+            }
+            data.blocks.split_off(&size.div_ceil(BLOCK_SIZE));
+        }
+        data.size = size;
+        Ok(())
+    }
+
+    fn supports_resize(&self) -> bool {
+        true
+    }
+
+    fn extent_status(&self, off: u64, len: u64) -> io::Result<Vec<Extent>> {
+        let data = self.0.lock().unwrap();
+        check_bounds(data.size, off, len)?;
+        let mut extents: Vec<Extent> = vec![];
+        for (block, _, len) in chunks(off, len) {
+            let hole = !data.blocks.contains_key(&block);
+            let extent = Extent {
+                len: len as u64,
+                hole,
+                zero: hole,
+            };
+            match extents.last_mut() {
+                Some(last) if last.hole == hole => last.len += extent.len,
+                _ => extents.push(extent),
+            }
+        }
+        Ok(extents)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use color_eyre::Result;
+
+    use super::*;
+
+    #[test]
+    fn test_sparse_read_write() -> Result<()> {
+        let blocks = SparseMemBlocks::new(BLOCK_SIZE * 100);
+        assert_eq!(blocks.allocated_bytes(), 0);
+
+        blocks.write_at(&[1, 2, 3], BLOCK_SIZE * 3 - 1)?;
+        assert_eq!(blocks.allocated_bytes(), 2 * BLOCK_SIZE);
+        let mut buf = [9u8; 5];
+        blocks.read_at(&mut buf, BLOCK_SIZE * 3 - 2)?;
+        assert_eq!(buf, [0, 1, 2, 3, 0]);
+
+        assert!(blocks.write_at(&[1], BLOCK_SIZE * 100).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_sparse_large() -> Result<()> {
+        let size = 100 << 30;
+        let blocks = SparseMemBlocks::new(size);
+        assert_eq!(blocks.size()?, size);
+        blocks.write_at(&[1, 2, 3], 10)?;
+        blocks.write_at(&[4, 5, 6], size - 3)?;
+        assert_eq!(blocks.0.lock().unwrap().blocks.len(), 2);
+
+        let mut buf = [9u8; 3];
+        blocks.read_at(&mut buf, size - 3)?;
+        assert_eq!(buf, [4, 5, 6]);
+        blocks.read_at(&mut buf, size / 2)?;
+        assert_eq!(buf, [0, 0, 0]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_sparse_extents() -> Result<()> {
+        let blocks = SparseMemBlocks::new(BLOCK_SIZE * 10);
+        blocks.write_at(&[1], BLOCK_SIZE * 2)?;
+        let extents = blocks.extent_status(BLOCK_SIZE, BLOCK_SIZE * 3)?;
+        assert_eq!(
+            extents,
+            [
+                Extent {
+                    len: BLOCK_SIZE,
+                    hole: true,
+                    zero: true
+                },
+                Extent {
+                    len: BLOCK_SIZE,
+                    hole: false,
+                    zero: false
+                },
+                Extent {
+                    len: BLOCK_SIZE,
+                    hole: true,
+                    zero: true
+                },
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_sparse_write_zeroes() -> Result<()> {
+        let blocks = SparseMemBlocks::new(BLOCK_SIZE * 10);
+        blocks.write_at(&vec![1u8; 3 * BLOCK_SIZE as usize], 0)?;
+        blocks.write_zeroes(BLOCK_SIZE / 2, 2 * BLOCK_SIZE, false)?;
+        // only the fully zeroed block is deallocated
+        assert_eq!(blocks.allocated_bytes(), 2 * BLOCK_SIZE);
+        blocks.write_zeroes(0, BLOCK_SIZE, true)?;
+        assert_eq!(blocks.allocated_bytes(), 2 * BLOCK_SIZE);
+
+        let mut buf = vec![1u8; 3 * BLOCK_SIZE as usize];
+        blocks.read_at(&mut buf, 0)?;
+        assert!(buf[..5 * BLOCK_SIZE as usize / 2].iter().all(|&b| b == 0));
+        assert!(buf[5 * BLOCK_SIZE as usize / 2..].iter().all(|&b| b == 1));
+        Ok(())
+    }
+
+    #[test]
+    fn test_sparse_resize() -> Result<()> {
+        let blocks = SparseMemBlocks::new(BLOCK_SIZE * 2);
+        blocks.write_at(&vec![1u8; 2 * BLOCK_SIZE as usize], 0)?;
+        blocks.resize(BLOCK_SIZE / 2)?;
+        blocks.resize(BLOCK_SIZE * 2)?;
+        assert_eq!(blocks.allocated_bytes(), BLOCK_SIZE);
+        let mut buf = [1u8; 2];
+        blocks.read_at(&mut buf, BLOCK_SIZE / 2 - 1)?;
+        assert_eq!(buf, [1, 0]);
+        Ok(())
+    }
+}
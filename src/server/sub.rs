@@ -0,0 +1,128 @@
+//! Export a sub-range of another Blocks.
+
+use std::io;
+use std::sync::Arc;
+
+use super::{check_bounds, Blocks, Extent};
+
+/// SubBlocks exports the byte range `[offset, offset+len)` of a shared
+/// Blocks, for example one partition of a disk image.
+///
+/// Several SubBlocks can share the same underlying Blocks to serve different
+/// ranges of it as separate exports.
+#[derive(Debug)]
+pub struct SubBlocks<F> {
+    inner: Arc<F>,
+    offset: u64,
+    len: u64,
+}
+
+impl<F> Clone for SubBlocks<F> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            offset: self.offset,
+            len: self.len,
+        }
+    }
+}
+
+impl<F: Blocks> SubBlocks<F> {
+    /// Export `len` bytes of `inner` starting at `offset`.
+    pub fn new(inner: Arc<F>, offset: u64, len: u64) -> Self {
+        Self { inner, offset, len }
+    }
+
+    /// Translate an access of `len` bytes at `off` to an offset in the
+    /// underlying Blocks.
+    fn translate(&self, off: u64, len: u64) -> io::Result<u64> {
+        check_bounds(self.len, off, len)?;
+        Ok(self.offset + off)
+    }
+}
+
+impl<F: Blocks> Blocks for SubBlocks<F> {
+    fn read_at(&self, buf: &mut [u8], off: u64) -> io::Result<()> {
+        let off = self.translate(off, buf.len() as u64)?;
+        self.inner.read_at(buf, off)
+    }
+
+    fn write_at(&self, buf: &[u8], off: u64) -> io::Result<()> {
+        let off = self.translate(off, buf.len() as u64)?;
+        self.inner.write_at(buf, off)
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.len)
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        self.inner.flush()
+    }
+
+    fn flush_range(&self, off: u64, len: u64) -> io::Result<()> {
+        let off = self.translate(off, len)?;
+        self.inner.flush_range(off, len)
+    }
+
+    fn supports_multi_conn(&self) -> bool {
+        self.inner.supports_multi_conn()
+    }
+
+    fn write_zeroes(&self, off: u64, len: u64, no_hole: bool) -> io::Result<()> {
+        let off = self.translate(off, len)?;
+        self.inner.write_zeroes(off, len, no_hole)
+    }
+
+    fn extent_status(&self, off: u64, len: u64) -> io::Result<Vec<Extent>> {
+        let off = self.translate(off, len)?;
+        self.inner.extent_status(off, len)
+    }
+
+    fn trim(&self, off: u64, len: u64) -> io::Result<()> {
+        let off = self.translate(off, len)?;
+        self.inner.trim(off, len)
+    }
+
+    fn supports_trim(&self) -> bool {
+        self.inner.supports_trim()
+    }
+
+    fn readahead(&self, off: u64, len: u64) -> io::Result<()> {
+        let off = self.translate(off, len)?;
+        self.inner.readahead(off, len)
+    }
+
+    fn cache(&self, off: u64, len: u64) -> io::Result<()> {
+        let off = self.translate(off, len)?;
+        self.inner.cache(off, len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use color_eyre::Result;
+
+    use super::*;
+    use crate::server::MemBlocks;
+
+    #[test]
+    fn test_sub_blocks() -> Result<()> {
+        let mem = Arc::new(MemBlocks::new(vec![0u8; 100]));
+        let a = SubBlocks::new(mem.clone(), 0, 50);
+        let b = SubBlocks::new(mem.clone(), 50, 30);
+        assert_eq!(b.size()?, 30);
+
+        b.write_at(&[1, 2], 0)?;
+        let mut buf = [0u8; 2];
+        mem.read_at(&mut buf, 50)?;
+        assert_eq!(buf, [1, 2]);
+        a.read_at(&mut buf, 48)?;
+        assert_eq!(buf, [0, 0]);
+
+        assert!(a.write_at(&[1, 2], 49).is_err());
+        assert!(b.read_at(&mut buf, 29).is_err());
+        assert!(b.read_at(&mut buf, u64::MAX).is_err());
+        Ok(())
+    }
+}
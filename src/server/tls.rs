@@ -0,0 +1,78 @@
+//! Upgrading connections to TLS with NBD_OPT_STARTTLS.
+
+use std::fmt;
+use std::io::prelude::*;
+#[cfg(feature = "tls")]
+use std::sync::Arc;
+
+use color_eyre::Result;
+#[cfg(feature = "tls")]
+use rustls::{ServerConfig, ServerConnection, StreamOwned};
+
+#[cfg(feature = "tls")]
+use super::{Blocks, Server};
+
+/// A byte stream in both directions, such as a connection or a TLS session
+/// running over one.
+pub(super) trait Stream: Read + Write {}
+
+impl<T: Read + Write> Stream for T {}
+
+/// The configuration for the server side of a TLS handshake (see
+/// [`Server::starttls`]).
+#[cfg(feature = "tls")]
+pub(super) struct TlsUpgrade(Arc<ServerConfig>);
+
+/// Without the `tls` feature, STARTTLS is never supported.
+#[cfg(not(feature = "tls"))]
+pub(super) enum TlsUpgrade {}
+
+impl TlsUpgrade {
+    /// Run the TLS handshake on `stream`, returning the TLS session to run
+    /// the rest of the connection over.
+    #[cfg(feature = "tls")]
+    pub(super) fn upgrade<'a>(&self, stream: Box<dyn Stream + 'a>) -> Result<Box<dyn Stream + 'a>> {
+        let conn = ServerConnection::new(self.0.clone())?;
+        let mut stream = StreamOwned::new(conn, stream);
+        // finish the handshake here so a failure is reported as one, rather
+        // than on the first read
+        while stream.conn.is_handshaking() {
+            stream.conn.complete_io(&mut stream.sock)?;
+        }
+        Ok(Box::new(stream))
+    }
+
+    #[cfg(not(feature = "tls"))]
+    pub(super) fn upgrade<'a>(
+        &self,
+        _stream: Box<dyn Stream + 'a>,
+    ) -> Result<Box<dyn Stream + 'a>> {
+        match *self {}
+    }
+}
+
+impl fmt::Debug for TlsUpgrade {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("TlsUpgrade")
+    }
+}
+
+#[cfg(feature = "tls")]
+impl<F: Blocks + Sync + Send + 'static> Server<F> {
+    /// Support TLS, by accepting NBD_OPT_STARTTLS and running the server side
+    /// of a TLS handshake with `config` (which has the server's certificate
+    /// and key).
+    ///
+    /// The rest of the negotiation and all transmission happen over TLS,
+    /// starting over as the spec requires (options such as structured
+    /// replies must be negotiated again). Without this, NBD_OPT_STARTTLS is
+    /// rejected with NBD_REP_ERR_UNSUP. Combine with [`Server::require_tls`]
+    /// to refuse clients that do not use TLS. TLS connections are always
+    /// served one request at a time, even with [`Server::workers`].
+    ///
+    /// Only available with the `tls` feature.
+    pub fn starttls(mut self, config: Arc<ServerConfig>) -> Self {
+        self.inner_mut().tls_upgrade = Some(TlsUpgrade(config));
+        self
+    }
+}
@@ -0,0 +1,183 @@
+//! Running a callback once clients have written a certain amount of data, for
+//! automating image creation.
+
+use std::fmt;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use log::{info, warn};
+
+use super::{Blocks, Export, Server};
+
+/// When the callback passed to [`Server::when_written`] runs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WriteCondition {
+    /// Once at least this many bytes have been written in total, counting
+    /// writes and write zeroes to every export.
+    TotalBytes(u64),
+    /// Once a write or write zeroes covers this offset (in any export), such
+    /// as a sentinel an installer writes last.
+    Offset(u64),
+}
+
+/// What the server does after the callback passed to
+/// [`Server::when_written`] runs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WriteAction {
+    /// Keep serving as before.
+    Continue,
+    /// Make every export read-only: from then on, writes, write zeroes,
+    /// trims, and resizes fail with EPERM.
+    StopWrites,
+}
+
+type Callback = dyn Fn(u64) -> WriteAction + Send + Sync;
+
+/// A [`WriteCondition`] and its callback, with the bytes written so far.
+pub(super) struct WriteTrigger {
+    condition: WriteCondition,
+    callback: Box<Callback>,
+    written: AtomicU64,
+    fired: AtomicBool,
+    stopped: AtomicBool,
+}
+
+impl fmt::Debug for WriteTrigger {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("WriteTrigger")
+            .field("condition", &self.condition)
+            .field("written", &self.written)
+            .field("fired", &self.fired)
+            .field("stopped", &self.stopped)
+            .finish()
+    }
+}
+
+impl WriteTrigger {
+    /// Writes have been stopped by the callback.
+    pub(super) fn stopped(&self) -> bool {
+        self.stopped.load(Ordering::SeqCst)
+    }
+
+    /// Record a successful write of `[off, off+len)` to `export`, firing the
+    /// callback if this write meets the condition.
+    pub(super) fn record<F: Blocks>(&self, export: &Export<F>, off: u64, len: u64) {
+        let written = self.written.fetch_add(len, Ordering::SeqCst) + len;
+        let met = match self.condition {
+            WriteCondition::TotalBytes(bytes) => written >= bytes,
+            WriteCondition::Offset(sentinel) => off <= sentinel && sentinel < off + len,
+        };
+        if !met || self.fired.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        info!(target: "nbd", "{:?} met after {written} bytes written", self.condition);
+        // the callback sees everything written so far on stable storage
+        if let Err(err) = export.flush() {
+            warn!(target: "nbd", "flush before write callback failed: {err}");
+        }
+        if (self.callback)(written) == WriteAction::StopWrites {
+            info!(target: "nbd", "stopping writes");
+            self.stopped.store(true, Ordering::SeqCst);
+        }
+    }
+}
+
+impl<F: Blocks + Sync + Send + 'static> Server<F> {
+    /// Call `callback` once `condition` is met, with the total number of bytes
+    /// written so far, for example to snapshot an image once an installer
+    /// has finished writing it.
+    ///
+    /// The export written to is flushed before the callback runs, and the
+    /// reply to the write that met the condition is sent after it returns.
+    /// The callback runs only once, and its result decides whether the server
+    /// keeps accepting writes.
+    pub fn when_written<C>(mut self, condition: WriteCondition, callback: C) -> Self
+    where
+        C: Fn(u64) -> WriteAction + Send + Sync + 'static,
+    {
+        self.inner_mut().write_trigger = Some(WriteTrigger {
+            condition,
+            callback: Box::new(callback),
+            written: AtomicU64::new(0),
+            fired: AtomicBool::new(false),
+            stopped: AtomicBool::new(false),
+        });
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use color_eyre::Result;
+
+    use super::{WriteAction, WriteCondition};
+    use crate::proto::*;
+    use crate::server::{MemBlocks, Server};
+
+    fn serve(server: &Server<MemBlocks>, req: Request, data: &[u8]) -> Result<ErrorType> {
+        let mut request = vec![];
+        req.put(data, &mut request)?;
+        let reply = server.serve_request(&request)?;
+        let reply_len = if req.typ == Cmd::READ { req.len } else { 0 };
+        let mut buf = vec![0u8; reply_len as usize];
+        Ok(SimpleReply::get(&mut &reply[..], &mut buf)?.err)
+    }
+
+    #[test]
+    fn test_when_written_total_bytes() -> Result<()> {
+        let calls = Arc::new(Mutex::new(vec![]));
+        let server = Server::new(MemBlocks::new(vec![0u8; 4096]))
+            .op_log_level(None)
+            .when_written(WriteCondition::TotalBytes(2500), {
+                let calls = calls.clone();
+                move |written| {
+                    calls.lock().unwrap().push(written);
+                    WriteAction::StopWrites
+                }
+            });
+
+        for i in 0..2 {
+            let req = Request::new(Cmd::WRITE, i * 1024, 1024);
+            assert_eq!(serve(&server, req, &[1; 1024])?, ErrorType::OK);
+        }
+        assert!(calls.lock().unwrap().is_empty());
+        let req = Request::new(Cmd::WRITE_ZEROES, 2048, 1024);
+        assert_eq!(serve(&server, req, &[])?, ErrorType::OK);
+        assert_eq!(*calls.lock().unwrap(), [3072]);
+
+        // writes are stopped, but reads still work
+        let req = Request::new(Cmd::WRITE, 3072, 1024);
+        assert_eq!(serve(&server, req, &[1; 1024])?, ErrorType::EPERM);
+        let req = Request::new(Cmd::TRIM, 0, 1024);
+        assert_eq!(serve(&server, req, &[])?, ErrorType::EPERM);
+        let req = Request::new(Cmd::READ, 0, 1024);
+        assert_eq!(serve(&server, req, &[])?, ErrorType::OK);
+        assert_eq!(calls.lock().unwrap().len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_when_written_offset() -> Result<()> {
+        let calls = Arc::new(Mutex::new(vec![]));
+        let server = Server::new(MemBlocks::new(vec![0u8; 4096]))
+            .op_log_level(None)
+            .when_written(WriteCondition::Offset(4000), {
+                let calls = calls.clone();
+                move |written| {
+                    calls.lock().unwrap().push(written);
+                    WriteAction::Continue
+                }
+            });
+
+        let req = Request::new(Cmd::WRITE, 0, 2048);
+        assert_eq!(serve(&server, req, &[1; 2048])?, ErrorType::OK);
+        let req = Request::new(Cmd::WRITE, 3968, 128);
+        assert_eq!(serve(&server, req, &[1; 128])?, ErrorType::OK);
+        // writing the sentinel again does not call the callback again
+        let req = Request::new(Cmd::WRITE, 4000, 1);
+        assert_eq!(serve(&server, req, &[1])?, ErrorType::OK);
+        assert_eq!(*calls.lock().unwrap(), [2048 + 128]);
+        Ok(())
+    }
+}
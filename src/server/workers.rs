@@ -0,0 +1,169 @@
+//! Handling a connection's requests concurrently, in a pool of workers.
+
+use std::io::{self, prelude::*, BufReader};
+use std::net::TcpStream;
+use std::os::unix::net::UnixStream;
+use std::sync::atomic::Ordering;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+
+use color_eyre::eyre::WrapErr;
+use color_eyre::Result;
+use log::{log, warn};
+
+use super::{allow_disconnect, Blocks, Negotiated, ServerInner, Session};
+use crate::proto::*;
+
+/// A connection that can be split into a read half and a write half, which
+/// can be used from different threads.
+pub(super) trait SplitStream: Read + Write + Send + Sized + 'static {
+    /// Get another handle to the connection, for writing.
+    fn try_split(&self) -> io::Result<Self>;
+}
+
+impl SplitStream for TcpStream {
+    fn try_split(&self) -> io::Result<Self> {
+        self.try_clone()
+    }
+}
+
+impl SplitStream for UnixStream {
+    fn try_split(&self) -> io::Result<Self> {
+        self.try_clone()
+    }
+}
+
+/// The read and write halves of a connection, used together for the
+/// handshake.
+struct Duplex<R, W>(R, W);
+
+impl<R: Read, W> Read for Duplex<R, W> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl<R, W: Write> Write for Duplex<R, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.1.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.1.flush()
+    }
+}
+
+impl<F: Blocks + Send + Sync> ServerInner<F> {
+    /// Handle a single client over a connection split into `reader` and
+    /// `writer`, and return on disconnect. Requests are handled by the
+    /// configured number of workers, or one at a time if there are none.
+    pub(super) fn handle_split_client<R: Read, W: Write + Send>(
+        &self,
+        reader: R,
+        writer: W,
+    ) -> Result<()> {
+        if self.workers == 0 {
+            return self.handle_client(Duplex(reader, writer));
+        }
+        self.connections.fetch_add(1, Ordering::SeqCst);
+        let r = self.serve_split_client(reader, writer);
+        self.connections.fetch_sub(1, Ordering::SeqCst);
+        r
+    }
+
+    fn serve_split_client<R: Read, W: Write + Send>(&self, reader: R, writer: W) -> Result<()> {
+        let r = match self.negotiate(Duplex(reader, writer))? {
+            Negotiated::Plain(session, Duplex(reader, writer)) => {
+                self.handle_ops_concurrently(&session, reader, writer)
+            }
+            // a TLS session cannot be split into a reader and a writer
+            Negotiated::Tls(session, mut stream) => self.handle_ops(&session, &mut stream),
+            Negotiated::Abort => return Ok(()),
+        };
+        allow_disconnect(r.wrap_err("handling client operations"))
+    }
+
+    /// Read requests and hand them to a pool of workers, which each write
+    /// their reply as soon as the request is done, so replies may be sent in
+    /// a different order than the requests arrived.
+    fn handle_ops_concurrently<R: Read, W: Write + Send>(
+        &self,
+        session: &Session<F>,
+        reader: R,
+        writer: W,
+    ) -> Result<()> {
+        let mut reader = BufReader::with_capacity(64 * 1024, reader);
+        let writer = Mutex::new(writer);
+        // each request comes with its write data, if any
+        let (tx, rx) = mpsc::channel::<(Request, Vec<u8>)>();
+        // dropped when every worker has stopped
+        let rx = Arc::new(Mutex::new(rx));
+        thread::scope(|s| {
+            let workers: Vec<_> = (0..self.workers)
+                .map(|_| {
+                    let rx = rx.clone();
+                    let writer = &writer;
+                    s.spawn(move || -> Result<()> {
+                        let mut buf = self.request_buf();
+                        let mut reply = vec![];
+                        loop {
+                            let next = rx.lock().unwrap().recv();
+                            let Ok((req, data)) = next else {
+                                return Ok(());
+                            };
+                            buf[..data.len()].copy_from_slice(&data);
+                            // build the whole reply before sending it, so
+                            // replies are not interleaved
+                            reply.clear();
+                            self.handle_request(session, &req, &mut buf, &mut reply)?;
+                            writer.lock().unwrap().write_all(&reply)?;
+                        }
+                    })
+                })
+                .collect();
+            drop(rx);
+            let r = self.dispatch_requests(&mut reader, &writer, tx);
+            for worker in workers {
+                worker.join().expect("worker panicked")?;
+            }
+            r
+        })
+    }
+
+    /// Read requests and send them to the workers, until the client
+    /// disconnects.
+    fn dispatch_requests<R: Read, W: Write>(
+        &self,
+        reader: &mut R,
+        writer: &Mutex<W>,
+        tx: mpsc::Sender<(Request, Vec<u8>)>,
+    ) -> Result<()> {
+        let mut buf = self.request_buf();
+        loop {
+            let req = match Request::get(reader, &mut buf) {
+                Ok(req) => req,
+                Err(err) => {
+                    if let Some(unknown) = err.downcast_ref::<UnknownCommand>() {
+                        warn!(target: "nbd", "{unknown}");
+                        unknown.reply().put(&mut *writer.lock().unwrap())?;
+                        continue;
+                    }
+                    return Err(err);
+                }
+            };
+            if let Some(level) = self.op_log_level {
+                log!(target: "nbd", level, "{:?}", req);
+            }
+            if req.typ == Cmd::DISCONNECT {
+                // the workers finish the requests already sent to them
+                return Ok(());
+            }
+            let data = buf[..req.data_len].to_vec();
+            // the workers only stop early if handling a request failed, which
+            // they report themselves
+            if tx.send((req, data)).is_err() {
+                return Ok(());
+            }
+        }
+    }
+}
@@ -1,22 +1,29 @@
 //! Integration tests for the client and server binaries.
-
+#![cfg(feature = "client-bin")]
 // On rust nightly, warning about not calling `.wait()` on server, but this is
 // actually called in `stop_server()`.
 #![allow(unknown_lints)]
 #![allow(clippy::zombie_processes)]
 
+#[cfg(feature = "tls")]
+use std::net::TcpStream;
 use std::os::unix::fs::FileExt;
 use std::path::{Path, PathBuf};
 use std::process;
+#[cfg(feature = "tls")]
+use std::sync::Arc;
 use std::{
     env,
-    fs::OpenOptions,
+    fs::{self, OpenOptions},
     process::{Command, Output},
     thread::sleep,
     time::Duration,
 };
 
 use color_eyre::Result;
+use nbd::client::{Client, NbdError};
+#[cfg(feature = "tls")]
+use nbd::rustls::{crypto::ring, ClientConfig, RootCertStore};
 use serial_test::serial;
 
 fn exe_path(name: &str) -> PathBuf {
@@ -35,8 +42,13 @@ fn cmd_stdout(out: Output) -> String {
 }
 
 fn start_server() -> process::Child {
+    start_server_with(&[])
+}
+
+fn start_server_with(args: &[&str]) -> process::Child {
     let server = Command::new(exe_path("server"))
         .args(["--size", "10"])
+        .args(args)
         .spawn()
         .expect("failed to start server");
     // wait for server to start listening for connections
@@ -84,6 +96,7 @@ fn test_client_help_flag() {
         .expect("failed to run client --help");
     let stdout = cmd_stdout(out);
     assert!(stdout.contains("client"));
+    assert!(stdout.contains("--socket"));
 }
 
 #[test]
@@ -96,6 +109,166 @@ fn test_server_help_flag() {
     assert!(stdout.contains("server"));
 }
 
+#[test]
+fn test_server_invalid_block_size() -> Result<()> {
+    let out = Command::new(exe_path("server"))
+        .args(["--mem", "--block-size", "1000"])
+        .output()?;
+    assert!(!out.status.success());
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    assert!(
+        stderr.contains("power of two") && !stderr.contains("panicked"),
+        "unexpected error: {stderr}"
+    );
+    Ok(())
+}
+
+#[test]
+fn test_server_export_out_of_bounds() -> Result<()> {
+    let path = env::temp_dir().join(format!("nbd-export-{}", process::id()));
+    fs::write(&path, [0u8; 1000])?;
+    let out = Command::new(exe_path("server"))
+        .arg("--export")
+        .arg(format!("p1={}:0:600", path.display()))
+        .arg("--export")
+        .arg(format!("p2={}:600:500", path.display()))
+        .output()?;
+    fs::remove_file(&path)?;
+    assert!(!out.status.success());
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    assert!(
+        stderr.contains("export p2 goes past the end"),
+        "unexpected error: {stderr}"
+    );
+    Ok(())
+}
+
+#[test]
+fn test_server_export_overflow() -> Result<()> {
+    let out = Command::new(exe_path("server"))
+        .arg("--export")
+        .arg(format!("p1=/dev/null:{}:2", u64::MAX))
+        .output()?;
+    assert!(!out.status.success());
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    assert!(
+        stderr.contains("overflows") && !stderr.contains("panicked"),
+        "unexpected error: {stderr}"
+    );
+    Ok(())
+}
+
+/// Write a self-signed certificate for "localhost" and its private key to
+/// PEM files named after `name`, returning their paths and a client
+/// configuration that trusts the certificate.
+#[cfg(feature = "tls")]
+fn tls_files(name: &str) -> Result<(PathBuf, PathBuf, Arc<ClientConfig>)> {
+    let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()])?;
+    let cert_path = env::temp_dir().join(format!("nbd-{name}-cert-{}.pem", process::id()));
+    let key_path = env::temp_dir().join(format!("nbd-{name}-key-{}.pem", process::id()));
+    fs::write(&cert_path, cert.cert.pem())?;
+    fs::write(&key_path, cert.key_pair.serialize_pem())?;
+    let mut roots = RootCertStore::empty();
+    roots.add(cert.cert.der().clone())?;
+    let config = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()?
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    Ok((cert_path, key_path, Arc::new(config)))
+}
+
+#[test]
+#[serial]
+#[cfg(feature = "tls")]
+fn test_server_tls() -> Result<()> {
+    let (cert, key, config) = tls_files("server-tls")?;
+    let server = start_server_with(&[
+        "--mem",
+        "--require-tls",
+        "--tls-cert",
+        cert.to_str().unwrap(),
+        "--tls-key",
+        key.to_str().unwrap(),
+    ]);
+    let r = (|| -> Result<()> {
+        let stream = TcpStream::connect(("127.0.0.1", 10809))?;
+        let mut client = Client::new_tls(stream, config.clone(), "localhost")?;
+        client.write(10, &[1, 2, 3])?;
+        assert_eq!(client.read(9, 5)?, [0, 1, 2, 3, 0]);
+        client.disconnect()?;
+        let mut client = Client::connect_tls("localhost", config.clone())?;
+        assert_eq!(client.read(10, 3)?, [1, 2, 3]);
+        client.disconnect()?;
+        match Client::connect("localhost") {
+            Ok(_) => panic!("connected without TLS"),
+            Err(err) => assert!(
+                err.to_string().contains("requires TLS"),
+                "unexpected error {err:?}"
+            ),
+        }
+        Ok(())
+    })();
+    stop_server(server);
+    fs::remove_file(&cert)?;
+    fs::remove_file(&key)?;
+    r
+}
+
+#[test]
+#[cfg(feature = "tls")]
+fn test_server_require_tls_without_cert() -> Result<()> {
+    let out = Command::new(exe_path("server"))
+        .args(["--mem", "--require-tls"])
+        .output()?;
+    assert!(!out.status.success());
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    assert!(stderr.contains("--tls-cert"), "unexpected error: {stderr}");
+    Ok(())
+}
+
+#[test]
+fn test_server_refuses_shrink() -> Result<()> {
+    let path = env::temp_dir().join(format!("nbd-shrink-{}", process::id()));
+    let data: Vec<u8> = (0..2 * 1024 * 1024).map(|i| i as u8).collect();
+    fs::write(&path, &data)?;
+    let out = Command::new(exe_path("server"))
+        .args(["--size", "1"])
+        .arg(&path)
+        .output()?;
+    let contents = fs::read(&path)?;
+    fs::remove_file(&path)?;
+    assert!(!out.status.success());
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    assert!(
+        stderr.contains("--allow-shrink"),
+        "unexpected error: {stderr}"
+    );
+    assert!(contents == data, "file was modified");
+    Ok(())
+}
+
+#[test]
+#[serial]
+fn test_copy_export() -> Result<()> {
+    let path = env::temp_dir().join(format!("nbd-copy-src-{}", process::id()));
+    let out = env::temp_dir().join(format!("nbd-copy-dst-{}", process::id()));
+    // the size start_server_with uses
+    let mut data = vec![0u8; 10 * 1024 * 1024];
+    data[4096..8192].fill(1);
+    data[1024 * 1024 + 10] = 2;
+    fs::write(&path, &data)?;
+
+    let server = start_server_with(&[path.to_str().unwrap()]);
+    let status = Command::new(exe_path("copy")).arg(&out).status()?;
+    stop_server(server);
+    let copy = fs::read(&out);
+    fs::remove_file(&path)?;
+    fs::remove_file(&out)?;
+    assert!(status.success());
+    assert!(copy? == data, "copy differs");
+    Ok(())
+}
+
 fn use_dev(path: &str) -> Result<()> {
     let f = OpenOptions::new().read(true).write(true).open(path)?;
 
@@ -148,6 +321,84 @@ fn test_connect_to_server() -> Result<()> {
     Ok(())
 }
 
+#[test]
+#[serial]
+#[cfg(feature = "tls")]
+#[cfg_attr(not(target_os = "linux"), ignore)]
+fn test_connect_over_tls() -> Result<()> {
+    let dev = "/dev/nbd1";
+    if !Path::new(dev).exists() {
+        eprintln!("nbd is not set up (run sudo modprobe nbd)");
+        return Ok(());
+    }
+
+    let (cert, key, _) = tls_files("client-tls")?;
+    let server = start_server_with(&[
+        "--mem",
+        "--require-tls",
+        "--tls-cert",
+        cert.to_str().unwrap(),
+        "--tls-key",
+        key.to_str().unwrap(),
+    ]);
+
+    // the client keeps running in the background to relay the TLS session
+    let s = Command::new(exe_path("client"))
+        .arg("--tls")
+        .arg("--ca-file")
+        .arg(&cert)
+        .arg(dev)
+        .status()?;
+    assert!(s.success());
+    sleep(Duration::from_millis(100));
+    make_public(dev);
+    use_dev(dev)?;
+    client_disconnect(dev);
+
+    stop_server(server);
+    fs::remove_file(&cert)?;
+    fs::remove_file(&key)?;
+    Ok(())
+}
+
+#[test]
+#[cfg(feature = "tls")]
+fn test_client_tls_needs_ca_file() -> Result<()> {
+    let out = Command::new(exe_path("client"))
+        .args(["--tls", "/dev/nbd1"])
+        .output()?;
+    assert!(!out.status.success());
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    assert!(stderr.contains("--ca-file"), "unexpected error: {stderr}");
+    Ok(())
+}
+
+#[test]
+#[serial]
+#[cfg_attr(not(target_os = "linux"), ignore)]
+fn test_client_timeout_zero() -> Result<()> {
+    let dev = "/dev/nbd1";
+    if !Path::new(dev).exists() {
+        eprintln!("nbd is not set up (run sudo modprobe nbd)");
+        return Ok(());
+    }
+
+    let server = start_server();
+
+    // --timeout 0 means no timeout, as without the flag
+    let s = Command::new(exe_path("client"))
+        .args(["--timeout", "0", dev])
+        .status()?;
+    assert!(s.success(), "client --timeout 0 failed: {s}");
+    sleep(Duration::from_millis(100));
+    make_public(dev);
+    use_dev(dev)?;
+    client_disconnect(dev);
+
+    stop_server(server);
+    Ok(())
+}
+
 #[test]
 // serialize because both tests connect to the same port
 #[serial]
@@ -256,3 +507,227 @@ fn test_multiple_connections() -> Result<()> {
     stop_server(server);
     Ok(())
 }
+
+#[test]
+#[serial]
+#[cfg_attr(not(target_os = "linux"), ignore)]
+fn test_device_block_size() -> Result<()> {
+    let dev = "/dev/nbd1";
+    if !Path::new(dev).exists() {
+        eprintln!("nbd is not set up (run sudo modprobe nbd)");
+        return Ok(());
+    }
+
+    let server = start_server_with(&["--block-size", "1024"]);
+
+    client_connect(dev);
+    let blksize = fs::read_to_string("/sys/block/nbd1/queue/logical_block_size")?;
+    assert_eq!(blksize.trim(), "1024");
+    client_disconnect(dev);
+
+    stop_server(server);
+    Ok(())
+}
+
+#[test]
+#[serial]
+#[cfg_attr(not(target_os = "linux"), ignore)]
+fn test_device_read_only() -> Result<()> {
+    let dev = "/dev/nbd1";
+    if !Path::new(dev).exists() {
+        eprintln!("nbd is not set up (run sudo modprobe nbd)");
+        return Ok(());
+    }
+
+    let server = start_server_with(&["--mem", "--read-only"]);
+
+    client_connect(dev);
+    let ro = fs::read_to_string("/sys/block/nbd1/ro")?;
+    assert_eq!(ro.trim(), "1");
+    client_disconnect(dev);
+
+    stop_server(server);
+    Ok(())
+}
+
+#[test]
+#[serial]
+#[cfg_attr(not(target_os = "linux"), ignore)]
+fn test_device_in_use() -> Result<()> {
+    let dev = "/dev/nbd1";
+    if !Path::new(dev).exists() {
+        eprintln!("nbd is not set up (run sudo modprobe nbd)");
+        return Ok(());
+    }
+
+    let server = start_server();
+    client_connect(dev);
+
+    // a second connection to the same device is refused
+    let out = Command::new(exe_path("client")).arg(dev).output()?;
+    assert!(!out.status.success());
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    assert!(
+        stderr.contains("already in use by pid"),
+        "unexpected error: {stderr}"
+    );
+
+    // unless forced
+    let s = Command::new(exe_path("client"))
+        .args(["--force", dev])
+        .status()?;
+    assert!(s.success());
+    sleep(Duration::from_millis(100));
+    make_public(dev);
+    use_dev(dev)?;
+    client_disconnect(dev);
+
+    stop_server(server);
+    Ok(())
+}
+
+/// Run `program` as root, using sudo unless this process already is root.
+fn root_command(program: &str) -> Command {
+    let is_root = Command::new("id")
+        .arg("-u")
+        .output()
+        .is_ok_and(|out| cmd_stdout(out).trim() == "0");
+    if is_root {
+        return Command::new(program);
+    }
+    let mut cmd = Command::new("sudo");
+    cmd.arg(program);
+    cmd
+}
+
+#[test]
+#[serial]
+#[cfg_attr(not(target_os = "linux"), ignore)]
+fn test_export_partition() -> Result<()> {
+    // a 4 MiB disk with an MBR partition table holding one 2 MiB partition,
+    // starting at 1 MiB
+    let img = env::temp_dir().join(format!("nbd-partition-{}", process::id()));
+    let mut disk = vec![0u8; 4 << 20];
+    disk[446 + 4] = 0x83; // partition type (Linux)
+    disk[446 + 8..446 + 12].copy_from_slice(&2048u32.to_le_bytes());
+    disk[446 + 12..446 + 16].copy_from_slice(&4096u32.to_le_bytes());
+    disk[510..512].copy_from_slice(&[0x55, 0xaa]);
+    fs::write(&img, &disk)?;
+
+    let out = root_command("losetup")
+        .args(["--find", "--show", "--partscan"])
+        .arg(&img)
+        .output();
+    let loop_dev = match out {
+        Ok(out) if out.status.success() => cmd_stdout(out).trim().to_string(),
+        _ => {
+            eprintln!("could not set up a loop device");
+            fs::remove_file(&img)?;
+            return Ok(());
+        }
+    };
+    sleep(Duration::from_millis(100));
+    let partition = format!("{loop_dev}p1");
+    let (dev, size) = if Path::new(&partition).exists() {
+        (partition, 2 << 20)
+    } else {
+        eprintln!("partitions of loop devices are not available, exporting {loop_dev}");
+        (loop_dev.clone(), 4 << 20)
+    };
+    root_command("chmod").args(["a+rw", &dev]).status()?;
+
+    let server = start_server_with(&[&dev]);
+    let result = (|| -> Result<()> {
+        let mut client = Client::connect("localhost")?;
+        assert_eq!(client.size(), size);
+        client.write(size - 4, &[1, 2, 3, 4])?;
+        let err = client.write(size - 2, &[1, 2, 3, 4]).unwrap_err();
+        assert!(
+            matches!(
+                err.downcast_ref::<NbdError>(),
+                Some(NbdError::Server { errno: 28, .. })
+            ),
+            "expected ENOSPC, got {err:?}"
+        );
+        client.disconnect()
+    })();
+    stop_server(server);
+    root_command("losetup").arg("-d").arg(&loop_dev).status()?;
+    fs::remove_file(&img)?;
+    result
+}
+
+/// Run a short fio job against the kernel device, which exercises the request
+/// patterns the kernel actually sends (including flushes and FUA writes
+/// under load).
+///
+/// This is slow, so it only runs if NBD_TEST_FIO is set (and fio is
+/// installed).
+#[test]
+#[serial]
+#[cfg_attr(not(target_os = "linux"), ignore)]
+fn test_fio() -> Result<()> {
+    let dev = "/dev/nbd1";
+    if env::var_os("NBD_TEST_FIO").is_none() {
+        eprintln!("skipping fio test (set NBD_TEST_FIO to run it)");
+        return Ok(());
+    }
+    if Command::new("fio").arg("--version").output().is_err() {
+        eprintln!("fio is not installed");
+        return Ok(());
+    }
+    if !Path::new(dev).exists() {
+        eprintln!("nbd is not set up (run sudo modprobe nbd)");
+        return Ok(());
+    }
+
+    let server = start_server();
+    client_connect(dev);
+    make_public(dev);
+
+    let result = (|| -> Result<()> {
+        // each job starts once the previous one finishes
+        let jobs: [&[&str]; 2] = [
+            &["--name=randrw", "--rw=randrw", "--bs=4k", "--fsync=32"],
+            // O_SYNC direct writes are sent as FUA writes
+            &[
+                "--name=seq",
+                "--rw=rw",
+                "--bs=256k",
+                "--sync=1",
+                "--stonewall",
+            ],
+        ];
+        let mut cmd = Command::new("fio");
+        cmd.arg(format!("--filename={dev}"))
+            .args(["--direct=1", "--ioengine=psync", "--size=8M"])
+            .args(["--time_based", "--runtime=2"]);
+        for job in jobs {
+            cmd.args(job);
+        }
+        let out = cmd.output()?;
+        let stdout = String::from_utf8_lossy(&out.stdout);
+        assert!(
+            out.status.success(),
+            "fio failed: {stdout}{}",
+            String::from_utf8_lossy(&out.stderr)
+        );
+        assert!(
+            stdout
+                .lines()
+                .filter(|l| l.contains("err="))
+                .all(|l| l.contains("err= 0")),
+            "fio reported errors:\n{stdout}"
+        );
+        // report throughput
+        for line in stdout.lines() {
+            if line.trim_start().starts_with("READ:") || line.trim_start().starts_with("WRITE:") {
+                eprintln!("{}", line.trim());
+            }
+        }
+        Ok(())
+    })();
+    client_disconnect(dev);
+    stop_server(server);
+    result
+}
@@ -0,0 +1,84 @@
+//! Benchmark for sequential FUA writes to a file, comparing the old behavior
+//! of a full `fsync` per write with [`Blocks::flush_range`].
+//!
+//! Ignored by default; run with
+//! `cargo test --release --test fua -- --ignored --nocapture`.
+
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::thread;
+use std::time::Instant;
+
+use color_eyre::Result;
+use readwrite::ReadWrite;
+
+use nbd::client::Client;
+use nbd::server::{Blocks, Server};
+
+const FILE_SIZE: u64 = 64 * 1024 * 1024;
+const WRITE_SIZE: usize = 64 * 1024;
+
+/// A file that syncs data and metadata on every flush, like the server did
+/// before [`Blocks::flush_range`].
+struct SyncAllFile(File);
+
+impl Blocks for SyncAllFile {
+    fn read_at(&self, buf: &mut [u8], off: u64) -> io::Result<()> {
+        self.0.read_exact_at(buf, off)
+    }
+
+    fn write_at(&self, buf: &[u8], off: u64) -> io::Result<()> {
+        self.0.write_all_at(buf, off)
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.0.metadata()?.len())
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        self.0.sync_all()
+    }
+}
+
+/// Write all of the export sequentially with FUA writes, returning the
+/// throughput in MB/s.
+fn sequential_fua_writes<F: Blocks + Send + Sync + 'static>(blocks: F) -> Result<f64> {
+    let (r1, w1) = pipe::pipe();
+    let (r2, w2) = pipe::pipe();
+    let server = Server::new(blocks).op_log_level(None);
+    let server = thread::spawn(move || server.handle_client(ReadWrite::new(r1, w2)));
+    let mut client = Client::new(ReadWrite::new(r2, w1))?;
+
+    let data = vec![1u8; WRITE_SIZE];
+    let start = Instant::now();
+    let mut off = 0;
+    while off < FILE_SIZE {
+        client.write_fua(off, &data)?;
+        off += WRITE_SIZE as u64;
+    }
+    let elapsed = start.elapsed();
+
+    client.disconnect()?;
+    server.join().unwrap()?;
+    Ok(FILE_SIZE as f64 / 1e6 / elapsed.as_secs_f64())
+}
+
+#[test]
+#[ignore]
+fn bench_fua_writes() -> Result<()> {
+    let path = std::env::temp_dir().join(format!("nbd-fua-{}", rand::random::<u64>()));
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create_new(true)
+        .open(&path)?;
+    file.set_len(FILE_SIZE)?;
+    let open = || OpenOptions::new().read(true).write(true).open(&path);
+
+    let before = sequential_fua_writes(SyncAllFile(open()?))?;
+    let after = sequential_fua_writes(open()?)?;
+    std::fs::remove_file(&path)?;
+    println!("sequential FUA writes: {before:.0} MB/s with sync_all, {after:.0} MB/s now");
+    Ok(())
+}
@@ -0,0 +1,139 @@
+//! Tests for per-request logging, which need their own process since they
+//! install a global logger.
+
+use std::io;
+use std::sync::{Mutex, Once};
+use std::thread;
+use std::time::Duration;
+
+use color_eyre::Result;
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use readwrite::ReadWrite;
+use serial_test::serial;
+
+use nbd::client::Client;
+use nbd::server::{Blocks, MemBlocks, Server};
+
+/// Logger that records the messages logged to the nbd target.
+struct CaptureLogger(Mutex<Vec<String>>);
+
+impl Log for CaptureLogger {
+    fn enabled(&self, _metadata: &Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &Record) {
+        if record.target() == "nbd" {
+            self.0.lock().unwrap().push(format!("{}", record.args()));
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: CaptureLogger = CaptureLogger(Mutex::new(vec![]));
+
+fn init_logger() {
+    static INIT: Once = Once::new();
+    INIT.call_once(|| {
+        log::set_logger(&LOGGER).unwrap();
+        log::set_max_level(LevelFilter::Trace);
+    });
+}
+
+fn run_ops(level: Option<Level>) -> Result<Vec<String>> {
+    let (r1, w1) = pipe::pipe();
+    let (r2, w2) = pipe::pipe();
+    let s1 = ReadWrite::new(r1, w2);
+    let s2 = ReadWrite::new(r2, w1);
+
+    let server = thread::spawn(move || -> Result<()> {
+        let server = Server::new(MemBlocks::new(vec![0u8; 1024])).op_log_level(level);
+        server.handle_client(s1)?;
+        Ok(())
+    });
+
+    LOGGER.0.lock().unwrap().clear();
+    let mut client = Client::new(s2)?;
+    client.write(0, &[1, 2, 3])?;
+    client.read(0, 3)?;
+    client.flush()?;
+    client.disconnect()?;
+    server.join().unwrap()?;
+
+    let logs = LOGGER.0.lock().unwrap().clone();
+    Ok(logs
+        .into_iter()
+        .filter(|msg| msg.starts_with("Request"))
+        .collect())
+}
+
+#[test]
+#[serial]
+fn test_op_log_level() -> Result<()> {
+    init_logger();
+
+    let logs = run_ops(Some(Level::Debug))?;
+    assert_eq!(logs.len(), 4, "unexpected request logs {logs:?}");
+
+    let logs = run_ops(None)?;
+    assert!(logs.is_empty(), "unexpected request logs {logs:?}");
+    Ok(())
+}
+
+/// A backend with slow reads.
+struct SlowBlocks(MemBlocks);
+
+impl Blocks for SlowBlocks {
+    fn read_at(&self, buf: &mut [u8], off: u64) -> io::Result<()> {
+        thread::sleep(Duration::from_millis(200));
+        self.0.read_at(buf, off)
+    }
+
+    fn write_at(&self, buf: &[u8], off: u64) -> io::Result<()> {
+        self.0.write_at(buf, off)
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        self.0.size()
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+#[test]
+#[serial]
+fn test_slow_op_warning() -> Result<()> {
+    init_logger();
+    let (r1, w1) = pipe::pipe();
+    let (r2, w2) = pipe::pipe();
+    let s1 = ReadWrite::new(r1, w2);
+    let s2 = ReadWrite::new(r2, w1);
+
+    let server = thread::spawn(move || -> Result<()> {
+        let server = Server::new(SlowBlocks(MemBlocks::new(vec![0u8; 1024])))
+            .op_log_level(None)
+            .slow_op_warning(Some(Duration::from_millis(50)));
+        server.handle_client(s1)?;
+        Ok(())
+    });
+
+    LOGGER.0.lock().unwrap().clear();
+    let mut client = Client::new(s2)?;
+    client.write(0, &[1, 2, 3])?;
+    client.read(100, 3)?;
+    client.disconnect()?;
+    server.join().unwrap()?;
+
+    let logs = LOGGER.0.lock().unwrap().clone();
+    let warnings: Vec<_> = logs
+        .iter()
+        .filter(|msg| msg.contains("has been running"))
+        .collect();
+    assert_eq!(warnings.len(), 1, "unexpected logs {logs:?}");
+    assert!(warnings[0].contains("READ"), "{}", warnings[0]);
+    assert!(warnings[0].contains("offset: 100"), "{}", warnings[0]);
+    Ok(())
+}
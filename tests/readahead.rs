@@ -0,0 +1,60 @@
+//! Benchmark for sequential reads with and without [`Server::readahead`].
+//!
+//! Ignored by default; run with
+//! `cargo test --release --test readahead -- --ignored --nocapture`. The
+//! difference is only visible when the file is not already in the page cache
+//! (for example, after `echo 1 > /proc/sys/vm/drop_caches`).
+
+use std::fs::{File, OpenOptions};
+use std::thread;
+use std::time::Instant;
+
+use color_eyre::Result;
+use readwrite::ReadWrite;
+
+use nbd::client::Client;
+use nbd::server::Server;
+
+const FILE_SIZE: u64 = 256 * 1024 * 1024;
+const READ_SIZE: u32 = 128 * 1024;
+
+/// Read all of `file` sequentially through a server, returning the
+/// throughput in MB/s.
+fn sequential_read(file: File, readahead: bool) -> Result<f64> {
+    let (r1, w1) = pipe::pipe();
+    let (r2, w2) = pipe::pipe();
+    let server = Server::new(file).op_log_level(None).readahead(readahead);
+    let server = thread::spawn(move || server.handle_client(ReadWrite::new(r1, w2)));
+    let mut client = Client::new(ReadWrite::new(r2, w1))?;
+
+    let start = Instant::now();
+    let mut off = 0;
+    while off < FILE_SIZE {
+        client.read(off, READ_SIZE)?;
+        off += READ_SIZE as u64;
+    }
+    let elapsed = start.elapsed();
+
+    client.disconnect()?;
+    server.join().unwrap()?;
+    Ok(FILE_SIZE as f64 / 1e6 / elapsed.as_secs_f64())
+}
+
+#[test]
+#[ignore]
+fn bench_readahead() -> Result<()> {
+    let path = std::env::temp_dir().join(format!("nbd-readahead-{}", rand::random::<u64>()));
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create_new(true)
+        .open(&path)?;
+    file.set_len(FILE_SIZE)?;
+    let open = || OpenOptions::new().read(true).write(true).open(&path);
+
+    let without = sequential_read(open()?, false)?;
+    let with = sequential_read(open()?, true)?;
+    std::fs::remove_file(&path)?;
+    println!("sequential reads: {without:.0} MB/s without readahead, {with:.0} MB/s with");
+    Ok(())
+}